@@ -0,0 +1,143 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Keys the clients spread their commands over
+const KEYS: usize = 10_000;
+/// Commands each client sends per round trip
+const PIPELINE: usize = 64;
+/// Round trips each client makes for a measurement
+const ROUNDS: usize = 500;
+
+/// The server under test, stopped when dropped
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start() -> Self {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("a free port")
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--loglevel",
+                "warning",
+            ])
+            .args(["--dir", &std::env::temp_dir().display().to_string()])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("the server starts");
+        let server = Self { child, port };
+        for _ in 0..100 {
+            if TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("the server did not start listening on port {port}");
+    }
+
+    fn connect(&self) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).expect("connected");
+        stream.set_nodelay(true).expect("no delay");
+        BufReader::new(stream)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        bytes.extend(format!("${}\r\n{arg}\r\n", arg.len()).as_bytes());
+    }
+    bytes
+}
+
+/// Read one reply, skipping over the body of bulk strings
+fn read_reply(reader: &mut BufReader<TcpStream>, line: &mut String) {
+    line.clear();
+    reader.read_line(line).expect("a reply");
+    if let Some(len) = line.strip_prefix('$') {
+        if let Ok(len) = len.trim_end().parse::<usize>() {
+            let mut body = vec![0; len + 2];
+            reader.read_exact(&mut body).expect("the bulk string");
+        }
+    }
+}
+
+/// Commands per second `clients` connections get through together,
+/// each sending pipelines of the commands `make` builds for a key
+fn measure(server: &Server, clients: usize, make: fn(&str) -> Vec<u8>) -> f64 {
+    let start = Instant::now();
+    let workers: Vec<_> = (0..clients)
+        .map(|client| {
+            let mut reader = server.connect();
+            thread::spawn(move || {
+                let mut line = String::new();
+                for round in 0..ROUNDS {
+                    let batch: Vec<u8> = (0..PIPELINE)
+                        .flat_map(|i| {
+                            let key = (client * 7919 + round * PIPELINE + i) % KEYS;
+                            make(&format!("key:{key}"))
+                        })
+                        .collect();
+                    reader.get_mut().write_all(&batch).expect("sent");
+                    for _ in 0..PIPELINE {
+                        read_reply(&mut reader, &mut line);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("the client finished");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let commands = (clients * ROUNDS * PIPELINE) as f64;
+    commands / start.elapsed().as_secs_f64()
+}
+
+/// Throughput of reads and writes as clients are added. Reads and writes of keys
+/// in different shards run in parallel, so both keep scaling with the cores.
+///
+/// Run with `cargo bench`.
+fn main() {
+    let server = Server::start();
+    let mut reader = server.connect();
+    let mut line = String::new();
+    for key in 0..KEYS {
+        let key = format!("key:{key}");
+        reader
+            .get_mut()
+            .write_all(&command(&["SET", &key, "value"]))
+            .expect("sent");
+        read_reply(&mut reader, &mut line);
+    }
+    let cores = thread::available_parallelism().map_or(1, usize::from);
+    println!("{cores} cores, {PIPELINE} commands per pipeline");
+    println!("{:>8} {:>14} {:>14}", "clients", "GET/s", "SET/s");
+    let mut clients = 1;
+    while clients <= 2 * cores {
+        let gets = measure(&server, clients, |key| command(&["GET", key]));
+        let sets = measure(&server, clients, |key| command(&["SET", key, "value"]));
+        println!("{clients:>8} {gets:>14.0} {sets:>14.0}");
+        clients *= 2;
+    }
+}
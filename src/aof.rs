@@ -0,0 +1,638 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::ValueEnum;
+use std::{
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::{
+    cmd::Command,
+    rdb, replication,
+    resp::RespData,
+    state::{AppState, State},
+};
+
+/// When writes to the AOF are flushed to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AppendFsync {
+    /// After every write command
+    Always,
+    /// Once a second, in the background
+    #[default]
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
+
+/// The role of a file listed in the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileType {
+    /// A snapshot the incremental files apply on top of
+    Base,
+    /// Commands appended since the base was written
+    Incr,
+    /// Left over from before a rewrite, deleted once the new manifest is in place
+    History,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    file_type: FileType,
+}
+
+/// The files making up the AOF, in the Redis 7 multi-part layout
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+    history: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Parse `file <name> seq <n> type <b|i|h>` lines
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in words.chunks(2) {
+                let [key, value] = pair else {
+                    bail!("Invalid AOF manifest line `{line}`");
+                };
+                match *key {
+                    "file" => name = Some(value.to_string()),
+                    "seq" => seq = Some(value.parse()?),
+                    "type" => {
+                        file_type = Some(match *value {
+                            "b" => FileType::Base,
+                            "i" => FileType::Incr,
+                            "h" => FileType::History,
+                            _ => bail!("Unknown AOF file type `{value}`"),
+                        });
+                    }
+                    // Unknown keys are skipped, as newer versions may add some
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(file_type)) = (name, seq, file_type) else {
+                bail!("Invalid AOF manifest line `{line}`");
+            };
+            let file = AofFile {
+                name,
+                seq,
+                file_type,
+            };
+            match file_type {
+                FileType::Base if manifest.base.is_some() => {
+                    bail!("Found duplicate base file information")
+                }
+                FileType::Base => manifest.base = Some(file),
+                FileType::Incr => manifest.incrs.push(file),
+                FileType::History => manifest.history.push(file),
+            }
+        }
+        manifest.incrs.sort_by_key(|file| file.seq);
+        Ok(manifest)
+    }
+
+    /// The files to load, in order
+    fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in self.files().chain(&self.history) {
+            let file_type = match file.file_type {
+                FileType::Base => "b",
+                FileType::Incr => "i",
+                FileType::History => "h",
+            };
+            writeln!(f, "file {} seq {} type {file_type}", file.name, file.seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// A BGREWRITEAOF writing a new base file on its own thread
+#[derive(Debug)]
+struct BackgroundRewrite {
+    thread: JoinHandle<anyhow::Result<()>>,
+    base: AofFile,
+    /// The first incremental file not covered by the new base
+    incr_seq: u64,
+}
+
+#[derive(Debug)]
+pub struct AofState {
+    pub enabled: bool,
+    pub fsync: AppendFsync,
+    /// Directory of the AOF directory, the same as for RDB files
+    pub dir: PathBuf,
+    pub dirname: String,
+    pub filename: String,
+    /// Load an AOF whose last command was cut short, dropping that command
+    pub load_truncated: bool,
+    pub last_rewrite_ok: bool,
+    manifest: Manifest,
+    /// The incremental file write commands are appended to
+    file: Option<File>,
+    /// Whether writes were made since the last fsync
+    unsynced: bool,
+    last_fsync: Instant,
+    /// Replication offset the AOF was written up to
+    written_offset: u64,
+    /// Replication offset the AOF was fsynced up to
+    fsynced_offset: u64,
+    rewrite: Option<BackgroundRewrite>,
+}
+
+impl Default for AofState {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fsync: AppendFsync::default(),
+            dir: PathBuf::from("."),
+            dirname: "appendonlydir".to_string(),
+            filename: "appendonly.aof".to_string(),
+            load_truncated: true,
+            last_rewrite_ok: true,
+            manifest: Manifest::default(),
+            file: None,
+            unsynced: false,
+            last_fsync: Instant::now(),
+            written_offset: 0,
+            fsynced_offset: 0,
+            rewrite: None,
+        }
+    }
+}
+
+impl AofState {
+    /// Whether every write is fsynced to the AOF before it is replied to
+    pub fn syncs_always(&self) -> bool {
+        self.file.is_some() && self.fsync == AppendFsync::Always
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(&self.dirname)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.path().join(format!("{}.manifest", self.filename))
+    }
+
+    fn base_file(&self, seq: u64) -> AofFile {
+        AofFile {
+            name: format!("{}.{seq}.base.rdb", self.filename),
+            seq,
+            file_type: FileType::Base,
+        }
+    }
+
+    fn incr_file(&self, seq: u64) -> AofFile {
+        AofFile {
+            name: format!("{}.{seq}.incr.aof", self.filename),
+            seq,
+            file_type: FileType::Incr,
+        }
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite.is_some()
+    }
+
+    /// Append a command to the current incremental file,
+    /// `offset` being the replication offset right after it
+    pub fn feed(&mut self, command: &[u8], offset: u64) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let written = file.write_all(command).and_then(|()| match self.fsync {
+            AppendFsync::Always => file.sync_data(),
+            AppendFsync::Everysec | AppendFsync::No => Ok(()),
+        });
+        if let Err(e) = written {
+            error!("Failed to write to the AOF: {e}");
+            return;
+        }
+        self.written_offset = offset;
+        match self.fsync {
+            AppendFsync::Everysec => self.unsynced = true,
+            // Without fsyncs of our own, a write is as durable as it gets
+            AppendFsync::Always | AppendFsync::No => self.fsynced_offset = offset,
+        }
+    }
+
+    /// Replication offset the AOF is durable up to, for WAITAOF, given the offset of the
+    /// stream. Once everything written is fsynced that is the whole stream, as the rest
+    /// of it, PINGs and REPLCONF GETACK, never reaches the AOF.
+    pub fn synced_offset(&self, stream_offset: u64) -> u64 {
+        let caught_up =
+            self.file.is_some() && !self.unsynced && self.fsynced_offset >= self.written_offset;
+        if caught_up {
+            stream_offset.max(self.fsynced_offset)
+        } else {
+            self.fsynced_offset
+        }
+    }
+
+    /// Flush everything written so far to disk
+    pub fn fsync(&mut self) {
+        if let Some(file) = &self.file {
+            if let Err(e) = file.sync_data() {
+                error!("Failed to fsync the AOF: {e}");
+            }
+        }
+        self.unsynced = false;
+        self.last_fsync = Instant::now();
+        self.fsynced_offset = self.written_offset;
+    }
+
+    /// Write the manifest through a temporary file, so it never lists half the files
+    fn persist_manifest(&self) -> anyhow::Result<()> {
+        rdb::write_file(&self.manifest_path(), self.manifest.to_string().as_bytes())
+    }
+
+    /// Start a new incremental file and append to it from now on
+    fn open_incr(&mut self) -> anyhow::Result<u64> {
+        let seq = self.manifest.incrs.last().map_or(1, |file| file.seq + 1);
+        let incr = self.incr_file(seq);
+        let file = File::create(self.path().join(&incr.name))
+            .with_context(|| format!("Failed to create {}", incr.name))?;
+        if self.file.is_some() {
+            self.fsync();
+        }
+        self.manifest.incrs.push(incr);
+        self.persist_manifest()?;
+        self.file = Some(file);
+        Ok(seq)
+    }
+}
+
+/// Load the AOF at startup, creating it if there is none, then append to it
+pub fn load(state: &mut AppState) -> anyhow::Result<()> {
+    let dir = state.aof.path();
+    let manifest_path = state.aof.manifest_path();
+    match fs::read_to_string(&manifest_path) {
+        Ok(text) => {
+            state.aof.manifest = Manifest::parse(&text)
+                .with_context(|| format!("Failed to parse {}", manifest_path.display()))?;
+            let files: Vec<AofFile> = state.aof.manifest.files().cloned().collect();
+            for (i, file) in files.iter().enumerate() {
+                let path = dir.join(&file.name);
+                let data = fs::read(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                if data.starts_with(b"REDIS") {
+                    let keys = rdb::parse(&data, state)
+                        .with_context(|| format!("Failed to load {}", path.display()))?;
+                    info!("Loaded {keys} keys from {}", path.display());
+                    continue;
+                }
+                let valid = replay(&data, state)
+                    .with_context(|| format!("Failed to load {}", path.display()))?;
+                if valid < data.len() {
+                    if i + 1 < files.len() || !state.aof.load_truncated {
+                        bail!(
+                            "Unexpected end of file {}, set aof-load-truncated to load it anyway",
+                            path.display()
+                        );
+                    }
+                    warn!(
+                        "{} ends with an incomplete command, truncating it to {valid} bytes",
+                        path.display()
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|file| file.set_len(valid as u64))
+                        .with_context(|| format!("Failed to truncate {}", path.display()))?;
+                }
+                info!("Loaded {}", path.display());
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("No AOF at {}, creating one", dir.display());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", manifest_path.display()))
+        }
+    }
+    *state.dirty.get_mut() = 0;
+    start(state)
+}
+
+/// Open the AOF for appending, writing a base file of the current data if there is none yet
+pub fn start(state: &mut AppState) -> anyhow::Result<()> {
+    let dir = state.aof.path();
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    if state.aof.manifest.base.is_none() && state.aof.manifest.incrs.is_empty() {
+        let base = state.aof.base_file(1);
+        rdb::write_file(
+            &dir.join(&base.name),
+            &rdb::dump(state.databases(), &state.functions)?,
+        )?;
+        state.aof.manifest.base = Some(base);
+        state.aof.open_incr()?;
+        state.join_stream(Some(0));
+        return Ok(());
+    }
+    match state.aof.manifest.incrs.last() {
+        Some(incr) => {
+            let path = dir.join(&incr.name);
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            state.aof.file = Some(file);
+            // Whatever database the file ended at, the next command selects its own
+            state.join_stream(None);
+        }
+        None => {
+            state.aof.open_incr()?;
+            state.join_stream(Some(0));
+        }
+    }
+    Ok(())
+}
+
+/// Run the commands in `data` against `state`, returning how many bytes were replayed.
+/// Anything after that is an incomplete command, or a transaction that never reached EXEC.
+fn replay(data: &[u8], state: &mut AppState) -> anyhow::Result<usize> {
+    let mut offset = 0;
+    let mut valid = 0;
+    let selected = state.selected;
+    // Every file is replayed from database 0, as it was written
+    let mut db = 0;
+    let mut transaction: Option<Vec<(usize, Command)>> = None;
+    while let Some(len) = RespData::frame_len(&data[offset..])
+        .with_context(|| format!("Bad file format at offset {offset}"))?
+    {
+        let request = RespData::try_from(&data[offset..offset + len])?;
+        offset += len;
+        let name = match &request {
+            RespData::Array(Some(elements)) => match elements.front() {
+                Some(RespData::BulkString(Some(name))) => {
+                    String::from_utf8_lossy(name).to_uppercase()
+                }
+                _ => bail!("Bad command at offset {offset}"),
+            },
+            _ => bail!("Bad command at offset {offset}"),
+        };
+        match (name.as_str(), &mut transaction) {
+            ("SELECT", _) => {
+                db = replication::select_index(&request)?;
+                ensure!(
+                    db < state.config.databases,
+                    "SELECT of database {db} at offset {offset}, only {} databases are configured",
+                    state.config.databases
+                );
+            }
+            ("MULTI", None) => transaction = Some(Vec::new()),
+            ("EXEC", Some(_)) => {
+                for (db, command) in transaction.take().expect("checked above") {
+                    state.select(db);
+                    command.execute(state)?;
+                }
+                valid = offset;
+            }
+            ("MULTI" | "EXEC", _) => bail!("Unbalanced {name} at offset {offset}"),
+            (_, Some(commands)) => commands.push((db, Command::try_from(request)?)),
+            (_, None) => {
+                state.select(db);
+                Command::try_from(request)?.execute(state)?;
+                valid = offset;
+            }
+        }
+    }
+    state.select(selected);
+    Ok(valid)
+}
+
+/// Open or close the AOF at runtime, after `appendonly` changed
+pub fn apply_enabled(state: &mut AppState) -> anyhow::Result<()> {
+    match (state.aof.enabled, state.aof.file.is_some()) {
+        (true, false) => enable(state),
+        (false, true) => {
+            disable(state);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Turn the AOF on, writing the current data to it
+fn enable(state: &mut AppState) -> anyhow::Result<()> {
+    // An AOF turned off earlier missed the writes since, so it gets a new base
+    let fresh = state.aof.manifest.base.is_none() && state.aof.manifest.incrs.is_empty();
+    let started = start(state).and_then(|()| if fresh { Ok(()) } else { bgrewrite(state) });
+    if started.is_err() {
+        state.aof.enabled = false;
+        state.aof.file = None;
+    }
+    started
+}
+
+/// Turn the AOF off, flushing what was written so far
+fn disable(state: &mut AppState) {
+    check_rewrite(state, true);
+    state.aof.fsync();
+    state.aof.file = None;
+    state.aof.enabled = false;
+}
+
+/// Write a new base file in the background, with commands from now on going to a new
+/// incremental file, and swap them into the manifest once done
+pub fn bgrewrite(state: &mut AppState) -> anyhow::Result<()> {
+    if !state.aof.enabled {
+        bail!("Background append only file rewriting is not possible while AOF is turned off");
+    }
+    if state.aof.rewrite_in_progress() {
+        bail!("Background append only file rewriting already in progress");
+    }
+    let incr_seq = state.aof.open_incr()?;
+    state.join_stream(Some(0));
+    let base = state.aof.base_file(
+        state
+            .aof
+            .manifest
+            .base
+            .as_ref()
+            .map_or(1, |base| base.seq + 1),
+    );
+    let path = state.aof.path().join(&base.name);
+    let dbs = rdb::clone_databases(state);
+    let functions = state.functions.clone();
+    let thread = thread::Builder::new()
+        .name("bgrewriteaof".to_string())
+        .spawn(move || {
+            let dbs = dbs.iter().map(|(index, db)| (*index, db));
+            rdb::write_file(&path, &rdb::dump(dbs, &functions)?)
+        })?;
+    state.aof.rewrite = Some(BackgroundRewrite {
+        thread,
+        base,
+        incr_seq,
+    });
+    info!("Background append only file rewriting started");
+    Ok(())
+}
+
+/// Finish a background rewrite once its thread is done, or wait for it if `wait`
+pub fn check_rewrite(state: &mut AppState, wait: bool) {
+    if !state
+        .aof
+        .rewrite
+        .as_ref()
+        .is_some_and(|rewrite| wait || rewrite.thread.is_finished())
+    {
+        return;
+    }
+    let rewrite = state.aof.rewrite.take().expect("checked above");
+    let result = rewrite
+        .thread
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("Background rewrite thread panicked")))
+        .and_then(|()| {
+            let aof = &mut state.aof;
+            let old_base = aof.manifest.base.replace(rewrite.base);
+            let (old_incrs, incrs) = aof
+                .manifest
+                .incrs
+                .drain(..)
+                .partition(|file| file.seq < rewrite.incr_seq);
+            aof.manifest.incrs = incrs;
+            aof.manifest
+                .history
+                .extend(old_base.into_iter().chain(old_incrs).map(|file| AofFile {
+                    file_type: FileType::History,
+                    ..file
+                }));
+            aof.persist_manifest()
+        });
+    state.aof.last_rewrite_ok = result.is_ok();
+    match result {
+        Ok(()) => {
+            let dir = state.aof.path();
+            for file in std::mem::take(&mut state.aof.manifest.history) {
+                if let Err(e) = fs::remove_file(dir.join(&file.name)) {
+                    warn!("Failed to remove {}: {e}", file.name);
+                }
+            }
+            if let Err(e) = state.aof.persist_manifest() {
+                warn!("Failed to persist the AOF manifest: {e:#}");
+            }
+            info!("Background AOF rewrite finished successfully");
+        }
+        Err(e) => error!("Background AOF rewrite error: {e:#}"),
+    }
+}
+
+/// Finish background rewrites and fsync the AOF once a second when asked to
+pub async fn aof_cycle(state: State) {
+    let mut interval = interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        let mut guard = state.write().await;
+        check_rewrite(&mut guard, false);
+        let aof = &mut guard.aof;
+        if !aof.unsynced || aof.last_fsync.elapsed() < Duration::from_secs(1) {
+            continue;
+        }
+        let Some(file) = aof.file.as_ref().and_then(|file| file.try_clone().ok()) else {
+            continue;
+        };
+        aof.unsynced = false;
+        aof.last_fsync = Instant::now();
+        let offset = aof.written_offset;
+        drop(guard);
+        // Other clients are served while the disk catches up
+        let state = state.clone();
+        tokio::spawn(async move {
+            match tokio::task::spawn_blocking(move || file.sync_data()).await {
+                Ok(Ok(())) => {
+                    let mut state = state.write().await;
+                    state.aof.fsynced_offset = state.aof.fsynced_offset.max(offset);
+                    state.notify_all(replication::ACK_WAIT_KEY);
+                }
+                Ok(Err(e)) => error!("Failed to fsync the AOF: {e}"),
+                Err(e) => error!("Failed to fsync the AOF: {e}"),
+            }
+        });
+    }
+}
+
+/// The commands of a transaction, written to the AOF as one MULTI/EXEC block
+pub fn wrap_transaction(commands: Vec<RespData>) -> Vec<u8> {
+    let mut bytes = RespData::command(["MULTI"]).as_bytes();
+    for command in commands {
+        bytes.extend(command.as_bytes());
+    }
+    bytes.extend(RespData::command(["EXEC"]).as_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn test_manifest() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n\
+                    file appendonly.aof.1.base.rdb seq 1 type h\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(
+            manifest.files().map(|file| file.seq).collect::<Vec<_>>(),
+            [2, 2, 3]
+        );
+        assert_eq!(manifest.history.len(), 1);
+        // Written back with incremental files in order
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+        assert!(Manifest::parse("file a seq 1 type x").is_err());
+        assert!(Manifest::parse("file a seq 1").is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut data = RespData::command(["SET", "a", "1"]).as_bytes();
+        data.extend(wrap_transaction(vec![
+            RespData::command(["RPUSH", "l", "x", "y"]),
+            RespData::command(["LPOP", "l", "1"]),
+        ]));
+        let complete = data.len();
+        // A transaction that never reached EXEC, then a command cut short
+        data.extend(RespData::command(["MULTI"]).as_bytes());
+        data.extend(RespData::command(["SET", "b", "2"]).as_bytes());
+        data.extend(RespData::command(["EXEC"]).as_bytes());
+        let mut truncated = data.clone();
+        truncated.truncate(data.len() - 3);
+
+        let mut state = AppState::default();
+        assert_eq!(replay(&data, &mut state).unwrap(), data.len());
+        assert!(state.db.kv.contains_key("b"));
+
+        let mut state = AppState::default();
+        assert_eq!(replay(&truncated, &mut state).unwrap(), complete);
+        assert!(matches!(state.db.kv.get("a"), Some(Value::String(v)) if v == b"1"));
+        assert!(matches!(state.db.kv.get("l"), Some(Value::List(l)) if l.len() == 1));
+        assert!(!state.db.kv.contains_key("b"));
+
+        assert!(replay(b"*1\r\n$4\r\nNOPE\r\n", &mut AppState::default()).is_err());
+    }
+}
@@ -1,13 +1,32 @@
-use std::net::Ipv4Addr;
+use std::path::Path;
 
 use clap::Parser;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    #[arg(long, default_value = "0.0.0.0")]
-    pub host: Ipv4Addr,
+    /// A config file in the redis.conf format, then any of its parameters as
+    /// `--name value`, applied over the file
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "[CONFIG_FILE] [--NAME VALUE]"
+    )]
+    args: Vec<String>,
+}
+
+impl Cli {
+    /// The config file, given as the first argument unless that is already an option
+    pub fn config_file(&self) -> Option<&Path> {
+        self.args
+            .first()
+            .filter(|arg| !arg.starts_with('-'))
+            .map(Path::new)
+    }
 
-    #[arg(short, long, default_value = "6379")]
-    pub port: u16,
+    /// The `--name value` options
+    pub fn options(&self) -> &[String] {
+        let skip = usize::from(self.config_file().is_some());
+        &self.args[skip..]
+    }
 }
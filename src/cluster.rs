@@ -0,0 +1,59 @@
+/// Number of hash slots the keyspace is divided into in cluster mode
+pub const SLOT_COUNT: u16 = 16384;
+
+/// CRC16-CCITT (XMODEM), as used by Redis Cluster for key hashing
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x1021
+            }
+        })
+    })
+}
+
+/// The hash slot of a key. If the key contains a non-empty `{...}` hash tag,
+/// only the tag is hashed, so related keys can be forced into the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            let close = tag.iter().position(|&b| b == b'}')?;
+            (close > 0).then(|| &tag[..close])
+        })
+        .unwrap_or(key);
+    crc16(hashed) % SLOT_COUNT
+}
+
+/// Whether all keys hash to the same slot, as required of multi-key commands in cluster mode
+pub fn same_slot<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> bool {
+    let mut slots = keys.into_iter().map(key_hash_slot);
+    let first = slots.next();
+    slots.all(|slot| Some(slot) == first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"user1000")
+        );
+        // An empty hash tag hashes the whole key
+        assert_eq!(
+            key_hash_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") % SLOT_COUNT
+        );
+        assert!(same_slot([b"{a}x".as_slice(), b"{a}y".as_slice()]));
+        assert!(!same_slot([b"foo".as_slice(), b"bar".as_slice()]));
+    }
+}
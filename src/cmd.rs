@@ -1,9 +1,26 @@
 use anyhow::{bail, ensure, Context};
-use std::{collections::VecDeque, time::Duration};
-use tokio::{select, time::sleep};
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLockWriteGuard;
 use tracing::{debug, warn};
 
-use crate::{resp::RespData, state::State};
+use crate::{
+    aof, config, evict,
+    function::{Functions, RestorePolicy},
+    glob::glob_match,
+    info, rdb, replication,
+    resp::RespData,
+    script,
+    state::{now_ms, stream_wait_key, wait_any, AppState, Db, ReadKeys, State, WriteKeys},
+    stream::{
+        entries_to_resp, entry_to_resp, ClaimOptions, GroupReadFrom, PendingFilter, Stream,
+        StreamFields, StreamId, StreamIdRequest, StreamReadFrom,
+    },
+    value::{wrong_type, Hash, List, Set, SortedSet, Value},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PushPopDirection {
@@ -17,14 +34,16 @@ pub enum Command {
     Echo(String),
     Set {
         key: String,
-        value: RespData,
+        value: Vec<u8>,
         expires: Option<Duration>, // Optional expiration duration
-        args: Vec<String>,         // Additional arguments if needed
+        /// Expiration time in milliseconds since the UNIX epoch, as given with PXAT
+        expires_at: Option<u64>,
+        args: Vec<String>, // Additional arguments if needed
     },
     Get(String),
     ListPush {
         key: String,
-        values: Vec<RespData>,
+        values: Vec<Vec<u8>>,
         direction: PushPopDirection,
     },
     ListRange {
@@ -33,6 +52,10 @@ pub enum Command {
         end: i64,
     },
     ListLen(String),
+    ListIndex {
+        key: String,
+        index: i64,
+    },
     ListPop {
         key: String,
         count: u32,
@@ -41,6 +64,418 @@ pub enum Command {
         /// Some(0) means blocking indefinitely
         blocking: Option<f64>,
     },
+    Type(String),
+    /// `DEL key [key ...]`
+    Del(Vec<String>),
+    /// `HSET key field value [field value ...]`
+    HashSet {
+        key: String,
+        fields: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// `SADD key member [member ...]`
+    SetAdd {
+        key: String,
+        members: Vec<Vec<u8>>,
+    },
+    /// `ZADD key score member [score member ...]`
+    SortedSetAdd {
+        key: String,
+        members: Vec<(f64, Vec<u8>)>,
+    },
+    StreamAdd {
+        key: String,
+        id: StreamIdRequest,
+        fields: StreamFields,
+    },
+    StreamRange {
+        key: String,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    },
+    StreamLen(String),
+    StreamRead {
+        streams: Vec<(String, StreamReadFrom)>,
+        count: Option<usize>,
+        /// None if not blocking, Some(n) if blocking with timeout n milliseconds
+        /// Some(0) means blocking indefinitely
+        block: Option<u64>,
+    },
+    StreamGroupCreate {
+        key: String,
+        group: String,
+        /// None for `$`, the last ID of the stream
+        id: Option<StreamId>,
+        make_stream: bool,
+        entries_read: Option<u64>,
+    },
+    StreamGroupSetId {
+        key: String,
+        group: String,
+        /// None for `$`, the last ID of the stream
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    },
+    StreamGroupDestroy {
+        key: String,
+        group: String,
+    },
+    StreamGroupCreateConsumer {
+        key: String,
+        group: String,
+        consumer: String,
+    },
+    StreamGroupDelConsumer {
+        key: String,
+        group: String,
+        consumer: String,
+    },
+    StreamReadGroup {
+        group: String,
+        consumer: String,
+        streams: Vec<(String, GroupReadFrom)>,
+        count: Option<usize>,
+        /// None if not blocking, Some(n) if blocking with timeout n milliseconds
+        /// Some(0) means blocking indefinitely
+        block: Option<u64>,
+        no_ack: bool,
+    },
+    StreamAck {
+        key: String,
+        group: String,
+        ids: Vec<StreamId>,
+    },
+    StreamPending {
+        key: String,
+        group: String,
+        /// None for the summary form
+        filter: Option<PendingFilter>,
+    },
+    StreamClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    StreamAutoClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    StreamInfo {
+        key: String,
+        /// Some(count) for the `FULL` form, 0 meaning all entries
+        full: Option<usize>,
+    },
+    StreamInfoGroups(String),
+    StreamInfoConsumers {
+        key: String,
+        group: String,
+    },
+    Publish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    /// `PUBSUB CHANNELS [pattern]`
+    PubSubChannels(Option<String>),
+    /// `PUBSUB NUMSUB [channel ...]`
+    PubSubNumSub(Vec<String>),
+    /// `PUBSUB NUMPAT`
+    PubSubNumPat,
+    ShardPublish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    /// `PUBSUB SHARDCHANNELS [pattern]`
+    PubSubShardChannels(Option<String>),
+    /// `PUBSUB SHARDNUMSUB [channel ...]`
+    PubSubShardNumSub(Vec<String>),
+    /// `EVAL` and `EVAL_RO`
+    Eval {
+        script: String,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+    },
+    /// `EVALSHA` and `EVALSHA_RO`, running a script from the cache
+    EvalSha {
+        sha: String,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+    },
+    ScriptLoad(String),
+    ScriptExists(Vec<String>),
+    ScriptFlush,
+    ScriptKill,
+    FunctionLoad {
+        code: String,
+        replace: bool,
+    },
+    FunctionList {
+        pattern: Option<String>,
+        with_code: bool,
+    },
+    FunctionDelete(String),
+    FunctionDump,
+    FunctionRestore {
+        payload: Vec<u8>,
+        policy: RestorePolicy,
+    },
+    FunctionFlush,
+    FunctionKill,
+    /// `FCALL` and `FCALL_RO`
+    FCall {
+        function: String,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+    },
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// `INFO [section ...]`
+    Info(Vec<String>),
+    /// `CONFIG GET pattern [pattern ...]`
+    ConfigGet(Vec<String>),
+    /// `CONFIG SET parameter value [parameter value ...]`
+    ConfigSet(Vec<(String, String)>),
+    ConfigResetStat,
+    ConfigRewrite,
+    /// `OBJECT ENCODING key`
+    ObjectEncoding(String),
+    /// `OBJECT IDLETIME key`, in seconds
+    ObjectIdleTime(String),
+    /// `OBJECT FREQ key`
+    ObjectFreq(String),
+    /// `OBJECT REFCOUNT key`
+    ObjectRefCount(String),
+    /// `MEMORY USAGE key [SAMPLES count]`, 0 samples measuring every element
+    MemoryUsage {
+        key: String,
+        samples: usize,
+    },
+    MemoryStats,
+    MemoryDoctor,
+    /// `WAIT numreplicas timeout`, the timeout in milliseconds, 0 meaning forever
+    Wait {
+        replicas: usize,
+        timeout: u64,
+    },
+    /// `WAITAOF numlocal numreplicas timeout`
+    WaitAof {
+        local: usize,
+        replicas: usize,
+        timeout: u64,
+    },
+    /// `SWAPDB index1 index2`
+    SwapDb(usize, usize),
+    /// `MOVE key db`
+    Move {
+        key: String,
+        db: usize,
+    },
+    /// `FLUSHDB [ASYNC|SYNC]`, freeing the keys in the background with ASYNC
+    FlushDb {
+        lazy: bool,
+    },
+    /// `FLUSHALL [ASYNC|SYNC]`
+    FlushAll {
+        lazy: bool,
+    },
+    DbSize,
+    /// `KEYS pattern`
+    Keys(String),
+    /// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+    Scan {
+        cursor: u64,
+        options: ScanOptions,
+    },
+    /// `HSCAN key cursor [MATCH pattern] [COUNT count]`
+    HashScan {
+        key: String,
+        cursor: u64,
+        options: ScanOptions,
+    },
+    /// `SSCAN key cursor [MATCH pattern] [COUNT count]`
+    SetScan {
+        key: String,
+        cursor: u64,
+        options: ScanOptions,
+    },
+    /// `ZSCAN key cursor [MATCH pattern] [COUNT count]`
+    SortedSetScan {
+        key: String,
+        cursor: u64,
+        options: ScanOptions,
+    },
+}
+
+/// Options of SCAN and the commands scanning a collection
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Only elements matching this glob-style pattern are returned
+    pattern: Option<String>,
+    /// Roughly how many elements to look at
+    count: usize,
+    /// Only keys of this type are returned, for SCAN
+    kind: Option<String>,
+}
+
+/// Parse `cursor [MATCH pattern] [COUNT count]`, and `[TYPE type]` for SCAN
+fn parse_scan(args: &[String], with_type: bool) -> anyhow::Result<(u64, ScanOptions)> {
+    let (cursor, mut args) = args
+        .split_first()
+        .context("wrong number of arguments for scan command")?;
+    let cursor = cursor.parse().context("invalid cursor")?;
+    let mut options = ScanOptions {
+        pattern: None,
+        count: 10,
+        kind: None,
+    };
+    while let [option, value, rest @ ..] = args {
+        match option.to_uppercase().as_str() {
+            "MATCH" => options.pattern = Some(value.clone()),
+            "COUNT" => {
+                options.count = value
+                    .parse()
+                    .context("value is not an integer or out of range")?;
+                ensure!(options.count > 0, "syntax error");
+            }
+            "TYPE" if with_type => options.kind = Some(value.clone()),
+            _ => bail!("syntax error"),
+        }
+        args = rest;
+    }
+    ensure!(args.is_empty(), "syntax error");
+    Ok((cursor, options))
+}
+
+/// Call `step` from `cursor` on until it found `count` elements or the scan is done,
+/// returning the cursor to continue from and the elements found
+fn scan_steps<T>(
+    mut cursor: u64,
+    count: usize,
+    mut step: impl FnMut(u64, &mut Vec<T>) -> u64,
+) -> (u64, Vec<T>) {
+    let mut found = Vec::new();
+    // Empty buckets count too, so a sparse table does not make one call walk all of it
+    let mut steps = count.saturating_mul(10);
+    loop {
+        cursor = step(cursor, &mut found);
+        steps -= 1;
+        if cursor == 0 || steps == 0 || found.len() >= count {
+            return (cursor, found);
+        }
+    }
+}
+
+/// The reply of a scan, elements with an optional value following them, those whose
+/// name does not match `pattern` left out
+fn scan_reply(
+    cursor: u64,
+    elements: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    pattern: Option<&str>,
+) -> RespData {
+    let elements = elements
+        .into_iter()
+        .filter(|(name, _)| pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), name)))
+        .flat_map(|(name, value)| std::iter::once(name).chain(value))
+        .map(|element| RespData::BulkString(Some(element)))
+        .collect();
+    RespData::array(VecDeque::from([
+        RespData::bulk_string(cursor.to_string()),
+        RespData::array(elements),
+    ]))
+}
+
+/// Parse the number of a database, which `databases` may still find out of range
+pub fn parse_db_index(index: &str) -> anyhow::Result<usize> {
+    let index: i64 = index
+        .parse()
+        .context("value is not an integer or out of range")?;
+    usize::try_from(index).context("DB index is out of range")
+}
+
+/// Parse the `ASYNC|SYNC` option of FLUSHDB and FLUSHALL, whether the keys are freed lazily
+fn parse_flush_mode(elements: &VecDeque<RespData>) -> anyhow::Result<bool> {
+    match string_args(elements, 1)?.as_slice() {
+        [] => Ok(false),
+        [mode] if mode.eq_ignore_ascii_case("ASYNC") => Ok(true),
+        [mode] if mode.eq_ignore_ascii_case("SYNC") => Ok(false),
+        _ => bail!("syntax error"),
+    }
+}
+
+/// The bulk string argument at `index` as a string, if present
+fn arg_string(elements: &VecDeque<RespData>, index: usize) -> Option<String> {
+    match elements.get(index) {
+        Some(RespData::BulkString(Some(arg))) => Some(String::from_utf8_lossy(arg).to_string()),
+        _ => None,
+    }
+}
+
+/// All bulk string arguments starting at `index`
+fn string_args(elements: &VecDeque<RespData>, index: usize) -> anyhow::Result<Vec<String>> {
+    (index..elements.len())
+        .map(|i| arg_string(elements, i))
+        .collect::<Option<Vec<_>>>()
+        .context("Arguments must be bulk strings")
+}
+
+/// Parse a stream range bound, `-` and `+` being the smallest and largest IDs
+fn parse_range_bound(bound: &str, is_start: bool) -> anyhow::Result<StreamId> {
+    match (bound, is_start) {
+        ("-", _) => Ok(StreamId::MIN),
+        ("+", _) => Ok(StreamId::MAX),
+        (bound, true) => StreamId::parse_bound(bound, 0),
+        (bound, false) => StreamId::parse_bound(bound, u64::MAX),
+    }
+}
+
+/// Parse a consumer group starting ID, `$` (returned as `None`) being the last ID of the stream
+fn parse_group_id(id: &str) -> anyhow::Result<Option<StreamId>> {
+    match id {
+        "$" => Ok(None),
+        id => Ok(Some(id.parse()?)),
+    }
+}
+
+/// The bulk string argument at `index` as raw bytes, if present
+fn arg_bytes(elements: &VecDeque<RespData>, index: usize) -> Option<Vec<u8>> {
+    match elements.get(index) {
+        Some(RespData::BulkString(Some(arg))) => Some(arg.clone()),
+        _ => None,
+    }
+}
+
+/// The script (or its SHA1), keys and arguments of `EVAL`-like commands,
+/// given as `<script> <numkeys> [key ...] [arg ...]`
+fn eval_args(elements: &VecDeque<RespData>) -> anyhow::Result<(String, Vec<String>, Vec<Vec<u8>>)> {
+    let script = arg_string(elements, 1).context("wrong number of arguments")?;
+    let numkeys: i64 = arg_string(elements, 2)
+        .context("wrong number of arguments")?
+        .parse()
+        .context("value is not an integer or out of range")?;
+    ensure!(numkeys >= 0, "Number of keys can't be negative");
+    let numkeys = usize::try_from(numkeys)?;
+    ensure!(
+        numkeys <= elements.len() - 3,
+        "Number of keys can't be greater than number of args"
+    );
+    let keys = string_args(elements, 3)?[..numkeys].to_vec();
+    let args = (3 + numkeys..elements.len())
+        .map(|i| arg_bytes(elements, i))
+        .collect::<Option<Vec<_>>>()
+        .context("Arguments must be bulk strings")?;
+    Ok((script, keys, args))
 }
 
 impl TryFrom<RespData> for Command {
@@ -72,8 +507,10 @@ impl TryFrom<RespData> for Command {
                 }
             }
             "SET" => {
-                if let (Some(RespData::BulkString(Some(key))), Some(value)) =
-                    (elements.get(1), elements.get(2))
+                if let (
+                    Some(RespData::BulkString(Some(key))),
+                    Some(RespData::BulkString(Some(value))),
+                ) = (elements.get(1), elements.get(2))
                 {
                     let args: Vec<String> = elements
                         .iter()
@@ -100,10 +537,20 @@ impl TryFrom<RespData> for Command {
                     } else {
                         None
                     };
+                    let expires_at = match args.iter().position(|s| s.to_uppercase() == "PXAT") {
+                        Some(i) => Some(
+                            args.get(i + 1)
+                                .context("PXAT argument requires a value")?
+                                .parse()
+                                .context("Failed to parse expiration time")?,
+                        ),
+                        None => None,
+                    };
                     Ok(Command::Set {
                         key: String::from_utf8_lossy(key).to_string(),
                         value: value.clone(),
                         expires,
+                        expires_at,
                         args,
                     })
                 } else {
@@ -126,7 +573,10 @@ impl TryFrom<RespData> for Command {
                     };
                     Ok(Command::ListPush {
                         key: String::from_utf8_lossy(key).to_string(),
-                        values: elements.iter().skip(2).cloned().collect(),
+                        values: (2..elements.len())
+                            .map(|i| arg_bytes(&elements, i))
+                            .collect::<Option<_>>()
+                            .context("Values must be bulk strings")?,
                         direction,
                     })
                 } else {
@@ -161,6 +611,14 @@ impl TryFrom<RespData> for Command {
                     bail!("LLEN command requires a key argument");
                 }
             }
+            "LINDEX" => {
+                let key = arg_string(&elements, 1).context("LINDEX command requires a key")?;
+                let index = elements
+                    .get(2)
+                    .and_then(RespData::as_number)
+                    .context("LINDEX command requires an integer index")?;
+                Ok(Command::ListIndex { key, index })
+            }
             "LPOP" | "RPOP" | "BLPOP" | "BRPOP" => {
                 if let Some(RespData::BulkString(Some(key))) = elements.get(1) {
                     let direction = if command.contains("RPOP") {
@@ -187,6 +645,743 @@ impl TryFrom<RespData> for Command {
                     bail!("LPOP/RPOP command requires a key argument");
                 }
             }
+            "TYPE" => {
+                let key =
+                    arg_string(&elements, 1).context("TYPE command requires a key argument")?;
+                Ok(Command::Type(key))
+            }
+            "DEL" => {
+                let keys = string_args(&elements, 1)?;
+                ensure!(!keys.is_empty(), "DEL command requires at least one key");
+                Ok(Command::Del(keys))
+            }
+            "HSET" => {
+                let key =
+                    arg_string(&elements, 1).context("HSET command requires a key argument")?;
+                let values = (2..elements.len())
+                    .map(|i| arg_bytes(&elements, i))
+                    .collect::<Option<Vec<_>>>()
+                    .context("HSET fields and values must be bulk strings")?;
+                ensure!(
+                    !values.is_empty() && values.len() % 2 == 0,
+                    "HSET command requires field value pairs"
+                );
+                let fields = values
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Ok(Command::HashSet { key, fields })
+            }
+            "SADD" => {
+                let key =
+                    arg_string(&elements, 1).context("SADD command requires a key argument")?;
+                let members = (2..elements.len())
+                    .map(|i| arg_bytes(&elements, i))
+                    .collect::<Option<Vec<_>>>()
+                    .context("SADD members must be bulk strings")?;
+                ensure!(
+                    !members.is_empty(),
+                    "SADD command requires at least one member"
+                );
+                Ok(Command::SetAdd { key, members })
+            }
+            "ZADD" => {
+                let key =
+                    arg_string(&elements, 1).context("ZADD command requires a key argument")?;
+                let values = (2..elements.len())
+                    .map(|i| arg_bytes(&elements, i))
+                    .collect::<Option<Vec<_>>>()
+                    .context("ZADD scores and members must be bulk strings")?;
+                ensure!(
+                    !values.is_empty() && values.len() % 2 == 0,
+                    "ZADD command requires score member pairs"
+                );
+                let members = values
+                    .chunks_exact(2)
+                    .map(|pair| {
+                        let score = std::str::from_utf8(&pair[0])
+                            .ok()
+                            .and_then(|score| score.parse::<f64>().ok())
+                            .filter(|score| !score.is_nan())
+                            .context("value is not a valid float")?;
+                        Ok((score, pair[1].clone()))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::SortedSetAdd { key, members })
+            }
+            "XADD" => {
+                let key =
+                    arg_string(&elements, 1).context("XADD command requires a key argument")?;
+                let id = arg_string(&elements, 2)
+                    .context("XADD command requires an ID argument")?
+                    .parse()?;
+                let values = (3..elements.len())
+                    .map(|i| arg_bytes(&elements, i))
+                    .collect::<Option<Vec<_>>>()
+                    .context("XADD fields and values must be bulk strings")?;
+                ensure!(
+                    !values.is_empty() && values.len() % 2 == 0,
+                    "XADD command requires field value pairs"
+                );
+                let fields = values
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Ok(Command::StreamAdd { key, id, fields })
+            }
+            "XRANGE" => {
+                let (Some(key), Some(start), Some(end)) = (
+                    arg_string(&elements, 1),
+                    arg_string(&elements, 2),
+                    arg_string(&elements, 3),
+                ) else {
+                    bail!("XRANGE command requires a key, a start and an end argument");
+                };
+                let start = parse_range_bound(&start, true)?;
+                let end = parse_range_bound(&end, false)?;
+                let count = match arg_string(&elements, 4) {
+                    Some(option) if option.to_uppercase() == "COUNT" => Some(
+                        elements
+                            .get(5)
+                            .and_then(RespData::as_number)
+                            .context("COUNT requires an integer value")?,
+                    ),
+                    Some(_) => bail!("XRANGE syntax error"),
+                    None => None,
+                };
+                Ok(Command::StreamRange {
+                    key,
+                    start,
+                    end,
+                    count,
+                })
+            }
+            "XLEN" => {
+                let key =
+                    arg_string(&elements, 1).context("XLEN command requires a key argument")?;
+                Ok(Command::StreamLen(key))
+            }
+            "XREAD" => {
+                let mut count = None;
+                let mut block = None;
+                let mut index = 1;
+                loop {
+                    let option = arg_string(&elements, index)
+                        .context("XREAD command requires a STREAMS argument")?;
+                    match option.to_uppercase().as_str() {
+                        "COUNT" => {
+                            count = Some(
+                                elements
+                                    .get(index + 1)
+                                    .and_then(RespData::as_number)
+                                    .context("COUNT requires an integer value")?,
+                            );
+                        }
+                        "BLOCK" => {
+                            block = Some(
+                                elements
+                                    .get(index + 1)
+                                    .and_then(RespData::as_number)
+                                    .context("BLOCK requires a non-negative timeout")?,
+                            );
+                        }
+                        "STREAMS" => break,
+                        _ => bail!("XREAD syntax error near `{option}`"),
+                    }
+                    index += 2;
+                }
+                let args = (index + 1..elements.len())
+                    .map(|i| arg_string(&elements, i))
+                    .collect::<Option<Vec<_>>>()
+                    .context("XREAD keys and IDs must be bulk strings")?;
+                ensure!(
+                    !args.is_empty() && args.len() % 2 == 0,
+                    "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified"
+                );
+                let (keys, ids) = args.split_at(args.len() / 2);
+                let streams = keys
+                    .iter()
+                    .zip(ids)
+                    .map(|(key, id)| Ok((key.clone(), id.parse()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::StreamRead {
+                    streams,
+                    count,
+                    block,
+                })
+            }
+            "XGROUP" => {
+                let args = string_args(&elements, 1)?;
+                let (Some(subcommand), Some(key), Some(group)) =
+                    (args.first(), args.get(1).cloned(), args.get(2).cloned())
+                else {
+                    bail!("XGROUP command requires a subcommand, a key and a group");
+                };
+                match subcommand.to_uppercase().as_str() {
+                    "CREATE" | "SETID" => {
+                        let id = parse_group_id(args.get(3).context("XGROUP requires an ID")?)?;
+                        let mut make_stream = false;
+                        let mut entries_read = None;
+                        let mut options = args.iter().skip(4);
+                        while let Some(option) = options.next() {
+                            match option.to_uppercase().as_str() {
+                                "MKSTREAM" => make_stream = true,
+                                "ENTRIESREAD" => {
+                                    entries_read = Some(
+                                        options
+                                            .next()
+                                            .context("ENTRIESREAD requires a value")?
+                                            .parse()?,
+                                    );
+                                }
+                                _ => bail!("XGROUP syntax error near `{option}`"),
+                            }
+                        }
+                        if subcommand.to_uppercase() == "CREATE" {
+                            Ok(Command::StreamGroupCreate {
+                                key,
+                                group,
+                                id,
+                                make_stream,
+                                entries_read,
+                            })
+                        } else {
+                            ensure!(!make_stream, "XGROUP SETID does not support MKSTREAM");
+                            Ok(Command::StreamGroupSetId {
+                                key,
+                                group,
+                                id,
+                                entries_read,
+                            })
+                        }
+                    }
+                    "DESTROY" => Ok(Command::StreamGroupDestroy { key, group }),
+                    "CREATECONSUMER" | "DELCONSUMER" => {
+                        let consumer =
+                            args.get(3).cloned().context("XGROUP requires a consumer")?;
+                        if subcommand.to_uppercase() == "CREATECONSUMER" {
+                            Ok(Command::StreamGroupCreateConsumer {
+                                key,
+                                group,
+                                consumer,
+                            })
+                        } else {
+                            Ok(Command::StreamGroupDelConsumer {
+                                key,
+                                group,
+                                consumer,
+                            })
+                        }
+                    }
+                    _ => bail!("Unknown XGROUP subcommand `{subcommand}`"),
+                }
+            }
+            "XREADGROUP" => {
+                let args = string_args(&elements, 1)?;
+                ensure!(
+                    args.first()
+                        .is_some_and(|arg| arg.to_uppercase() == "GROUP"),
+                    "XREADGROUP command requires a GROUP argument"
+                );
+                let (Some(group), Some(consumer)) = (args.get(1).cloned(), args.get(2).cloned())
+                else {
+                    bail!("XREADGROUP GROUP requires a group and a consumer");
+                };
+                let mut count = None;
+                let mut block = None;
+                let mut no_ack = false;
+                let mut index = 3;
+                loop {
+                    let option = args
+                        .get(index)
+                        .context("XREADGROUP command requires a STREAMS argument")?;
+                    index += 1;
+                    match option.to_uppercase().as_str() {
+                        "COUNT" => {
+                            count =
+                                Some(args.get(index).context("COUNT requires a value")?.parse()?);
+                            index += 1;
+                        }
+                        "BLOCK" => {
+                            block =
+                                Some(args.get(index).context("BLOCK requires a value")?.parse()?);
+                            index += 1;
+                        }
+                        "NOACK" => no_ack = true,
+                        "STREAMS" => break,
+                        _ => bail!("XREADGROUP syntax error near `{option}`"),
+                    }
+                }
+                let args = &args[index..];
+                ensure!(
+                    !args.is_empty() && args.len() % 2 == 0,
+                    "Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified"
+                );
+                let (keys, ids) = args.split_at(args.len() / 2);
+                let streams = keys
+                    .iter()
+                    .zip(ids)
+                    .map(|(key, id)| Ok((key.clone(), id.parse()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::StreamReadGroup {
+                    group,
+                    consumer,
+                    streams,
+                    count,
+                    block,
+                    no_ack,
+                })
+            }
+            "XACK" => {
+                let args = string_args(&elements, 1)?;
+                ensure!(
+                    args.len() >= 3,
+                    "XACK command requires a key, a group and at least one ID"
+                );
+                let ids = args[2..]
+                    .iter()
+                    .map(|id| id.parse())
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::StreamAck {
+                    key: args[0].clone(),
+                    group: args[1].clone(),
+                    ids,
+                })
+            }
+            "XPENDING" => {
+                let args = string_args(&elements, 1)?;
+                let (Some(key), Some(group)) = (args.first().cloned(), args.get(1).cloned()) else {
+                    bail!("XPENDING command requires a key and a group");
+                };
+                let mut rest = &args[2..];
+                let mut min_idle = 0;
+                if rest.first().is_some_and(|arg| arg.to_uppercase() == "IDLE") {
+                    min_idle = rest.get(1).context("IDLE requires a value")?.parse()?;
+                    rest = &rest[2.min(rest.len())..];
+                }
+                let filter = match rest {
+                    [] if min_idle == 0 => None,
+                    [start, end, count, consumer @ ..] if consumer.len() <= 1 => {
+                        Some(PendingFilter {
+                            min_idle,
+                            start: parse_range_bound(start, true)?,
+                            end: parse_range_bound(end, false)?,
+                            count: count.parse()?,
+                            consumer: consumer.first().cloned(),
+                        })
+                    }
+                    _ => bail!("XPENDING syntax error"),
+                };
+                Ok(Command::StreamPending { key, group, filter })
+            }
+            "XCLAIM" => {
+                let args = string_args(&elements, 1)?;
+                ensure!(
+                    args.len() >= 5,
+                    "XCLAIM command requires a key, a group, a consumer, a min-idle-time and IDs"
+                );
+                let min_idle = args[3].parse()?;
+                let mut ids = Vec::new();
+                let mut options = ClaimOptions::default();
+                let mut rest = args[4..].iter();
+                while let Some(arg) = rest.next() {
+                    match arg.to_uppercase().as_str() {
+                        "IDLE" => {
+                            options.idle =
+                                Some(rest.next().context("IDLE requires a value")?.parse()?)
+                        }
+                        "TIME" => {
+                            options.time =
+                                Some(rest.next().context("TIME requires a value")?.parse()?)
+                        }
+                        "RETRYCOUNT" => {
+                            options.retry_count = Some(
+                                rest.next()
+                                    .context("RETRYCOUNT requires a value")?
+                                    .parse()?,
+                            );
+                        }
+                        "LASTID" => {
+                            options.last_id =
+                                Some(rest.next().context("LASTID requires a value")?.parse()?);
+                        }
+                        "FORCE" => options.force = true,
+                        "JUSTID" => options.just_id = true,
+                        id => {
+                            ensure!(
+                                options == ClaimOptions::default(),
+                                "XCLAIM IDs must precede the options"
+                            );
+                            ids.push(id.parse()?);
+                        }
+                    }
+                }
+                ensure!(!ids.is_empty(), "XCLAIM command requires at least one ID");
+                Ok(Command::StreamClaim {
+                    key: args[0].clone(),
+                    group: args[1].clone(),
+                    consumer: args[2].clone(),
+                    min_idle,
+                    ids,
+                    options,
+                })
+            }
+            "XAUTOCLAIM" => {
+                let args = string_args(&elements, 1)?;
+                ensure!(
+                    args.len() >= 5,
+                    "XAUTOCLAIM command requires a key, a group, a consumer, a min-idle-time and a start ID"
+                );
+                let mut count = 100;
+                let mut just_id = false;
+                let mut rest = args[5..].iter();
+                while let Some(arg) = rest.next() {
+                    match arg.to_uppercase().as_str() {
+                        "COUNT" => {
+                            count = rest.next().context("COUNT requires a value")?.parse()?
+                        }
+                        "JUSTID" => just_id = true,
+                        _ => bail!("XAUTOCLAIM syntax error near `{arg}`"),
+                    }
+                }
+                ensure!(count > 0, "COUNT must be > 0");
+                Ok(Command::StreamAutoClaim {
+                    key: args[0].clone(),
+                    group: args[1].clone(),
+                    consumer: args[2].clone(),
+                    min_idle: args[3].parse()?,
+                    start: parse_range_bound(&args[4], true)?,
+                    count,
+                    just_id,
+                })
+            }
+            "XINFO" => {
+                let args = string_args(&elements, 1)?;
+                let (Some(subcommand), Some(key)) = (args.first(), args.get(1).cloned()) else {
+                    bail!("XINFO command requires a subcommand and a key");
+                };
+                match (subcommand.to_uppercase().as_str(), &args[2..]) {
+                    ("STREAM", []) => Ok(Command::StreamInfo { key, full: None }),
+                    ("STREAM", [full]) if full.to_uppercase() == "FULL" => {
+                        Ok(Command::StreamInfo {
+                            key,
+                            full: Some(10),
+                        })
+                    }
+                    ("STREAM", [full, count_arg, count])
+                        if full.to_uppercase() == "FULL" && count_arg.to_uppercase() == "COUNT" =>
+                    {
+                        Ok(Command::StreamInfo {
+                            key,
+                            full: Some(count.parse()?),
+                        })
+                    }
+                    ("GROUPS", []) => Ok(Command::StreamInfoGroups(key)),
+                    ("CONSUMERS", [group]) => Ok(Command::StreamInfoConsumers {
+                        key,
+                        group: group.clone(),
+                    }),
+                    _ => bail!("XINFO syntax error"),
+                }
+            }
+            "PUBLISH" | "SPUBLISH" => {
+                let (Some(channel), Some(message)) =
+                    (arg_string(&elements, 1), arg_bytes(&elements, 2))
+                else {
+                    bail!("{command} command requires a channel and a message");
+                };
+                if command == "SPUBLISH" {
+                    Ok(Command::ShardPublish { channel, message })
+                } else {
+                    Ok(Command::Publish { channel, message })
+                }
+            }
+            "PUBSUB" => {
+                let args = string_args(&elements, 1)?;
+                let subcommand = args
+                    .first()
+                    .context("PUBSUB command requires a subcommand")?;
+                match (subcommand.to_uppercase().as_str(), &args[1..]) {
+                    ("CHANNELS", []) => Ok(Command::PubSubChannels(None)),
+                    ("CHANNELS", [pattern]) => Ok(Command::PubSubChannels(Some(pattern.clone()))),
+                    ("NUMSUB", channels) => Ok(Command::PubSubNumSub(channels.to_vec())),
+                    ("NUMPAT", []) => Ok(Command::PubSubNumPat),
+                    ("SHARDCHANNELS", []) => Ok(Command::PubSubShardChannels(None)),
+                    ("SHARDCHANNELS", [pattern]) => {
+                        Ok(Command::PubSubShardChannels(Some(pattern.clone())))
+                    }
+                    ("SHARDNUMSUB", channels) => Ok(Command::PubSubShardNumSub(channels.to_vec())),
+                    _ => bail!("Unknown PUBSUB subcommand or wrong number of arguments"),
+                }
+            }
+            "EVAL" | "EVAL_RO" => {
+                let (script, keys, args) = eval_args(&elements)?;
+                Ok(Command::Eval {
+                    script,
+                    keys,
+                    args,
+                    read_only: command == "EVAL_RO",
+                })
+            }
+            "EVALSHA" | "EVALSHA_RO" => {
+                let (sha, keys, args) = eval_args(&elements)?;
+                Ok(Command::EvalSha {
+                    sha: sha.to_lowercase(),
+                    keys,
+                    args,
+                    read_only: command == "EVALSHA_RO",
+                })
+            }
+            "SCRIPT" => {
+                let args = string_args(&elements, 1)?;
+                let subcommand = args
+                    .first()
+                    .context("SCRIPT command requires a subcommand")?;
+                match (subcommand.to_uppercase().as_str(), &args[1..]) {
+                    ("LOAD", [script]) => Ok(Command::ScriptLoad(script.clone())),
+                    ("EXISTS", shas) if !shas.is_empty() => Ok(Command::ScriptExists(
+                        shas.iter().map(|sha| sha.to_lowercase()).collect(),
+                    )),
+                    // Flushing is always synchronous, the cache is small
+                    ("FLUSH", [] | [_]) => Ok(Command::ScriptFlush),
+                    ("KILL", []) => Ok(Command::ScriptKill),
+                    _ => bail!("Unknown SCRIPT subcommand or wrong number of arguments"),
+                }
+            }
+            "FUNCTION" => {
+                let subcommand = arg_string(&elements, 1)
+                    .context("FUNCTION command requires a subcommand")?
+                    .to_uppercase();
+                if subcommand == "RESTORE" {
+                    let payload = arg_bytes(&elements, 2).context("wrong number of arguments")?;
+                    let policy = match arg_string(&elements, 3).map(|p| p.to_uppercase()) {
+                        None => RestorePolicy::Append,
+                        Some(policy) if elements.len() == 4 => match policy.as_str() {
+                            "APPEND" => RestorePolicy::Append,
+                            "REPLACE" => RestorePolicy::Replace,
+                            "FLUSH" => RestorePolicy::Flush,
+                            _ => bail!("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."),
+                        },
+                        Some(_) => bail!("wrong number of arguments"),
+                    };
+                    return Ok(Command::FunctionRestore { payload, policy });
+                }
+                let args = string_args(&elements, 2)?;
+                match (subcommand.as_str(), args.as_slice()) {
+                    ("LOAD", [code]) => Ok(Command::FunctionLoad {
+                        code: code.clone(),
+                        replace: false,
+                    }),
+                    ("LOAD", [replace, code]) if replace.eq_ignore_ascii_case("REPLACE") => {
+                        Ok(Command::FunctionLoad {
+                            code: code.clone(),
+                            replace: true,
+                        })
+                    }
+                    ("LIST", options) => {
+                        let mut pattern = None;
+                        let mut with_code = false;
+                        let mut options = options.iter();
+                        while let Some(option) = options.next() {
+                            match option.to_uppercase().as_str() {
+                                "WITHCODE" => with_code = true,
+                                "LIBRARYNAME" => {
+                                    pattern = Some(
+                                        options
+                                            .next()
+                                            .context("library name argument was not given")?
+                                            .clone(),
+                                    );
+                                }
+                                _ => bail!("Unknown argument {option}"),
+                            }
+                        }
+                        Ok(Command::FunctionList { pattern, with_code })
+                    }
+                    ("DELETE", [library]) => Ok(Command::FunctionDelete(library.clone())),
+                    ("DUMP", []) => Ok(Command::FunctionDump),
+                    // Flushing is always synchronous, like SCRIPT FLUSH
+                    ("FLUSH", [] | [_]) => Ok(Command::FunctionFlush),
+                    ("KILL", []) => Ok(Command::FunctionKill),
+                    _ => bail!("Unknown FUNCTION subcommand or wrong number of arguments"),
+                }
+            }
+            "SAVE" => Ok(Command::Save),
+            "BGSAVE" => Ok(Command::BgSave),
+            "LASTSAVE" => Ok(Command::LastSave),
+            "BGREWRITEAOF" => Ok(Command::BgRewriteAof),
+            "INFO" => Ok(Command::Info(string_args(&elements, 1)?)),
+            "CONFIG" => {
+                let subcommand = arg_string(&elements, 1)
+                    .context("CONFIG command requires a subcommand")?
+                    .to_uppercase();
+                let args = string_args(&elements, 2)?;
+                match (subcommand.as_str(), args.as_slice()) {
+                    ("GET", patterns) if !patterns.is_empty() => {
+                        Ok(Command::ConfigGet(patterns.to_vec()))
+                    }
+                    ("SET", pairs) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+                        Ok(Command::ConfigSet(
+                            pairs
+                                .chunks(2)
+                                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                                .collect(),
+                        ))
+                    }
+                    ("RESETSTAT", []) => Ok(Command::ConfigResetStat),
+                    ("REWRITE", []) => Ok(Command::ConfigRewrite),
+                    _ => bail!(
+                        "unknown subcommand or wrong number of arguments for '{}'",
+                        subcommand.to_lowercase()
+                    ),
+                }
+            }
+            "OBJECT" => {
+                let subcommand = arg_string(&elements, 1)
+                    .context("OBJECT command requires a subcommand")?
+                    .to_uppercase();
+                let args = string_args(&elements, 2)?;
+                match (subcommand.as_str(), args.as_slice()) {
+                    ("ENCODING", [key]) => Ok(Command::ObjectEncoding(key.clone())),
+                    ("IDLETIME", [key]) => Ok(Command::ObjectIdleTime(key.clone())),
+                    ("FREQ", [key]) => Ok(Command::ObjectFreq(key.clone())),
+                    ("REFCOUNT", [key]) => Ok(Command::ObjectRefCount(key.clone())),
+                    _ => bail!(
+                        "unknown subcommand or wrong number of arguments for '{}'",
+                        subcommand.to_lowercase()
+                    ),
+                }
+            }
+            "MEMORY" => {
+                let subcommand = arg_string(&elements, 1)
+                    .context("MEMORY command requires a subcommand")?
+                    .to_uppercase();
+                let args = string_args(&elements, 2)?;
+                match (subcommand.as_str(), args.as_slice()) {
+                    ("USAGE", [key]) => Ok(Command::MemoryUsage {
+                        key: key.clone(),
+                        samples: 5,
+                    }),
+                    ("USAGE", [key, option, samples]) if option.eq_ignore_ascii_case("SAMPLES") => {
+                        Ok(Command::MemoryUsage {
+                            key: key.clone(),
+                            samples: samples
+                                .parse()
+                                .context("value is not an integer or out of range")?,
+                        })
+                    }
+                    ("USAGE", [_, ..]) => bail!("syntax error"),
+                    ("STATS", []) => Ok(Command::MemoryStats),
+                    ("DOCTOR", []) => Ok(Command::MemoryDoctor),
+                    _ => bail!(
+                        "unknown subcommand or wrong number of arguments for '{}'",
+                        subcommand.to_lowercase()
+                    ),
+                }
+            }
+            "SWAPDB" => {
+                let args = string_args(&elements, 1)?;
+                let [a, b] = args.as_slice() else {
+                    bail!("wrong number of arguments for 'swapdb' command");
+                };
+                Ok(Command::SwapDb(
+                    a.parse().context("invalid first DB index")?,
+                    b.parse().context("invalid second DB index")?,
+                ))
+            }
+            "MOVE" => {
+                let args = string_args(&elements, 1)?;
+                let [key, db] = args.as_slice() else {
+                    bail!("wrong number of arguments for 'move' command");
+                };
+                Ok(Command::Move {
+                    key: key.clone(),
+                    db: parse_db_index(db)?,
+                })
+            }
+            "FLUSHDB" => Ok(Command::FlushDb {
+                lazy: parse_flush_mode(&elements)?,
+            }),
+            "FLUSHALL" => Ok(Command::FlushAll {
+                lazy: parse_flush_mode(&elements)?,
+            }),
+            "DBSIZE" => Ok(Command::DbSize),
+            "KEYS" => match string_args(&elements, 1)?.as_slice() {
+                [pattern] => Ok(Command::Keys(pattern.clone())),
+                _ => bail!("wrong number of arguments for 'keys' command"),
+            },
+            "SCAN" => {
+                let (cursor, options) = parse_scan(&string_args(&elements, 1)?, true)?;
+                Ok(Command::Scan { cursor, options })
+            }
+            "HSCAN" | "SSCAN" | "ZSCAN" => {
+                let args = string_args(&elements, 1)?;
+                let (key, args) = args.split_first().with_context(|| {
+                    format!(
+                        "wrong number of arguments for '{}' command",
+                        command.to_lowercase()
+                    )
+                })?;
+                let key = key.clone();
+                let (cursor, options) = parse_scan(args, false)?;
+                Ok(match command.as_str() {
+                    "HSCAN" => Command::HashScan {
+                        key,
+                        cursor,
+                        options,
+                    },
+                    "SSCAN" => Command::SetScan {
+                        key,
+                        cursor,
+                        options,
+                    },
+                    _ => Command::SortedSetScan {
+                        key,
+                        cursor,
+                        options,
+                    },
+                })
+            }
+            "WAIT" => {
+                let args = string_args(&elements, 1)?;
+                let [replicas, timeout] = args.as_slice() else {
+                    bail!("wrong number of arguments for 'wait' command");
+                };
+                Ok(Command::Wait {
+                    replicas: replicas
+                        .parse()
+                        .context("value is not an integer or out of range")?,
+                    timeout: timeout
+                        .parse()
+                        .context("timeout is not an integer or out of range")?,
+                })
+            }
+            "WAITAOF" => {
+                let args = string_args(&elements, 1)?;
+                let [local, replicas, timeout] = args.as_slice() else {
+                    bail!("wrong number of arguments for 'waitaof' command");
+                };
+                Ok(Command::WaitAof {
+                    local: local
+                        .parse()
+                        .context("value is not an integer or out of range")?,
+                    replicas: replicas
+                        .parse()
+                        .context("value is not an integer or out of range")?,
+                    timeout: timeout
+                        .parse()
+                        .context("timeout is not an integer or out of range")?,
+                })
+            }
+            "FCALL" | "FCALL_RO" => {
+                let (function, keys, args) = eval_args(&elements)?;
+                Ok(Command::FCall {
+                    function,
+                    keys,
+                    args,
+                    read_only: command == "FCALL_RO",
+                })
+            }
             _ => bail!("Unsupported command"),
         }
     }
@@ -210,110 +1405,933 @@ impl TryFrom<&mut &[u8]> for Command {
     }
 }
 
-async fn expire_key(state: State, key: String, duration: Duration) {
-    sleep(duration).await;
-    state.lock().await.kv.remove(&key);
+/// Block until any of `wait_keys` is signalled, or until `deadline` passes
+/// (`None` waits indefinitely). The lock is released while waiting.
+/// Returns the re-acquired lock and `false` if the wait timed out.
+async fn wait_for_keys<'a>(
+    state: &'a State,
+    mut guard: RwLockWriteGuard<'a, AppState>,
+    wait_keys: &[String],
+    deadline: Option<Instant>,
+) -> (RwLockWriteGuard<'a, AppState>, bool) {
+    let signals: Vec<_> = wait_keys
+        .iter()
+        .map(|wait_key| guard.block_on(wait_key.clone()))
+        .collect();
+    let mut notified: Vec<_> = signals
+        .iter()
+        .map(|signal| Box::pin(signal.notified()))
+        .collect();
+    // Join the wait queues before releasing the lock, so waiters are served in arrival order
+    for future in &mut notified {
+        future.as_mut().enable();
+    }
+    let db = guard.selected;
+    drop(guard); // Release the lock before waiting
+    let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+    let woken = wait_any(notified, remaining).await;
+    let mut guard = lock_db(state, db).await;
+    for wait_key in wait_keys {
+        guard.unblock(wait_key);
+    }
+    (guard, woken)
 }
 
-impl Command {
-    #[allow(clippy::too_many_lines)]
-    pub async fn handle(self, state: State) -> anyhow::Result<RespData> {
-        let response = match self {
-            Command::Ping => RespData::simple_string("PONG"),
-            Command::Echo(arg) => RespData::bulk_string(&arg),
-            Command::Set {
-                key,
-                value,
-                expires,
-                args: _args,
-            } => {
-                debug!("Setting `{key}` to `{value}`");
-                state.lock().await.kv.insert(key.clone(), value);
-                if let Some(expires) = expires {
-                    tokio::spawn(expire_key(state.clone(), key, expires));
-                }
-                RespData::simple_string("OK")
-            }
-            Command::Get(key) => {
-                debug!("Getting value for key: {}", key);
-                let state = state.lock().await;
-                if let Some(value) = state.kv.get(&key) {
-                    value.clone()
-                } else {
-                    RespData::null_bulk_string()
-                }
-            }
-            Command::ListPush {
-                key,
-                values,
-                direction,
-            } => {
-                let mut state = state.lock().await;
-                let array = state
-                    .kv
-                    .entry(key.clone())
-                    .or_insert_with(|| RespData::Array(Some(VecDeque::new())));
-                let len = match (array, direction) {
-                    (RespData::Array(Some(elements)), PushPopDirection::Right) => {
-                        elements.extend(values);
-                        elements.len()
-                    }
-                    (RespData::Array(Some(elements)), PushPopDirection::Left) => {
-                        for value in values {
-                            elements.push_front(value);
-                        }
-                        elements.len()
-                    }
-                    _ => unreachable!("known to be an array"),
-                };
+/// Lock the state for a command of a client that selected database `db`
+async fn lock_db(state: &State, db: usize) -> RwLockWriteGuard<'_, AppState> {
+    let mut guard = state.write().await;
+    guard.select(db);
+    guard
+}
 
-                // Notify one waiting client that the list has changed
-                let wait_list = state.waiting_lists.entry(format!("*{key}")).or_default();
-                wait_list.signal.notify_one();
-                // Decrement the count of waiting clients
-                wait_list.count = wait_list.count.saturating_sub(1);
-                state.prune_waiting_lists();
-                RespData::Integer(i64::try_from(len)?)
+/// Free the keys of flushed databases, in the background if `lazy`
+fn free_dbs(dbs: Vec<Db>, lazy: bool) {
+    if lazy {
+        std::thread::spawn(move || drop(dbs));
+    }
+}
+
+/// Block until enough replicas, and the local AOF for WAITAOF, acknowledged the writes
+/// made so far, or until `timeout` milliseconds pass (0 waits indefinitely)
+async fn wait_acknowledgements(
+    state: &State,
+    local: Option<usize>,
+    replicas: usize,
+    timeout: u64,
+) -> anyhow::Result<RespData> {
+    let deadline = block_deadline(Duration::from_millis(timeout));
+    let mut guard = state.write().await;
+    let offset = guard.replication.offset;
+    let (reply, done) = acknowledgements(&guard, offset, local, replicas);
+    if done {
+        return Ok(reply);
+    }
+    guard.replication.request_acks();
+    let wait_keys = [replication::ACK_WAIT_KEY.to_string()];
+    loop {
+        let woken;
+        (guard, woken) = wait_for_keys(state, guard, &wait_keys, deadline).await;
+        let (reply, done) = acknowledgements(&guard, offset, local, replicas);
+        if done || !woken {
+            return Ok(reply);
+        }
+    }
+}
+
+/// The stream at `key` if it has a consumer group named `group`, or the error to reply with
+fn stream_with_group<'a>(
+    state: &'a mut AppState,
+    key: &str,
+    group: &str,
+) -> Result<&'a mut Stream, RespData> {
+    match state.db.kv.get_mut(key) {
+        Some(Value::Stream(stream)) if stream.groups.contains_key(group) => Ok(stream),
+        Some(Value::Stream(_)) | None => Err(RespData::simple_error(
+            "NOGROUP",
+            format!("No such key '{key}' or consumer group '{group}'"),
+        )),
+        Some(_) => Err(wrong_type()),
+    }
+}
+
+/// Resolve the `$` and `+` IDs of XREAD against the streams as they are right now
+fn resolve_stream_ids(
+    keys: &ReadKeys,
+    streams: Vec<(String, StreamReadFrom)>,
+) -> Result<Vec<(String, StreamId)>, RespData> {
+    let mut from = Vec::with_capacity(streams.len());
+    for (key, read_from) in streams {
+        let stream = match keys.lookup(&key) {
+            Some(Value::Stream(stream)) => Some(stream),
+            Some(_) => return Err(wrong_type()),
+            None => None,
+        };
+        let id = match read_from {
+            StreamReadFrom::After(id) => id,
+            StreamReadFrom::New => stream.map_or(StreamId::MIN, |s| s.last_id),
+            StreamReadFrom::Last => stream
+                .and_then(Stream::last_entry)
+                .and_then(|(id, _)| id.prev())
+                .or_else(|| stream.map(|s| s.last_id))
+                .unwrap_or(StreamId::MIN),
+        };
+        from.push((key, id));
+    }
+    Ok(from)
+}
+
+/// Collect the entries of each stream newer than its ID, skipping streams without any.
+/// Returns `None` if no stream has new entries.
+fn read_streams(
+    keys: &ReadKeys,
+    from: &[(String, StreamId)],
+    count: Option<usize>,
+) -> Option<RespData> {
+    let results: VecDeque<RespData> = from
+        .iter()
+        .filter_map(|(key, id)| {
+            let Some(Value::Stream(stream)) = keys.lookup(key) else {
+                return None;
+            };
+            let entries = stream.after(*id, count);
+            (!entries.is_empty()).then(|| {
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string(key),
+                    entries_to_resp(&entries),
+                ]))
+            })
+        })
+        .collect();
+    (!results.is_empty()).then(|| RespData::array(results))
+}
+
+/// Read from each stream on behalf of a consumer group, as in XREADGROUP.
+/// Returns `Ok(None)` if there is nothing to reply with yet.
+fn read_groups(
+    state: &mut AppState,
+    group: &str,
+    consumer: &str,
+    streams: &[(String, GroupReadFrom)],
+    count: Option<usize>,
+    no_ack: bool,
+) -> Result<Option<RespData>, RespData> {
+    let now = now_ms();
+    let mut results = VecDeque::new();
+    for (key, from) in streams {
+        let stream = stream_with_group(state, key, group)?;
+        let new_consumer = !stream.groups[group].consumers.contains_key(consumer);
+        let entries = stream
+            .read_group(group, consumer, *from, count, no_ack, now)
+            .unwrap_or_default();
+        if new_consumer {
+            propagate_create_consumer(state, key, group, consumer);
+        }
+        // Reading the pending entries list always replies for the stream,
+        // even if it is empty, new entries only when there are some
+        if !entries.is_empty() {
+            state.touch_key(key);
+            if matches!(from, GroupReadFrom::New) {
+                if !no_ack {
+                    for (id, _) in &entries {
+                        propagate_claim(state, key, group, *id);
+                    }
+                }
+                propagate_group_position(state, "SETID", key, group);
+            }
+        }
+        if matches!(from, GroupReadFrom::Pending(_)) || !entries.is_empty() {
+            let entries = entries
+                .iter()
+                .map(|(id, fields)| entry_to_resp(*id, fields.as_ref()))
+                .collect();
+            results.push_back(RespData::array(VecDeque::from([
+                RespData::bulk_string(key),
+                RespData::array(entries),
+            ])));
+        }
+    }
+    Ok((!results.is_empty()).then(|| RespData::array(results)))
+}
+
+/// Propagate `XGROUP CREATE` or `XGROUP SETID` restoring where `group` is at,
+/// however its last delivered ID was given or moved
+fn propagate_group_position(state: &mut AppState, subcommand: &str, key: &str, group: &str) {
+    let Some(Value::Stream(stream)) = state.db.kv.get(key) else {
+        return;
+    };
+    let Some(position) = stream.groups.get(group) else {
+        return;
+    };
+    let mut command = vec![
+        "XGROUP".to_string(),
+        subcommand.to_string(),
+        key.to_string(),
+        group.to_string(),
+        position.last_delivered_id.to_string(),
+    ];
+    if subcommand == "CREATE" {
+        command.push("MKSTREAM".to_string());
+    }
+    if let Some(entries_read) = position.entries_read {
+        command.extend(["ENTRIESREAD".to_string(), entries_read.to_string()]);
+    }
+    state.propagate(RespData::command(command));
+}
+
+/// Propagate the pending entry `id` of `group` as the XCLAIM recreating it exactly,
+/// which is how deliveries to consumers are replayed
+fn propagate_claim(state: &mut AppState, key: &str, group: &str, id: StreamId) {
+    let Some(Value::Stream(stream)) = state.db.kv.get(key) else {
+        return;
+    };
+    let Some(position) = stream.groups.get(group) else {
+        return;
+    };
+    let Some(entry) = position.pending.get(&id) else {
+        return;
+    };
+    let command = RespData::command([
+        "XCLAIM",
+        key,
+        group,
+        &entry.consumer,
+        "0",
+        &id.to_string(),
+        "TIME",
+        &entry.delivery_time.to_string(),
+        "RETRYCOUNT",
+        &entry.delivery_count.to_string(),
+        "FORCE",
+        "JUSTID",
+        "LASTID",
+        &position.last_delivered_id.to_string(),
+    ]);
+    state.propagate(command);
+}
+
+/// Propagate the effects of XCLAIM or XAUTOCLAIM: the consumer if it was created,
+/// each claimed entry, and the acknowledgement of entries deleted from the stream
+fn propagate_claims(
+    state: &mut AppState,
+    key: &str,
+    group: &str,
+    consumer: &str,
+    new_consumer: bool,
+    claimed: &[(StreamId, StreamFields)],
+    deleted: &[StreamId],
+) {
+    if new_consumer {
+        propagate_create_consumer(state, key, group, consumer);
+    }
+    for (id, _) in claimed {
+        propagate_claim(state, key, group, *id);
+    }
+    if !deleted.is_empty() {
+        let mut command = vec!["XACK".to_string(), key.to_string(), group.to_string()];
+        command.extend(deleted.iter().map(StreamId::to_string));
+        state.propagate(RespData::command(command));
+    }
+}
+
+fn propagate_create_consumer(state: &mut AppState, key: &str, group: &str, consumer: &str) {
+    state.propagate(RespData::command([
+        "XGROUP",
+        "CREATECONSUMER",
+        key,
+        group,
+        consumer,
+    ]));
+}
+
+/// The reply of WAIT, or of WAITAOF when `local` is set, given the acknowledgements of
+/// writes up to `offset` so far, and whether they are enough to reply right away
+fn acknowledgements(
+    state: &AppState,
+    offset: u64,
+    local: Option<usize>,
+    replicas: usize,
+) -> (RespData, bool) {
+    let replication = &state.replication;
+    let name = if local.is_some() { "WAITAOF" } else { "WAIT" };
+    if replication.is_replica() {
+        let error = RespData::simple_error(
+            "ERR",
+            format!("{name} cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."),
+        );
+        return (error, true);
+    }
+    let Some(local) = local else {
+        let acked = replication.acked(offset);
+        let reply = RespData::Integer(i64::try_from(acked).unwrap_or(i64::MAX));
+        return (reply, acked >= replicas);
+    };
+    if local > 0 && !state.aof.enabled {
+        let error = RespData::simple_error(
+            "ERR",
+            "WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+        );
+        return (error, true);
+    }
+    let synced_offset = state.aof.synced_offset(replication.offset);
+    let local_acked = usize::from(state.aof.enabled && synced_offset >= offset);
+    let acked = replication.aof_acked(offset);
+    let reply = RespData::array(VecDeque::from([
+        RespData::Integer(i64::try_from(local_acked).unwrap_or(i64::MAX)),
+        RespData::Integer(i64::try_from(acked).unwrap_or(i64::MAX)),
+    ]));
+    (reply, local_acked >= local && acked >= replicas)
+}
+
+/// The positions selected by inclusive `start` and `end` indexes,
+/// which count from the end when negative, in a sequence of `len` elements
+fn index_range(start: i64, end: i64, len: usize) -> Range<usize> {
+    let len = i64::try_from(len).unwrap_or(i64::MAX);
+    let resolve = |index: i64| if index < 0 { index + len } else { index };
+    let start = resolve(start).clamp(0, len);
+    let end = resolve(end).saturating_add(1).clamp(start, len);
+    // Both are within 0..=len now
+    usize::try_from(start).unwrap_or_default()..usize::try_from(end).unwrap_or_default()
+}
+
+/// The instant a blocking command gives up, `None` for a zero timeout which blocks forever
+fn block_deadline(timeout: Duration) -> Option<Instant> {
+    (!timeout.is_zero()).then(|| Instant::now() + timeout)
+}
+
+impl Command {
+    /// Whether the command modifies the dataset. Scripts only do through the commands
+    /// they call, which are checked as they run.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Del(_)
+                | Command::ListPush { .. }
+                | Command::ListPop { .. }
+                | Command::HashSet { .. }
+                | Command::SetAdd { .. }
+                | Command::SortedSetAdd { .. }
+                | Command::StreamAdd { .. }
+                | Command::StreamGroupCreate { .. }
+                | Command::StreamGroupSetId { .. }
+                | Command::StreamGroupDestroy { .. }
+                | Command::StreamGroupCreateConsumer { .. }
+                | Command::StreamGroupDelConsumer { .. }
+                | Command::StreamReadGroup { .. }
+                | Command::StreamAck { .. }
+                | Command::StreamClaim { .. }
+                | Command::StreamAutoClaim { .. }
+                | Command::FunctionLoad { .. }
+                | Command::FunctionDelete(_)
+                | Command::FunctionRestore { .. }
+                | Command::FunctionFlush
+                | Command::SwapDb(..)
+                | Command::Move { .. }
+                | Command::FlushDb { .. }
+                | Command::FlushAll { .. }
+        )
+    }
+
+    /// Whether the command may grow the dataset, which it may not while over `maxmemory`
+    pub fn denies_oom(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::ListPush { .. }
+                | Command::HashSet { .. }
+                | Command::SetAdd { .. }
+                | Command::SortedSetAdd { .. }
+                | Command::StreamAdd { .. }
+                | Command::StreamGroupCreate { .. }
+                | Command::StreamGroupCreateConsumer { .. }
+                | Command::FunctionLoad { .. }
+                | Command::FunctionRestore { .. }
+        )
+    }
+
+    /// The keys the command reads or writes. Scripts are left out, the commands
+    /// they call report their own keys.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Get(key)
+            | Command::ListPush { key, .. }
+            | Command::ListRange { key, .. }
+            | Command::ListLen(key)
+            | Command::ListIndex { key, .. }
+            | Command::ListPop { key, .. }
+            | Command::Type(key)
+            | Command::HashSet { key, .. }
+            | Command::SetAdd { key, .. }
+            | Command::SortedSetAdd { key, .. }
+            | Command::StreamAdd { key, .. }
+            | Command::StreamRange { key, .. }
+            | Command::StreamLen(key)
+            | Command::StreamGroupCreate { key, .. }
+            | Command::StreamGroupSetId { key, .. }
+            | Command::StreamGroupDestroy { key, .. }
+            | Command::StreamGroupCreateConsumer { key, .. }
+            | Command::StreamGroupDelConsumer { key, .. }
+            | Command::StreamAck { key, .. }
+            | Command::StreamPending { key, .. }
+            | Command::StreamClaim { key, .. }
+            | Command::StreamAutoClaim { key, .. }
+            | Command::StreamInfo { key, .. }
+            | Command::StreamInfoGroups(key)
+            | Command::StreamInfoConsumers { key, .. }
+            | Command::Move { key, .. }
+            | Command::HashScan { key, .. }
+            | Command::SetScan { key, .. }
+            | Command::SortedSetScan { key, .. } => vec![key.as_str()],
+            Command::StreamRead { streams, .. } => {
+                streams.iter().map(|(key, _)| key.as_str()).collect()
+            }
+            Command::StreamReadGroup { streams, .. } => {
+                streams.iter().map(|(key, _)| key.as_str()).collect()
+            }
+            Command::Del(keys) => keys.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether a replica that lost its master may run the command
+    /// even when it must not serve stale data
+    pub fn allowed_when_stale(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping
+                | Command::Echo(_)
+                | Command::Info(_)
+                | Command::ConfigGet(_)
+                | Command::ConfigSet(_)
+                | Command::ConfigResetStat
+                | Command::ConfigRewrite
+                | Command::Publish { .. }
+                | Command::ShardPublish { .. }
+                | Command::PubSubChannels(_)
+                | Command::PubSubNumSub(_)
+                | Command::PubSubNumPat
+                | Command::PubSubShardChannels(_)
+                | Command::PubSubShardNumSub(_)
+                | Command::ScriptKill
+                | Command::FunctionKill
+        )
+    }
+
+    /// Whether the command only reads keys, so it may run while other commands do.
+    /// Blocking reads are served by `handle` before this is asked, in transactions
+    /// and scripts they read without blocking.
+    fn reads_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Get(_)
+                | Command::ListRange { .. }
+                | Command::ListLen(_)
+                | Command::ListIndex { .. }
+                | Command::Type(_)
+                | Command::StreamRange { .. }
+                | Command::StreamLen(_)
+                | Command::StreamRead { .. }
+                | Command::HashScan { .. }
+                | Command::SetScan { .. }
+                | Command::SortedSetScan { .. }
+        )
+    }
+
+    /// Whether the command only writes its keys, so it may run while commands
+    /// on keys of other shards do
+    fn writes_keys(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::ListPush { .. }
+                | Command::ListPop { .. }
+                | Command::Del(_)
+                | Command::HashSet { .. }
+                | Command::SetAdd { .. }
+                | Command::SortedSetAdd { .. }
+                | Command::StreamAdd { .. }
+        )
+    }
+
+    /// Whether the command pushes to a list clients are blocked popping from,
+    /// as serving them takes the state exclusively
+    fn serves_blocked(&self, state: &AppState, db: usize) -> bool {
+        matches!(self, Command::ListPush { key, .. }
+            if state.blocked_pops.contains_key(&(db, key.clone())))
+    }
+
+    /// Whether the command runs or manages scripts, which scripts may not call themselves
+    pub fn is_script(&self) -> bool {
+        matches!(
+            self,
+            Command::Eval { .. }
+                | Command::EvalSha { .. }
+                | Command::ScriptLoad(_)
+                | Command::ScriptExists(_)
+                | Command::ScriptFlush
+                | Command::ScriptKill
+                | Command::FunctionLoad { .. }
+                | Command::FunctionList { .. }
+                | Command::FunctionDelete(_)
+                | Command::FunctionDump
+                | Command::FunctionRestore { .. }
+                | Command::FunctionFlush
+                | Command::FunctionKill
+                | Command::FCall { .. }
+        )
+    }
+
+    /// Run the command against database `db`, waiting for data first if it is a blocking
+    /// command. Everything else runs under a single acquisition of the state lock.
+    pub async fn handle(self, state: State, db: usize) -> anyhow::Result<RespData> {
+        match self {
+            Command::ListPop {
+                key,
+                direction,
+                blocking: Some(timeout),
+                ..
+            } => {
+                let pop = Command::ListPop {
+                    key: key.clone(),
+                    count: 1,
+                    direction,
+                    blocking: Some(timeout),
+                };
+                let mut receiver = {
+                    let mut guard = lock_db(&state, db).await;
+                    let response = pop.execute(&mut guard)?;
+                    if !matches!(response, RespData::BulkString(None)) {
+                        return Ok(response);
+                    }
+                    guard.block_pop(&key, direction)
+                }; // Release the lock before waiting
+                let served = match block_deadline(Duration::from_secs_f64(timeout)) {
+                    Some(deadline) => tokio::time::timeout_at(deadline.into(), &mut receiver)
+                        .await
+                        .ok(),
+                    None => Some((&mut receiver).await),
+                };
+                if let Some(Ok(response)) = served {
+                    return Ok(response);
+                }
+                debug!("Blocking pop for key `{key}` timed out after {timeout} seconds");
+                let mut guard = lock_db(&state, db).await;
+                // A push may have served us while we were waiting for the lock
+                let response = receiver
+                    .try_recv()
+                    .unwrap_or_else(|_| RespData::null_bulk_string());
+                drop(receiver);
+                guard.prune_blocked_pops(&key);
+                Ok(response)
+            }
+            Command::StreamRead {
+                streams,
+                count,
+                block: Some(block),
+            } => {
+                let deadline = block_deadline(Duration::from_millis(block));
+                let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                let mut guard = lock_db(&state, db).await;
+                let from = match resolve_stream_ids(&guard.read_keys(db, &keys), streams) {
+                    Ok(from) => from,
+                    Err(error) => return Ok(error),
+                };
+                let wait_keys: Vec<String> = from
+                    .iter()
+                    .map(|(key, _)| stream_wait_key(db, key))
+                    .collect();
+                loop {
+                    if let Some(response) = read_streams(&guard.read_keys(db, &keys), &from, count)
+                    {
+                        return Ok(response);
+                    }
+                    let woken;
+                    (guard, woken) = wait_for_keys(&state, guard, &wait_keys, deadline).await;
+                    if !woken {
+                        debug!("Blocking read timed out after {block} milliseconds");
+                        return Ok(read_streams(&guard.read_keys(db, &keys), &from, count)
+                            .unwrap_or(RespData::Array(None)));
+                    }
+                }
+            }
+            Command::StreamReadGroup {
+                group,
+                consumer,
+                streams,
+                count,
+                block: Some(block),
+                no_ack,
+            } => {
+                let deadline = block_deadline(Duration::from_millis(block));
+                let wait_keys: Vec<String> = streams
+                    .iter()
+                    .map(|(key, _)| stream_wait_key(db, key))
+                    .collect();
+                let mut guard = lock_db(&state, db).await;
+                loop {
+                    match read_groups(&mut guard, &group, &consumer, &streams, count, no_ack) {
+                        Ok(Some(response)) | Err(response) => return Ok(response),
+                        Ok(None) => {}
+                    }
+                    let woken;
+                    (guard, woken) = wait_for_keys(&state, guard, &wait_keys, deadline).await;
+                    if !woken {
+                        debug!("Blocking group read timed out after {block} milliseconds");
+                        return Ok(RespData::Array(None));
+                    }
+                }
+            }
+            Command::Wait { replicas, timeout } => {
+                wait_acknowledgements(&state, None, replicas, timeout).await
+            }
+            Command::WaitAof {
+                local,
+                replicas,
+                timeout,
+            } => wait_acknowledgements(&state, Some(local), replicas, timeout).await,
+            // Killing a script must not wait for the lock the script is holding
+            Command::ScriptKill => Ok(script::kill(false)),
+            Command::FunctionKill => Ok(script::kill(true)),
+            command @ (Command::Eval { .. }
+            | Command::EvalSha { .. }
+            | Command::FunctionLoad { .. }
+            | Command::FunctionRestore { .. }
+            | Command::FCall { .. }) => {
+                // A long script would stall an async worker, run it on a blocking thread
+                let mut guard = state.write_owned().await;
+                guard.select(db);
+                tokio::task::spawn_blocking(move || command.execute(&mut guard)).await?
+            }
+            command if command.reads_only() => command.execute_shared(&*state.read().await, db),
+            command if command.writes_keys() => {
+                {
+                    let guard = state.read().await;
+                    if guard.shares_writes(db) && !command.serves_blocked(&guard, db) {
+                        return command.execute_keyed(&guard, db);
+                    }
+                }
+                command.execute(&mut *lock_db(&state, db).await)
+            }
+            command => command.execute(&mut *lock_db(&state, db).await),
+        }
+    }
+
+    /// Run the command against the state without ever blocking,
+    /// blocking commands behave as if their timeout already passed
+    pub fn execute(self, state: &mut AppState) -> anyhow::Result<RespData> {
+        state.remove_expired();
+        if self.reads_only() {
+            return self.execute_shared(state, state.selected);
+        }
+        if self.writes_keys() {
+            // Serving the clients blocked on a list takes the state exclusively
+            let pushed = match &self {
+                Command::ListPush { key, .. } => Some(key.clone()),
+                _ => None,
+            };
+            let response = self.execute_keyed(state, state.selected);
+            if let Some(key) = pushed {
+                state.serve_blocked_pops(&key);
+                state.account(&key);
+            }
+            state.feed_pending();
+            return response;
+        }
+        let keys: Vec<String> = self.keys().into_iter().map(str::to_string).collect();
+        for key in &keys {
+            state.access_key(key);
+        }
+        let response = self.run(state);
+        // Keys the command wrote take a different amount of memory now
+        for key in &keys {
+            state.account(key);
+        }
+        response
+    }
+
+    /// Run a command that only reads keys of database `db` while holding the state shared,
+    /// alongside other such commands and locking only the shards of its keys
+    fn execute_shared(self, state: &AppState, db: usize) -> anyhow::Result<RespData> {
+        let keys: Vec<String> = self.keys().into_iter().map(str::to_string).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        self.read(&state.read_keys(db, &keys))
+    }
+
+    /// Run a command that only reads keys, against their locked shards
+    fn read(self, keys: &ReadKeys) -> anyhow::Result<RespData> {
+        let response = match self {
+            Command::Get(key) => {
+                debug!("Getting value for key: {}", key);
+                match keys.lookup(&key) {
+                    Some(Value::String(value)) => RespData::BulkString(Some(value.clone())),
+                    Some(_) => wrong_type(),
+                    None => RespData::null_bulk_string(),
+                }
             }
             Command::ListRange { key, start, end } => {
                 debug!("Getting range for key: {}", key);
-                let state = state.lock().await;
-                let response_array =
-                    if let Some(RespData::Array(Some(elements))) = state.kv.get(&key) {
-                        let len = i64::try_from(elements.len())?;
-                        let start = if start < 0 {
-                            (len + start).max(0)
-                        } else if start >= len {
-                            len
-                        } else {
-                            start
-                        };
-                        let end = if end < 0 {
-                            (len + end).max(0)
-                        } else if end >= len {
-                            len - 1
-                        } else {
-                            end
-                        };
-                        elements
-                            .iter()
-                            .skip(usize::try_from(start)?)
-                            .take(usize::try_from(end - start + 1)?)
-                            .cloned()
-                            .collect()
-                    } else {
-                        VecDeque::new()
-                    };
+                let response_array = if let Some(Value::List(elements)) = keys.lookup(&key) {
+                    let range = index_range(start, end, elements.len());
+                    elements
+                        .range(range.start, range.len())
+                        .into_iter()
+                        .map(|element| RespData::BulkString(Some(element)))
+                        .collect()
+                } else {
+                    VecDeque::new()
+                };
                 RespData::array(response_array)
             }
             Command::ListLen(key) => {
-                let state = state.lock().await;
-                if let Some(RespData::Array(Some(elements))) = state.kv.get(&key) {
+                if let Some(Value::List(elements)) = keys.lookup(&key) {
                     RespData::Integer(i64::try_from(elements.len())?)
                 } else {
                     RespData::Integer(0)
                 }
             }
+            Command::ListIndex { key, index } => match keys.lookup(&key) {
+                Some(Value::List(elements)) => {
+                    let len = i64::try_from(elements.len())?;
+                    let index = if index < 0 { index + len } else { index };
+                    match usize::try_from(index) {
+                        Ok(index) if index < elements.len() => elements
+                            .range(index, 1)
+                            .pop()
+                            .map_or_else(RespData::null_bulk_string, |element| {
+                                RespData::BulkString(Some(element))
+                            }),
+                        _ => RespData::null_bulk_string(),
+                    }
+                }
+                Some(_) => wrong_type(),
+                None => RespData::null_bulk_string(),
+            },
+            Command::Type(key) => {
+                RespData::simple_string(keys.lookup(&key).map_or("none", Value::type_name))
+            }
+            Command::HashScan {
+                key,
+                cursor,
+                options,
+            } => {
+                let (cursor, fields) = match keys.lookup(&key) {
+                    Some(Value::Hash(Hash::Hashtable(hash))) => {
+                        scan_steps(cursor, options.count, |cursor, found| {
+                            hash.scan(cursor, |field, value| {
+                                found.push((field.clone(), Some(value.clone())));
+                            })
+                        })
+                    }
+                    // Compact encodings are returned whole
+                    Some(Value::Hash(hash)) => (
+                        0,
+                        hash.iter()
+                            .map(|(field, value)| (field.into_owned(), Some(value.into_owned())))
+                            .collect(),
+                    ),
+                    Some(_) => return Ok(wrong_type()),
+                    None => (0, Vec::new()),
+                };
+                scan_reply(cursor, fields, options.pattern.as_deref())
+            }
+            Command::SetScan {
+                key,
+                cursor,
+                options,
+            } => {
+                let (cursor, members) = match keys.lookup(&key) {
+                    Some(Value::Set(Set::Hashtable(set))) => {
+                        scan_steps(cursor, options.count, |cursor, found| {
+                            set.scan(cursor, |member, ()| found.push((member.clone(), None)))
+                        })
+                    }
+                    Some(Value::Set(set)) => (
+                        0,
+                        set.iter()
+                            .map(|member| (member.into_owned(), None))
+                            .collect(),
+                    ),
+                    Some(_) => return Ok(wrong_type()),
+                    None => (0, Vec::new()),
+                };
+                scan_reply(cursor, members, options.pattern.as_deref())
+            }
+            Command::SortedSetScan {
+                key,
+                cursor,
+                options,
+            } => {
+                let (cursor, members) = match keys.lookup(&key) {
+                    Some(Value::SortedSet(SortedSet::Skiplist(zset))) => {
+                        scan_steps(cursor, options.count, |cursor, found| {
+                            zset.scan(cursor, |member, score| {
+                                found.push((member.to_vec(), Some(score.to_string().into_bytes())));
+                            })
+                        })
+                    }
+                    Some(Value::SortedSet(zset)) => (
+                        0,
+                        zset.iter()
+                            .map(|(member, score)| {
+                                (member.into_owned(), Some(score.to_string().into_bytes()))
+                            })
+                            .collect(),
+                    ),
+                    Some(_) => return Ok(wrong_type()),
+                    None => (0, Vec::new()),
+                };
+                scan_reply(cursor, members, options.pattern.as_deref())
+            }
+            Command::StreamRange {
+                key,
+                start,
+                end,
+                count,
+            } => match keys.lookup(&key) {
+                Some(Value::Stream(stream)) => entries_to_resp(&stream.range(start, end, count)),
+                Some(_) => wrong_type(),
+                None => RespData::array(VecDeque::new()),
+            },
+            Command::StreamLen(key) => match keys.lookup(&key) {
+                Some(Value::Stream(stream)) => {
+                    RespData::Integer(i64::try_from(stream.entries.len())?)
+                }
+                Some(_) => wrong_type(),
+                None => RespData::Integer(0),
+            },
+            Command::StreamRead {
+                streams,
+                count,
+                block: _,
+            } => {
+                // Blocking reads were served by `handle`, here the timeout has already passed
+                match resolve_stream_ids(keys, streams) {
+                    Ok(from) => read_streams(keys, &from, count).unwrap_or(RespData::Array(None)),
+                    Err(error) => error,
+                }
+            }
+            command => unreachable!("{command:?} does not only read keys"),
+        };
+        Ok(response)
+    }
+
+    /// Run a command that only writes keys of database `db` while holding the state shared,
+    /// alongside other such commands and locking only the shards of its keys
+    fn execute_keyed(self, state: &AppState, db: usize) -> anyhow::Result<RespData> {
+        let keys: Vec<String> = self.keys().into_iter().map(str::to_string).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let mut write_keys = state.write_keys(db, &keys);
+        let response = self.write(&mut write_keys);
+        // Keys the command wrote take a different amount of memory now
+        write_keys.account(&keys);
+        response
+    }
+
+    /// Run a command that only writes keys, against their locked shards
+    fn write(self, keys: &mut WriteKeys) -> anyhow::Result<RespData> {
+        let response = match self {
+            Command::Set {
+                key,
+                value,
+                expires,
+                expires_at,
+                args: _args,
+            } => {
+                debug!("Setting `{key}` to `{}`", String::from_utf8_lossy(&value));
+                let at = match expires {
+                    Some(expires) => Some(now_ms() + u64::try_from(expires.as_millis())?),
+                    None => expires_at,
+                };
+                // Relative expiries are propagated as absolute times, so replaying them later
+                // expires the key at the same moment
+                let mut command = vec![b"SET".to_vec(), key.as_bytes().to_vec(), value.clone()];
+                if let Some(at) = at {
+                    command.extend([b"PXAT".to_vec(), at.to_string().into_bytes()]);
+                }
+                keys.insert(key.clone(), Value::String(value));
+                keys.touch(&key);
+                match at {
+                    Some(at) => keys.set_expiry(&key, at),
+                    None => {
+                        keys.clear_expiry(&key);
+                    }
+                }
+                keys.propagate(RespData::command(command));
+                RespData::simple_string("OK")
+            }
+            Command::ListPush {
+                key,
+                values,
+                direction,
+            } => {
+                let limits = keys.encoding();
+                let Value::List(elements) =
+                    keys.get_or_insert_with(key.clone(), || Value::List(List::default()))
+                else {
+                    return Ok(wrong_type());
+                };
+                let name = match direction {
+                    PushPopDirection::Right => "RPUSH",
+                    PushPopDirection::Left => "LPUSH",
+                };
+                let command = RespData::command(
+                    [name.as_bytes(), key.as_bytes()]
+                        .into_iter()
+                        .chain(values.iter().map(Vec::as_slice)),
+                );
+                for value in values {
+                    match direction {
+                        PushPopDirection::Right => elements.push_back(value, limits),
+                        PushPopDirection::Left => elements.push_front(value, limits),
+                    }
+                }
+                let len = elements.len();
+                keys.touch(&key);
+                keys.propagate(command);
+                RespData::Integer(i64::try_from(len)?)
+            }
             Command::ListPop {
                 key,
                 count,
@@ -324,88 +2342,773 @@ impl Command {
                     // If count is 0, return an empty array (without blocking)
                     return Ok(RespData::array(VecDeque::new()));
                 }
-                if let Some(blocking) = blocking {
-                    let signal = {
-                        let mut state = state.lock().await;
-                        let wait_list = state.waiting_lists.entry(format!("*{key}")).or_default();
-                        wait_list.count += 1;
-                        wait_list.signal.clone()
-                    }; // Release the lock before waiting
-                    if blocking == 0.0 {
-                        // Blocking indefinitely
-                        signal.notified().await;
-                    } else {
-                        // Blocking with timeout
-                        select! {
-                            () = signal.notified() => {}
-                            () = sleep(Duration::from_secs_f64(blocking)) => {
-                                debug!("Blocking pop for key `{key}` timed out after {blocking} seconds");
-                            }
-                        }
+                let limits = keys.encoding();
+                let popped: VecDeque<RespData> = match keys.get_mut(&key) {
+                    Some(Value::List(elements)) => {
+                        let count = usize::try_from(count).unwrap_or(usize::MAX);
+                        let count = count.min(elements.len());
+                        (0..count)
+                            .filter_map(|_| match direction {
+                                PushPopDirection::Right => elements.pop_back(limits),
+                                PushPopDirection::Left => elements.pop_front(limits),
+                            })
+                            .map(|element| RespData::BulkString(Some(element)))
+                            .collect()
+                    }
+                    Some(_) => return Ok(wrong_type()),
+                    None => VecDeque::new(),
+                };
+                if !popped.is_empty() {
+                    keys.touch(&key);
+                    let name = match direction {
+                        PushPopDirection::Right => "RPOP",
+                        PushPopDirection::Left => "LPOP",
+                    };
+                    keys.propagate(RespData::command([name, &key, &popped.len().to_string()]));
+                }
+                if matches!(keys.get(&key), Some(Value::List(elements)) if elements.is_empty()) {
+                    // Lists are removed as soon as they are empty
+                    keys.remove(&key);
+                }
+                match (popped.front(), blocking) {
+                    // A blocking pop that found nothing replies with a null bulk string
+                    (None, Some(_)) => RespData::null_bulk_string(),
+                    // Blocking pops reply with the key and the popped value
+                    (Some(value), Some(_)) => RespData::array(VecDeque::from([
+                        RespData::bulk_string(&key),
+                        value.clone(),
+                    ])),
+                    // 1 is a special case as we return the popped value directly
+                    (Some(value), None) if count == 1 => value.clone(),
+                    _ => RespData::array(popped),
+                }
+            }
+            Command::Del(deleted) => {
+                let removed = deleted
+                    .iter()
+                    .filter(|key| keys.remove(key).is_some())
+                    .count();
+                if removed > 0 {
+                    keys.propagate(RespData::command(
+                        std::iter::once("DEL").chain(deleted.iter().map(String::as_str)),
+                    ));
+                }
+                RespData::Integer(i64::try_from(removed)?)
+            }
+            Command::HashSet { key, fields } => {
+                let limits = keys.encoding();
+                let Value::Hash(hash) =
+                    keys.get_or_insert_with(key.clone(), || Value::Hash(Hash::default()))
+                else {
+                    return Ok(wrong_type());
+                };
+                let command = RespData::command(
+                    [b"HSET".as_slice(), key.as_bytes()].into_iter().chain(
+                        fields
+                            .iter()
+                            .flat_map(|(field, value)| [field.as_slice(), value.as_slice()]),
+                    ),
+                );
+                let added = fields
+                    .into_iter()
+                    .map(|(field, value)| hash.insert(field, value, limits))
+                    .filter(|added| *added)
+                    .count();
+                keys.touch(&key);
+                keys.propagate(command);
+                RespData::Integer(i64::try_from(added)?)
+            }
+            Command::SetAdd { key, members } => {
+                let limits = keys.encoding();
+                let Value::Set(set) =
+                    keys.get_or_insert_with(key.clone(), || Value::Set(Set::default()))
+                else {
+                    return Ok(wrong_type());
+                };
+                let command = RespData::command(
+                    [b"SADD".as_slice(), key.as_bytes()]
+                        .into_iter()
+                        .chain(members.iter().map(Vec::as_slice)),
+                );
+                let added = members
+                    .into_iter()
+                    .map(|member| set.insert(member, limits))
+                    .filter(|added| *added)
+                    .count();
+                // Adding only members the set has leaves it as it was
+                if added > 0 {
+                    keys.touch(&key);
+                    keys.propagate(command);
+                }
+                RespData::Integer(i64::try_from(added)?)
+            }
+            Command::SortedSetAdd { key, members } => {
+                let limits = keys.encoding();
+                let Value::SortedSet(zset) =
+                    keys.get_or_insert_with(key.clone(), || Value::SortedSet(SortedSet::default()))
+                else {
+                    return Ok(wrong_type());
+                };
+                let mut command = vec![b"ZADD".to_vec(), key.as_bytes().to_vec()];
+                for (score, member) in &members {
+                    command.extend([score.to_string().into_bytes(), member.clone()]);
+                }
+                let added = members
+                    .into_iter()
+                    .map(|(score, member)| zset.insert(member, score, limits))
+                    .filter(|added| *added)
+                    .count();
+                keys.touch(&key);
+                keys.propagate(RespData::command(command));
+                RespData::Integer(i64::try_from(added)?)
+            }
+            Command::StreamAdd { key, id, fields } => {
+                let id = match keys.get(&key) {
+                    Some(Value::Stream(stream)) => stream.next_id(id),
+                    Some(_) => return Ok(wrong_type()),
+                    None => Stream::default().next_id(id),
+                };
+                let id = match id {
+                    Ok(id) => id,
+                    Err(e) => return Ok(RespData::simple_error("ERR", e.to_string())),
+                };
+                // Propagated with the ID it resolved to, so replaying it adds the same entry
+                let mut command = vec![b"XADD".to_vec(), key.as_bytes().to_vec()];
+                command.push(id.to_string().into_bytes());
+                for (field, value) in &fields {
+                    command.extend([field.clone(), value.clone()]);
+                }
+                let command = RespData::command(command);
+                if let Value::Stream(stream) =
+                    keys.get_or_insert_with(key.clone(), || Value::Stream(Stream::default()))
+                {
+                    stream.add(id, fields);
+                }
+                keys.touch(&key);
+                keys.propagate(command);
+                // Wake every reader blocked on this stream, each of them gets the new entry
+                keys.notify_stream(&key);
+                RespData::bulk_string(id.to_string())
+            }
+            command => unreachable!("{command:?} does not only write keys"),
+        };
+        Ok(response)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn run(self, state: &mut AppState) -> anyhow::Result<RespData> {
+        let response = match self {
+            Command::Ping => RespData::simple_string("PONG"),
+            Command::Echo(arg) => RespData::bulk_string(&arg),
+            Command::StreamGroupCreate {
+                key,
+                group,
+                id,
+                make_stream,
+                entries_read,
+            } => {
+                if !state.db.kv.contains_key(&key) {
+                    if !make_stream {
+                        return Ok(RespData::simple_error(
+                            "ERR",
+                            "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+                        ));
                     }
+                    state
+                        .db
+                        .kv
+                        .insert(key.clone(), Value::Stream(Stream::default()));
                 }
-                let mut state = state.lock().await;
-                let len = if let Some(RespData::Array(Some(elements))) = state.kv.get(&key) {
-                    elements.len()
+                let Some(Value::Stream(stream)) = state.db.kv.get_mut(&key) else {
+                    return Ok(wrong_type());
+                };
+                if stream.create_group(group.clone(), id, entries_read) {
+                    state.touch_key(&key);
+                    propagate_group_position(state, "CREATE", &key, &group);
+                    RespData::simple_string("OK")
                 } else {
-                    0
+                    RespData::simple_error("BUSYGROUP", "Consumer Group name already exists")
+                }
+            }
+            Command::StreamGroupSetId {
+                key,
+                group,
+                id,
+                entries_read,
+            } => match stream_with_group(state, &key, &group) {
+                Ok(stream) => {
+                    stream.set_group_id(&group, id, entries_read);
+                    state.touch_key(&key);
+                    propagate_group_position(state, "SETID", &key, &group);
+                    RespData::simple_string("OK")
+                }
+                Err(error) => error,
+            },
+            Command::StreamGroupDestroy { key, group } => {
+                let destroyed = match state.db.kv.get_mut(&key) {
+                    Some(Value::Stream(stream)) => stream.groups.remove(&group).is_some(),
+                    Some(_) => return Ok(wrong_type()),
+                    None => false,
                 };
-                if len == 0 {
-                    // If the list is already empty, remove the key and return an empty array
-                    if blocking.is_some() {
-                        // If we were blocking and still, we return a null bulk string
-                        return Ok(RespData::null_bulk_string());
+                if destroyed {
+                    state.touch_key(&key);
+                    state.propagate(RespData::command(["XGROUP", "DESTROY", &key, &group]));
+                }
+                // Readers blocked on the group must find out it is gone
+                state.notify_all(&stream_wait_key(state.selected, &key));
+                RespData::Integer(i64::from(destroyed))
+            }
+            Command::StreamGroupCreateConsumer {
+                key,
+                group,
+                consumer,
+            } => match stream_with_group(state, &key, &group) {
+                Ok(stream) => {
+                    let consumer_group = stream.groups.get_mut(&group).expect("group exists");
+                    let created = !consumer_group.consumers.contains_key(&consumer);
+                    consumer_group.consumer(&consumer, now_ms());
+                    if created {
+                        state.touch_key(&key);
+                        propagate_create_consumer(state, &key, &group, &consumer);
                     }
-                    state.kv.remove(&key);
-                    return Ok(RespData::array(VecDeque::new()));
+                    RespData::Integer(i64::from(created))
                 }
-                if usize::try_from(count).unwrap_or(usize::MAX) > len {
-                    // If count is greater or equal than the list length
-                    // remove the key and return the entire list
-                    let array = state
-                        .kv
-                        .remove(&key)
-                        .unwrap_or(RespData::Array(Some(VecDeque::new())));
-                    return Ok(array);
+                Err(error) => error,
+            },
+            Command::StreamGroupDelConsumer {
+                key,
+                group,
+                consumer,
+            } => match stream_with_group(state, &key, &group) {
+                Ok(stream) => {
+                    let consumer_group = stream.groups.get_mut(&group).expect("group exists");
+                    let Some(pending) = consumer_group.remove_consumer(&consumer) else {
+                        return Ok(RespData::Integer(0));
+                    };
+                    state.touch_key(&key);
+                    state.propagate(RespData::command([
+                        "XGROUP",
+                        "DELCONSUMER",
+                        &key,
+                        &group,
+                        &consumer,
+                    ]));
+                    RespData::Integer(i64::try_from(pending)?)
                 }
-                if count == 1 {
-                    // 1 is a special case as we return the popped value directly
-                    // instead of an array, unless we were blocking, then we
-                    // return an array with the key and the popped value
-                    if let Some(RespData::Array(Some(elements))) = state.kv.get_mut(&key) {
-                        let popped_value = match direction {
-                            PushPopDirection::Right => elements.pop_back(),
-                            PushPopDirection::Left => elements.pop_front(),
+                Err(error) => error,
+            },
+            Command::StreamReadGroup {
+                group,
+                consumer,
+                streams,
+                count,
+                block: _,
+                no_ack,
+            } => match read_groups(state, &group, &consumer, &streams, count, no_ack) {
+                Ok(response) => response.unwrap_or(RespData::Array(None)),
+                Err(error) => error,
+            },
+            Command::StreamAck { key, group, ids } => {
+                match stream_with_group(state, &key, &group) {
+                    Ok(stream) => {
+                        let consumer_group = stream.groups.get_mut(&group).expect("group exists");
+                        let acked = ids.iter().filter(|id| consumer_group.ack(**id)).count();
+                        if acked > 0 {
+                            state.touch_key(&key);
+                            let mut command = vec!["XACK".to_string(), key, group];
+                            command.extend(ids.iter().map(StreamId::to_string));
+                            state.propagate(RespData::command(command));
                         }
-                        .expect("Empty list was handled above");
-                        if blocking.is_some() {
-                            let elements =
-                                VecDeque::from([RespData::bulk_string(&key), popped_value]);
-                            return Ok(RespData::array(elements));
+                        RespData::Integer(i64::try_from(acked)?)
+                    }
+                    // Acknowledging against a missing key or group is not an error
+                    Err(RespData::SimpleError { kind, .. }) if kind == "NOGROUP" => {
+                        RespData::Integer(0)
+                    }
+                    Err(error) => error,
+                }
+            }
+            Command::StreamPending { key, group, filter } => {
+                match stream_with_group(state, &key, &group) {
+                    Ok(stream) => {
+                        let group = &stream.groups[&group];
+                        match filter {
+                            Some(filter) => group.pending_range(&filter, now_ms()),
+                            None => group.pending_summary(),
                         }
-                        return Ok(popped_value);
                     }
-                    return Ok(RespData::array(VecDeque::new()));
+                    Err(error) => error,
                 }
-                let result = if let Some(RespData::Array(Some(elements))) = state.kv.get_mut(&key) {
-                    let mut popped_values = VecDeque::new();
-                    for _ in 0..count {
-                        if let Some(value) = match direction {
-                            PushPopDirection::Right => elements.pop_back(),
-                            PushPopDirection::Left => elements.pop_front(),
-                        } {
-                            popped_values.push_back(value);
+            }
+            Command::StreamClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                ids,
+                options,
+            } => {
+                let stream = match stream_with_group(state, &key, &group) {
+                    Ok(stream) => stream,
+                    Err(error) => return Ok(error),
+                };
+                let new_consumer = !stream.groups[&group].consumers.contains_key(&consumer);
+                let deleted: Vec<StreamId> = ids
+                    .iter()
+                    .filter(|id| !stream.entries.contains_key(id))
+                    .copied()
+                    .collect();
+                let claimed = stream
+                    .claim(&group, &consumer, min_idle, &ids, &options, now_ms())
+                    .unwrap_or_default();
+                state.touch_key(&key);
+                propagate_claims(
+                    state,
+                    &key,
+                    &group,
+                    &consumer,
+                    new_consumer,
+                    &claimed,
+                    &deleted,
+                );
+                let claimed = claimed
+                    .iter()
+                    .map(|(id, fields)| {
+                        if options.just_id {
+                            RespData::bulk_string(id.to_string())
                         } else {
-                            break; // No more elements to pop
+                            entry_to_resp(*id, Some(fields))
                         }
+                    })
+                    .collect();
+                RespData::array(claimed)
+            }
+            Command::StreamAutoClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                start,
+                count,
+                just_id,
+            } => {
+                let stream = match stream_with_group(state, &key, &group) {
+                    Ok(stream) => stream,
+                    Err(error) => return Ok(error),
+                };
+                let new_consumer = !stream.groups[&group].consumers.contains_key(&consumer);
+                let (cursor, claimed, deleted) = stream
+                    .auto_claim(&group, &consumer, min_idle, start, count, just_id, now_ms())
+                    .unwrap_or_default();
+                state.touch_key(&key);
+                propagate_claims(
+                    state,
+                    &key,
+                    &group,
+                    &consumer,
+                    new_consumer,
+                    &claimed,
+                    &deleted,
+                );
+                let claimed = claimed
+                    .iter()
+                    .map(|(id, fields)| {
+                        if just_id {
+                            RespData::bulk_string(id.to_string())
+                        } else {
+                            entry_to_resp(*id, Some(fields))
+                        }
+                    })
+                    .collect();
+                let deleted = deleted
+                    .iter()
+                    .map(|id| RespData::bulk_string(id.to_string()))
+                    .collect();
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string(cursor.to_string()),
+                    RespData::array(claimed),
+                    RespData::array(deleted),
+                ]))
+            }
+            Command::StreamInfo { key, full } => match state.db.kv.get(&key) {
+                Some(Value::Stream(stream)) => stream.info(full),
+                Some(_) => wrong_type(),
+                None => RespData::simple_error("ERR", "no such key"),
+            },
+            Command::StreamInfoGroups(key) => match state.db.kv.get(&key) {
+                Some(Value::Stream(stream)) => stream.info_groups(),
+                Some(_) => wrong_type(),
+                None => RespData::simple_error("ERR", "no such key"),
+            },
+            Command::StreamInfoConsumers { key, group } => {
+                match stream_with_group(state, &key, &group) {
+                    Ok(stream) => stream.groups[&group].info_consumers(now_ms()),
+                    Err(error) => error,
+                }
+            }
+            Command::Publish { channel, message } => {
+                let receivers = state.pubsub.publish(&channel, &message);
+                RespData::Integer(i64::try_from(receivers)?)
+            }
+            Command::PubSubChannels(pattern) => {
+                let channels = state
+                    .pubsub
+                    .active_channels(pattern.as_deref())
+                    .into_iter()
+                    .map(RespData::bulk_string)
+                    .collect();
+                RespData::array(channels)
+            }
+            Command::PubSubNumSub(channels) => {
+                let mut counts = VecDeque::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let count = state.pubsub.subscriber_count(&channel);
+                    counts.push_back(RespData::bulk_string(channel));
+                    counts.push_back(RespData::Integer(i64::try_from(count)?));
+                }
+                RespData::array(counts)
+            }
+            Command::PubSubNumPat => {
+                RespData::Integer(i64::try_from(state.pubsub.pattern_count())?)
+            }
+            Command::ShardPublish { channel, message } => {
+                let receivers = state.pubsub.spublish(&channel, &message);
+                RespData::Integer(i64::try_from(receivers)?)
+            }
+            Command::PubSubShardChannels(pattern) => {
+                let channels = state
+                    .pubsub
+                    .active_shard_channels(pattern.as_deref())
+                    .into_iter()
+                    .map(RespData::bulk_string)
+                    .collect();
+                RespData::array(channels)
+            }
+            Command::PubSubShardNumSub(channels) => {
+                let mut counts = VecDeque::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let count = state.pubsub.shard_subscriber_count(&channel);
+                    counts.push_back(RespData::bulk_string(channel));
+                    counts.push_back(RespData::Integer(i64::try_from(count)?));
+                }
+                RespData::array(counts)
+            }
+            Command::Eval {
+                script,
+                keys,
+                args,
+                read_only,
+            } => {
+                // Scripts run with EVAL are cached for EVALSHA as well
+                state
+                    .scripts
+                    .entry(script::sha1_hex(&script))
+                    .or_insert_with(|| script.clone());
+                // The writes of a script are propagated instead of the script itself
+                state.begin_atomic();
+                let reply = script::eval(state, &script, &keys, &args, read_only);
+                state.end_atomic();
+                reply
+            }
+            Command::EvalSha {
+                sha,
+                keys,
+                args,
+                read_only,
+            } => {
+                let Some(script) = state.scripts.get(&sha).cloned() else {
+                    return Ok(RespData::simple_error(
+                        "NOSCRIPT",
+                        "No matching script. Please use EVAL.",
+                    ));
+                };
+                state.begin_atomic();
+                let reply = script::eval(state, &script, &keys, &args, read_only);
+                state.end_atomic();
+                reply
+            }
+            Command::ScriptLoad(script) => {
+                let sha = script::sha1_hex(&script);
+                state.scripts.insert(sha.clone(), script);
+                RespData::bulk_string(sha)
+            }
+            Command::ScriptExists(shas) => RespData::array(
+                shas.iter()
+                    .map(|sha| RespData::Integer(i64::from(state.scripts.contains_key(sha))))
+                    .collect(),
+            ),
+            Command::ScriptFlush => {
+                state.scripts.clear();
+                RespData::simple_string("OK")
+            }
+            Command::ScriptKill => script::kill(false),
+            Command::FunctionLoad { code, replace } => {
+                let added = script::load_library(&code).and_then(|library| {
+                    let name = library.name.clone();
+                    state.functions.add(library, replace).map(|()| name)
+                });
+                match added {
+                    Ok(name) => {
+                        *state.dirty.get_mut() += 1;
+                        let command = if replace {
+                            RespData::command(["FUNCTION", "LOAD", "REPLACE", &code])
+                        } else {
+                            RespData::command(["FUNCTION", "LOAD", &code])
+                        };
+                        state.propagate(command);
+                        RespData::bulk_string(name)
                     }
-                    RespData::array(popped_values)
+                    Err(message) => RespData::simple_error("ERR", message),
+                }
+            }
+            Command::FunctionList { pattern, with_code } => {
+                state.functions.list(pattern.as_deref(), with_code)
+            }
+            Command::FunctionDelete(library) => {
+                if state.functions.libraries.remove(&library).is_some() {
+                    *state.dirty.get_mut() += 1;
+                    state.propagate(RespData::command(["FUNCTION", "DELETE", &library]));
+                    RespData::simple_string("OK")
                 } else {
-                    RespData::array(VecDeque::new())
+                    RespData::simple_error("ERR", "Library not found")
+                }
+            }
+            Command::FunctionDump => RespData::BulkString(Some(state.functions.dump())),
+            Command::FunctionRestore { payload, policy } => {
+                let Some(codes) = Functions::parse_dump(&payload) else {
+                    return Ok(RespData::simple_error(
+                        "ERR",
+                        "payload version or checksum are wrong",
+                    ));
                 };
-                state.prune_waiting_lists();
-                result
+                let restored = codes
+                    .iter()
+                    .map(|code| script::load_library(code))
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|libraries| state.functions.restore(libraries, policy));
+                match restored {
+                    Ok(()) => {
+                        *state.dirty.get_mut() += 1;
+                        let policy = match policy {
+                            RestorePolicy::Append => "APPEND",
+                            RestorePolicy::Replace => "REPLACE",
+                            RestorePolicy::Flush => "FLUSH",
+                        };
+                        state.propagate(RespData::command([
+                            b"FUNCTION".as_slice(),
+                            b"RESTORE",
+                            &payload,
+                            policy.as_bytes(),
+                        ]));
+                        RespData::simple_string("OK")
+                    }
+                    Err(message) => RespData::simple_error("ERR", message),
+                }
+            }
+            Command::FunctionFlush => {
+                state.functions = Functions::default();
+                *state.dirty.get_mut() += 1;
+                state.propagate(RespData::command(["FUNCTION", "FLUSH"]));
+                RespData::simple_string("OK")
+            }
+            Command::FunctionKill => script::kill(true),
+            Command::FCall {
+                function,
+                keys,
+                args,
+                read_only,
+            } => {
+                let Some((library, info)) = state.functions.find(&function) else {
+                    return Ok(RespData::simple_error("ERR", "Function not found"));
+                };
+                if read_only && !info.no_writes() {
+                    return Ok(RespData::simple_error(
+                        "ERR",
+                        "Can not execute a script with write flag using *_ro command.",
+                    ));
+                }
+                // Functions flagged `no-writes` may not write even when called with FCALL
+                let read_only = read_only || info.no_writes();
+                let code = library.code.clone();
+                state.begin_atomic();
+                let reply = script::fcall(state, &code, &function, &keys, &args, read_only);
+                state.end_atomic();
+                reply
+            }
+            Command::Save => {
+                rdb::save(state)?;
+                RespData::simple_string("OK")
+            }
+            Command::BgSave => {
+                rdb::bgsave(state)?;
+                RespData::simple_string("Background saving started")
+            }
+            Command::LastSave => RespData::Integer(i64::try_from(state.rdb.last_save)?),
+            // Inside transactions and scripts there is no waiting, only the current count
+            Command::Wait { replicas, .. } => {
+                acknowledgements(state, state.replication.offset, None, replicas).0
             }
+            Command::WaitAof {
+                local, replicas, ..
+            } => acknowledgements(state, state.replication.offset, Some(local), replicas).0,
+            Command::BgRewriteAof => {
+                aof::bgrewrite(state)?;
+                RespData::simple_string("Background append only file rewriting started")
+            }
+            Command::Info(sections) => RespData::bulk_string(info::info(state, &sections)),
+            Command::ConfigGet(patterns) => config::get(state, &patterns),
+            Command::ConfigSet(pairs) => config::set_many(state, &pairs),
+            Command::ConfigResetStat => {
+                config::reset_stats(state);
+                RespData::simple_string("OK")
+            }
+            Command::ConfigRewrite => {
+                config::rewrite(state)?;
+                RespData::simple_string("OK")
+            }
+            Command::ObjectEncoding(key) => match state.db.kv.get(&key) {
+                Some(value) => RespData::bulk_string(value.encoding()),
+                None => RespData::null_bulk_string(),
+            },
+            Command::ObjectIdleTime(key) => {
+                if state.eviction.policy.lfu() {
+                    return Ok(RespData::simple_error(
+                        "ERR",
+                        "An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.",
+                    ));
+                }
+                match state.db.kv.shard(&key).meta.get(&key) {
+                    Some(meta) => RespData::Integer(i64::try_from(
+                        now_ms().saturating_sub(meta.last_access) / 1000,
+                    )?),
+                    None => RespData::null_bulk_string(),
+                }
+            }
+            Command::ObjectFreq(key) => {
+                if !state.eviction.policy.lfu() {
+                    return Ok(RespData::simple_error(
+                        "ERR",
+                        "An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.",
+                    ));
+                }
+                match state.db.kv.shard(&key).meta.get(&key) {
+                    Some(meta) => RespData::Integer(i64::from(evict::lfu_decayed(
+                        meta,
+                        state.eviction.lfu_decay_time,
+                        now_ms(),
+                    ))),
+                    None => RespData::null_bulk_string(),
+                }
+            }
+            Command::ObjectRefCount(key) => {
+                if state.db.kv.contains_key(&key) {
+                    RespData::Integer(1)
+                } else {
+                    RespData::null_bulk_string()
+                }
+            }
+            Command::MemoryUsage { key, samples } => {
+                if state.db.kv.contains_key(&key) {
+                    RespData::Integer(i64::try_from(state.key_memory(&key, samples))?)
+                } else {
+                    RespData::null_bulk_string()
+                }
+            }
+            Command::MemoryStats => info::memory_stats(state),
+            Command::MemoryDoctor => RespData::bulk_string(info::memory_doctor(state)),
+            Command::SwapDb(a, b) => {
+                let databases = state.config.databases;
+                if a >= databases || b >= databases {
+                    return Ok(RespData::simple_error("ERR", "DB index is out of range"));
+                }
+                if a != b {
+                    state.swap_dbs(a, b);
+                    state.propagate(RespData::command([
+                        "SWAPDB",
+                        &a.to_string(),
+                        &b.to_string(),
+                    ]));
+                    // Clients blocked on either database may find their keys now
+                    state.serve_blocked_db(a);
+                    state.serve_blocked_db(b);
+                }
+                RespData::simple_string("OK")
+            }
+            Command::Move { key, db } => {
+                if db >= state.config.databases {
+                    return Ok(RespData::simple_error("ERR", "DB index is out of range"));
+                }
+                let source = state.selected;
+                if db == source {
+                    return Ok(RespData::simple_error(
+                        "ERR",
+                        "source and destination objects are the same",
+                    ));
+                }
+                if !state.db.kv.contains_key(&key) {
+                    return Ok(RespData::Integer(0));
+                }
+                state.select(db);
+                state.remove_expired();
+                let exists = state.db.kv.contains_key(&key);
+                state.select(source);
+                if exists {
+                    return Ok(RespData::Integer(0));
+                }
+                let expiry = state.db.kv.expiry(&key);
+                let value = state.remove(&key).context("key was found above")?;
+                state.propagate(RespData::command(["MOVE", &key, &db.to_string()]));
+                state.select(db);
+                state.db.kv.insert(key.clone(), value);
+                if let Some(at) = expiry {
+                    state.set_expiry(&key, at);
+                }
+                state.account(&key);
+                state.touch_key(&key);
+                state.serve_blocked_pops(&key);
+                state.notify_all(&stream_wait_key(db, &key));
+                state.select(source);
+                RespData::Integer(1)
+            }
+            Command::FlushDb { lazy } => {
+                let db = state.flush_db(state.selected);
+                free_dbs(vec![db], lazy);
+                let mode = if lazy { "ASYNC" } else { "SYNC" };
+                state.propagate(RespData::command(["FLUSHDB", mode]));
+                RespData::simple_string("OK")
+            }
+            Command::FlushAll { lazy } => {
+                let dbs = state.clear();
+                free_dbs(dbs, lazy);
+                let mode = if lazy { "ASYNC" } else { "SYNC" };
+                state.propagate(RespData::command(["FLUSHALL", mode]));
+                RespData::simple_string("OK")
+            }
+            Command::DbSize => RespData::Integer(i64::try_from(state.db.kv.len())?),
+            Command::Keys(pattern) => RespData::array(
+                state
+                    .db
+                    .kv
+                    .keys()
+                    .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+                    .map(RespData::bulk_string)
+                    .collect(),
+            ),
+            Command::Scan { cursor, options } => {
+                let kv = &state.db.kv;
+                let (cursor, keys) = scan_steps(cursor, options.count, |cursor, found| {
+                    kv.scan(cursor, |key, value| {
+                        let kind = options.kind.as_deref();
+                        if kind.is_none_or(|kind| kind.eq_ignore_ascii_case(value.type_name())) {
+                            found.push((key.as_bytes().to_vec(), None));
+                        }
+                    })
+                });
+                scan_reply(cursor, keys, options.pattern.as_deref())
+            }
+            command => unreachable!(
+                "{command:?} only reads or writes keys, it runs in `execute_shared` or `execute_keyed`"
+            ),
         };
         Ok(response)
     }
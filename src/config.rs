@@ -0,0 +1,1302 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::ValueEnum;
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
+use tracing::info;
+
+use crate::{
+    aof::{self, AppendFsync},
+    evict::{self, MaxmemoryPolicy},
+    glob::glob_match,
+    info::{TOTAL_COMMANDS_PROCESSED, TOTAL_CONNECTIONS_RECEIVED},
+    rdb,
+    replication::{self, MasterLink},
+    resp::RespData,
+    script,
+    state::AppState,
+};
+
+/// Settings without a subsystem of their own to live in
+#[derive(Debug)]
+pub struct Config {
+    pub bind: Ipv4Addr,
+    pub port: u16,
+    /// Shared with the connections, which check them without the state lock
+    pub clients: Arc<ClientLimits>,
+    /// Number of databases clients may SELECT
+    pub databases: usize,
+    /// Whether the background cycle moves on the rehashing of the keyspace
+    pub activerehashing: bool,
+    pub loglevel: LogLevel,
+    /// The config file the server started with, which CONFIG REWRITE updates
+    pub file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: Ipv4Addr::UNSPECIFIED,
+            port: 6379,
+            clients: Arc::default(),
+            databases: 16,
+            activerehashing: true,
+            loglevel: LogLevel::default(),
+            file: None,
+        }
+    }
+}
+
+/// Settings of client connections that are read while a script may hold the state lock
+#[derive(Debug)]
+pub struct ClientLimits {
+    pub maxclients: AtomicU64,
+    /// Seconds a client may stay idle before it is disconnected, 0 for never
+    pub timeout: AtomicU64,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        Self {
+            maxclients: AtomicU64::new(10000),
+            timeout: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Verbosity of the log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    #[default]
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+    Nothing,
+}
+
+impl LogLevel {
+    /// The tracing filter logging what this level does
+    pub fn filter(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose | LogLevel::Notice => "info",
+            LogLevel::Warning => "warn",
+            LogLevel::Nothing => "off",
+        }
+    }
+}
+
+type FilterReloader = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+static LOG_FILTER_RELOADER: OnceLock<FilterReloader> = OnceLock::new();
+
+/// Register how the log filter is replaced, for `loglevel` to change at runtime
+pub fn set_log_filter_reloader(
+    reload: impl Fn(&str) -> anyhow::Result<()> + Send + Sync + 'static,
+) {
+    let _ = LOG_FILTER_RELOADER.set(Box::new(reload));
+}
+
+fn apply_log_level(state: &mut AppState) -> anyhow::Result<()> {
+    if let Some(reload) = LOG_FILTER_RELOADER.get() {
+        reload(state.config.loglevel.filter())?;
+    }
+    Ok(())
+}
+
+/// How a parameter is typed, read and written
+enum Access {
+    Bool(
+        fn(&AppState) -> bool,
+        fn(&mut AppState, bool) -> anyhow::Result<()>,
+    ),
+    Integer {
+        min: i64,
+        max: i64,
+        get: fn(&AppState) -> i64,
+        set: fn(&mut AppState, i64) -> anyhow::Result<()>,
+    },
+    /// A size in bytes, given with an optional unit such as `100mb`
+    Memory(
+        fn(&AppState) -> u64,
+        fn(&mut AppState, u64) -> anyhow::Result<()>,
+    ),
+    Enum(
+        &'static [&'static str],
+        fn(&AppState) -> String,
+        fn(&mut AppState, &str) -> anyhow::Result<()>,
+    ),
+    String(
+        fn(&AppState) -> String,
+        fn(&mut AppState, &str) -> anyhow::Result<()>,
+    ),
+}
+
+/// A configuration parameter
+struct Parameter {
+    name: &'static str,
+    alias: Option<&'static str>,
+    /// Whether CONFIG SET may change it, all of them can be set at startup
+    mutable: bool,
+    access: Access,
+    /// Puts a value changed by CONFIG SET into effect, values set at startup
+    /// take effect as the server starts
+    apply: Option<fn(&mut AppState) -> anyhow::Result<()>>,
+}
+
+impl Parameter {
+    fn get(&self, state: &AppState) -> String {
+        match &self.access {
+            Access::Bool(get, _) => if get(state) { "yes" } else { "no" }.to_string(),
+            Access::Integer { get, .. } => get(state).to_string(),
+            Access::Memory(get, _) => get(state).to_string(),
+            Access::Enum(_, get, _) | Access::String(get, _) => get(state),
+        }
+    }
+
+    /// Check that `value` suits the parameter, without applying it
+    fn validate(&self, value: &str) -> anyhow::Result<()> {
+        match &self.access {
+            Access::Bool(..) => {
+                parse_bool(value)?;
+            }
+            Access::Integer { min, max, .. } => {
+                parse_integer(value, *min, *max)?;
+            }
+            Access::Memory(..) => {
+                parse_memory(value)?;
+            }
+            Access::Enum(values, ..) => parse_enum(values, value)?,
+            Access::String(..) => {}
+        }
+        Ok(())
+    }
+
+    fn set(&self, state: &mut AppState, value: &str) -> anyhow::Result<()> {
+        match &self.access {
+            Access::Bool(_, set) => set(state, parse_bool(value)?),
+            Access::Integer { min, max, set, .. } => set(state, parse_integer(value, *min, *max)?),
+            Access::Memory(_, set) => set(state, parse_memory(value)?),
+            Access::Enum(values, _, set) => {
+                parse_enum(values, value)?;
+                set(state, &value.to_lowercase())
+            }
+            Access::String(_, set) => set(state, value),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => bail!("argument must be 'yes' or 'no'"),
+    }
+}
+
+fn parse_integer(value: &str, min: i64, max: i64) -> anyhow::Result<i64> {
+    let n: i64 = value
+        .parse()
+        .context("argument couldn't be parsed into an integer")?;
+    ensure!(
+        (min..=max).contains(&n),
+        "argument must be between {min} and {max} inclusive"
+    );
+    Ok(n)
+}
+
+/// Parse a size in bytes, with an optional unit: `k`, `m` and `g` are powers of 1000,
+/// `kb`, `mb` and `gb` powers of 1024
+pub fn parse_memory(value: &str) -> anyhow::Result<u64> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => bail!("argument must be a memory value"),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .context("argument must be a memory value")
+}
+
+fn parse_enum(values: &[&str], value: &str) -> anyhow::Result<()> {
+    ensure!(
+        values.iter().any(|v| v.eq_ignore_ascii_case(value)),
+        "argument(s) must be one of the following: {}",
+        values.join(", ")
+    );
+    Ok(())
+}
+
+pub fn value_enum_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn positive(n: i64) -> u64 {
+    u64::try_from(n).unwrap_or_default()
+}
+
+fn signed(n: u64) -> i64 {
+    i64::try_from(n).unwrap_or(i64::MAX)
+}
+
+/// Every configuration parameter, in the order CONFIG REWRITE appends them
+static PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "bind",
+        alias: None,
+        mutable: false,
+        apply: None,
+        access: Access::String(
+            |state| state.config.bind.to_string(),
+            |state, value| {
+                state.config.bind = value.parse().context("Invalid bind address")?;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "port",
+        alias: None,
+        mutable: false,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: 65535,
+            get: |state| i64::from(state.config.port),
+            set: |state, port| {
+                state.config.port = u16::try_from(port)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "dir",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::String(
+            |state| state.rdb.dir.display().to_string(),
+            |state, value| {
+                ensure!(Path::new(value).is_dir(), "No such directory: {value}");
+                state.rdb.dir = PathBuf::from(value);
+                state.aof.dir = PathBuf::from(value);
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "dbfilename",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::String(
+            |state| state.rdb.dbfilename.clone(),
+            |state, value| {
+                ensure!(
+                    !value.contains('/'),
+                    "dbfilename can't be a path, just a filename"
+                );
+                state.rdb.dbfilename = value.to_string();
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "save",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::String(
+            |state| {
+                state
+                    .rdb
+                    .save_rules
+                    .iter()
+                    .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            },
+            |state, value| {
+                state.rdb.save_rules = rdb::parse_save_rules(value)?;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "appendonly",
+        alias: None,
+        mutable: true,
+        apply: Some(aof::apply_enabled),
+        access: Access::Bool(
+            |state| state.aof.enabled,
+            |state, enabled| {
+                state.aof.enabled = enabled;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "appendfilename",
+        alias: None,
+        mutable: false,
+        apply: None,
+        access: Access::String(
+            |state| state.aof.filename.clone(),
+            |state, value| {
+                ensure!(
+                    !value.contains('/'),
+                    "appendfilename can't be a path, just a filename"
+                );
+                state.aof.filename = value.to_string();
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "appenddirname",
+        alias: None,
+        mutable: false,
+        apply: None,
+        access: Access::String(
+            |state| state.aof.dirname.clone(),
+            |state, value| {
+                ensure!(
+                    !value.contains('/'),
+                    "appenddirname can't be a path, just a dirname"
+                );
+                state.aof.dirname = value.to_string();
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "appendfsync",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Enum(
+            &["always", "everysec", "no"],
+            |state| value_enum_name(&state.aof.fsync),
+            |state, value| {
+                state.aof.fsync = AppendFsync::from_str(value, true).map_err(anyhow::Error::msg)?;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "aof-load-truncated",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Bool(
+            |state| state.aof.load_truncated,
+            |state, enabled| {
+                state.aof.load_truncated = enabled;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "replicaof",
+        alias: Some("slaveof"),
+        mutable: false,
+        apply: None,
+        access: Access::String(
+            |state| {
+                state
+                    .replication
+                    .master
+                    .as_ref()
+                    .map_or(String::new(), |master| {
+                        format!("{} {}", master.host, master.port)
+                    })
+            },
+            |state, value| {
+                state.replication.master =
+                    replication::parse_master(&[value.to_string()])?.map(|(host, port)| {
+                        MasterLink {
+                            host,
+                            port,
+                            link_up: false,
+                        }
+                    });
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "repl-backlog-size",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Memory(
+            |state| state.replication.backlog_size() as u64,
+            |state, size| {
+                state.replication.set_backlog_size(usize::try_from(size)?);
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "replica-read-only",
+        alias: Some("slave-read-only"),
+        mutable: true,
+        apply: None,
+        access: Access::Bool(
+            |state| state.replication.read_only,
+            |state, enabled| {
+                state.replication.read_only = enabled;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "replica-serve-stale-data",
+        alias: Some("slave-serve-stale-data"),
+        mutable: true,
+        apply: None,
+        access: Access::Bool(
+            |state| state.replication.serve_stale_data,
+            |state, enabled| {
+                state.replication.serve_stale_data = enabled;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "busy-reply-threshold",
+        alias: Some("lua-time-limit"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |_| signed(script::busy_reply_threshold()),
+            set: |_, milliseconds| {
+                script::set_busy_reply_threshold(positive(milliseconds));
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "maxmemory",
+        alias: None,
+        mutable: true,
+        apply: Some(evict::apply_maxmemory),
+        access: Access::Memory(
+            |state| state.eviction.maxmemory,
+            |state, bytes| {
+                state.eviction.maxmemory = bytes;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Enum(
+            &[
+                "volatile-lru",
+                "volatile-lfu",
+                "volatile-random",
+                "volatile-ttl",
+                "allkeys-lru",
+                "allkeys-lfu",
+                "allkeys-random",
+                "noeviction",
+            ],
+            |state| value_enum_name(&state.eviction.policy),
+            |state, value| {
+                let policy = MaxmemoryPolicy::from_str(value, true).map_err(anyhow::Error::msg)?;
+                state.eviction.set_policy(policy);
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "maxmemory-samples",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 1,
+            max: 64,
+            get: |state| signed(state.eviction.samples as u64),
+            set: |state, samples| {
+                state.eviction.samples = usize::try_from(samples)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "lfu-log-factor",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+            get: |state| signed(state.eviction.lfu_log_factor),
+            set: |state, factor| {
+                state.eviction.lfu_log_factor = positive(factor);
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "lfu-decay-time",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+            get: |state| signed(state.eviction.lfu_decay_time),
+            set: |state, minutes| {
+                state.eviction.lfu_decay_time = positive(minutes);
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "list-max-listpack-size",
+        alias: Some("list-max-ziplist-size"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: i32::MIN as i64,
+            max: i32::MAX as i64,
+            get: |state| state.encoding.list_max_listpack_size,
+            set: |state, size| {
+                state.encoding.list_max_listpack_size = size;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "list-compress-depth",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+            get: |state| signed(state.encoding.list_compress_depth as u64),
+            set: |state, depth| {
+                state.encoding.list_compress_depth = usize::try_from(depth)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "hash-max-listpack-entries",
+        alias: Some("hash-max-ziplist-entries"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.hash_max_listpack_entries as u64),
+            set: |state, n| {
+                state.encoding.hash_max_listpack_entries = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "hash-max-listpack-value",
+        alias: Some("hash-max-ziplist-value"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.hash_max_listpack_value as u64),
+            set: |state, n| {
+                state.encoding.hash_max_listpack_value = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "set-max-intset-entries",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.set_max_intset_entries as u64),
+            set: |state, n| {
+                state.encoding.set_max_intset_entries = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "set-max-listpack-entries",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.set_max_listpack_entries as u64),
+            set: |state, n| {
+                state.encoding.set_max_listpack_entries = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "set-max-listpack-value",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.set_max_listpack_value as u64),
+            set: |state, n| {
+                state.encoding.set_max_listpack_value = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-entries",
+        alias: Some("zset-max-ziplist-entries"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.zset_max_listpack_entries as u64),
+            set: |state, n| {
+                state.encoding.zset_max_listpack_entries = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-value",
+        alias: Some("zset-max-ziplist-value"),
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.encoding.zset_max_listpack_value as u64),
+            set: |state, n| {
+                state.encoding.zset_max_listpack_value = usize::try_from(n)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "maxclients",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 1,
+            max: i64::MAX,
+            get: |state| signed(state.config.clients.maxclients.load(Ordering::Relaxed)),
+            set: |state, clients| {
+                let maxclients = &state.config.clients.maxclients;
+                maxclients.store(positive(clients), Ordering::Relaxed);
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "timeout",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Integer {
+            min: 0,
+            max: i64::MAX,
+            get: |state| signed(state.config.clients.timeout.load(Ordering::Relaxed)),
+            set: |state, seconds| {
+                let timeout = &state.config.clients.timeout;
+                timeout.store(positive(seconds), Ordering::Relaxed);
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "databases",
+        alias: None,
+        mutable: false,
+        apply: None,
+        access: Access::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+            get: |state| signed(state.config.databases as u64),
+            set: |state, databases| {
+                state.config.databases = usize::try_from(databases)?;
+                Ok(())
+            },
+        },
+    },
+    Parameter {
+        name: "activerehashing",
+        alias: None,
+        mutable: true,
+        apply: None,
+        access: Access::Bool(
+            |state| state.config.activerehashing,
+            |state, enabled| {
+                state.config.activerehashing = enabled;
+                Ok(())
+            },
+        ),
+    },
+    Parameter {
+        name: "loglevel",
+        alias: None,
+        mutable: true,
+        apply: Some(apply_log_level),
+        access: Access::Enum(
+            &["debug", "verbose", "notice", "warning", "nothing"],
+            |state| value_enum_name(&state.config.loglevel),
+            |state, value| {
+                state.config.loglevel =
+                    LogLevel::from_str(value, true).map_err(anyhow::Error::msg)?;
+                Ok(())
+            },
+        ),
+    },
+];
+
+fn find(name: &str) -> Option<&'static Parameter> {
+    PARAMETERS.iter().find(|parameter| {
+        parameter.name.eq_ignore_ascii_case(name)
+            || parameter
+                .alias
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+/// How deep config files may include each other
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Command line options kept from before the config registry, with the parameter they set
+const OPTION_ALIASES: &[(&str, &str)] = &[("--host", "bind"), ("-p", "port")];
+
+/// A directive of the config file or the command line
+struct Directive {
+    /// The parameter name followed by its values
+    args: Vec<String>,
+    /// Where the directive comes from, as `line 3 of redis.conf`
+    location: String,
+    text: String,
+}
+
+/// Load the config file, if any, then apply the command line options over it.
+/// Options are given as `--name value ...`, like a directive of the config file,
+/// or as one of the [`OPTION_ALIASES`].
+pub fn load(state: &mut AppState, file: Option<&Path>, options: &[String]) -> anyhow::Result<()> {
+    let mut directives = Vec::new();
+    if let Some(file) = file {
+        read_file(file, 0, &mut directives)?;
+        state.config.file = Some(std::path::absolute(file)?);
+    }
+    for option in options {
+        let name = OPTION_ALIASES
+            .iter()
+            .find(|(alias, _)| alias == option)
+            .map(|(_, name)| *name)
+            .or_else(|| option.strip_prefix("--"));
+        match name {
+            Some(name) => directives.push(Directive {
+                args: vec![name.to_string()],
+                location: format!("option {option} of the command line"),
+                text: String::new(),
+            }),
+            None => match directives.last_mut() {
+                Some(directive) if directive.text.is_empty() => {
+                    directive.args.push(option.clone());
+                }
+                _ => bail!("Invalid argument `{option}`, options are given as --name value"),
+            },
+        }
+    }
+    // Every save directive adds rules, the defaults are only replaced by the first one
+    let mut save_rules: Option<String> = None;
+    for directive in &directives {
+        apply_directive(state, &directive.args, &mut save_rules).map_err(|e| {
+            let text = if directive.text.is_empty() {
+                directive.args.join(" ")
+            } else {
+                directive.text.clone()
+            };
+            anyhow!(
+                "Reading the configuration, at {}\n>>> '{text}'\n{e:#}",
+                directive.location
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Read the directives of a config file, following its `include` directives
+fn read_file(path: &Path, depth: usize, directives: &mut Vec<Directive>) -> anyhow::Result<()> {
+    ensure!(
+        depth <= MAX_INCLUDE_DEPTH,
+        "Too many nested includes reading {}",
+        path.display()
+    );
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the config file {}", path.display()))?;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("line {} of {}", i + 1, path.display());
+        let args = split_args(line)
+            .map_err(|e| anyhow!("Reading the configuration, at {location}\n>>> '{line}'\n{e}"))?;
+        match args.as_slice() {
+            [include, file] if include.eq_ignore_ascii_case("include") => {
+                read_file(Path::new(file), depth + 1, directives)?;
+            }
+            _ => directives.push(Directive {
+                args,
+                location,
+                text: line.to_string(),
+            }),
+        }
+    }
+    Ok(())
+}
+
+fn apply_directive(
+    state: &mut AppState,
+    args: &[String],
+    save_rules: &mut Option<String>,
+) -> anyhow::Result<()> {
+    let (name, values) = args.split_first().context("Empty directive")?;
+    let parameter = find(name).context("Bad directive or wrong number of arguments")?;
+    match (parameter.name, values) {
+        // `save ""` disables snapshots like a bare `save`, dropping the rules before it
+        ("save", []) => parameter.set(state, save_rules.insert(String::new())),
+        ("save", [rules]) if rules.is_empty() => {
+            parameter.set(state, save_rules.insert(String::new()))
+        }
+        ("save", values) => {
+            let rules = save_rules.get_or_insert_with(String::new);
+            for value in values {
+                rules.push(' ');
+                rules.push_str(value);
+            }
+            parameter.set(state, rules)
+        }
+        // A master may also be given as a host and a port in one argument
+        ("replicaof", [_, ..]) => parameter.set(state, &values.join(" ")),
+        (_, [value]) => parameter.set(state, value),
+        _ => bail!("wrong number of arguments"),
+    }
+}
+
+/// Split a config line into arguments the way Redis does: arguments in double quotes
+/// may hold escapes such as `\n` and `\x41`, in single quotes only `\'`
+fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                let c = chars
+                    .next()
+                    .context("Unbalanced quotes in configuration line")?;
+                match (first, c) {
+                    (_, c) if c == first => break,
+                    ('"', '\\') => {
+                        let escaped = chars
+                            .next()
+                            .context("Unbalanced quotes in configuration line")?;
+                        match escaped {
+                            'n' => arg.push(b'\n'),
+                            'r' => arg.push(b'\r'),
+                            't' => arg.push(b'\t'),
+                            'b' => arg.push(8),
+                            'a' => arg.push(7),
+                            'x' => {
+                                let hex: String = chars.clone().take(2).collect();
+                                match u8::from_str_radix(&hex, 16) {
+                                    Ok(byte) if hex.len() == 2 => {
+                                        arg.push(byte);
+                                        chars.nth(1);
+                                    }
+                                    _ => arg.push(b'x'),
+                                }
+                            }
+                            c => arg.extend(c.to_string().bytes()),
+                        }
+                    }
+                    ('\'', '\\') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        arg.push(b'\'');
+                    }
+                    (_, c) => arg.extend(c.to_string().bytes()),
+                }
+            }
+            // A closing quote must end the argument
+            ensure!(
+                chars.peek().is_none_or(|c| c.is_whitespace()),
+                "Unbalanced quotes in configuration line"
+            );
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.extend(c.to_string().bytes());
+            }
+        }
+        args.push(String::from_utf8_lossy(&arg).to_string());
+    }
+}
+
+/// `CONFIG GET pattern [pattern ...]`, the parameters whose name matches any pattern.
+/// Aliases are only matched by their exact name.
+pub fn get(state: &AppState, patterns: &[String]) -> RespData {
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.to_lowercase();
+        for parameter in PARAMETERS {
+            let name = if glob_match(pattern.as_bytes(), parameter.name.as_bytes()) {
+                parameter.name
+            } else if parameter.alias == Some(pattern.as_str()) {
+                parameter.alias.unwrap_or_default()
+            } else {
+                continue;
+            };
+            if seen.insert(name) {
+                entries.push((
+                    RespData::bulk_string(name),
+                    RespData::bulk_string(parameter.get(state)),
+                ));
+            }
+        }
+    }
+    RespData::Map(entries)
+}
+
+/// `CONFIG SET parameter value [parameter value ...]`, applying all of them or none
+pub fn set_many(state: &mut AppState, pairs: &[(String, String)]) -> RespData {
+    let failed = |name: &str, reason: &str| {
+        RespData::simple_error(
+            "ERR",
+            format!("CONFIG SET failed (possibly related to argument '{name}') - {reason}"),
+        )
+    };
+    let mut parameters = Vec::with_capacity(pairs.len());
+    for (name, value) in pairs {
+        let Some(parameter) = find(name) else {
+            return RespData::simple_error(
+                "ERR",
+                format!("Unknown option or number of arguments for CONFIG SET - '{name}'"),
+            );
+        };
+        if !parameter.mutable {
+            return failed(name, "can't set immutable config");
+        }
+        if parameters
+            .iter()
+            .any(|(other, _): &(&Parameter, &str)| std::ptr::eq(*other, parameter))
+        {
+            return failed(name, "duplicate parameter");
+        }
+        if let Err(e) = parameter.validate(value) {
+            return failed(name, &e.to_string());
+        }
+        parameters.push((parameter, value.as_str()));
+    }
+    let previous: Vec<String> = parameters
+        .iter()
+        .map(|(parameter, _)| parameter.get(state))
+        .collect();
+    let mut result = Ok(());
+    for (parameter, value) in &parameters {
+        result = parameter.set(state, value).map_err(|e| (parameter.name, e));
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        for (parameter, _) in &parameters {
+            if let Some(apply) = parameter.apply {
+                result = apply(state).map_err(|e| (parameter.name, e));
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let Err((name, e)) = result else {
+        return RespData::simple_string("OK");
+    };
+    // Undo every change, so the parameters change together or not at all
+    for ((parameter, _), previous) in parameters.iter().zip(&previous) {
+        let _ = parameter.set(state, previous);
+        if let Some(apply) = parameter.apply {
+            let _ = apply(state);
+        }
+    }
+    failed(name, &format!("{e:#}"))
+}
+
+/// `CONFIG RESETSTAT`, zeroing the counters INFO reports
+pub fn reset_stats(state: &mut AppState) {
+    let used_memory = state.used_memory();
+    let stats = &mut state.stats;
+    TOTAL_CONNECTIONS_RECEIVED.store(0, Ordering::Relaxed);
+    TOTAL_COMMANDS_PROCESSED.store(0, Ordering::Relaxed);
+    stats.keyspace_hits.store(0, Ordering::Relaxed);
+    stats.keyspace_misses.store(0, Ordering::Relaxed);
+    stats.expired_keys.store(0, Ordering::Relaxed);
+    stats.evicted_keys = 0;
+    stats.peak_memory.store(used_memory, Ordering::Relaxed);
+}
+
+/// `CONFIG REWRITE`, writing the current configuration to the config file
+pub fn rewrite(state: &AppState) -> anyhow::Result<()> {
+    let path = state
+        .config
+        .file
+        .as_ref()
+        .context("The server is running without a config file")?;
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => bail!("Failed to read {}: {e}", path.display()),
+    };
+    let defaults = AppState::default();
+    let rewritten = rewrite_contents(&contents, state, &defaults);
+    rdb::write_file(path, rewritten.as_bytes())
+        .with_context(|| format!("Rewriting config file: {}", path.display()))?;
+    info!("CONFIG REWRITE executed with success");
+    Ok(())
+}
+
+/// The config file `contents` with the lines of every parameter set to its current value.
+/// Comments and unknown lines are kept, parameters that differ from `defaults` and have
+/// no line yet are appended.
+fn rewrite_contents(contents: &str, state: &AppState, defaults: &AppState) -> String {
+    let mut written = HashSet::new();
+    let mut lines: VecDeque<String> = VecDeque::new();
+    for line in contents.lines() {
+        let directive = line.split_whitespace().next().unwrap_or_default();
+        let parameter = (!directive.starts_with('#'))
+            .then(|| find(directive))
+            .flatten();
+        match parameter {
+            None => lines.push_back(line.to_string()),
+            // Repeated directives collapse into the first one
+            Some(parameter) if !written.insert(parameter.name) => {}
+            Some(parameter) => lines.extend(directive_line(parameter, state)),
+        }
+    }
+    let mut appended = false;
+    for parameter in PARAMETERS {
+        if written.contains(parameter.name) || parameter.get(state) == parameter.get(defaults) {
+            continue;
+        }
+        if !appended && !lines.is_empty() {
+            lines.push_back("# Generated by CONFIG REWRITE".to_string());
+        }
+        appended = true;
+        lines.extend(directive_line(parameter, state));
+    }
+    let mut rewritten = Vec::from(lines).join("\n");
+    rewritten.push('\n');
+    rewritten
+}
+
+/// The config file line of a parameter, `None` for a master that is not set
+fn directive_line(parameter: &Parameter, state: &AppState) -> Option<String> {
+    let value = parameter.get(state);
+    match parameter.name {
+        "replicaof" if value.is_empty() => None,
+        // Lists of arguments are written as they are, the rest is quoted when needed
+        "save" | "replicaof" if !value.is_empty() => Some(format!("{} {value}", parameter.name)),
+        _ => Some(format!("{} {}", parameter.name, quote(&value))),
+    }
+}
+
+/// Quote a config file argument that is empty or holds spaces, quotes or escapes
+fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+    {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("2MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_memory("1gb").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_memory("1tb").is_err());
+        assert!(parse_memory("-1").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn test_set_many() {
+        let mut state = AppState::default();
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        let reply = set_many(
+            &mut state,
+            &pairs(&[("maxmemory", "1mb"), ("TIMEOUT", "5")]),
+        );
+        assert_eq!(reply.to_string(), "+OK\r\n");
+        assert_eq!(state.eviction.maxmemory, 1024 * 1024);
+        assert_eq!(state.config.clients.timeout.load(Ordering::Relaxed), 5);
+        // One invalid value rejects the whole set
+        let reply = set_many(
+            &mut state,
+            &pairs(&[("maxmemory", "2mb"), ("timeout", "x")]),
+        );
+        assert!(reply.to_string().starts_with("-ERR CONFIG SET failed"));
+        assert_eq!(state.eviction.maxmemory, 1024 * 1024);
+        let reply = set_many(&mut state, &pairs(&[("port", "1234")]));
+        assert!(reply
+            .to_string()
+            .ends_with("can't set immutable config\r\n"));
+        let reply = get(
+            &state,
+            &[
+                "maxmemory".to_string(),
+                "maxc*".to_string(),
+                "timeout".to_string(),
+            ],
+        );
+        assert_eq!(
+            reply.to_string(),
+            "%3\r\n$9\r\nmaxmemory\r\n$7\r\n1048576\r\n$10\r\nmaxclients\r\n\
+             $5\r\n10000\r\n$7\r\ntimeout\r\n$1\r\n5\r\n"
+        );
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args("  save 60   1000 ").unwrap(),
+            ["save", "60", "1000"]
+        );
+        assert_eq!(
+            split_args(r#"dir "/my dir\x41\n" 'it\'s'"#).unwrap(),
+            ["dir", "/my dirA\n", "it's"]
+        );
+        assert_eq!(split_args(r#"save """#).unwrap(), ["save", ""]);
+        assert!(split_args(r#"dir "/tmp"#).is_err());
+        assert!(split_args(r#"dir "/tmp"x"#).is_err());
+    }
+
+    #[test]
+    fn test_load_options() {
+        let mut state = AppState::default();
+        let options = "--save 60 1 --save 10 2 --port 6380 --replicaof localhost 6379";
+        let options: Vec<String> = options.split(' ').map(String::from).collect();
+        load(&mut state, None, &options).unwrap();
+        assert_eq!(find("save").unwrap().get(&state), "60 1 10 2");
+        assert_eq!(state.config.port, 6380);
+        assert_eq!(find("slaveof").unwrap().get(&state), "localhost 6379");
+        let error = load(&mut state, None, &["--nope".to_string(), "1".to_string()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Reading the configuration, at option --nope of the command line\n\
+             >>> 'nope 1'\nBad directive or wrong number of arguments"
+        );
+        assert!(load(&mut state, None, &["--port".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path = std::env::temp_dir().join(format!("redis-{}.conf", std::process::id()));
+        std::fs::write(&path, "save 900 1\nsave 300 10\nsave \"\"\nport 6380\n").unwrap();
+        let mut state = AppState::default();
+        let loaded = load(&mut state, Some(&path), &[]);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(find("save").unwrap().get(&state), "");
+        assert_eq!(state.config.port, 6380);
+        // As an option of the command line, `--save ""` drops the default rules
+        let mut state = AppState::default();
+        let options = ["--save".to_string(), String::new()];
+        load(&mut state, None, &options).unwrap();
+        assert_eq!(find("save").unwrap().get(&state), "");
+    }
+
+    #[test]
+    fn test_load_option_aliases() {
+        let mut state = AppState::default();
+        let cli = Cli::parse_from(["redis-server", "--host", "127.0.0.1", "-p", "6380"]);
+        assert_eq!(cli.config_file(), None);
+        load(&mut state, cli.config_file(), cli.options()).unwrap();
+        assert_eq!(find("bind").unwrap().get(&state), "127.0.0.1");
+        assert_eq!(state.config.port, 6380);
+    }
+
+    #[test]
+    fn test_rewrite_contents() {
+        let defaults = AppState::default();
+        let mut state = AppState::default();
+        state.eviction.maxmemory = 100;
+        state.rdb.dbfilename = "my dump.rdb".to_string();
+        state.rdb.save_rules.clear();
+        let contents =
+            "# comment\nport 6379\ndbfilename old.rdb\nunknown x\nsave 60 1\nsave 10 5\n";
+        assert_eq!(
+            rewrite_contents(contents, &state, &defaults),
+            "# comment\nport 6379\ndbfilename \"my dump.rdb\"\nunknown x\nsave \"\"\n\
+             # Generated by CONFIG REWRITE\nmaxmemory 100\n"
+        );
+    }
+}
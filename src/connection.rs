@@ -0,0 +1,1125 @@
+use anyhow::{bail, ensure, Context};
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::interval,
+};
+use tracing::{debug, info, instrument};
+
+use crate::{
+    cluster::same_slot,
+    cmd::{parse_db_index, Command},
+    config::ClientLimits,
+    evict,
+    info::TOTAL_COMMANDS_PROCESSED,
+    pubsub::push_frame,
+    replication,
+    resp::RespData,
+    script,
+    state::{AppState, State},
+};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+/// How often connections check whether they were idle for longer than `timeout`
+const IDLE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Commands a RESP2 connection may run while it has active subscriptions
+const SUBSCRIBED_MODE_COMMANDS: &[&str] = &[
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "SSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "PING",
+    "QUIT",
+    "RESET",
+];
+
+/// Commands acting on the connection itself rather than on the keyspace
+#[derive(Debug, Clone)]
+pub enum ConnectionCommand {
+    Subscribe(Vec<String>),
+    /// An empty list unsubscribes from every channel
+    Unsubscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    /// An empty list unsubscribes from every pattern
+    PUnsubscribe(Vec<String>),
+    SSubscribe(Vec<String>),
+    /// An empty list unsubscribes from every shard channel
+    SUnsubscribe(Vec<String>),
+    /// Switch the protocol version, None to keep the current one
+    Hello(Option<u8>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+    Reset,
+    Quit,
+    /// `REPLCONF <option> <value> ...`, sent by replicas
+    ReplConf(Vec<String>),
+    /// `PSYNC <replid> <offset>`, a replica asking for the replication stream
+    Psync {
+        replid: String,
+        offset: i64,
+    },
+    /// `REPLICAOF <host> <port>` or `REPLICAOF NO ONE`, switching the replication role
+    ReplicaOf(Option<(String, u16)>),
+    /// `SELECT index`, switching the database the connection runs commands against
+    Select(usize),
+}
+
+impl ConnectionCommand {
+    /// Parse a connection command, returns `None` if `name` is not one
+    fn parse(name: &str, args: &[String]) -> anyhow::Result<Option<Self>> {
+        let command = match name {
+            "SUBSCRIBE" => {
+                ensure!(!args.is_empty(), "SUBSCRIBE command requires a channel");
+                Self::Subscribe(args.to_vec())
+            }
+            "UNSUBSCRIBE" => Self::Unsubscribe(args.to_vec()),
+            "PSUBSCRIBE" => {
+                ensure!(!args.is_empty(), "PSUBSCRIBE command requires a pattern");
+                Self::PSubscribe(args.to_vec())
+            }
+            "PUNSUBSCRIBE" => Self::PUnsubscribe(args.to_vec()),
+            "SSUBSCRIBE" => {
+                ensure!(
+                    !args.is_empty(),
+                    "SSUBSCRIBE command requires a shard channel"
+                );
+                Self::SSubscribe(args.to_vec())
+            }
+            "SUNSUBSCRIBE" => Self::SUnsubscribe(args.to_vec()),
+            "HELLO" => match args {
+                [] => Self::Hello(None),
+                [version] => Self::Hello(Some(
+                    version
+                        .parse()
+                        .context("Protocol version is not an integer or out of range")?,
+                )),
+                _ => bail!("HELLO options are not supported"),
+            },
+            "MULTI" => Self::Multi,
+            "EXEC" => Self::Exec,
+            "DISCARD" => Self::Discard,
+            "WATCH" => {
+                ensure!(
+                    !args.is_empty(),
+                    "wrong number of arguments for 'watch' command"
+                );
+                Self::Watch(args.to_vec())
+            }
+            "UNWATCH" => Self::Unwatch,
+            "RESET" => Self::Reset,
+            "QUIT" => Self::Quit,
+            "REPLCONF" => {
+                ensure!(
+                    args.len().is_multiple_of(2),
+                    "wrong number of arguments for 'replconf' command"
+                );
+                Self::ReplConf(args.to_vec())
+            }
+            "PSYNC" => {
+                let [replid, offset] = args else {
+                    bail!("wrong number of arguments for 'psync' command");
+                };
+                Self::Psync {
+                    replid: replid.clone(),
+                    offset: offset.parse().context("Invalid PSYNC offset")?,
+                }
+            }
+            "REPLICAOF" | "SLAVEOF" => {
+                ensure!(
+                    args.len() == 2,
+                    "wrong number of arguments for '{}' command",
+                    name.to_lowercase()
+                );
+                Self::ReplicaOf(replication::parse_master(args)?)
+            }
+            "SELECT" => {
+                let [index] = args else {
+                    bail!("wrong number of arguments for 'select' command");
+                };
+                Self::Select(parse_db_index(index)?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// A command queued since `MULTI`
+#[derive(Debug)]
+enum Queued {
+    Command(Command),
+    /// `SELECT`, switching the database of the commands queued after it
+    Select(usize),
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    /// Address the client connected from
+    pub addr: SocketAddr,
+    /// RESP protocol version, 2 unless switched with `HELLO 3`
+    pub protocol: u8,
+    pub channels: BTreeSet<String>,
+    pub patterns: BTreeSet<String>,
+    pub shard_channels: BTreeSet<String>,
+    /// Queue of messages pushed to the client outside of the request/response flow
+    sender: UnboundedSender<RespData>,
+    /// Commands queued since `MULTI`, `None` outside of a transaction
+    transaction: Option<Vec<Queued>>,
+    /// Set when a command fails to queue, `EXEC` then discards the transaction
+    transaction_aborted: bool,
+    /// Database commands run against, selected with `SELECT`
+    db: usize,
+    /// Keys under WATCH by database, with their version when they were watched
+    watched: HashMap<(usize, String), u64>,
+    /// Set by `QUIT`, the connection is closed once the reply is sent
+    pub quitting: bool,
+    /// Port a replica said it listens on with `REPLCONF listening-port`
+    listening_port: Option<u16>,
+    /// The replication stream, once the client turned into a replica with `PSYNC`
+    replica_stream: Option<UnboundedReceiver<Vec<u8>>>,
+}
+
+impl Connection {
+    pub fn new(addr: SocketAddr) -> (Self, UnboundedReceiver<RespData>) {
+        let (sender, receiver) = unbounded_channel();
+        let connection = Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            protocol: 2,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+            sender,
+            transaction: None,
+            transaction_aborted: false,
+            db: 0,
+            watched: HashMap::new(),
+            quitting: false,
+            listening_port: None,
+            replica_stream: None,
+        };
+        (connection, receiver)
+    }
+
+    /// Number of channel and pattern subscriptions, shard channels are counted apart
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Whether a RESP2 client may only run subscription related commands
+    fn in_subscribed_mode(&self) -> bool {
+        self.protocol == 2 && self.subscriptions() + self.shard_channels.len() > 0
+    }
+
+    /// Serialize a reply for the protocol version of this connection
+    pub fn encode(&self, reply: RespData) -> Vec<u8> {
+        if self.protocol == 2 {
+            reply.into_resp2().as_bytes()
+        } else {
+            reply.as_bytes()
+        }
+    }
+
+    /// Run a request in the context of this connection, returning the replies to send.
+    /// Requests that fail to parse or run are answered with an error reply.
+    pub async fn execute(&mut self, request: RespData, state: &State) -> Vec<RespData> {
+        TOTAL_COMMANDS_PROCESSED.fetch_add(1, Ordering::Relaxed);
+        match self.try_execute(request, state).await {
+            Ok(replies) => replies,
+            Err(e) => {
+                // Inside a transaction commands are only parsed, so this is a queuing failure
+                if self.transaction.is_some() {
+                    self.transaction_aborted = true;
+                }
+                vec![RespData::simple_error("ERR", e.to_string())]
+            }
+        }
+    }
+
+    async fn try_execute(
+        &mut self,
+        request: RespData,
+        state: &State,
+    ) -> anyhow::Result<Vec<RespData>> {
+        let (name, args) = match &request {
+            RespData::Array(Some(elements)) => match elements.front() {
+                Some(RespData::BulkString(Some(name))) => {
+                    let args: Vec<String> = elements
+                        .iter()
+                        .skip(1)
+                        .filter_map(|arg| match arg {
+                            RespData::BulkString(Some(arg)) => {
+                                Some(String::from_utf8_lossy(arg).to_string())
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    (String::from_utf8_lossy(name).to_uppercase(), args)
+                }
+                _ => bail!("Expected a bulk string command, got {:?}", elements.front()),
+            },
+            _ => bail!("Expected an array for command parsing, got {request:?}"),
+        };
+        // Everything but SCRIPT KILL and FUNCTION KILL waits for a running script to finish
+        let kills_script = matches!(name.as_str(), "SCRIPT" | "FUNCTION")
+            && args
+                .first()
+                .is_some_and(|arg| arg.eq_ignore_ascii_case("KILL"));
+        if !kills_script {
+            if let Some(busy) = script::wait_while_busy().await {
+                return Ok(vec![busy]);
+            }
+        }
+        let subscribed_mode = self.in_subscribed_mode();
+        if subscribed_mode && !SUBSCRIBED_MODE_COMMANDS.contains(&name.as_str()) {
+            return Ok(vec![RespData::simple_error(
+                "ERR",
+                format!(
+                    "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                    name.to_lowercase()
+                ),
+            )]);
+        }
+        // The running script holds the state lock, a kill must not wait for it
+        if kills_script && self.transaction.is_none() {
+            let command = Command::try_from(request)?;
+            return Ok(vec![script::kill(matches!(command, Command::FunctionKill))]);
+        }
+        let connection_command = ConnectionCommand::parse(&name, &args)?;
+        if let Some(transaction) = &mut self.transaction {
+            match connection_command {
+                None => {
+                    let command = Command::try_from(request)?;
+                    if let Some(error) = check_command(state, &command).await {
+                        self.transaction_aborted = true;
+                        return Ok(vec![error]);
+                    }
+                    debug!("Queued command: {command:?}");
+                    transaction.push(Queued::Command(command));
+                    return Ok(vec![RespData::simple_string("QUEUED")]);
+                }
+                Some(ConnectionCommand::Select(index)) => {
+                    transaction.push(Queued::Select(index));
+                    return Ok(vec![RespData::simple_string("QUEUED")]);
+                }
+                Some(
+                    ConnectionCommand::Multi
+                    | ConnectionCommand::Exec
+                    | ConnectionCommand::Discard
+                    | ConnectionCommand::Reset
+                    | ConnectionCommand::Quit,
+                ) => {}
+                // Unlike a command that fails to queue, this leaves the transaction as it is
+                Some(ConnectionCommand::Watch(_)) => {
+                    return Ok(vec![RespData::simple_error(
+                        "ERR",
+                        "WATCH inside MULTI is not allowed",
+                    )]);
+                }
+                Some(_) => bail!("Command not allowed inside a transaction"),
+            }
+        }
+        if let Some(command) = connection_command {
+            return Ok(self.execute_connection_command(command, state).await);
+        }
+        let command = Command::try_from(request)?;
+        debug!("Parsed command: {command:?}");
+        if let Some(error) = check_command(state, &command).await {
+            return Ok(vec![error]);
+        }
+        if subscribed_mode && matches!(command, Command::Ping) {
+            return Ok(vec![RespData::array(VecDeque::from([
+                RespData::bulk_string("pong"),
+                RespData::bulk_string(""),
+            ]))]);
+        }
+        Ok(vec![command.handle(state.clone(), self.db).await?])
+    }
+
+    async fn execute_connection_command(
+        &mut self,
+        command: ConnectionCommand,
+        state: &State,
+    ) -> Vec<RespData> {
+        match command {
+            ConnectionCommand::Subscribe(channels) => {
+                let mut state = state.write().await;
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        if self.channels.insert(channel.clone()) {
+                            state
+                                .pubsub
+                                .subscribe(&channel, self.id, self.sender.clone());
+                        }
+                        self.subscription_reply("subscribe", Some(&channel), self.subscriptions())
+                    })
+                    .collect()
+            }
+            ConnectionCommand::Unsubscribe(channels) => {
+                let mut state = state.write().await;
+                let channels = if channels.is_empty() {
+                    self.channels.iter().cloned().collect()
+                } else {
+                    channels
+                };
+                if channels.is_empty() {
+                    return vec![self.subscription_reply(
+                        "unsubscribe",
+                        None,
+                        self.subscriptions(),
+                    )];
+                }
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        if self.channels.remove(&channel) {
+                            state.pubsub.unsubscribe(&channel, self.id);
+                        }
+                        self.subscription_reply("unsubscribe", Some(&channel), self.subscriptions())
+                    })
+                    .collect()
+            }
+            ConnectionCommand::PSubscribe(patterns) => {
+                let mut state = state.write().await;
+                patterns
+                    .into_iter()
+                    .map(|pattern| {
+                        if self.patterns.insert(pattern.clone()) {
+                            state
+                                .pubsub
+                                .psubscribe(&pattern, self.id, self.sender.clone());
+                        }
+                        self.subscription_reply("psubscribe", Some(&pattern), self.subscriptions())
+                    })
+                    .collect()
+            }
+            ConnectionCommand::PUnsubscribe(patterns) => {
+                let mut state = state.write().await;
+                let patterns = if patterns.is_empty() {
+                    self.patterns.iter().cloned().collect()
+                } else {
+                    patterns
+                };
+                if patterns.is_empty() {
+                    return vec![self.subscription_reply(
+                        "punsubscribe",
+                        None,
+                        self.subscriptions(),
+                    )];
+                }
+                patterns
+                    .into_iter()
+                    .map(|pattern| {
+                        if self.patterns.remove(&pattern) {
+                            state.pubsub.punsubscribe(&pattern, self.id);
+                        }
+                        self.subscription_reply(
+                            "punsubscribe",
+                            Some(&pattern),
+                            self.subscriptions(),
+                        )
+                    })
+                    .collect()
+            }
+            ConnectionCommand::SSubscribe(channels) => {
+                if !same_slot(channels.iter().map(String::as_bytes)) {
+                    return vec![cross_slot_error()];
+                }
+                let mut state = state.write().await;
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        if self.shard_channels.insert(channel.clone()) {
+                            state
+                                .pubsub
+                                .ssubscribe(&channel, self.id, self.sender.clone());
+                        }
+                        self.subscription_reply(
+                            "ssubscribe",
+                            Some(&channel),
+                            self.shard_channels.len(),
+                        )
+                    })
+                    .collect()
+            }
+            ConnectionCommand::SUnsubscribe(channels) => {
+                if !same_slot(channels.iter().map(String::as_bytes)) {
+                    return vec![cross_slot_error()];
+                }
+                let mut state = state.write().await;
+                let channels = if channels.is_empty() {
+                    self.shard_channels.iter().cloned().collect()
+                } else {
+                    channels
+                };
+                if channels.is_empty() {
+                    return vec![self.subscription_reply("sunsubscribe", None, 0)];
+                }
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        if self.shard_channels.remove(&channel) {
+                            state.pubsub.sunsubscribe(&channel, self.id);
+                        }
+                        self.subscription_reply(
+                            "sunsubscribe",
+                            Some(&channel),
+                            self.shard_channels.len(),
+                        )
+                    })
+                    .collect()
+            }
+            ConnectionCommand::Hello(version) => {
+                match version {
+                    Some(version @ (2 | 3)) => self.protocol = version,
+                    Some(_) => {
+                        return vec![RespData::simple_error(
+                            "NOPROTO",
+                            "unsupported protocol version",
+                        )]
+                    }
+                    None => {}
+                }
+                let role = if state.read().await.replication.is_replica() {
+                    "replica"
+                } else {
+                    "master"
+                };
+                vec![RespData::Map(vec![
+                    (
+                        RespData::bulk_string("server"),
+                        RespData::bulk_string("redis"),
+                    ),
+                    (
+                        RespData::bulk_string("version"),
+                        RespData::bulk_string(env!("CARGO_PKG_VERSION")),
+                    ),
+                    (
+                        RespData::bulk_string("proto"),
+                        RespData::Integer(i64::from(self.protocol)),
+                    ),
+                    (
+                        RespData::bulk_string("id"),
+                        RespData::Integer(i64::try_from(self.id).unwrap_or(i64::MAX)),
+                    ),
+                    (
+                        RespData::bulk_string("mode"),
+                        RespData::bulk_string("standalone"),
+                    ),
+                    (RespData::bulk_string("role"), RespData::bulk_string(role)),
+                    (
+                        RespData::bulk_string("modules"),
+                        RespData::array(VecDeque::new()),
+                    ),
+                ])]
+            }
+            ConnectionCommand::Multi => {
+                if self.transaction.is_some() {
+                    return vec![RespData::simple_error(
+                        "ERR",
+                        "MULTI calls can not be nested",
+                    )];
+                }
+                self.transaction = Some(Vec::new());
+                self.transaction_aborted = false;
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::Exec => {
+                let Some(commands) = self.transaction.take() else {
+                    return vec![RespData::simple_error("ERR", "EXEC without MULTI")];
+                };
+                let mut state = state.write().await;
+                state.select(self.db);
+                if std::mem::take(&mut self.transaction_aborted) {
+                    self.unwatch_all(&mut state);
+                    return vec![RespData::simple_error(
+                        "EXECABORT",
+                        "Transaction discarded because of previous errors.",
+                    )];
+                }
+                // Writes queued while there was memory to spare may not run out of it now
+                if let Some(RespData::SimpleError { kind, message }) =
+                    commands.iter().find_map(|queued| match queued {
+                        Queued::Command(command) => evict::check_command(&mut state, command),
+                        Queued::Select(_) => None,
+                    })
+                {
+                    self.unwatch_all(&mut state);
+                    return vec![RespData::simple_error(
+                        "EXECABORT",
+                        format!("Transaction discarded because of: {kind} {message}"),
+                    )];
+                }
+                // Keys that expired since WATCH count as modified
+                state.remove_expired();
+                let modified = self
+                    .watched
+                    .iter()
+                    .any(|((db, key), version)| state.key_version(*db, key) != *version);
+                self.unwatch_all(&mut state);
+                if modified {
+                    return vec![self.null_array()];
+                }
+                // Hold the lock for the whole transaction so no other client sees it half done.
+                // A failing command does not roll back the ones before it.
+                state.begin_atomic();
+                let replies = commands
+                    .into_iter()
+                    .map(|queued| match queued {
+                        Queued::Command(command) => command
+                            .execute(&mut state)
+                            .unwrap_or_else(|e| RespData::simple_error("ERR", e.to_string())),
+                        Queued::Select(index) if index >= state.config.databases => {
+                            RespData::simple_error("ERR", "DB index is out of range")
+                        }
+                        // The database stays selected after the transaction
+                        Queued::Select(index) => {
+                            self.db = index;
+                            state.select(index);
+                            RespData::simple_string("OK")
+                        }
+                    })
+                    .collect();
+                state.end_atomic();
+                vec![RespData::array(replies)]
+            }
+            ConnectionCommand::Discard => {
+                if self.transaction.take().is_none() {
+                    return vec![RespData::simple_error("ERR", "DISCARD without MULTI")];
+                }
+                self.transaction_aborted = false;
+                self.unwatch_all(&mut *state.write().await);
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::Watch(keys) => {
+                let mut state = state.write().await;
+                state.select(self.db);
+                // A key that already expired is watched as missing
+                state.remove_expired();
+                for key in keys {
+                    if let Entry::Vacant(entry) = self.watched.entry((self.db, key)) {
+                        let (db, key) = entry.key();
+                        let version = state.watch(*db, key);
+                        entry.insert(version);
+                    }
+                }
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::Unwatch => {
+                self.unwatch_all(&mut *state.write().await);
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::Reset => {
+                self.transaction = None;
+                self.transaction_aborted = false;
+                self.unwatch_all(&mut *state.write().await);
+                self.unsubscribe_all(state).await;
+                self.protocol = 2;
+                self.db = 0;
+                vec![RespData::simple_string("RESET")]
+            }
+            ConnectionCommand::Quit => {
+                self.quitting = true;
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::ReplConf(args) => {
+                if args
+                    .first()
+                    .is_some_and(|option| option.eq_ignore_ascii_case("ACK"))
+                {
+                    // `ACK <offset> [FACK <aof offset>]` is not replied to
+                    let offset = args.get(1).and_then(|offset| offset.parse().ok());
+                    let aof_offset = match args.get(2..4) {
+                        Some([fack, offset]) if fack.eq_ignore_ascii_case("FACK") => {
+                            offset.parse().ok()
+                        }
+                        _ => None,
+                    };
+                    if let Some(offset) = offset {
+                        let mut state = state.write().await;
+                        state.replication.ack(self.id, offset, aof_offset);
+                        state.notify_all(replication::ACK_WAIT_KEY);
+                    }
+                    return vec![];
+                }
+                for option in args.chunks(2) {
+                    match option[0].to_lowercase().as_str() {
+                        "listening-port" => match option[1].parse() {
+                            Ok(port) => self.listening_port = Some(port),
+                            Err(_) => {
+                                return vec![RespData::simple_error("ERR", "Invalid port")];
+                            }
+                        },
+                        "capa" | "getack" => {}
+                        option => {
+                            return vec![RespData::simple_error(
+                                "ERR",
+                                format!("Unrecognized REPLCONF option: {option}"),
+                            )]
+                        }
+                    }
+                }
+                vec![RespData::simple_string("OK")]
+            }
+            ConnectionCommand::Psync { replid, offset } => {
+                let mut state = state.write().await;
+                // Replicas connect from some port but announce the one they listen on
+                let port = self.listening_port.unwrap_or(self.addr.port());
+                let addr = SocketAddr::new(self.addr.ip(), port);
+                match replication::psync(&mut state, self.id, addr, &replid, offset) {
+                    Ok((reply, stream)) => {
+                        self.replica_stream = Some(stream);
+                        vec![reply]
+                    }
+                    Err(e) => vec![RespData::simple_error("ERR", e.to_string())],
+                }
+            }
+            ConnectionCommand::ReplicaOf(master) => {
+                let mut guard = state.write().await;
+                vec![replication::replicaof(&mut guard, state, master)]
+            }
+            ConnectionCommand::Select(index) => {
+                if index >= state.read().await.config.databases {
+                    return vec![RespData::simple_error("ERR", "DB index is out of range")];
+                }
+                self.db = index;
+                vec![RespData::simple_string("OK")]
+            }
+        }
+    }
+
+    /// A `[kind, name, subscription count]` confirmation
+    fn subscription_reply(&self, kind: &str, name: Option<&str>, count: usize) -> RespData {
+        push_frame(&[
+            RespData::bulk_string(kind),
+            name.map_or(RespData::null_bulk_string(), RespData::bulk_string),
+            RespData::Integer(i64::try_from(count).unwrap_or(i64::MAX)),
+        ])
+    }
+
+    /// The reply of an EXEC whose watched keys were modified
+    fn null_array(&self) -> RespData {
+        if self.protocol == 2 {
+            RespData::Array(None)
+        } else {
+            RespData::Null
+        }
+    }
+
+    /// Stop watching every key watched by this connection
+    pub fn unwatch_all(&mut self, state: &mut AppState) {
+        for (db, key) in std::mem::take(&mut self.watched).into_keys() {
+            state.unwatch(db, &key);
+        }
+    }
+
+    /// Drop every subscription of this connection, without confirmations
+    pub async fn unsubscribe_all(&mut self, state: &State) {
+        if self.subscriptions() + self.shard_channels.len() == 0 {
+            return;
+        }
+        let mut state = state.write().await;
+        for channel in std::mem::take(&mut self.channels) {
+            state.pubsub.unsubscribe(&channel, self.id);
+        }
+        for pattern in std::mem::take(&mut self.patterns) {
+            state.pubsub.punsubscribe(&pattern, self.id);
+        }
+        for channel in std::mem::take(&mut self.shard_channels) {
+            state.pubsub.sunsubscribe(&channel, self.id);
+        }
+    }
+
+    async fn serve(
+        &mut self,
+        stream: &mut TcpStream,
+        messages: &mut UnboundedReceiver<RespData>,
+        state: &State,
+        limits: &ClientLimits,
+    ) -> anyhow::Result<()> {
+        let (mut reader, mut writer) = stream.split();
+        let mut buf = [0; 1024];
+        // Bytes received but not yet parsed, a read may end in the middle of a request
+        let mut pending = Vec::new();
+        let mut replica_stream = None;
+        let mut last_interaction = Instant::now();
+        let mut idle_check = interval(IDLE_CHECK_PERIOD);
+        loop {
+            select! {
+                n = reader.read(&mut buf[..]) => {
+                    let n = n?;
+                    last_interaction = Instant::now();
+                    if n == 0 {
+                        info!("Disconnected");
+                        return Ok(());
+                    }
+                    pending.extend_from_slice(&buf[..n]);
+                    // Serve every complete request, clients may pipeline several in one write
+                    loop {
+                        let len = match RespData::frame_len(&pending) {
+                            Ok(Some(len)) => len,
+                            Ok(None) => break,
+                            Err(e) => {
+                                let reply =
+                                    RespData::simple_error("ERR", format!("Protocol error: {e}"));
+                                writer.write_all(&self.encode(reply)).await?;
+                                return Err(e.context("Failed to parse request from buffer"));
+                            }
+                        };
+                        let request = RespData::try_from(&pending[..len])
+                            .context("Failed to parse request from buffer")?;
+                        pending.drain(..len);
+                        for reply in self.execute(request, state).await {
+                            debug!("Response: {reply:?}");
+                            writer
+                                .write_all(&self.encode(reply))
+                                .await
+                                .context("Failed to write response")?;
+                        }
+                        if self.quitting {
+                            info!("Client quit");
+                            return Ok(());
+                        }
+                        if let Some(stream) = self.replica_stream.take() {
+                            info!("Client is now a replica");
+                            replica_stream = Some(stream);
+                        }
+                    }
+                }
+                bytes = next_replication_bytes(&mut replica_stream) => {
+                    let Some(bytes) = bytes else {
+                        info!("Replica dropped, it has to sync again");
+                        return Ok(());
+                    };
+                    writer
+                        .write_all(&bytes)
+                        .await
+                        .context("Failed to write to replica")?;
+                }
+                Some(message) = messages.recv() => {
+                    writer
+                        .write_all(&self.encode(message))
+                        .await
+                        .context("Failed to write message")?;
+                }
+                _ = idle_check.tick() => {
+                    // Replicas and subscribers may rightfully stay silent
+                    let subscribed = self.subscriptions() + self.shard_channels.len() > 0;
+                    if replica_stream.is_some() || subscribed {
+                        continue;
+                    }
+                    let timeout = limits.timeout.load(Ordering::Relaxed);
+                    if timeout > 0 && last_interaction.elapsed() >= Duration::from_secs(timeout) {
+                        info!("Closing idle client after {timeout} seconds");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The next bytes of the replication stream, waiting forever for a client that is not a replica
+async fn next_replication_bytes(
+    stream: &mut Option<UnboundedReceiver<Vec<u8>>>,
+) -> Option<Vec<u8>> {
+    match stream {
+        Some(stream) => stream.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The error `command` gets instead of running, from the replica state or from
+/// `maxmemory` when evicting keys was not enough to make room
+async fn check_command(state: &State, command: &Command) -> Option<RespData> {
+    // Only evicting needs the state exclusively, and only while over `maxmemory`
+    {
+        let state = state.read().await;
+        if let Some(error) = replication::check_command(&state, command) {
+            return Some(error);
+        }
+        if !evict::over_limit(&state) {
+            return None;
+        }
+    }
+    evict::check_command(&mut *state.write().await, command)
+}
+
+/// The error for commands whose keys or channels do not all hash to the same slot
+pub fn cross_slot_error() -> RespData {
+    RespData::simple_error("CROSSSLOT", "Keys in request don't hash to the same slot")
+}
+
+#[instrument(skip(stream, state, limits))]
+pub async fn handle_client(
+    mut stream: TcpStream,
+    client: SocketAddr,
+    state: State,
+    limits: Arc<ClientLimits>,
+) -> anyhow::Result<()> {
+    // Replies are written one by one, they must not wait for the previous ones to be acknowledged
+    stream
+        .set_nodelay(true)
+        .context("Failed to disable Nagle's algorithm")?;
+    let (mut connection, mut messages) = Connection::new(client);
+    let result = connection
+        .serve(&mut stream, &mut messages, &state, &limits)
+        .await;
+    connection.unsubscribe_all(&state).await;
+    let mut guard = state.write().await;
+    connection.unwatch_all(&mut guard);
+    guard.replication.replicas.remove(&connection.id);
+    drop(guard);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(connection: &mut Connection, state: &State, args: &[&str]) -> String {
+        let replies = connection.execute(RespData::command(args), state).await;
+        replies.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_transactions() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        assert_eq!(run(&mut connection, &state, &["MULTI"]).await, "+OK\r\n");
+        let queued = run(
+            &mut connection,
+            &state,
+            &["XREAD", "BLOCK", "0", "STREAMS", "s", "0"],
+        );
+        assert_eq!(queued.await, "+QUEUED\r\n");
+        // Blocking commands do not block inside a transaction
+        assert_eq!(
+            run(&mut connection, &state, &["EXEC"]).await,
+            "*1\r\n*-1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hello_role() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let hello = run(&mut connection, &state, &["HELLO", "3"]).await;
+        assert!(hello.contains("$4\r\nrole\r\n$6\r\nmaster\r\n"), "{hello}");
+        state.write().await.replication.master = Some(crate::replication::MasterLink {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            link_up: false,
+        });
+        let hello = run(&mut connection, &state, &["HELLO"]).await;
+        assert!(hello.contains("$4\r\nrole\r\n$7\r\nreplica\r\n"), "{hello}");
+    }
+
+    #[tokio::test]
+    async fn test_kill_script() {
+        let state = State::default();
+        let client = || Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let (mut killer, _messages) = client();
+        let script = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let (mut connection, _messages) = client();
+                run(&mut connection, &state, &["EVAL", "while true do end", "0"]).await
+            }
+        });
+        let killed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match run(&mut killer, &state, &["SCRIPT", "KILL"]).await.as_str() {
+                    "+OK\r\n" => break,
+                    reply => assert!(reply.starts_with("-NOTBUSY"), "{reply}"),
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            script.await.unwrap()
+        });
+        let reply = killed.await.expect("the script is killed");
+        assert!(reply.starts_with("-ERR Script killed by user"), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let dir = std::env::temp_dir().join(format!("wait-aof-{}", std::process::id()));
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        run(&mut connection, &state, &["SET", "k", "v"]).await;
+        assert_eq!(
+            run(&mut connection, &state, &["WAIT", "0", "0"]).await,
+            ":0\r\n"
+        );
+        // Without replicas to acknowledge, WAIT times out with the count so far
+        assert_eq!(
+            run(&mut connection, &state, &["WAIT", "1", "50"]).await,
+            ":0\r\n"
+        );
+        assert!(run(&mut connection, &state, &["WAITAOF", "1", "0", "50"])
+            .await
+            .starts_with("-ERR WAITAOF cannot be used when numlocal is set"));
+
+        {
+            let mut guard = state.write().await;
+            guard.aof.enabled = true;
+            guard.aof.dir = dir.clone();
+            crate::aof::load(&mut guard).unwrap();
+        }
+        run(&mut connection, &state, &["SET", "k", "w"]).await;
+        // The write is only fsynced once a second
+        let waitaof = ["WAITAOF", "1", "0", "50"];
+        assert_eq!(
+            run(&mut connection, &state, &waitaof).await,
+            "*2\r\n:0\r\n:0\r\n"
+        );
+        state.write().await.aof.fsync();
+        assert_eq!(
+            run(&mut connection, &state, &waitaof).await,
+            "*2\r\n:1\r\n:0\r\n"
+        );
+        // Frames of the stream that never reach the AOF do not hold it back
+        state
+            .write()
+            .await
+            .replication
+            .feed(b"*1\r\n$4\r\nPING\r\n");
+        let reply = run(&mut connection, &state, &waitaof).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reply, "*2\r\n:1\r\n:0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_watch_in_transaction() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        assert_eq!(run(&mut connection, &state, &["MULTI"]).await, "+OK\r\n");
+        run(&mut connection, &state, &["SET", "k", "1"]).await;
+        assert_eq!(
+            run(&mut connection, &state, &["WATCH", "k"]).await,
+            "-ERR WATCH inside MULTI is not allowed\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &["EXEC"]).await,
+            "*1\r\n+OK\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_to_new_database() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        run(&mut connection, &state, &["SELECT", "3"]).await;
+        run(&mut connection, &state, &["SET", "k", "1"]).await;
+        run(&mut connection, &state, &["RPUSH", "l", "a"]).await;
+        assert_eq!(run(&mut connection, &state, &["DBSIZE"]).await, ":2\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_collection_encodings() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let encoding = |key| ["OBJECT", "ENCODING", key];
+        run(
+            &mut connection,
+            &state,
+            &["CONFIG", "SET", "set-max-intset-entries", "2"],
+        )
+        .await;
+        assert_eq!(
+            run(&mut connection, &state, &["SADD", "s", "1", "2", "2"]).await,
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &encoding("s")).await,
+            "$6\r\nintset\r\n"
+        );
+        run(&mut connection, &state, &["SADD", "s", "3"]).await;
+        assert_eq!(
+            run(&mut connection, &state, &encoding("s")).await,
+            "$8\r\nlistpack\r\n"
+        );
+
+        run(&mut connection, &state, &["HSET", "h", "a", "1", "b", "2"]).await;
+        assert_eq!(
+            run(
+                &mut connection,
+                &state,
+                &["HSET", "h", "a", "3", "c", &"x".repeat(65)]
+            )
+            .await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &encoding("h")).await,
+            "$9\r\nhashtable\r\n"
+        );
+
+        run(&mut connection, &state, &["ZADD", "z", "2", "b", "1", "a"]).await;
+        assert_eq!(
+            run(&mut connection, &state, &encoding("z")).await,
+            "$8\r\nlistpack\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &["ZADD", "z", "0", "a"]).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &["ZSCAN", "z", "0"]).await,
+            "*2\r\n$1\r\n0\r\n*4\r\n$1\r\na\r\n$1\r\n0\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert!(run(&mut connection, &state, &["ZADD", "z", "nan", "a"])
+            .await
+            .starts_with("-ERR value is not a valid float"));
+        assert!(run(&mut connection, &state, &["SADD", "h", "x"])
+            .await
+            .starts_with("-WRONGTYPE"));
+    }
+
+    #[tokio::test]
+    async fn test_select_in_transaction() {
+        let state = State::default();
+        let (mut connection, _messages) = Connection::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        for args in [
+            &["MULTI"][..],
+            &["SELECT", "1"],
+            &["SET", "k", "1"],
+            &["SELECT", "99"],
+            &["GET", "k"],
+        ] {
+            run(&mut connection, &state, args).await;
+        }
+        assert_eq!(
+            run(&mut connection, &state, &["EXEC"]).await,
+            "*4\r\n+OK\r\n+OK\r\n-ERR DB index is out of range\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &["GET", "k"]).await,
+            "$1\r\n1\r\n"
+        );
+        assert_eq!(
+            run(&mut connection, &state, &["SELECT", "0"]).await,
+            "+OK\r\n"
+        );
+        assert_eq!(run(&mut connection, &state, &["GET", "k"]).await, "$-1\r\n");
+    }
+}
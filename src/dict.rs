@@ -0,0 +1,433 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash, RandomState},
+    sync::LazyLock,
+};
+
+/// Buckets of a table once something is inserted, and the fewest a table shrinks to
+const MIN_BUCKETS: usize = 4;
+/// A table shrinks once it is filled less than one entry per this many buckets
+const MIN_FILL: usize = 8;
+/// Buckets moved to the new table by each operation while rehashing
+const REHASH_STEP: usize = 1;
+/// Empty buckets skipped per bucket a rehash step moves, so a sparse table
+/// does not make a single step slow
+const MAX_EMPTY_VISITS: usize = 10;
+/// Buckets [`Dict::random_keys`] visits per key asked for, at most, once it found one
+const SAMPLE_VISITS: usize = 10;
+
+/// Keys of the hash, drawn randomly once per process so clients cannot pick keys
+/// that all land in the same bucket
+static SEED: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// The hash of a key, fixed for the life of the process so cursors stay valid
+pub fn hash<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    SEED.hash_one(key)
+}
+
+type Table<K, V> = Vec<Vec<(K, V)>>;
+
+/// The bucket of `table` a key of this hash belongs to, `None` if it has no buckets
+fn bucket_index<K, V>(table: &Table<K, V>, hash: u64) -> Option<usize> {
+    let mask = table.len().checked_sub(1)?;
+    Some(usize::try_from(hash).unwrap_or_default() & mask)
+}
+
+/// The cursor of the bucket after `cursor` in a table of `mask + 1` buckets
+fn next_cursor(cursor: u64, mask: u64) -> u64 {
+    // Increment the reversed cursor, with the bits above the mask set so they carry over
+    ((cursor | !mask).reverse_bits().wrapping_add(1)).reverse_bits()
+}
+
+/// A hash table of chained buckets, as Redis keeps its keyspace and large collections.
+///
+/// The number of buckets is a power of two and a key goes to the bucket of the low bits of
+/// its hash, so [`Dict::scan`] can walk the buckets with a cursor that stays valid as the
+/// table grows or shrinks between calls.
+///
+/// Resizing allocates a second table and moves the entries over a few buckets at a time,
+/// with every write and with [`Dict::rehash`], so no single operation has to move them all.
+#[derive(Debug, Clone)]
+pub struct Dict<K, V> {
+    /// The table in use, and while rehashing the one the entries move out of
+    /// followed by the one they move to
+    tables: [Table<K, V>; 2],
+    /// While rehashing, the first bucket of the first table not moved yet
+    rehash_index: Option<usize>,
+    len: usize,
+}
+
+impl<K, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Self {
+            tables: [Vec::new(), Vec::new()],
+            rehash_index: None,
+            len: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    /// The table, bucket and position in it of `key`
+    fn position<Q>(&self, key: &Q) -> Option<(usize, usize, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = hash(key);
+        (0..=usize::from(self.is_rehashing())).find_map(|table| {
+            let index = bucket_index(&self.tables[table], hash)?;
+            let position = self.tables[table][index]
+                .iter()
+                .position(|(candidate, _)| candidate.borrow() == key)?;
+            Some((table, index, position))
+        })
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (table, index, position) = self.position(key)?;
+        Some(&self.tables[table][index][position].1)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash(REHASH_STEP);
+        let (table, index, position) = self.position(key)?;
+        Some(&mut self.tables[table][index][position].1)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.position(key).is_some()
+    }
+
+    /// Set the value of `key`, returning the value it replaced
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(existing) = self.get_mut(&key) {
+            return Some(std::mem::replace(existing, value));
+        }
+        self.push(key, value);
+        None
+    }
+
+    /// The value of `key`, inserting the one `default` makes if there is none
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        self.rehash(REHASH_STEP);
+        let (table, index, position) = match self.position(&key) {
+            Some(found) => found,
+            None => self.push(key, default()),
+        };
+        &mut self.tables[table][index][position].1
+    }
+
+    /// Add a key known to be missing, returning where it went
+    fn push(&mut self, key: K, value: V) -> (usize, usize, usize) {
+        if self.tables[0].is_empty() {
+            self.tables[0] = empty_table(MIN_BUCKETS);
+        } else if !self.is_rehashing() && self.len >= self.tables[0].len() {
+            // Grow to keep about one entry per bucket, so chains stay short
+            self.start_rehash(2 * self.tables[0].len());
+        }
+        // New keys go straight to the table being rehashed to
+        let table = usize::from(self.is_rehashing());
+        let index = bucket_index(&self.tables[table], hash(&key)).expect("there are buckets");
+        self.tables[table][index].push((key, value));
+        self.len += 1;
+        (table, index, self.tables[table][index].len() - 1)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash(REHASH_STEP);
+        let (table, index, position) = self.position(key)?;
+        let (_, value) = self.tables[table][index].swap_remove(position);
+        self.len -= 1;
+        let buckets = self.tables[0].len();
+        if !self.is_rehashing() && buckets > MIN_BUCKETS && self.len * MIN_FILL < buckets {
+            self.start_rehash(self.len.next_power_of_two().max(MIN_BUCKETS));
+        }
+        Some(value)
+    }
+
+    /// Start moving the entries to a table of `buckets` buckets
+    fn start_rehash(&mut self, buckets: usize) {
+        self.tables[1] = empty_table(buckets);
+        self.rehash_index = Some(0);
+    }
+
+    /// Move up to `buckets` non-empty buckets to the new table while rehashing,
+    /// returning whether there are more to move
+    pub fn rehash(&mut self, buckets: usize) -> bool {
+        let Some(mut index) = self.rehash_index else {
+            return false;
+        };
+        let mut empty_visits = buckets * MAX_EMPTY_VISITS;
+        for _ in 0..buckets {
+            while self.tables[0].get(index).is_some_and(Vec::is_empty) {
+                index += 1;
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    self.rehash_index = Some(index);
+                    return true;
+                }
+            }
+            let Some(bucket) = self.tables[0].get_mut(index) else {
+                break;
+            };
+            for (key, value) in std::mem::take(bucket) {
+                let target = bucket_index(&self.tables[1], hash(&key)).expect("there are buckets");
+                self.tables[1][target].push((key, value));
+            }
+            index += 1;
+        }
+        if index < self.tables[0].len() {
+            self.rehash_index = Some(index);
+            return true;
+        }
+        self.tables[0] = std::mem::take(&mut self.tables[1]);
+        self.rehash_index = None;
+        false
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + Clone {
+        self.tables[0]
+            .iter()
+            .chain(&self.tables[1])
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> + Clone {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> + Clone {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Up to `count` keys from the buckets following the one `random` picks, like
+    /// `dictGetSomeKeys` of Redis: a sample from a few buckets rather than a walk over
+    /// every entry. Neighboring keys come together, so this is not uniformly random.
+    pub fn random_keys(&self, count: usize, random: u64) -> Vec<&K> {
+        let count = count.min(self.len);
+        let mut keys = Vec::with_capacity(count);
+        let Some(mask) = self
+            .tables
+            .iter()
+            .map(Vec::len)
+            .max()
+            .and_then(|len| len.checked_sub(1))
+        else {
+            return keys;
+        };
+        let mut index = usize::try_from(random).unwrap_or_default() & mask;
+        let mut visits = count * SAMPLE_VISITS;
+        // Some key is always found, the walk wraps around to every bucket
+        while keys.len() < count && (visits > 0 || keys.is_empty()) {
+            for table in &self.tables {
+                let bucket = table.get(index).into_iter().flatten();
+                keys.extend(bucket.map(|(key, _)| key).take(count - keys.len()));
+            }
+            index = (index + 1) & mask;
+            visits = visits.saturating_sub(1);
+        }
+        keys
+    }
+
+    /// Call `f` with the entries of the bucket at `cursor`, returning the cursor of the next
+    /// bucket, 0 once every bucket was visited.
+    ///
+    /// The cursor counts with its bits reversed, so the buckets a bucket splits into when the
+    /// table grows, or merges with when it shrinks, are visited together. Every entry present
+    /// for the whole scan is visited, entries may be visited twice only if the table shrank.
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(&K, &V)) -> u64 {
+        let mut visit = |table: &Table<K, V>, cursor: u64, mask: u64| {
+            for (key, value) in &table[usize::try_from(cursor & mask).unwrap_or_default()] {
+                f(key, value);
+            }
+        };
+        if !self.is_rehashing() {
+            let Some(mask) = (self.tables[0].len() as u64).checked_sub(1) else {
+                return 0;
+            };
+            visit(&self.tables[0], cursor, mask);
+            return next_cursor(cursor, mask);
+        }
+        // While rehashing, the bucket of the smaller table and every bucket of the larger
+        // one it expands to, so the cursor means the same whichever table an entry is in
+        let (small, large) = if self.tables[0].len() <= self.tables[1].len() {
+            (&self.tables[0], &self.tables[1])
+        } else {
+            (&self.tables[1], &self.tables[0])
+        };
+        let small_mask = small.len() as u64 - 1;
+        let large_mask = large.len() as u64 - 1;
+        visit(small, cursor, small_mask);
+        let mut cursor = cursor;
+        loop {
+            visit(large, cursor, large_mask);
+            cursor = next_cursor(cursor, large_mask);
+            if cursor & (small_mask ^ large_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+}
+
+fn empty_table<K, V>(buckets: usize) -> Table<K, V> {
+    (0..buckets).map(|_| Vec::new()).collect()
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for Dict<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Self::default();
+        for (key, value) in iter {
+            dict.insert(key, value);
+        }
+        dict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Every key a full scan visits, resizing the dict with `between` after each call
+    fn scan_all(dict: &mut Dict<u32, ()>, mut between: impl FnMut(&mut Dict<u32, ()>)) -> Vec<u32> {
+        let mut visited = Vec::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, ()| visited.push(*key));
+            if cursor == 0 {
+                return visited;
+            }
+            between(dict);
+        }
+    }
+
+    /// Buckets of the dict once it finished rehashing
+    fn buckets(dict: &mut Dict<u32, ()>) -> usize {
+        while dict.rehash(100) {}
+        dict.tables[0].len()
+    }
+
+    #[test]
+    fn test_incremental_rehash() {
+        let mut dict: Dict<u32, ()> = (0..64).map(|i| (i, ())).collect();
+        assert_eq!(buckets(&mut dict), 64);
+
+        // Growing moves a bucket per write, the keys stay reachable from either table
+        dict.insert(64, ());
+        assert!(dict.is_rehashing());
+        assert_eq!(dict.tables[1].len(), 128);
+        for i in 65..80 {
+            dict.insert(i, ());
+        }
+        assert!(dict.is_rehashing());
+        assert!(dict
+            .tables
+            .iter()
+            .all(|table| table.iter().any(|b| !b.is_empty())));
+        assert!((0..80).all(|i| dict.contains_key(&i)));
+        assert_eq!(dict.iter().count(), 80);
+        assert!(!dict.rehash(1000));
+        assert_eq!(dict.tables[0].len(), 128);
+        assert!(dict.tables[1].is_empty());
+        assert!((0..80).all(|i| dict.get(&i).is_some()));
+    }
+
+    #[test]
+    fn test_random_keys() {
+        let mut dict: Dict<u32, ()> = (0..1000).map(|i| (i, ())).collect();
+        while dict.rehash(100) {}
+        let mut seen = HashSet::new();
+        for random in 0..1024 {
+            let keys = dict.random_keys(5, random * 7919);
+            assert_eq!(keys.len(), 5);
+            assert!(keys.iter().all(|key| **key < 1000));
+            seen.extend(keys.into_iter().copied());
+        }
+        // Different starting buckets reach most of the keys
+        assert!(seen.len() > 900);
+
+        // The visits are bounded, a sparse table may yield fewer keys than asked for
+        for key in 4..1000 {
+            dict.remove(&key);
+        }
+        assert!(dict.is_rehashing());
+        assert_eq!(buckets(&mut dict), 128);
+        assert!((0..128).all(|random| (1..=4).contains(&dict.random_keys(4, random).len())));
+
+        // Keys are taken from both tables while rehashing
+        let mut dict: Dict<u32, ()> = (0..4).map(|i| (i, ())).collect();
+        assert_eq!(buckets(&mut dict), 4);
+        dict.insert(4, ());
+        assert!(dict.is_rehashing());
+        let mut keys = dict.random_keys(100, 0);
+        keys.sort_unstable();
+        assert_eq!(keys, [&0, &1, &2, &3, &4]);
+        assert!(Dict::<u32, ()>::default().random_keys(5, 1).is_empty());
+    }
+
+    #[test]
+    fn test_scan_across_resizes() {
+        let mut dict: Dict<u32, ()> = (0..100).map(|i| (i, ())).collect();
+        assert_eq!(dict.len(), 100);
+        assert_eq!(buckets(&mut dict), 128);
+        let mut visited = scan_all(&mut dict, |_| {});
+        visited.sort_unstable();
+        assert_eq!(visited, (0..100).collect::<Vec<_>>());
+
+        // Keys added during the scan grow the table, the first 100 are all still visited
+        // whether the scan meets them before, during or after the rehashing
+        let mut next = 100;
+        let visited = scan_all(&mut dict, |dict| {
+            for _ in 0..50 {
+                if next < 1000 {
+                    dict.insert(next, ());
+                    next += 1;
+                }
+            }
+        });
+        assert!(buckets(&mut dict) > 128);
+        assert!((0..100).all(|key| visited.contains(&key)));
+
+        // Removing the later keys during the scan shrinks it
+        let mut visited = scan_all(&mut dict, |dict| {
+            for key in 100..next {
+                dict.remove(&key);
+            }
+        });
+        assert_eq!(buckets(&mut dict), 128);
+        visited.sort_unstable();
+        visited.dedup();
+        assert!((0..100).all(|key| visited.binary_search(&key).is_ok()));
+    }
+}
@@ -0,0 +1,363 @@
+use clap::ValueEnum;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    cmd::Command,
+    resp::RespData,
+    state::{now_ms, AppState},
+};
+
+/// Candidates kept between evictions, as Redis does
+const POOL_SIZE: usize = 16;
+/// The LFU counter of a new key, so it is not evicted before it had a chance to be used
+pub const LFU_INIT_VAL: u8 = 5;
+
+/// Which keys are evicted once the dataset reaches `maxmemory`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MaxmemoryPolicy {
+    /// Evict nothing, writes that need more memory fail
+    #[default]
+    #[value(name = "noeviction")]
+    NoEviction,
+    AllkeysLru,
+    AllkeysLfu,
+    AllkeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    /// Whether keys are evicted by how often they are used, rather than how recently
+    pub fn lfu(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu
+        )
+    }
+
+    /// Whether only keys with an expiry may be evicted
+    fn volatile(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+}
+
+/// What eviction knows of a key besides its value
+#[derive(Debug, Clone, Copy)]
+pub struct KeyMeta {
+    /// Last access, in milliseconds since the UNIX epoch
+    pub last_access: u64,
+    /// Logarithmic access counter as of the last access, see [`lfu_increment`]
+    pub frequency: u8,
+    /// Bytes accounted for the key in [`AppState::used_memory`]
+    pub memory: usize,
+}
+
+impl KeyMeta {
+    pub fn new(memory: usize) -> Self {
+        Self {
+            last_access: now_ms(),
+            frequency: LFU_INIT_VAL,
+            memory,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EvictionState {
+    /// Memory limit of the dataset in bytes, 0 for no limit
+    pub maxmemory: u64,
+    pub policy: MaxmemoryPolicy,
+    /// Keys sampled for each eviction by the approximated policies
+    pub samples: usize,
+    /// How many accesses it takes for the LFU counter to saturate, higher is slower
+    pub lfu_log_factor: u64,
+    /// Minutes it takes for the LFU counter of a key nobody accesses to go down by one
+    pub lfu_decay_time: u64,
+    /// Best candidates seen so far with their database, by increasing idle score
+    pool: Vec<(u64, usize, String)>,
+}
+
+impl Default for EvictionState {
+    fn default() -> Self {
+        Self {
+            maxmemory: 0,
+            policy: MaxmemoryPolicy::default(),
+            samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            pool: Vec::with_capacity(POOL_SIZE),
+        }
+    }
+}
+
+impl EvictionState {
+    /// Change the policy, forgetting candidates scored by the previous one
+    pub fn set_policy(&mut self, policy: MaxmemoryPolicy) {
+        self.policy = policy;
+        self.pool.clear();
+    }
+
+    /// Keep `key` of database `db` as a candidate if it is among the `POOL_SIZE` best seen
+    fn offer(&mut self, idle: u64, db: usize, key: String) {
+        if self
+            .pool
+            .iter()
+            .any(|(_, candidate_db, candidate)| *candidate_db == db && *candidate == key)
+        {
+            return;
+        }
+        let mut position = self
+            .pool
+            .partition_point(|(candidate, _, _)| *candidate < idle);
+        if self.pool.len() == POOL_SIZE {
+            if position == 0 {
+                return;
+            }
+            self.pool.remove(0);
+            position -= 1;
+        }
+        self.pool.insert(position, (idle, db, key));
+    }
+}
+
+/// A random number, from the randomly keyed hasher of the standard library
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// The LFU counter of a key after the decay for the time nobody accessed it
+pub fn lfu_decayed(meta: &KeyMeta, decay_time: u64, now: u64) -> u8 {
+    if decay_time == 0 {
+        return meta.frequency;
+    }
+    let periods = now.saturating_sub(meta.last_access) / 60_000 / decay_time;
+    meta.frequency
+        .saturating_sub(u8::try_from(periods).unwrap_or(u8::MAX))
+}
+
+/// Count an access in an LFU counter. The counter grows with the logarithm of the accesses,
+/// so 8 bits cover millions of them.
+pub fn lfu_increment(counter: u8, log_factor: u64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL);
+    #[allow(clippy::cast_precision_loss)]
+    let (chance, probability) = (
+        random() as f64 / u64::MAX as f64,
+        1.0 / (f64::from(base) * log_factor as f64 + 1.0),
+    );
+    if chance < probability {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// The error of writes refused because the dataset is over `maxmemory`
+pub fn oom_error() -> RespData {
+    RespData::simple_error("OOM", "command not allowed when used memory > 'maxmemory'.")
+}
+
+/// Whether the dataset is over `maxmemory`. Replicas leave eviction to their master.
+pub fn over_limit(state: &AppState) -> bool {
+    let maxmemory = state.eviction.maxmemory;
+    maxmemory > 0 && !state.replication.is_replica() && state.used_memory() as u64 > maxmemory
+}
+
+/// Evict keys until the dataset fits in `maxmemory`,
+/// returning `false` if the policy found nothing more to evict
+pub fn perform_evictions(state: &mut AppState) -> bool {
+    let selected = state.selected;
+    let mut evicted = true;
+    while over_limit(state) {
+        let Some((db, key)) = select_key(state) else {
+            evicted = false;
+            break;
+        };
+        debug!("Evicting `{key}` from database {db}");
+        state.select(db);
+        state.remove(&key);
+        state.stats.evicted_keys += 1;
+        state.propagate(RespData::command(["DEL", key.as_str()]));
+    }
+    state.select(selected);
+    evicted
+}
+
+/// Up to `count` keys of the selected database the policy may evict
+fn sample_candidates(state: &mut AppState, policy: MaxmemoryPolicy, count: usize) -> Vec<String> {
+    state.db.kv.sample_keys(count, policy.volatile(), random)
+}
+
+/// The key the policy evicts next, with its database
+fn select_key(state: &mut AppState) -> Option<(usize, String)> {
+    let policy = state.eviction.policy;
+    let samples = state.eviction.samples;
+    let dbs: Vec<usize> = state
+        .databases()
+        .filter(|(_, db)| !policy.volatile() || db.kv.volatile_len() > 0)
+        .map(|(index, _)| index)
+        .collect();
+    match policy {
+        MaxmemoryPolicy::NoEviction => None,
+        MaxmemoryPolicy::AllkeysRandom | MaxmemoryPolicy::VolatileRandom => {
+            let pick = random() % dbs.len().max(1) as u64;
+            let db = *dbs.get(usize::try_from(pick).unwrap_or_default())?;
+            state.select(db);
+            let key = sample_candidates(state, policy, 1).pop()?;
+            Some((db, key))
+        }
+        _ => loop {
+            // Every database is sampled, so keys compete for eviction across them
+            let now = now_ms();
+            let mut sampled = false;
+            for &db in &dbs {
+                state.select(db);
+                for key in sample_candidates(state, policy, samples) {
+                    sampled = true;
+                    let idle = idle_score(state, policy, &key, now);
+                    state.eviction.offer(idle, db, key);
+                }
+            }
+            if !sampled {
+                return None;
+            }
+            // Candidates may have been removed or lost their expiry since they were sampled
+            while let Some((_, db, key)) = state.eviction.pool.pop() {
+                let db_keys = state.database(db);
+                let exists = if policy.volatile() {
+                    db_keys.kv.expiry(&key).is_some()
+                } else {
+                    db_keys.kv.contains_key(&key)
+                };
+                if exists {
+                    return Some((db, key));
+                }
+            }
+        },
+    }
+}
+
+/// How good a candidate `key` of the selected database is, higher is evicted first
+fn idle_score(state: &mut AppState, policy: MaxmemoryPolicy, key: &str, now: u64) -> u64 {
+    if policy == MaxmemoryPolicy::VolatileTtl {
+        return u64::MAX - state.db.kv.expiry(key).unwrap_or(u64::MAX);
+    }
+    let decay_time = state.eviction.lfu_decay_time;
+    let Some(meta) = state.db.kv.shard(key).meta.get(key) else {
+        return u64::MAX;
+    };
+    if policy.lfu() {
+        u64::from(u8::MAX - lfu_decayed(meta, decay_time, now))
+    } else {
+        now.saturating_sub(meta.last_access)
+    }
+}
+
+/// Evict keys as needed before `command` runs, returning the error it gets
+/// if it may grow the dataset while it is still over `maxmemory`
+pub fn check_command(state: &mut AppState, command: &Command) -> Option<RespData> {
+    if perform_evictions(state) {
+        return None;
+    }
+    let denied = match command {
+        // Functions that may write must be allowed to run out of memory explicitly
+        Command::FCall {
+            function,
+            read_only: false,
+            ..
+        } => state
+            .functions
+            .find(function)
+            .is_some_and(|(_, info)| !info.no_writes() && !info.allow_oom()),
+        command => command.denies_oom(),
+    };
+    denied.then(oom_error)
+}
+
+/// Evict what no longer fits once `maxmemory` is changed by CONFIG SET
+pub fn apply_maxmemory(state: &mut AppState) -> anyhow::Result<()> {
+    if !perform_evictions(state) {
+        warn!("WARNING: the new maxmemory value set via CONFIG SET is smaller than the current memory usage, and the eviction policy does not allow freeing memory");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    fn fill(state: &mut AppState, keys: usize) {
+        for i in 0..keys {
+            let key = format!("key:{i}");
+            state
+                .db
+                .kv
+                .insert(key.clone(), Value::String(vec![b'x'; 100]));
+            state.account(&key);
+        }
+    }
+
+    #[test]
+    fn test_evictions() {
+        let mut state = AppState::default();
+        fill(&mut state, 100);
+        state.eviction.maxmemory = state.used_memory() as u64 / 2;
+        assert!(!perform_evictions(&mut state));
+        assert_eq!(state.db.kv.len(), 100);
+
+        // Sampling every key makes the approximation exact
+        state.eviction.policy = MaxmemoryPolicy::AllkeysLru;
+        state.eviction.samples = 100;
+        for i in 0..90 {
+            let key = format!("key:{i}");
+            state
+                .db
+                .kv
+                .shard(&key)
+                .meta
+                .get_mut(&key)
+                .unwrap()
+                .last_access = 0;
+        }
+        assert!(perform_evictions(&mut state));
+        assert!(state.used_memory() as u64 <= state.eviction.maxmemory);
+        assert_eq!(state.stats.evicted_keys, 100 - state.db.kv.len() as u64);
+        assert!((90..100).all(|i| state.db.kv.contains_key(&format!("key:{i}"))));
+
+        // Nothing to evict without expiries
+        state.eviction.policy = MaxmemoryPolicy::VolatileTtl;
+        state.eviction.maxmemory = 1;
+        assert!(!perform_evictions(&mut state));
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        let mut counter = LFU_INIT_VAL;
+        for _ in 0..1000 {
+            counter = lfu_increment(counter, 10);
+        }
+        assert!(counter > LFU_INIT_VAL && counter < u8::MAX);
+        let meta = KeyMeta {
+            last_access: 0,
+            frequency: 10,
+            memory: 0,
+        };
+        assert_eq!(lfu_decayed(&meta, 1, 3 * 60_000), 7);
+        assert_eq!(lfu_decayed(&meta, 0, 3 * 60_000), 10);
+    }
+}
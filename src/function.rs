@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+
+use crate::{glob::glob_match, resp::RespData};
+
+/// Flags a function may be registered with
+pub const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// A function registered by a library with `redis.register_function`
+#[derive(Debug, Clone, Default)]
+pub struct FunctionInfo {
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    /// Functions flagged `no-writes` may run with FCALL_RO and never modify the dataset
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+
+    /// Functions flagged `allow-oom` may run while the dataset is over `maxmemory`
+    pub fn allow_oom(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "allow-oom")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub name: String,
+    /// The full code, including the `#!lua` header
+    pub code: String,
+    pub functions: BTreeMap<String, FunctionInfo>,
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Keep them, failing on any conflict
+    Append,
+    /// Replace the libraries that conflict
+    Replace,
+    /// Delete them all first
+    Flush,
+}
+
+/// Every loaded library, by name
+#[derive(Debug, Clone, Default)]
+pub struct Functions {
+    pub libraries: BTreeMap<String, Library>,
+}
+
+/// Whether `name` is a valid library or function name
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Split library code into the library name and the Lua body,
+/// from a first line of the form `#!lua name=<library>`
+pub fn parse_header(code: &str) -> Result<(String, &str), String> {
+    let (first_line, body) = code.split_once('\n').unwrap_or((code, ""));
+    let header = first_line
+        .strip_prefix("#!")
+        .ok_or("Missing library metadata")?;
+    let mut parts = header.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("Engine '{engine}' not found"));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) => name = Some(value),
+            _ => return Err(format!("Invalid metadata value given: {part}")),
+        }
+    }
+    let name = name.ok_or("Library name was not given")?;
+    if !valid_name(name) {
+        return Err("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok((name.to_string(), body))
+}
+
+impl Functions {
+    /// The library defining `function`, along with the function itself
+    pub fn find(&self, function: &str) -> Option<(&Library, &FunctionInfo)> {
+        self.libraries
+            .values()
+            .find_map(|library| library.functions.get(function).map(|info| (library, info)))
+    }
+
+    /// Add a library, replacing the one with the same name only if `replace` is set
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<(), String> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(format!("Library '{}' already exists", library.name));
+        }
+        for (name, owner) in self
+            .libraries
+            .iter()
+            .flat_map(|(owner, library)| library.functions.keys().map(move |name| (name, owner)))
+        {
+            if *owner != library.name && library.functions.contains_key(name) {
+                return Err(format!("Function {name} already exists"));
+            }
+        }
+        self.libraries.insert(library.name.clone(), library);
+        Ok(())
+    }
+
+    /// The FUNCTION LIST reply, optionally filtered by a library name pattern
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> RespData {
+        let libraries = self
+            .libraries
+            .values()
+            .filter(|library| {
+                pattern
+                    .is_none_or(|pattern| glob_match(pattern.as_bytes(), library.name.as_bytes()))
+            })
+            .map(|library| {
+                let functions = library
+                    .functions
+                    .iter()
+                    .map(|(name, info)| {
+                        RespData::Map(vec![
+                            (RespData::bulk_string("name"), RespData::bulk_string(name)),
+                            (
+                                RespData::bulk_string("description"),
+                                info.description
+                                    .as_ref()
+                                    .map_or(RespData::null_bulk_string(), RespData::bulk_string),
+                            ),
+                            (
+                                RespData::bulk_string("flags"),
+                                RespData::array(
+                                    info.flags.iter().map(RespData::bulk_string).collect(),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect();
+                let mut fields = vec![
+                    (
+                        RespData::bulk_string("library_name"),
+                        RespData::bulk_string(&library.name),
+                    ),
+                    (
+                        RespData::bulk_string("engine"),
+                        RespData::bulk_string("LUA"),
+                    ),
+                    (
+                        RespData::bulk_string("functions"),
+                        RespData::array(functions),
+                    ),
+                ];
+                if with_code {
+                    fields.push((
+                        RespData::bulk_string("library_code"),
+                        RespData::bulk_string(&library.code),
+                    ));
+                }
+                RespData::Map(fields)
+            })
+            .collect();
+        RespData::array(libraries)
+    }
+
+    /// The FUNCTION DUMP payload: the code of every library
+    pub fn dump(&self) -> Vec<u8> {
+        RespData::array(
+            self.libraries
+                .values()
+                .map(|library| RespData::bulk_string(&library.code))
+                .collect(),
+        )
+        .as_bytes()
+    }
+
+    /// The library codes in a FUNCTION DUMP payload
+    pub fn parse_dump(payload: &[u8]) -> Option<Vec<String>> {
+        let RespData::Array(Some(codes)) = RespData::try_from(payload).ok()? else {
+            return None;
+        };
+        codes
+            .into_iter()
+            .map(|code| match code {
+                RespData::BulkString(Some(code)) => String::from_utf8(code).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Add restored libraries according to `policy`, leaving the libraries untouched on failure
+    pub fn restore(
+        &mut self,
+        libraries: Vec<Library>,
+        policy: RestorePolicy,
+    ) -> Result<(), String> {
+        let mut restored = match policy {
+            RestorePolicy::Flush => Functions::default(),
+            RestorePolicy::Append | RestorePolicy::Replace => self.clone(),
+        };
+        for library in libraries {
+            if policy == RestorePolicy::Replace {
+                // Libraries defining any of the same functions are replaced as well
+                restored.libraries.retain(|_, existing| {
+                    existing.name == library.name
+                        || !existing
+                            .functions
+                            .keys()
+                            .any(|name| library.functions.contains_key(name))
+                });
+            }
+            restored.add(library, policy == RestorePolicy::Replace)?;
+        }
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The FUNCTION LIST reply as seen by a RESP2 client
+    fn list_resp2(functions: &Functions, pattern: Option<&str>) -> String {
+        functions.list(pattern, false).into_resp2().to_string()
+    }
+
+    fn library(name: &str, functions: &[&str]) -> Library {
+        Library {
+            name: name.to_string(),
+            code: format!("#!lua name={name}\n"),
+            functions: functions
+                .iter()
+                .map(|name| (name.to_string(), FunctionInfo::default()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("#!lua name=mylib\nreturn 1"),
+            Ok(("mylib".to_string(), "return 1"))
+        );
+        assert!(parse_header("return 1").is_err());
+        assert!(parse_header("#!js name=mylib\n").is_err());
+        assert!(parse_header("#!lua name=my-lib\n").is_err());
+        assert!(parse_header("#!lua\n").is_err());
+    }
+
+    #[test]
+    fn test_add_and_restore() {
+        let mut functions = Functions::default();
+        functions.add(library("a", &["f", "g"]), false).unwrap();
+        assert!(functions.add(library("a", &["h"]), false).is_err());
+        assert!(functions.add(library("b", &["f"]), false).is_err());
+        functions.add(library("a", &["f"]), true).unwrap();
+        assert_eq!(functions.find("f").unwrap().0.name, "a");
+        assert!(functions.find("g").is_none());
+        assert!(list_resp2(&functions, Some("b*")).starts_with("*0"));
+
+        let mut restored = functions.clone();
+        assert!(restored
+            .restore(vec![library("b", &["f"])], RestorePolicy::Append)
+            .is_err());
+        restored
+            .restore(vec![library("b", &["f"])], RestorePolicy::Replace)
+            .unwrap();
+        assert_eq!(restored.libraries.keys().collect::<Vec<_>>(), ["b"]);
+        restored
+            .restore(vec![library("c", &["x"])], RestorePolicy::Flush)
+            .unwrap();
+        assert_eq!(restored.libraries.keys().collect::<Vec<_>>(), ["c"]);
+
+        let codes = Functions::parse_dump(&functions.dump()).unwrap();
+        assert_eq!(codes, ["#!lua name=a\n"]);
+    }
+}
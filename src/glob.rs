@@ -0,0 +1,84 @@
+/// Match `text` against a Redis glob-style `pattern`, supporting `*`, `?`,
+/// `[abc]`, `[^abc]`, `[a-z]` and `\` escapes
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            // Collapse consecutive stars, then try every possible split of `text`
+            let rest = &rest[rest.iter().take_while(|&&b| b == b'*').count()..];
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, text_rest)) = text.split_first() else {
+                return false;
+            };
+            match match_class(rest, c) {
+                Some((true, rest)) => glob_match(rest, text_rest),
+                Some((false, _)) => false,
+                // An unterminated class matches a literal `[`
+                None => c == b'[' && glob_match(rest, text_rest),
+            }
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((&p, rest)) => text.first() == Some(&p) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Match `c` against a character class (the pattern following `[`),
+/// returning whether it matched and the pattern after the closing `]`
+fn match_class(mut pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => return None,
+            [b']', rest @ ..] => return Some((matched != negate, rest)),
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"news.*", b"news.sport"));
+        assert!(!glob_match(b"news.*", b"weather"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hallo"));
+        assert!(glob_match(b"a*b*c", b"aXXbYYc"));
+    }
+}
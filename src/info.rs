@@ -0,0 +1,446 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    config::value_enum_name,
+    rdb::now_secs,
+    resp::RespData,
+    state::{expiry_overhead, now_ms, AppState, KEY_OVERHEAD},
+};
+
+/// Version reported to clients, the Redis release whose behavior is followed
+pub const REDIS_VERSION: &str = "7.4.0";
+
+/// Sections of `INFO` in the order they are reported, all of them part of the default set
+const SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "keyspace",
+];
+
+/// Commands run since the start or the last `CONFIG RESETSTAT`, counted apart from
+/// the state so that counting takes no lock
+pub static TOTAL_COMMANDS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+/// Clients connected now, counted by the accept loop without the state lock
+pub static CONNECTED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+/// Clients accepted since the start or the last `CONFIG RESETSTAT`
+pub static TOTAL_CONNECTIONS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters reported by `INFO`
+#[derive(Debug)]
+pub struct Stats {
+    /// Time the server started, in seconds since the UNIX epoch
+    pub start_time: u64,
+    /// Counted by commands sharing the state
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    /// Counted by writes sharing the state too
+    pub expired_keys: AtomicU64,
+    pub evicted_keys: u64,
+    /// The most memory the dataset took, in bytes
+    pub peak_memory: AtomicUsize,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            start_time: now_secs(),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: 0,
+            peak_memory: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a lookup of a key as a keyspace hit or miss
+    pub fn count_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.keyspace_hits
+        } else {
+            &self.keyspace_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The `INFO` report of the requested sections, the default ones when none is given
+pub fn info(state: &AppState, sections: &[String]) -> String {
+    let all = sections.is_empty()
+        || sections.iter().any(|section| {
+            ["all", "default", "everything"].contains(&section.to_lowercase().as_str())
+        });
+    let mut report = String::new();
+    for &section in SECTIONS {
+        if !all
+            && !sections
+                .iter()
+                .any(|requested| requested.eq_ignore_ascii_case(section))
+        {
+            continue;
+        }
+        if !report.is_empty() {
+            report.push_str("\r\n");
+        }
+        let fields = match section {
+            "server" => server(state),
+            "clients" => clients(state),
+            "memory" => memory(state),
+            "persistence" => persistence(state),
+            "stats" => stats(state),
+            "replication" => replication(state),
+            _ => keyspace(state),
+        };
+        let mut title = section.to_string();
+        title[..1].make_ascii_uppercase();
+        let _ = write!(report, "# {title}\r\n");
+        for (name, value) in fields {
+            let _ = write!(report, "{name}:{value}\r\n");
+        }
+    }
+    report
+}
+
+type Fields = Vec<(String, String)>;
+
+fn field(name: &str, value: impl ToString) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+fn server(state: &AppState) -> Fields {
+    let uptime = now_secs().saturating_sub(state.stats.start_time);
+    vec![
+        field("redis_version", REDIS_VERSION),
+        field("redis_mode", "standalone"),
+        field(
+            "os",
+            format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        ),
+        field("arch_bits", usize::BITS),
+        field("process_id", std::process::id()),
+        field("tcp_port", state.config.port),
+        field("server_time_usec", now_ms() * 1000),
+        field("uptime_in_seconds", uptime),
+        field("uptime_in_days", uptime / (24 * 60 * 60)),
+    ]
+}
+
+fn clients(state: &AppState) -> Fields {
+    let blocked_pops: usize = state.blocked_pops.values().map(|pops| pops.len()).sum();
+    let blocked_reads: u32 = state.waiting_lists.values().map(|list| list.count).sum();
+    vec![
+        field(
+            "connected_clients",
+            CONNECTED_CLIENTS.load(Ordering::Relaxed),
+        ),
+        field("blocked_clients", blocked_pops + blocked_reads as usize),
+    ]
+}
+
+/// The most memory the dataset took, in bytes
+fn peak_memory(state: &AppState) -> usize {
+    state.stats.peak_memory.load(Ordering::Relaxed)
+}
+
+fn memory(state: &AppState) -> Fields {
+    let used_memory = state.used_memory();
+    vec![
+        field("used_memory", used_memory),
+        field("used_memory_human", human_bytes(used_memory)),
+        field("used_memory_peak", peak_memory(state)),
+        field("used_memory_peak_human", human_bytes(peak_memory(state))),
+        field("maxmemory", state.eviction.maxmemory),
+        field(
+            "maxmemory_human",
+            human_bytes(usize::try_from(state.eviction.maxmemory).unwrap_or(usize::MAX)),
+        ),
+        field("maxmemory_policy", value_enum_name(&state.eviction.policy)),
+    ]
+}
+
+fn persistence(state: &AppState) -> Fields {
+    let status = |ok: bool| if ok { "ok" } else { "err" };
+    vec![
+        field("loading", 0),
+        field(
+            "rdb_changes_since_last_save",
+            state.dirty.load(Ordering::Relaxed),
+        ),
+        field(
+            "rdb_bgsave_in_progress",
+            u8::from(state.rdb.bgsave_in_progress()),
+        ),
+        field("rdb_last_save_time", state.rdb.last_save),
+        field("rdb_last_bgsave_status", status(state.rdb.last_bgsave_ok)),
+        field("aof_enabled", u8::from(state.aof.enabled)),
+        field(
+            "aof_rewrite_in_progress",
+            u8::from(state.aof.rewrite_in_progress()),
+        ),
+        field(
+            "aof_last_bgrewrite_status",
+            status(state.aof.last_rewrite_ok),
+        ),
+    ]
+}
+
+fn stats(state: &AppState) -> Fields {
+    let stats = &state.stats;
+    vec![
+        field(
+            "total_connections_received",
+            TOTAL_CONNECTIONS_RECEIVED.load(Ordering::Relaxed),
+        ),
+        field(
+            "total_commands_processed",
+            TOTAL_COMMANDS_PROCESSED.load(Ordering::Relaxed),
+        ),
+        field("expired_keys", stats.expired_keys.load(Ordering::Relaxed)),
+        field("evicted_keys", stats.evicted_keys),
+        field("keyspace_hits", stats.keyspace_hits.load(Ordering::Relaxed)),
+        field(
+            "keyspace_misses",
+            stats.keyspace_misses.load(Ordering::Relaxed),
+        ),
+        field("pubsub_channels", state.pubsub.channels.len()),
+        field("pubsub_patterns", state.pubsub.patterns.len()),
+        field("pubsub_shardchannels", state.pubsub.shard_channels.len()),
+    ]
+}
+
+fn replication(state: &AppState) -> Fields {
+    let replication = &state.replication;
+    let mut fields = Vec::new();
+    match &replication.master {
+        Some(master) => {
+            fields.extend([
+                field("role", "slave"),
+                field("master_host", &master.host),
+                field("master_port", master.port),
+                field(
+                    "master_link_status",
+                    if master.link_up { "up" } else { "down" },
+                ),
+                field("master_sync_in_progress", u8::from(!master.link_up)),
+                field("slave_read_repl_offset", replication.offset),
+                field("slave_repl_offset", replication.offset),
+                field("slave_read_only", u8::from(replication.read_only)),
+            ]);
+        }
+        None => fields.push(field("role", "master")),
+    }
+    fields.push(field("connected_slaves", replication.replicas.len()));
+    let now = now_secs();
+    let mut replicas: Vec<_> = replication.replicas.iter().collect();
+    replicas.sort_by_key(|(id, _)| **id);
+    for (i, (_, replica)) in replicas.into_iter().enumerate() {
+        fields.push(field(
+            &format!("slave{i}"),
+            format!(
+                "ip={},port={},state=online,offset={},lag={}",
+                replica.addr.ip(),
+                replica.addr.port(),
+                replica.ack_offset,
+                now.saturating_sub(replica.last_ack / 1000)
+            ),
+        ));
+    }
+    let (first_byte_offset, histlen) = replication.backlog_range();
+    fields.extend([
+        field("master_replid", &replication.replid),
+        field("master_replid2", &replication.replid2),
+        field("master_repl_offset", replication.offset),
+        field(
+            "second_repl_offset",
+            replication
+                .second_replid_offset
+                .map_or(-1, |offset| i64::try_from(offset).unwrap_or(i64::MAX)),
+        ),
+        field("repl_backlog_active", 1),
+        field("repl_backlog_size", replication.backlog_size()),
+        field("repl_backlog_first_byte_offset", first_byte_offset),
+        field("repl_backlog_histlen", histlen),
+    ]);
+    fields
+}
+
+fn keyspace(state: &AppState) -> Fields {
+    state
+        .databases()
+        .map(|(index, db)| {
+            field(
+                &format!("db{index}"),
+                format!(
+                    "keys={},expires={},avg_ttl={}",
+                    db.kv.len(),
+                    db.kv.volatile_len(),
+                    db.kv.avg_ttl(now_ms())
+                ),
+            )
+        })
+        .collect()
+}
+
+/// `MEMORY STATS`, where the memory of the dataset goes
+pub fn memory_stats(state: &AppState) -> RespData {
+    let total = state.used_memory();
+    let peak = peak_memory(state).max(total);
+    let integer = |n: usize| RespData::Integer(i64::try_from(n).unwrap_or(i64::MAX));
+    let entry = |name: &str, value: RespData| (RespData::bulk_string(name), value);
+    let mut keys = 0;
+    let mut overhead = 0;
+    let mut dbs = Vec::new();
+    for (index, db) in state.databases() {
+        let main = db.kv.len() * KEY_OVERHEAD;
+        let expires: usize = db
+            .kv
+            .shards()
+            .flat_map(|shard| {
+                let overheads: Vec<usize> = shard
+                    .expires
+                    .keys()
+                    .map(|key| expiry_overhead(key))
+                    .collect();
+                overheads
+            })
+            .sum();
+        keys += db.kv.len();
+        overhead += main + expires;
+        dbs.push(entry(
+            &format!("db.{index}"),
+            RespData::Map(vec![
+                entry("overhead.hashtable.main", integer(main)),
+                entry("overhead.hashtable.expires", integer(expires)),
+            ]),
+        ));
+    }
+    let dataset = total.saturating_sub(overhead);
+    let lua: usize = state.scripts.values().map(String::len).sum();
+    let functions: usize = state
+        .functions
+        .libraries
+        .values()
+        .map(|library| library.code.len())
+        .sum();
+    let percentage = |part: usize, whole: usize| {
+        #[allow(clippy::cast_precision_loss)]
+        let percentage = if whole == 0 {
+            0.0
+        } else {
+            part as f64 * 100.0 / whole as f64
+        };
+        RespData::bulk_string(format!("{percentage:.2}"))
+    };
+    let mut stats = vec![
+        entry("peak.allocated", integer(peak)),
+        entry("total.allocated", integer(total)),
+        entry(
+            "replication.backlog",
+            integer(state.replication.backlog_range().1),
+        ),
+        entry("lua.caches", integer(lua)),
+        entry("functions.caches", integer(functions)),
+    ];
+    stats.extend(dbs);
+    stats.extend([
+        entry("overhead.total", integer(overhead)),
+        entry("keys.count", integer(keys)),
+        entry(
+            "keys.bytes-per-key",
+            integer(total.checked_div(keys).unwrap_or_default()),
+        ),
+        entry("dataset.bytes", integer(dataset)),
+        entry("dataset.percentage", percentage(dataset, total)),
+        entry("peak.percentage", percentage(total, peak)),
+    ]);
+    RespData::Map(stats)
+}
+
+/// `MEMORY DOCTOR`, a report of what looks wrong with the memory of the dataset
+pub fn memory_doctor(state: &AppState) -> String {
+    // Below this there is too little data to tell anything
+    const MIN_MEMORY: usize = 5 * 1024 * 1024;
+    let total = state.used_memory();
+    if total < MIN_MEMORY {
+        return "Hi Sam, this instance is empty or is using very little memory, my issues detector can't be used in these conditions. Please, leave for your mission on Earth and fill it with some data. The new Sam and I will be back to our programming as soon as I finished rebooting.".to_string();
+    }
+    let mut issues = Vec::new();
+    if peak_memory(state) > total / 2 * 3 {
+        issues.push(" * Peak memory: In the past this instance used more than 150% the memory that is currently using. The allocator is normally not able to release memory after a peak, so you can expect to see a big fragmentation ratio, however this is actually harmless and is only due to the memory peak. CONFIG RESETSTAT resets the peak once you are done looking into it.");
+    }
+    let maxmemory = usize::try_from(state.eviction.maxmemory).unwrap_or(usize::MAX);
+    if maxmemory > 0 && total >= maxmemory / 10 * 9 {
+        issues.push(" * Memory limit: The dataset uses more than 90% of maxmemory, so keys are about to be evicted, or writes refused with the noeviction policy. Consider raising maxmemory or checking the maxmemory-policy.");
+    }
+    if issues.is_empty() {
+        return "Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs on this base.".to_string();
+    }
+    format!(
+        "Sam, I detected a few issues in this Redis instance memory implants:\n\n{}\n\nI'm here to keep you safe, Sam. I want to help you.\n",
+        issues.join("\n\n")
+    )
+}
+
+/// Bytes in the unit `INFO` uses for them, as `1.50K`
+fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2}{}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections() {
+        let mut state = AppState::default();
+        state.db.kv.insert(
+            "key".to_string(),
+            crate::value::Value::String(b"v".to_vec()),
+        );
+        let report = info(&state, &["keyspace".to_string(), "Memory".to_string()]);
+        assert!(report.starts_with("# Memory\r\nused_memory:"));
+        assert!(report.ends_with("\r\n# Keyspace\r\ndb0:keys=1,expires=0,avg_ttl=0\r\n"));
+        assert!(!report.contains("# Server"));
+        let report = info(&state, &[]);
+        assert!(report.contains("\r\nrole:master\r\n"));
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut state = AppState::default();
+        state.db.kv.insert(
+            "key".to_string(),
+            crate::value::Value::String(b"value".to_vec()),
+        );
+        state.account("key");
+        let stats = memory_stats(&state).to_string();
+        assert!(stats.contains("$18\r\nkeys.bytes-per-key\r\n:56\r\n"));
+        assert!(stats.contains("$13\r\ndataset.bytes\r\n:8\r\n"));
+        assert!(memory_doctor(&state).starts_with("Hi Sam, this instance is empty"));
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(100), "100B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.00M");
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{bail, ensure};
+
+/// The width of the integers and their count
+const HEADER_SIZE: usize = 8;
+
+/// A sorted set of integers packed at the smallest width that holds them all,
+/// in the format of Redis so it is saved to RDB files as is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intset {
+    data: Vec<u8>,
+}
+
+impl Default for Intset {
+    fn default() -> Self {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..4].copy_from_slice(&2u32.to_le_bytes());
+        Self { data }
+    }
+}
+
+/// Bytes it takes to store `n`
+fn width_for(n: i64) -> usize {
+    if i16::try_from(n).is_ok() {
+        2
+    } else if i32::try_from(n).is_ok() {
+        4
+    } else {
+        8
+    }
+}
+
+impl Intset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An intset as found in an RDB file, checked to be well formed
+    pub fn from_bytes(data: Vec<u8>) -> anyhow::Result<Self> {
+        ensure!(data.len() >= HEADER_SIZE, "Truncated intset");
+        let intset = Self { data };
+        if !matches!(intset.width(), 2 | 4 | 8) {
+            bail!("Invalid intset encoding {}", intset.width());
+        }
+        ensure!(
+            intset.data.len() == HEADER_SIZE + intset.len() * intset.width(),
+            "Intset of {} integers doesn't match its {} bytes",
+            intset.len(),
+            intset.data.len()
+        );
+        ensure!(
+            (1..intset.len()).all(|i| intset.get(i - 1) < intset.get(i)),
+            "Intset is not sorted"
+        );
+        Ok(intset)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn width(&self) -> usize {
+        u32::from_le_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]) as usize
+    }
+
+    pub fn len(&self) -> usize {
+        u32::from_le_bytes([self.data[4], self.data[5], self.data[6], self.data[7]]) as usize
+    }
+
+    fn get(&self, index: usize) -> i64 {
+        let width = self.width();
+        let start = HEADER_SIZE + index * width;
+        let bytes = &self.data[start..start + width];
+        match width {
+            2 => i64::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+            4 => i64::from(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            _ => i64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = i64> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+
+    /// The index of `n`, or where it would be inserted
+    fn search(&self, n: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.get(middle).cmp(&n) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(middle),
+            }
+        }
+        Err(low)
+    }
+
+    pub fn contains(&self, n: i64) -> bool {
+        self.search(n).is_ok()
+    }
+
+    /// Add `n`, widening every integer if it needs more bytes. Returns `true` if it was added.
+    pub fn insert(&mut self, n: i64) -> bool {
+        if width_for(n) > self.width() {
+            let values: Vec<i64> = self.iter().collect();
+            self.data.truncate(HEADER_SIZE);
+            let width = width_for(n);
+            self.data[..4].copy_from_slice(&u32::try_from(width).unwrap_or(8).to_le_bytes());
+            for value in values {
+                self.data.extend(&value.to_le_bytes()[..width]);
+            }
+        }
+        let Err(index) = self.search(n) else {
+            return false;
+        };
+        let width = self.width();
+        let start = HEADER_SIZE + index * width;
+        self.data
+            .splice(start..start, n.to_le_bytes()[..width].iter().copied());
+        let len = u32::try_from(self.len() + 1).unwrap_or(u32::MAX);
+        self.data[4..8].copy_from_slice(&len.to_le_bytes());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut intset = Intset::new();
+        assert!(intset.insert(5));
+        assert!(intset.insert(-3));
+        assert!(!intset.insert(5));
+        assert_eq!(intset.as_bytes().len(), HEADER_SIZE + 2 * 2);
+        // Widened for the larger integer, keeping the order
+        assert!(intset.insert(1 << 40));
+        assert!(intset.insert(0));
+        assert_eq!(intset.iter().collect::<Vec<_>>(), [-3, 0, 5, 1 << 40]);
+        assert_eq!(intset.as_bytes().len(), HEADER_SIZE + 4 * 8);
+        assert!(intset.contains(0) && !intset.contains(1));
+        let reloaded = Intset::from_bytes(intset.as_bytes().to_vec()).unwrap();
+        assert_eq!(reloaded, intset);
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use crate::{
+    dict::{self, Dict},
+    evict::KeyMeta,
+    state::{expiry_overhead, KEY_OVERHEAD},
+    value::Value,
+};
+
+/// Bits of the hash of a key picking its shard
+const SHARD_BITS: u32 = 4;
+/// Partitions of the keyspace, locked independently so commands on different keys
+/// run in parallel
+const SHARDS: usize = 1 << SHARD_BITS;
+
+/// The keys that hash to one partition of the keyspace
+#[derive(Debug, Default, Clone)]
+pub struct Shard {
+    pub kv: Dict<String, Value>,
+    /// Access times and memory accounted for every key,
+    /// kept with the keys as reading them updates their access times
+    pub meta: Dict<String, KeyMeta>,
+    /// Expiry deadlines of the volatile keys, in milliseconds since the UNIX epoch
+    pub expires: Dict<String, u64>,
+    /// The same deadlines ordered by time, so expired keys are found without a full scan
+    expiry_queue: BTreeSet<(u64, String)>,
+}
+
+impl Shard {
+    /// Expire `key` at `at` milliseconds since the UNIX epoch, replacing any previous expiry
+    pub fn set_expiry(&mut self, key: &str, at: u64) {
+        self.clear_expiry(key);
+        self.expires.insert(key.to_string(), at);
+        self.expiry_queue.insert((at, key.to_string()));
+    }
+
+    /// Make `key` persistent, returning whether it had an expiry
+    pub fn clear_expiry(&mut self, key: &str) -> bool {
+        match self.expires.remove(key) {
+            Some(at) => self.expiry_queue.remove(&(at, key.to_string())),
+            None => false,
+        }
+    }
+
+    /// Whether the expiry of `key` is at or before `now`
+    pub fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.expires.get(key).is_some_and(|at| *at <= now)
+    }
+
+    /// Remove `key` along with its expiry
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.clear_expiry(key);
+        self.kv.remove(key)
+    }
+
+    /// Rough estimate of the bytes `key` takes in memory, with its value and expiry,
+    /// collections estimated from `samples` elements as in [`Value::memory_usage`]
+    pub fn key_memory(&self, key: &str, samples: usize) -> usize {
+        let Some(value) = self.kv.get(key) else {
+            return 0;
+        };
+        let overhead = if self.expires.contains_key(key) {
+            KEY_OVERHEAD + expiry_overhead(key)
+        } else {
+            KEY_OVERHEAD
+        };
+        key.len() + value.memory_usage(samples) + overhead
+    }
+
+    /// Bring the memory accounted for `key` up to date after it was written,
+    /// returning the bytes it was accounted for before and now
+    pub fn account(&mut self, key: &str) -> (usize, usize) {
+        // Collections are estimated from a few elements, so accounting stays cheap
+        const SAMPLES: usize = 5;
+        let memory = self.key_memory(key, SAMPLES);
+        let previous = if memory == 0 {
+            self.meta.remove(key).map(|meta| meta.memory)
+        } else {
+            match self.meta.get_mut(key) {
+                Some(meta) => Some(std::mem::replace(&mut meta.memory, memory)),
+                None => {
+                    self.meta.insert(key.to_string(), KeyMeta::new(memory));
+                    None
+                }
+            }
+        };
+        (previous.unwrap_or_default(), memory)
+    }
+}
+
+/// The keyspace, sharded by the hash of the keys.
+///
+/// Holding the keyspace exclusively reaches every shard without locking it,
+/// while commands sharing it lock the shards of their keys with [`Keyspace::lock`].
+#[derive(Debug)]
+pub struct Keyspace {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl Clone for Keyspace {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self
+                .shards()
+                .map(|shard| Mutex::new(shard.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The shard `key` belongs to, from the high bits of its hash
+/// as the dict of the shard picks buckets from the low bits
+fn shard_index(key: &str) -> usize {
+    usize::try_from(dict::hash(key) >> (u64::BITS - SHARD_BITS)).unwrap_or_default()
+}
+
+/// Expiries of each shard [`Keyspace::avg_ttl`] estimates the average from
+const TTL_SAMPLES: usize = 16;
+
+/// Buckets rehashed between checks of the time left for [`Keyspace::rehash`]
+const REHASH_BATCH: usize = 100;
+
+/// A poisoned shard is still consistent, as no command panics halfway through a write
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Keyspace {
+    /// The shard of `key`, reached through exclusive access
+    pub fn shard(&mut self, key: &str) -> &mut Shard {
+        self.shards[shard_index(key)]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        self.shard(key).kv.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.shard(key).kv.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        self.shard(&key).kv.insert(key, value)
+    }
+
+    /// Remove `key` along with its expiry
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.shard(key).remove(key)
+    }
+
+    pub fn set_expiry(&mut self, key: &str, at: u64) {
+        self.shard(key).set_expiry(key, at);
+    }
+
+    pub fn expiry(&self, key: &str) -> Option<u64> {
+        lock(&self.shards[shard_index(key)])
+            .expires
+            .get(key)
+            .copied()
+    }
+
+    /// Number of keys with an expiry
+    pub fn volatile_len(&self) -> usize {
+        self.shards().map(|shard| shard.expires.len()).sum()
+    }
+
+    /// The average time to live in milliseconds of the keys with an expiry,
+    /// estimated from a sample of them, 0 without any
+    pub fn avg_ttl(&self, now: u64) -> u64 {
+        let (sum, samples) = self.shards().fold((0, 0), |totals, shard| {
+            let ttls = shard.expires.values().take(TTL_SAMPLES);
+            ttls.fold(totals, |(sum, samples), at| {
+                (sum + u128::from(at.saturating_sub(now)), samples + 1)
+            })
+        });
+        u64::try_from(sum.checked_div(samples).unwrap_or_default()).unwrap_or(u64::MAX)
+    }
+
+    /// Forget the expiries at or before `now`, returning their keys to be removed
+    pub fn pop_expired(&mut self, now: u64) -> Vec<String> {
+        let mut expired = Vec::new();
+        for shard in &mut self.shards {
+            let shard = shard.get_mut().unwrap_or_else(PoisonError::into_inner);
+            while shard.expiry_queue.first().is_some_and(|(at, _)| *at <= now) {
+                let (_, key) = shard.expiry_queue.pop_first().expect("checked above");
+                shard.expires.remove(&key);
+                expired.push(key);
+            }
+        }
+        expired
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        lock(&self.shards[shard_index(key)]).kv.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards().map(|shard| shard.kv.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards().all(|shard| shard.kv.is_empty())
+    }
+
+    /// Every shard, reached through exclusive access
+    fn shards_mut(&mut self) -> impl Iterator<Item = &Shard> + Clone {
+        let shards: Vec<&Shard> = self
+            .shards
+            .iter_mut()
+            .map(|shard| &*shard.get_mut().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        shards.into_iter()
+    }
+
+    /// Every key, shard after shard
+    pub fn keys(&mut self) -> impl Iterator<Item = &String> + Clone {
+        self.shards_mut().flat_map(|shard| shard.kv.keys())
+    }
+
+    /// Up to `count` keys, or keys with an expiry if `volatile`, from random buckets of
+    /// the shards following a random one. `random` picks the shard and the buckets.
+    pub fn sample_keys(
+        &mut self,
+        count: usize,
+        volatile: bool,
+        random: impl Fn() -> u64,
+    ) -> Vec<String> {
+        let first = usize::try_from(random() % SHARDS as u64).unwrap_or_default();
+        let shards: Vec<&Shard> = self.shards_mut().collect();
+        let mut keys = Vec::new();
+        for shard in shards[first..].iter().chain(&shards[..first]) {
+            let wanted = count - keys.len();
+            if wanted == 0 {
+                break;
+            }
+            let sampled = if volatile {
+                shard.expires.random_keys(wanted, random())
+            } else {
+                shard.kv.random_keys(wanted, random())
+            };
+            keys.extend(sampled.into_iter().cloned());
+        }
+        keys
+    }
+
+    /// Call `f` with the keys of the next buckets from `cursor` on, returning the cursor
+    /// to continue from, 0 once every shard was scanned. The low bits of the cursor
+    /// are the shard, the others the cursor of the dict of the shard.
+    pub fn scan(&self, cursor: u64, f: impl FnMut(&String, &Value)) -> u64 {
+        let index = usize::try_from(cursor % SHARDS as u64).unwrap_or_default();
+        match lock(&self.shards[index]).kv.scan(cursor >> SHARD_BITS, f) {
+            0 if index + 1 == SHARDS => 0,
+            0 => index as u64 + 1,
+            next => next << SHARD_BITS | index as u64,
+        }
+    }
+
+    /// Whether a table of any shard is being resized
+    pub fn is_rehashing(&self) -> bool {
+        self.shards().any(|shard| {
+            shard.kv.is_rehashing() || shard.meta.is_rehashing() || shard.expires.is_rehashing()
+        })
+    }
+
+    /// Move on the rehashing of the shards until `deadline`, so tables no command
+    /// happens to write to still finish resizing. Returns `false` if time ran out.
+    pub fn rehash(&mut self, deadline: Instant) -> bool {
+        for shard in &mut self.shards {
+            let shard = shard.get_mut().unwrap_or_else(PoisonError::into_inner);
+            while shard.kv.rehash(REHASH_BATCH)
+                | shard.meta.rehash(REHASH_BATCH)
+                | shard.expires.rehash(REHASH_BATCH)
+            {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Every shard, each locked while it is being looked at
+    pub fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, Shard>> {
+        self.shards.iter().map(lock)
+    }
+
+    /// Lock the shards of `keys`, in ascending order so that commands locking
+    /// overlapping shards never wait for each other in a cycle
+    pub fn lock(&self, keys: &[&str]) -> LockedShards<'_> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| shard_index(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        LockedShards {
+            shards: indexes
+                .into_iter()
+                .map(|index| (index, lock(&self.shards[index])))
+                .collect(),
+        }
+    }
+}
+
+/// The shards of the keys of a command, locked by [`Keyspace::lock`]
+pub struct LockedShards<'a> {
+    shards: Vec<(usize, MutexGuard<'a, Shard>)>,
+}
+
+impl LockedShards<'_> {
+    /// The locked shard of `key`, which must be one of the keys the shards were locked for
+    pub fn shard(&mut self, key: &str) -> &mut Shard {
+        let index = shard_index(key);
+        let (_, shard) = self
+            .shards
+            .iter_mut()
+            .find(|(i, _)| *i == index)
+            .expect("shard of the key is locked");
+        shard
+    }
+
+    /// The locked shard of `key`, `None` if it is not one of the keys locked for
+    fn shard_ref(&self, key: &str) -> Option<&Shard> {
+        let index = shard_index(key);
+        self.shards
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, shard)| &**shard)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.shard_ref(key)?.kv.get(key)
+    }
+
+    pub fn expiry(&self, key: &str) -> Option<u64> {
+        self.shard_ref(key)?.expires.get(key).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locked_shards() {
+        let mut keyspace = Keyspace::default();
+        for i in 0..100 {
+            keyspace.insert(
+                format!("key:{i}"),
+                Value::String(i.to_string().into_bytes()),
+            );
+        }
+        assert_eq!(keyspace.len(), 100);
+        assert!(keyspace.shards().all(|shard| !shard.kv.is_empty()));
+        let mut scanned = Vec::new();
+        let mut cursor = 0;
+        loop {
+            cursor = keyspace.scan(cursor, |key, _| scanned.push(key.clone()));
+            if cursor == 0 {
+                break;
+            }
+        }
+        scanned.sort_unstable();
+        scanned.dedup();
+        assert_eq!(scanned.len(), 100);
+
+        let keys = ["key:7", "key:3", "key:7", "missing"];
+        let locked = keyspace.lock(&keys);
+        assert!(locked.shards.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(matches!(locked.get("key:7"), Some(Value::String(v)) if v == b"7"));
+        assert!(locked.get("missing").is_none());
+        drop(locked);
+
+        let snapshot = keyspace.clone();
+        keyspace.remove("key:3");
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!(keyspace.keys().count(), 99);
+
+        let mut sampled = keyspace.sample_keys(200, false, || 5);
+        sampled.sort_unstable();
+        sampled.dedup();
+        assert_eq!(sampled.len(), 99);
+        assert_eq!(keyspace.sample_keys(3, false, || 5).len(), 3);
+        assert!(keyspace.sample_keys(3, true, || 5).is_empty());
+    }
+
+    #[test]
+    fn test_avg_ttl() {
+        let mut keyspace = Keyspace::default();
+        assert_eq!(keyspace.avg_ttl(1000), 0);
+        for (key, at) in [("a", 2000), ("b", 4000), ("c", 500)] {
+            keyspace.insert(key.to_string(), Value::String(Vec::new()));
+            keyspace.set_expiry(key, at);
+        }
+        // Keys past their expiry count as having no time left
+        assert_eq!(keyspace.avg_ttl(1000), 4000 / 3);
+    }
+}
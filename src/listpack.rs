@@ -0,0 +1,462 @@
+use anyhow::{bail, ensure, Context};
+use std::borrow::Cow;
+
+/// The total size and the element count
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xff;
+/// The element count stored once there are too many to count in the header
+const UNKNOWN_COUNT: u16 = u16::MAX;
+
+/// An element of a listpack, strings holding an integer are stored as one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Element<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl<'a> Element<'a> {
+    pub fn count(n: usize) -> Self {
+        Element::Int(i64::try_from(n).unwrap_or(i64::MAX))
+    }
+
+    /// The element for a string, an integer if it is the canonical form of one
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        match parse_int(bytes) {
+            Some(n) => Element::Int(n),
+            None => Element::Str(bytes),
+        }
+    }
+
+    /// The element as a string, integers in their decimal form
+    pub fn to_bytes(self) -> Cow<'a, [u8]> {
+        match self {
+            Element::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            Element::Str(s) => Cow::Borrowed(s),
+        }
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+/// The integer a string holds, if it is its canonical decimal form
+/// with no sign or zeros that would be lost converting it back
+pub fn parse_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 20 {
+        return None;
+    }
+    let n: i64 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+    (n.to_string().as_bytes() == bytes).then_some(n)
+}
+
+/// Bytes taken by the length of a listpack entry stored after it
+pub fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..16_383 => 2,
+        16_383..2_097_151 => 3,
+        2_097_151..268_435_455 => 4,
+        _ => 5,
+    }
+}
+
+/// Append the encoding of `element` to `out`, followed by its length for iterating backwards
+fn encode(element: Element, out: &mut Vec<u8>) {
+    let start = out.len();
+    match element {
+        Element::Int(n @ 0..=127) => out.push(n as u8),
+        Element::Int(n @ -4096..=4095) => {
+            let n = n as u16 & 0x1fff;
+            out.extend([0xc0 | (n >> 8) as u8, n as u8]);
+        }
+        Element::Int(n) => {
+            if let Ok(n) = i16::try_from(n) {
+                out.push(0xf1);
+                out.extend(n.to_le_bytes());
+            } else if (-(1 << 23)..1 << 23).contains(&n) {
+                out.push(0xf2);
+                out.extend(&n.to_le_bytes()[..3]);
+            } else if let Ok(n) = i32::try_from(n) {
+                out.push(0xf3);
+                out.extend(n.to_le_bytes());
+            } else {
+                out.push(0xf4);
+                out.extend(n.to_le_bytes());
+            }
+        }
+        Element::Str(s) => {
+            match s.len() {
+                len @ 0..64 => out.push(0x80 | len as u8),
+                len @ 64..4096 => out.extend([0xe0 | (len >> 8) as u8, len as u8]),
+                len => {
+                    out.push(0xf0);
+                    out.extend(u32::try_from(len).unwrap_or(u32::MAX).to_le_bytes());
+                }
+            }
+            out.extend(s);
+        }
+    }
+    // The entry length, most significant bits first, every byte but the first flagged
+    let len = out.len() - start;
+    let size = backlen_size(len);
+    for i in 0..size {
+        let bits = (len >> (7 * (size - 1 - i))) as u8 & 0x7f;
+        out.push(if i == 0 { bits } else { bits | 0x80 });
+    }
+}
+
+/// The element at `offset` and the offset of the one after it
+fn decode(data: &[u8], offset: usize) -> anyhow::Result<(Element<'_>, usize)> {
+    let take = |from: usize, len: usize| {
+        data.get(from..from + len)
+            .context("Truncated listpack entry")
+    };
+    let encoding = *data.get(offset).context("Truncated listpack")?;
+    let (element, len) = match encoding {
+        0x00..=0x7f => (Element::Int(i64::from(encoding)), 1),
+        0x80..=0xbf => {
+            let len = usize::from(encoding & 0x3f);
+            (Element::Str(take(offset + 1, len)?), 1 + len)
+        }
+        0xc0..=0xdf => {
+            let n = i64::from(encoding & 0x1f) << 8 | i64::from(take(offset + 1, 1)?[0]);
+            // 13 bit two's complement
+            let n = if n >= 1 << 12 { n - (1 << 13) } else { n };
+            (Element::Int(n), 2)
+        }
+        0xe0..=0xef => {
+            let len = usize::from(encoding & 0x0f) << 8 | usize::from(take(offset + 1, 1)?[0]);
+            (Element::Str(take(offset + 2, len)?), 2 + len)
+        }
+        0xf0 => {
+            let len = u32::from_le_bytes(take(offset + 1, 4)?.try_into()?);
+            let len = usize::try_from(len)?;
+            (Element::Str(take(offset + 5, len)?), 5 + len)
+        }
+        0xf1 => {
+            let n = i16::from_le_bytes(take(offset + 1, 2)?.try_into()?);
+            (Element::Int(i64::from(n)), 3)
+        }
+        0xf2 => {
+            let [a, b, c] = take(offset + 1, 3)?.try_into()?;
+            (
+                Element::Int(i64::from(i32::from_le_bytes([0, a, b, c]) >> 8)),
+                4,
+            )
+        }
+        0xf3 => {
+            let n = i32::from_le_bytes(take(offset + 1, 4)?.try_into()?);
+            (Element::Int(i64::from(n)), 5)
+        }
+        0xf4 => (
+            Element::Int(i64::from_le_bytes(take(offset + 1, 8)?.try_into()?)),
+            9,
+        ),
+        _ => bail!("Invalid listpack encoding {encoding:#x}"),
+    };
+    Ok((element, offset + len + backlen_size(len)))
+}
+
+/// The offset of the entry ending at `offset`, read from the length stored at its end
+fn previous(data: &[u8], offset: usize) -> Option<usize> {
+    if offset <= HEADER_SIZE {
+        return None;
+    }
+    let (mut len, mut shift, mut position) = (0, 0, offset);
+    loop {
+        position = position.checked_sub(1)?;
+        let byte = *data.get(position)?;
+        len |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    position.checked_sub(len)
+}
+
+/// A sequence of strings and integers packed in a single buffer, in the format of Redis
+/// so it is saved to RDB files as is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listpack {
+    data: Vec<u8>,
+}
+
+impl Default for Listpack {
+    fn default() -> Self {
+        let mut listpack = Self {
+            data: vec![0; HEADER_SIZE],
+        };
+        listpack.data.push(EOF);
+        listpack.set_header(0);
+        listpack
+    }
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_elements(elements: &[Element]) -> Self {
+        let mut data = vec![0; HEADER_SIZE];
+        for element in elements {
+            encode(*element, &mut data);
+        }
+        data.push(EOF);
+        let mut listpack = Self { data };
+        listpack.set_header(elements.len());
+        listpack
+    }
+
+    /// A listpack as found in an RDB file, checked to be well formed
+    pub fn from_bytes(data: Vec<u8>) -> anyhow::Result<Self> {
+        ensure!(data.len() > HEADER_SIZE, "Truncated listpack");
+        let total = u32::from_le_bytes(data[..4].try_into()?);
+        ensure!(
+            usize::try_from(total)? == data.len(),
+            "Listpack size {total} doesn't match its {} bytes",
+            data.len()
+        );
+        let mut offset = HEADER_SIZE;
+        let mut count = 0;
+        while data[offset] != EOF {
+            let (_, next) = decode(&data, offset)?;
+            ensure!(
+                previous(&data, next) == Some(offset),
+                "Invalid listpack entry length"
+            );
+            offset = next;
+            count += 1;
+        }
+        ensure!(
+            offset == data.len() - 1,
+            "Data after the end of the listpack"
+        );
+        let header_count = u16::from_le_bytes(data[4..6].try_into()?);
+        ensure!(
+            header_count == UNKNOWN_COUNT || usize::from(header_count) == count,
+            "Listpack count {header_count} doesn't match its {count} elements"
+        );
+        Ok(Self { data })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        match u16::from_le_bytes([self.data[4], self.data[5]]) {
+            UNKNOWN_COUNT => self.iter().count(),
+            count => usize::from(count),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.len() == HEADER_SIZE + 1
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            data: &self.data,
+            front: HEADER_SIZE,
+            back: self.data.len() - 1,
+        }
+    }
+
+    /// Consecutive elements two by two, as the fields and values of a map
+    pub fn pairs(&self) -> Pairs<'_> {
+        Pairs(self.iter())
+    }
+
+    pub fn push_back(&mut self, element: Element) {
+        let end = self.data.len() - 1;
+        self.insert_at(end, element);
+    }
+
+    pub fn push_front(&mut self, element: Element) {
+        self.insert_at(HEADER_SIZE, element);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        self.remove_at(HEADER_SIZE)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let last = previous(&self.data, self.data.len() - 1)?;
+        self.remove_at(last)
+    }
+
+    /// Insert `element` before the one at `index`, or at the end past the last one
+    pub fn insert(&mut self, index: usize, element: Element) {
+        let offset = self.offset(index);
+        self.insert_at(offset, element);
+    }
+
+    /// Replace the element at `index`
+    pub fn replace(&mut self, index: usize, element: Element) {
+        let offset = self.offset(index);
+        if let Ok((_, next)) = decode(&self.data, offset) {
+            let mut encoded = Vec::new();
+            encode(element, &mut encoded);
+            self.data.splice(offset..next, encoded);
+            self.set_header(self.len());
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Vec<u8>> {
+        let offset = self.offset(index);
+        self.remove_at(offset)
+    }
+
+    /// The offset of the element at `index`, that of the end marker if there are fewer
+    fn offset(&self, index: usize) -> usize {
+        let mut offset = HEADER_SIZE;
+        for _ in 0..index {
+            match decode(&self.data, offset) {
+                Ok((_, next)) => offset = next,
+                Err(_) => break,
+            }
+        }
+        offset
+    }
+
+    fn insert_at(&mut self, offset: usize, element: Element) {
+        let count = self.len() + 1;
+        let mut encoded = Vec::new();
+        encode(element, &mut encoded);
+        self.data.splice(offset..offset, encoded);
+        self.set_header(count);
+    }
+
+    fn remove_at(&mut self, offset: usize) -> Option<Vec<u8>> {
+        if self.data[offset] == EOF {
+            return None;
+        }
+        let count = self.len() - 1;
+        let (element, next) = decode(&self.data, offset).ok()?;
+        let element = element.to_vec();
+        self.data.drain(offset..next);
+        self.set_header(count);
+        Some(element)
+    }
+
+    fn set_header(&mut self, count: usize) {
+        let total = u32::try_from(self.data.len()).unwrap_or(u32::MAX);
+        let count = u16::try_from(count).unwrap_or(UNKNOWN_COUNT);
+        self.data[..4].copy_from_slice(&total.to_le_bytes());
+        self.data[4..6].copy_from_slice(&count.to_le_bytes());
+    }
+}
+
+/// The elements of a listpack, from either end
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    data: &'a [u8],
+    /// Offset of the next element from the front
+    front: usize,
+    /// Offset past the next element from the back
+    back: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        let (element, next) = decode(self.data, self.front).ok()?;
+        self.front = next;
+        Some(element)
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        let start = previous(self.data, self.back)?;
+        let (element, _) = decode(self.data, start).ok()?;
+        self.back = start;
+        Some(element)
+    }
+}
+
+/// The elements of a listpack two by two
+#[derive(Debug, Clone)]
+pub struct Pairs<'a>(Iter<'a>);
+
+impl<'a> Iterator for Pairs<'a> {
+    type Item = (Element<'a>, Element<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((self.0.next()?, self.0.next()?))
+    }
+}
+
+impl DoubleEndedIterator for Pairs<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let second = self.0.next_back()?;
+        Some((self.0.next_back()?, second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let mut listpack = Listpack::new();
+        let long = vec![b'x'; 300];
+        listpack.push_back(Element::from_bytes(b"a"));
+        listpack.push_back(Element::from_bytes(b"-5000"));
+        listpack.push_front(Element::from_bytes(&long));
+        listpack.push_back(Element::from_bytes(b"007"));
+        assert_eq!(listpack.len(), 4);
+        assert_eq!(listpack.iter().nth(1), Some(Element::Str(b"a")));
+        assert_eq!(listpack.iter().nth(2), Some(Element::Int(-5000)));
+        assert_eq!(
+            listpack
+                .iter()
+                .rev()
+                .map(Element::to_vec)
+                .collect::<Vec<_>>(),
+            [
+                b"007".to_vec(),
+                b"-5000".to_vec(),
+                b"a".to_vec(),
+                long.clone()
+            ]
+        );
+        let reloaded = Listpack::from_bytes(listpack.as_bytes().to_vec()).unwrap();
+        assert_eq!(reloaded, listpack);
+
+        listpack.replace(1, Element::Int(1));
+        listpack.insert(1, Element::Str(b"b"));
+        assert_eq!(listpack.remove(3), Some(b"-5000".to_vec()));
+        assert_eq!(listpack.pop_front(), Some(long));
+        assert_eq!(listpack.pop_back(), Some(b"007".to_vec()));
+        assert_eq!(
+            listpack.pairs().collect::<Vec<_>>(),
+            [(Element::Str(b"b"), Element::Int(1))]
+        );
+        assert_eq!(listpack.pop_back(), Some(b"1".to_vec()));
+        assert_eq!(listpack.pop_back(), Some(b"b".to_vec()));
+        assert_eq!(listpack.pop_back(), None);
+        assert_eq!(listpack, Listpack::new());
+    }
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(parse_int(b"-12"), Some(-12));
+        assert_eq!(parse_int(b"9223372036854775807"), Some(i64::MAX));
+        assert_eq!(parse_int(b"012"), None);
+        assert_eq!(parse_int(b"+1"), None);
+        assert_eq!(parse_int(b"-0"), None);
+        assert_eq!(parse_int(b"1.5"), None);
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::{bail, ensure, Context};
+
+/// Bits of the hash of the next 3 bytes, indexing the last position they were seen at
+const HASH_BITS: u32 = 14;
+/// Longest literal run, and the furthest and longest back references
+const MAX_LITERAL: usize = 32;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REFERENCE: usize = 7 + 255 + 2;
+
+/// Compress `data` with LZF, or `None` if it doesn't get any smaller
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literals = Vec::with_capacity(MAX_LITERAL);
+    let flush = |literals: &mut Vec<u8>, out: &mut Vec<u8>| {
+        if !literals.is_empty() {
+            out.push(literals.len() as u8 - 1);
+            out.append(literals);
+        }
+    };
+    let mut position = 0;
+    while position < data.len() {
+        if position + 2 < data.len() {
+            let next =
+                u32::from_le_bytes([data[position], data[position + 1], data[position + 2], 0]);
+            let hash = (next.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+            let candidate = std::mem::replace(&mut table[hash], position);
+            if candidate < position
+                && position - candidate <= MAX_OFFSET
+                && data[candidate..candidate + 3] == data[position..position + 3]
+            {
+                let max_len = MAX_REFERENCE.min(data.len() - position);
+                let len = 3
+                    + (3..max_len)
+                        .take_while(|&i| data[candidate + i] == data[position + i])
+                        .count();
+                flush(&mut literals, &mut out);
+                let (run, offset) = (len - 2, position - candidate - 1);
+                if run < 7 {
+                    out.push((run << 5 | offset >> 8) as u8);
+                } else {
+                    out.push((7 << 5 | offset >> 8) as u8);
+                    out.push((run - 7) as u8);
+                }
+                out.push(offset as u8);
+                position += len;
+                if out.len() >= data.len() {
+                    return None;
+                }
+                continue;
+            }
+        }
+        literals.push(data[position]);
+        if literals.len() == MAX_LITERAL {
+            flush(&mut literals, &mut out);
+        }
+        position += 1;
+    }
+    flush(&mut literals, &mut out);
+    (out.len() < data.len()).then_some(out)
+}
+
+/// Decompress LZF `data` that expands to exactly `len` bytes
+pub fn decompress(data: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut input = data.iter().copied();
+    while let Some(ctrl) = input.next() {
+        let ctrl = usize::from(ctrl);
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes
+            for _ in 0..=ctrl {
+                out.push(input.next().context("Truncated LZF literal run")?);
+            }
+            continue;
+        }
+        // A back reference of at least 3 bytes
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += usize::from(input.next().context("Truncated LZF back reference")?);
+        }
+        let offset = ((ctrl & 0x1f) << 8)
+            + usize::from(input.next().context("Truncated LZF back reference")?)
+            + 1;
+        let Some(start) = out.len().checked_sub(offset) else {
+            bail!("LZF back reference before the start of the data");
+        };
+        // The reference may overlap the bytes it produces, so copy one at a time
+        for i in start..start + run + 2 {
+            out.push(out[i]);
+        }
+    }
+    ensure!(
+        out.len() == len,
+        "LZF data expanded to {} bytes instead of {len}",
+        out.len()
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // A literal "a", then a back reference repeating it 9 more times
+        assert_eq!(
+            decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 10).unwrap(),
+            b"aaaaaaaaaa"
+        );
+        assert_eq!(decompress(&[0x02, b'a', b'b', b'c'], 3).unwrap(), b"abc");
+        assert!(decompress(&[0x02, b'a'], 3).is_err());
+        assert!(decompress(&[0x20, 0x05], 3).is_err());
+    }
+
+    #[test]
+    fn test_compress() {
+        let data: Vec<u8> = (0..2000u32).flat_map(|i| (i % 300).to_le_bytes()).collect();
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        assert_eq!(compress(b"abc"), None);
+    }
+}
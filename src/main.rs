@@ -1,81 +1,135 @@
 use anyhow::Context;
 use clap::Parser;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::atomic::Ordering};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
     select,
-    sync::Mutex,
 };
-use tracing::{debug, error, info, instrument, warn};
+use tracing::{error, info, warn};
 
+mod aof;
 mod cli;
+mod cluster;
 mod cmd;
+mod config;
+mod connection;
+mod crc64;
+mod dict;
+mod evict;
+mod function;
+mod glob;
+mod info;
+mod intset;
+mod keyspace;
+mod listpack;
+mod lzf;
+mod pubsub;
+mod quicklist;
+mod rdb;
+mod replication;
 mod resp;
+mod script;
 mod state;
+mod stream;
+mod value;
 
-use crate::{cmd::Command, state::{AppState, State}};
-
-#[instrument(skip(stream, state))]
-async fn handle_client(
-    mut stream: TcpStream,
-    client: SocketAddr,
-    state: State,
-) -> anyhow::Result<()> {
-    // let mut stream = BufStream::new(stream);
-    let mut buf = [0; 1024];
-    loop {
-        let n = stream.read(&mut buf[..]).await?;
-        if n == 0 {
-            info!("Disconnected");
-            return Ok(());
-        }
-        let command =
-            Command::try_from(&buf[..n]).context("Failed to parse command from buffer")?;
-        debug!("Parsed command: {command:?}");
-        let response = command.handle(state.clone()).await?;
-        debug!("Response: {response:?}");
-        stream
-            .write_all(response.as_bytes().as_slice())
-            .await
-            .context("Failed to write response")?;
-    }
-}
+use crate::{
+    connection::handle_client,
+    info::{CONNECTED_CLIENTS, TOTAL_CONNECTIONS_RECEIVED},
+    state::{database_cycle, propagation_cycle, AppState, State},
+};
 
-async fn handle_ctrl_c() -> anyhow::Result<()> {
+async fn handle_ctrl_c(state: State) -> anyhow::Result<()> {
     tokio::signal::ctrl_c()
         .await
         .context("Failed to listen for Ctrl+C")?;
     info!("Received Ctrl+C, shutting down...");
+    let mut state = state.write().await;
+    if !state.rdb.save_rules.is_empty() {
+        rdb::check_bgsave(&mut state, true);
+        if let Err(e) = rdb::save(&mut state) {
+            error!("Failed to save the DB before shutting down: {e:#}");
+        }
+    }
+    if state.aof.enabled {
+        aof::check_rewrite(&mut state, true);
+        state.aof.fsync();
+    }
     std::process::exit(0);
 }
 
+/// Turn away a client over the `maxclients` limit
+async fn reject_client(mut stream: TcpStream) {
+    warn!("Rejected a client, the maxclients limit is reached");
+    let _ = stream
+        .write_all(b"-ERR max number of clients reached\r\n")
+        .await;
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = cli::Cli::parse();
-    let addr = SocketAddr::new(cli.host.into(), cli.port);
-    let state = Arc::new(Mutex::new(AppState::default()));
-    tracing_subscriber::fmt()
-        .with_env_filter("debug")
+    let mut app_state = AppState::default();
+    config::load(&mut app_state, cli.config_file(), cli.options())?;
+    let addr = SocketAddr::new(app_state.config.bind.into(), app_state.config.port);
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(app_state.config.loglevel.filter())
         .without_time()
         // .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .init();
+        .with_filter_reloading();
+    let log_filter = subscriber.reload_handle();
+    subscriber.init();
+    config::set_log_filter_reloader(move |filter| {
+        log_filter.reload(filter).map_err(anyhow::Error::msg)
+    });
+    if let Some(file) = &app_state.config.file {
+        info!("Configuration loaded from {}", file.display());
+    }
+    // The AOF holds every write, so the RDB file is only loaded without it
+    if app_state.aof.enabled {
+        aof::load(&mut app_state)?;
+    } else {
+        rdb::load(&app_state.rdb.path(), &mut app_state)?;
+    }
+    let limits = app_state.config.clients.clone();
+    let state = State::new(app_state);
     let listener = TcpListener::bind(addr)
         .await
         .context("Failed to bind to address")?;
     info!("Server listening on {}", listener.local_addr()?);
+    tokio::spawn(database_cycle(state.clone()));
+    tokio::spawn(propagation_cycle(state.clone()));
+    tokio::spawn(rdb::save_cycle(state.clone()));
+    tokio::spawn(aof::aof_cycle(state.clone()));
+    tokio::spawn(replication::ping_cycle(state.clone()));
+    replication::connect(&mut *state.write().await, &state);
     loop {
         select! {
-            _ = handle_ctrl_c() => {}
+            _ = handle_ctrl_c(state.clone()) => {}
             connection = listener.accept() => {
                 match connection {
                     Ok((stream, client)) => {
                         info!("Accepted connection from {client}");
+                        // A running script holds the state lock, accepting may not wait for it
+                        let maxclients = limits.maxclients.load(Ordering::Relaxed);
+                        if CONNECTED_CLIENTS.load(Ordering::Relaxed) >= maxclients {
+                            tokio::spawn(reject_client(stream));
+                            continue;
+                        }
+                        TOTAL_CONNECTIONS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+                        CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
                         let state = state.clone();
+                        let limits = limits.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = handle_client(stream, client, state).await {
-                                error!("Error handling client: {e}");
+                            // Run the client in a task of its own, so its slot is freed
+                            // even if handling it panics
+                            match tokio::spawn(handle_client(stream, client, state.clone(), limits)).await {
+                                Ok(Ok(())) => {}
+                                Ok(Err(e)) => error!("Error handling client: {e}"),
+                                Err(e) => error!("Client task failed: {e}"),
                             }
+                            CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
                         });
                     }
                     Err(e) => {
@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{glob::glob_match, resp::RespData};
+
+/// Subscribed clients by client ID, with the queue to push their messages to
+pub type Subscribers = HashMap<u64, UnboundedSender<RespData>>;
+
+#[derive(Debug, Default)]
+pub struct PubSub {
+    pub channels: HashMap<String, Subscribers>,
+    pub patterns: HashMap<String, Subscribers>,
+    /// Sharded channels, whose messages are not matched against patterns
+    pub shard_channels: HashMap<String, Subscribers>,
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, channel: &str, client_id: u64, sender: UnboundedSender<RespData>) {
+        self.channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client_id, sender);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str, client_id: u64) {
+        remove_subscriber(&mut self.channels, channel, client_id);
+    }
+
+    pub fn psubscribe(&mut self, pattern: &str, client_id: u64, sender: UnboundedSender<RespData>) {
+        self.patterns
+            .entry(pattern.to_string())
+            .or_default()
+            .insert(client_id, sender);
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str, client_id: u64) {
+        remove_subscriber(&mut self.patterns, pattern, client_id);
+    }
+
+    pub fn ssubscribe(&mut self, channel: &str, client_id: u64, sender: UnboundedSender<RespData>) {
+        self.shard_channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client_id, sender);
+    }
+
+    pub fn sunsubscribe(&mut self, channel: &str, client_id: u64) {
+        remove_subscriber(&mut self.shard_channels, channel, client_id);
+    }
+
+    /// Deliver `message` to the subscribers of `channel` and of every matching pattern,
+    /// returning the number of clients that received it
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            let frame = push_frame(&[
+                RespData::bulk_string("message"),
+                RespData::bulk_string(channel),
+                RespData::BulkString(Some(message.to_vec())),
+            ]);
+            receivers += deliver(subscribers, &frame);
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                let frame = push_frame(&[
+                    RespData::bulk_string("pmessage"),
+                    RespData::bulk_string(pattern),
+                    RespData::bulk_string(channel),
+                    RespData::BulkString(Some(message.to_vec())),
+                ]);
+                receivers += deliver(subscribers, &frame);
+            }
+        }
+        receivers
+    }
+
+    /// Deliver `message` to the subscribers of the shard channel `channel`,
+    /// returning the number of clients that received it
+    pub fn spublish(&self, channel: &str, message: &[u8]) -> usize {
+        self.shard_channels.get(channel).map_or(0, |subscribers| {
+            let frame = push_frame(&[
+                RespData::bulk_string("smessage"),
+                RespData::bulk_string(channel),
+                RespData::BulkString(Some(message.to_vec())),
+            ]);
+            deliver(subscribers, &frame)
+        })
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a glob pattern
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<&String> {
+        filter_channels(&self.channels, pattern)
+    }
+
+    /// Shard channels with at least one subscriber, optionally filtered by a glob pattern
+    pub fn active_shard_channels(&self, pattern: Option<&str>) -> Vec<&String> {
+        filter_channels(&self.shard_channels, pattern)
+    }
+
+    pub fn subscriber_count(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    pub fn shard_subscriber_count(&self, channel: &str) -> usize {
+        self.shard_channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+fn filter_channels<'a>(
+    registry: &'a HashMap<String, Subscribers>,
+    pattern: Option<&str>,
+) -> Vec<&'a String> {
+    let mut channels: Vec<&String> = registry
+        .keys()
+        .filter(|channel| {
+            pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+        })
+        .collect();
+    channels.sort();
+    channels
+}
+
+fn remove_subscriber(registry: &mut HashMap<String, Subscribers>, name: &str, client_id: u64) {
+    if let Some(subscribers) = registry.get_mut(name) {
+        subscribers.remove(&client_id);
+        if subscribers.is_empty() {
+            registry.remove(name);
+        }
+    }
+}
+
+/// Queue `frame` to every subscriber, returning how many are still connected
+fn deliver(subscribers: &Subscribers, frame: &RespData) -> usize {
+    subscribers
+        .values()
+        .filter(|sender| sender.send(frame.clone()).is_ok())
+        .count()
+}
+
+/// A Pub/Sub push frame, sent as a plain array to RESP2 clients
+pub fn push_frame(elements: &[RespData]) -> RespData {
+    RespData::Push(elements.iter().cloned().collect::<VecDeque<_>>())
+}
@@ -0,0 +1,294 @@
+use std::{borrow::Cow, collections::VecDeque};
+
+use crate::{
+    listpack::{Element, Listpack},
+    lzf,
+    value::EncodingLimits,
+};
+
+/// Nodes smaller than this are not worth compressing
+const MIN_COMPRESS_BYTES: usize = 48;
+/// Bytes compression must save for a node to be kept compressed
+const MIN_COMPRESS_IMPROVE: usize = 8;
+/// Bookkeeping cost of each node
+const NODE_OVERHEAD: usize = 32;
+
+/// The elements of a node, compressed with LZF while it is away from both ends of the list
+#[derive(Debug, Clone)]
+pub enum NodeData {
+    Packed(Listpack),
+    Compressed {
+        data: Vec<u8>,
+        /// Bytes of the listpack before compression
+        len: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// Elements in the node, so ranges skip whole nodes without looking into them
+    len: usize,
+    data: NodeData,
+}
+
+impl Node {
+    fn new(listpack: Listpack) -> Self {
+        Self {
+            len: listpack.len(),
+            data: NodeData::Packed(listpack),
+        }
+    }
+
+    /// The elements of the node, decompressed if needed
+    fn listpack(&self) -> Cow<'_, Listpack> {
+        match &self.data {
+            NodeData::Packed(listpack) => Cow::Borrowed(listpack),
+            NodeData::Compressed { data, len } => Cow::Owned(
+                lzf::decompress(data, *len)
+                    .and_then(Listpack::from_bytes)
+                    .expect("node was compressed from a listpack"),
+            ),
+        }
+    }
+
+    /// The elements of the node to modify, decompressing it for good
+    fn packed(&mut self) -> &mut Listpack {
+        self.decompress();
+        match &mut self.data {
+            NodeData::Packed(listpack) => listpack,
+            NodeData::Compressed { .. } => unreachable!("decompressed above"),
+        }
+    }
+
+    fn compress(&mut self) {
+        if let NodeData::Packed(listpack) = &self.data {
+            let bytes = listpack.as_bytes();
+            if bytes.len() < MIN_COMPRESS_BYTES {
+                return;
+            }
+            match lzf::compress(bytes) {
+                Some(data) if data.len() + MIN_COMPRESS_IMPROVE < bytes.len() => {
+                    self.data = NodeData::Compressed {
+                        data,
+                        len: bytes.len(),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn decompress(&mut self) {
+        if let NodeData::Compressed { .. } = self.data {
+            self.data = NodeData::Packed(self.listpack().into_owned());
+        }
+    }
+
+    /// Bytes of the node as it is kept
+    fn size(&self) -> usize {
+        match &self.data {
+            NodeData::Packed(listpack) => listpack.as_bytes().len(),
+            NodeData::Compressed { data, .. } => data.len(),
+        }
+    }
+}
+
+/// A long list, kept as a sequence of listpacks each limited by `list-max-listpack-size`.
+/// Nodes further than `list-compress-depth` from both ends are compressed, as lists
+/// are mostly used from their ends.
+#[derive(Debug, Clone, Default)]
+pub struct Quicklist {
+    nodes: VecDeque<Node>,
+    len: usize,
+}
+
+impl Quicklist {
+    pub fn from_listpack(listpack: Listpack) -> Self {
+        let len = listpack.len();
+        Self {
+            nodes: VecDeque::from([Node::new(listpack)]),
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The listpack of a list down to a single node
+    pub fn single_node(&self) -> Option<Cow<'_, Listpack>> {
+        match self.nodes.len() {
+            1 => Some(self.nodes[0].listpack()),
+            _ => None,
+        }
+    }
+
+    pub fn nodes(&self) -> impl ExactSizeIterator<Item = &NodeData> {
+        self.nodes.iter().map(|node| &node.data)
+    }
+
+    pub fn push_back(&mut self, element: &[u8], limits: &EncodingLimits) {
+        if !self
+            .nodes
+            .back()
+            .is_some_and(|node| fits(node, element, limits))
+        {
+            self.nodes.push_back(Node::new(Listpack::new()));
+            self.recompress(limits);
+        }
+        let node = self
+            .nodes
+            .back_mut()
+            .expect("a node was added if there were none");
+        node.packed().push_back(Element::from_bytes(element));
+        node.len += 1;
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, element: &[u8], limits: &EncodingLimits) {
+        if !self
+            .nodes
+            .front()
+            .is_some_and(|node| fits(node, element, limits))
+        {
+            self.nodes.push_front(Node::new(Listpack::new()));
+            self.recompress(limits);
+        }
+        let node = self
+            .nodes
+            .front_mut()
+            .expect("a node was added if there were none");
+        node.packed().push_front(Element::from_bytes(element));
+        node.len += 1;
+        self.len += 1;
+    }
+
+    pub fn pop_back(&mut self, limits: &EncodingLimits) -> Option<Vec<u8>> {
+        let node = self.nodes.back_mut()?;
+        let element = node.packed().pop_back()?;
+        node.len -= 1;
+        if node.len == 0 {
+            self.nodes.pop_back();
+            self.recompress(limits);
+        }
+        self.len -= 1;
+        Some(element)
+    }
+
+    pub fn pop_front(&mut self, limits: &EncodingLimits) -> Option<Vec<u8>> {
+        let node = self.nodes.front_mut()?;
+        let element = node.packed().pop_front()?;
+        node.len -= 1;
+        if node.len == 0 {
+            self.nodes.pop_front();
+            self.recompress(limits);
+        }
+        self.len -= 1;
+        Some(element)
+    }
+
+    /// Up to `count` elements from `start` on, skipping the nodes before it entirely
+    pub fn range(&self, mut start: usize, count: usize) -> Vec<Vec<u8>> {
+        let mut elements = Vec::with_capacity(count.min(self.len));
+        for node in &self.nodes {
+            if elements.len() == count {
+                break;
+            }
+            if start >= node.len {
+                start -= node.len;
+                continue;
+            }
+            let listpack = node.listpack();
+            elements.extend(
+                listpack
+                    .iter()
+                    .skip(start)
+                    .take(count - elements.len())
+                    .map(Element::to_vec),
+            );
+            start = 0;
+        }
+        elements
+    }
+
+    /// Bytes taken by the nodes as they are kept
+    pub fn memory_usage(&self) -> usize {
+        self.nodes
+            .iter()
+            .map(|node| node.size() + NODE_OVERHEAD)
+            .sum()
+    }
+
+    /// Keep the nodes within `list-compress-depth` of either end decompressed,
+    /// and compress those that just moved further away as nodes are added or removed
+    fn recompress(&mut self, limits: &EncodingLimits) {
+        let depth = limits.list_compress_depth;
+        if depth == 0 {
+            return;
+        }
+        let len = self.nodes.len();
+        if len <= 2 * depth {
+            self.nodes.iter_mut().for_each(Node::decompress);
+            return;
+        }
+        for i in 0..depth {
+            self.nodes[i].decompress();
+            self.nodes[len - 1 - i].decompress();
+        }
+        self.nodes[depth].compress();
+        self.nodes[len - 1 - depth].compress();
+    }
+}
+
+/// Whether `element` fits in `node` within `list-max-listpack-size`
+fn fits(node: &Node, element: &[u8], limits: &EncodingLimits) -> bool {
+    let bytes = match &node.data {
+        NodeData::Packed(listpack) => listpack.as_bytes().len(),
+        NodeData::Compressed { len, .. } => *len,
+    };
+    // The element itself and at most 6 bytes of encoding and length
+    limits.list_fits(node.len + 1, bytes + element.len() + 6, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_nodes() {
+        let limits = EncodingLimits {
+            list_max_listpack_size: 10,
+            list_compress_depth: 1,
+            ..EncodingLimits::default()
+        };
+        let mut quicklist = Quicklist::default();
+        for i in 0..100 {
+            quicklist.push_back(format!("element:{i:04}").as_bytes(), &limits);
+        }
+        quicklist.push_front(b"first", &limits);
+        assert_eq!(quicklist.len(), 101);
+        let compressed = |quicklist: &Quicklist| {
+            quicklist
+                .nodes()
+                .map(|node| matches!(node, NodeData::Compressed { .. }))
+                .collect::<Vec<_>>()
+        };
+        let nodes = compressed(&quicklist);
+        assert_eq!(nodes.len(), 11);
+        assert!(!nodes[0] && !nodes[10] && nodes[1..10].iter().all(|&c| c));
+        assert_eq!(
+            quicklist.range(10, 2),
+            [b"element:0009".to_vec(), b"element:0010".to_vec()]
+        );
+
+        for _ in 0..10 {
+            quicklist.pop_back(&limits);
+        }
+        assert_eq!(quicklist.pop_back(&limits), Some(b"element:0089".to_vec()));
+        assert_eq!(quicklist.pop_front(&limits), Some(b"first".to_vec()));
+        // The node now at the end was decompressed
+        let nodes = compressed(&quicklist);
+        assert!(!nodes[nodes.len() - 1]);
+        assert_eq!(quicklist.range(88, 5), [b"element:0088".to_vec()]);
+    }
+}
@@ -0,0 +1,1274 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tokio::time::interval;
+use tracing::{debug, error, info};
+
+use crate::{
+    crc64::crc64,
+    function::Functions,
+    intset::Intset,
+    listpack::{Element, Listpack},
+    lzf,
+    quicklist::NodeData,
+    script,
+    state::{now_ms, AppState, Db, State},
+    stream::{Consumer, ConsumerGroup, Stream, StreamFields, StreamId},
+    value::{EncodingLimits, Hash, List, Set, SortedSet, Value},
+};
+
+const MAGIC: &[u8] = b"REDIS";
+/// The newest RDB format version this server can read
+const MAX_VERSION: u32 = 12;
+/// The RDB format version written by SAVE and BGSAVE, that of Redis 7.2
+const VERSION: u32 = 11;
+/// The Redis version recorded in saved files
+const REDIS_VERSION: &str = "7.2.0";
+/// Elements per listpack node when saving lists and streams
+const NODE_MAX_ENTRIES: usize = 128;
+/// Seconds to wait before retrying an automatic save that failed
+const SAVE_RETRY_DELAY: u64 = 5;
+/// The default `save` rules: after an hour if anything changed,
+/// after 5 minutes for 100 changes and after a minute for 10000 changes
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xf6;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Quicklist nodes holding a single large element rather than a listpack
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+const STREAM_ITEM_FLAG_DELETED: u64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: u64 = 2;
+
+/// Load the RDB file at `path` into `state`, which is left empty if there is no such file
+pub fn load(path: &Path, state: &mut AppState) -> anyhow::Result<()> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("No RDB file at {}, starting empty", path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let keys = parse(&data, state)
+        .with_context(|| format!("Failed to load RDB file {}", path.display()))?;
+    info!("Loaded {keys} keys from {}", path.display());
+    Ok(())
+}
+
+/// Parse a whole RDB file into `state`, returning the number of keys loaded
+pub fn parse(data: &[u8], state: &mut AppState) -> anyhow::Result<usize> {
+    let mut reader = Reader(data);
+    ensure!(reader.take(MAGIC.len())? == MAGIC, "Not an RDB file");
+    let version = std::str::from_utf8(reader.take(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .context("Invalid RDB version")?;
+    ensure!(
+        version <= MAX_VERSION,
+        "Can't handle RDB format version {version}"
+    );
+    let now = now_ms();
+    let selected = state.selected;
+    let mut db = 0;
+    let mut expires_at = None;
+    let mut loaded = 0;
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => {
+                let checked = &data[..data.len() - reader.0.len()];
+                // A zero checksum means the file was saved without one
+                if version >= 5 {
+                    let checksum = reader.u64_le()?;
+                    ensure!(
+                        checksum == 0 || checksum == crc64(0, checked),
+                        "Wrong RDB checksum"
+                    );
+                }
+                break;
+            }
+            OPCODE_SELECTDB => {
+                db = usize::try_from(reader.length()?)?;
+                ensure!(
+                    db < state.config.databases,
+                    "The RDB file has keys in database {db}, only {} databases are configured",
+                    state.config.databases
+                );
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_EXPIRETIME_MS => expires_at = Some(reader.u64_le()?),
+            OPCODE_EXPIRETIME => expires_at = Some(u64::from(reader.u32_le()?) * 1000),
+            OPCODE_AUX => {
+                let field = reader.string()?;
+                let value = reader.string()?;
+                debug!(
+                    "RDB {}: {}",
+                    String::from_utf8_lossy(&field),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OPCODE_FUNCTION2 => {
+                let code = String::from_utf8(reader.string()?)
+                    .context("Function library code is not valid UTF-8")?;
+                let library = script::load_library(&code)
+                    .map_err(|e| anyhow!("Failed to load function library: {e}"))?;
+                state
+                    .functions
+                    .add(library, false)
+                    .map_err(|e| anyhow!("Failed to load function library: {e}"))?;
+            }
+            opcode @ (OPCODE_FUNCTION_PRE_GA | OPCODE_MODULE_AUX) => {
+                bail!("Unsupported RDB opcode {opcode:#x}")
+            }
+            value_type => {
+                let key = String::from_utf8_lossy(&reader.string()?).into_owned();
+                let value = read_value(&mut reader, value_type, &state.encoding)
+                    .with_context(|| format!("Failed to load key {key}"))?;
+                let expires_at = expires_at.take();
+                if expires_at.is_none_or(|at| at > now) {
+                    state.select(db);
+                    state.db.kv.insert(key.clone(), value);
+                    if let Some(at) = expires_at {
+                        state.set_expiry(&key, at);
+                    }
+                    state.account(&key);
+                    loaded += 1;
+                }
+            }
+        }
+    }
+    state.select(selected);
+    Ok(loaded)
+}
+
+fn read_value(
+    reader: &mut Reader,
+    value_type: u8,
+    limits: &EncodingLimits,
+) -> anyhow::Result<Value> {
+    let value = match value_type {
+        TYPE_STRING => Value::String(reader.string()?),
+        TYPE_LIST => list(
+            (0..reader.count()?)
+                .map(|_| reader.string())
+                .collect::<Result<_, _>>()?,
+            limits,
+        ),
+        TYPE_SET => set(
+            (0..reader.count()?)
+                .map(|_| reader.string())
+                .collect::<Result<Vec<_>, _>>()?,
+            limits,
+        ),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut zset = SortedSet::default();
+            for _ in 0..reader.count()? {
+                let member = reader.string()?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(reader.array()?)
+                } else {
+                    reader.double_string()?
+                };
+                zset.insert(member, score, limits);
+            }
+            Value::SortedSet(zset)
+        }
+        TYPE_HASH => {
+            let mut fields = Vec::new();
+            for _ in 0..reader.count()? {
+                fields.push((reader.string()?, reader.string()?));
+            }
+            hash(fields, limits)
+        }
+        TYPE_HASH_ZIPMAP => hash(zipmap(&reader.string()?)?, limits),
+        TYPE_LIST_ZIPLIST => list(ziplist(&reader.string()?)?, limits),
+        TYPE_SET_INTSET => set(intset(reader.string()?)?, limits),
+        TYPE_SET_LISTPACK => set(listpack(reader.string()?)?, limits),
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let blob = reader.string()?;
+            let elements = if value_type == TYPE_ZSET_ZIPLIST {
+                ziplist(&blob)?
+            } else {
+                listpack(blob)?
+            };
+            let mut zset = SortedSet::default();
+            for (member, score) in pairs(elements)? {
+                let score = std::str::from_utf8(&score)
+                    .ok()
+                    .and_then(|score| score.parse().ok())
+                    .context("Invalid sorted set score")?;
+                zset.insert(member, score, limits);
+            }
+            Value::SortedSet(zset)
+        }
+        TYPE_HASH_ZIPLIST => hash(pairs(ziplist(&reader.string()?)?)?, limits),
+        TYPE_HASH_LISTPACK => hash(pairs(listpack(reader.string()?)?)?, limits),
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let mut elements = Vec::new();
+            for _ in 0..reader.count()? {
+                if value_type == TYPE_LIST_QUICKLIST {
+                    elements.extend(ziplist(&reader.string()?)?);
+                } else if reader.length()? == QUICKLIST_NODE_PLAIN {
+                    elements.push(reader.string()?);
+                } else {
+                    elements.extend(listpack(reader.string()?)?);
+                }
+            }
+            list(elements, limits)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(read_stream(reader, value_type)?)
+        }
+        _ => bail!("Unsupported RDB value type {value_type}"),
+    };
+    Ok(value)
+}
+
+/// Collections are rebuilt element by element, so they get the encoding their size calls for
+/// whatever encoding they were saved in
+fn list(elements: Vec<Vec<u8>>, limits: &EncodingLimits) -> Value {
+    let mut list = List::default();
+    for element in elements {
+        list.push_back(element, limits);
+    }
+    Value::List(list)
+}
+
+fn set(members: Vec<Vec<u8>>, limits: &EncodingLimits) -> Value {
+    let mut set = Set::default();
+    for member in members {
+        set.insert(member, limits);
+    }
+    Value::Set(set)
+}
+
+fn hash(fields: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>, limits: &EncodingLimits) -> Value {
+    let mut hash = Hash::default();
+    for (field, value) in fields {
+        hash.insert(field, value, limits);
+    }
+    Value::Hash(hash)
+}
+
+/// Group the elements of a flattened map into (field, value) pairs
+fn pairs(elements: Vec<Vec<u8>>) -> anyhow::Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>> {
+    ensure!(
+        elements.len().is_multiple_of(2),
+        "Odd number of elements in a map"
+    );
+    let mut elements = elements.into_iter();
+    Ok(std::iter::from_fn(move || {
+        Some((elements.next()?, elements.next()?))
+    }))
+}
+
+fn read_stream(reader: &mut Reader, value_type: u8) -> anyhow::Result<Stream> {
+    let mut stream = Stream::default();
+    for _ in 0..reader.count()? {
+        let master = raw_stream_id(&reader.string()?)?;
+        read_stream_node(&mut stream, master, &listpack(reader.string()?)?)?;
+    }
+    let length = reader.length()?;
+    stream.last_id = StreamId::new(reader.length()?, reader.length()?);
+    stream.entries_added = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        // The first ID and the largest deleted ID are derived from the entries
+        for _ in 0..4 {
+            reader.length()?;
+        }
+        reader.length()?
+    } else {
+        length
+    };
+    for _ in 0..reader.count()? {
+        let name = String::from_utf8_lossy(&reader.string()?).into_owned();
+        let mut group = ConsumerGroup {
+            last_delivered_id: StreamId::new(reader.length()?, reader.length()?),
+            ..ConsumerGroup::default()
+        };
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // Saved as -1 when unknown
+            group.entries_read = Some(reader.length()?).filter(|&read| read != u64::MAX);
+        }
+        let mut deliveries = BTreeMap::new();
+        for _ in 0..reader.count()? {
+            let id = raw_stream_id(reader.take(16)?)?;
+            let delivery_time = reader.u64_le()?;
+            deliveries.insert(id, (delivery_time, reader.length()?));
+        }
+        for _ in 0..reader.count()? {
+            let consumer = String::from_utf8_lossy(&reader.string()?).into_owned();
+            let seen_time = reader.u64_le()?;
+            let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                Some(reader.u64_le()?).filter(|&time| time != u64::MAX)
+            } else {
+                Some(seen_time)
+            };
+            group.consumers.insert(
+                consumer.clone(),
+                Consumer {
+                    pending: BTreeSet::new(),
+                    seen_time,
+                    active_time,
+                },
+            );
+            for _ in 0..reader.count()? {
+                let id = raw_stream_id(reader.take(16)?)?;
+                let (delivery_time, delivery_count) = deliveries
+                    .remove(&id)
+                    .context("Consumer owns an entry missing from the group's PEL")?;
+                group.assign(id, &consumer, delivery_time, delivery_count);
+            }
+        }
+        ensure!(
+            deliveries.is_empty(),
+            "Group PEL has entries not owned by any consumer"
+        );
+        stream.groups.insert(name, group);
+    }
+    Ok(stream)
+}
+
+/// Add the entries of a stream listpack, whose IDs and fields are relative to its master entry
+fn read_stream_node(
+    stream: &mut Stream,
+    master: StreamId,
+    elements: &[Vec<u8>],
+) -> anyhow::Result<()> {
+    let mut elements = elements.iter();
+    let mut next = || {
+        elements
+            .next()
+            .map(Vec::as_slice)
+            .context("Truncated stream listpack")
+    };
+    let count = int(next()?)?;
+    let deleted = int(next()?)?;
+    let master_fields = (0..int(next()?)?)
+        .map(|_| next().map(<[u8]>::to_vec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // The master entry terminator
+    next()?;
+    for _ in 0..count + deleted {
+        let flags = u64::try_from(int(next()?)?)?;
+        let id = StreamId::new(
+            master.ms.wrapping_add_signed(int(next()?)?),
+            master.seq.wrapping_add_signed(int(next()?)?),
+        );
+        let fields: StreamFields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS == 0 {
+            (0..int(next()?)?)
+                .map(|_| Ok((next()?.to_vec(), next()?.to_vec())))
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?.to_vec())))
+                .collect::<anyhow::Result<_>>()?
+        };
+        // The number of listpack elements in the entry, used to iterate backwards
+        next()?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// A stream ID stored as 128 big endian bits
+fn raw_stream_id(raw: &[u8]) -> anyhow::Result<StreamId> {
+    ensure!(raw.len() == 16, "Invalid stream ID length {}", raw.len());
+    let (ms, seq) = raw.split_at(8);
+    Ok(StreamId::new(
+        u64::from_be_bytes(ms.try_into()?),
+        u64::from_be_bytes(seq.try_into()?),
+    ))
+}
+
+/// An integer element of a listpack or ziplist
+fn int(element: &[u8]) -> anyhow::Result<i64> {
+    std::str::from_utf8(element)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .context("Expected an integer element")
+}
+
+/// The elements of a ziplist, integers converted to their decimal representation
+fn ziplist(data: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut reader = Reader(data);
+    // Total bytes, tail offset and element count
+    reader.take(10)?;
+    let mut elements = Vec::new();
+    loop {
+        // The previous entry length, 5 bytes if the first is 0xfe, or the end marker
+        match reader.byte()? {
+            0xff => break,
+            0xfe => {
+                reader.take(4)?;
+            }
+            _ => {}
+        }
+        let encoding = reader.byte()?;
+        let element = match encoding >> 6 {
+            0b00 => reader.take(usize::from(encoding & 0x3f))?.to_vec(),
+            0b01 => {
+                let len = usize::from(encoding & 0x3f) << 8 | usize::from(reader.byte()?);
+                reader.take(len)?.to_vec()
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(reader.array()?);
+                reader.take(usize::try_from(len)?)?.to_vec()
+            }
+            _ => {
+                let n = match encoding {
+                    0xc0 => i64::from(i16::from_le_bytes(reader.array()?)),
+                    0xd0 => i64::from(i32::from_le_bytes(reader.array()?)),
+                    0xe0 => i64::from_le_bytes(reader.array()?),
+                    0xf0 => {
+                        let [a, b, c] = reader.array()?;
+                        i64::from(i32::from_le_bytes([0, a, b, c]) >> 8)
+                    }
+                    0xfe => i64::from(i8::from_le_bytes(reader.array()?)),
+                    0xf1..=0xfd => i64::from(encoding & 0x0f) - 1,
+                    _ => bail!("Invalid ziplist encoding {encoding:#x}"),
+                };
+                n.to_string().into_bytes()
+            }
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+/// The elements of a listpack, integers converted to their decimal representation
+fn listpack(data: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+    Ok(Listpack::from_bytes(data)?
+        .iter()
+        .map(Element::to_vec)
+        .collect())
+}
+
+/// The members of an intset, as decimal strings
+fn intset(data: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+    Ok(Intset::from_bytes(data)?
+        .iter()
+        .map(|n| n.to_string().into_bytes())
+        .collect())
+}
+
+/// The fields of a zipmap, the hash encoding used before Redis 2.6
+fn zipmap(data: &[u8]) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
+    fn zipmap_len(reader: &mut Reader) -> anyhow::Result<Option<usize>> {
+        match reader.byte()? {
+            0xff => Ok(None),
+            0xfe => Ok(Some(usize::try_from(reader.u32_le()?)?)),
+            len => Ok(Some(usize::from(len))),
+        }
+    }
+
+    let mut reader = Reader(data);
+    // The element count, unreliable above 253
+    reader.byte()?;
+    let mut hash = HashMap::new();
+    while let Some(len) = zipmap_len(&mut reader)? {
+        let field = reader.take(len)?.to_vec();
+        let len = zipmap_len(&mut reader)?.context("Truncated zipmap")?;
+        let free = reader.byte()?;
+        hash.insert(field, reader.take(len)?.to_vec());
+        reader.take(usize::from(free))?;
+    }
+    Ok(hash)
+}
+
+/// Save the dataset when at least `changes` changes happened in `seconds`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parse `save` rules from pairs of `<seconds> <changes>`, an empty string meaning no rules
+pub fn parse_save_rules(rules: &str) -> anyhow::Result<Vec<SaveRule>> {
+    let numbers = rules
+        .split_whitespace()
+        .map(|n| {
+            n.parse::<u64>()
+                .with_context(|| format!("Invalid save parameter {n}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    ensure!(
+        numbers.len().is_multiple_of(2),
+        "Invalid save parameters, expected pairs of seconds and changes"
+    );
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SaveRule {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+/// A snapshot being written by a background thread
+#[derive(Debug)]
+struct BackgroundSave {
+    thread: JoinHandle<anyhow::Result<()>>,
+    /// The dirty counter when the snapshot was taken
+    dirty: u64,
+}
+
+/// Where snapshots are saved, and when
+#[derive(Debug)]
+pub struct RdbState {
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub save_rules: Vec<SaveRule>,
+    /// Time of the last successful save, in seconds since the UNIX epoch
+    pub last_save: u64,
+    pub last_bgsave_ok: bool,
+    /// Time of the last background save attempt, in seconds since the UNIX epoch
+    last_bgsave_try: u64,
+    bgsave: Option<BackgroundSave>,
+}
+
+impl Default for RdbState {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            save_rules: parse_save_rules(DEFAULT_SAVE_RULES).unwrap_or_default(),
+            last_save: now_secs(),
+            last_bgsave_ok: true,
+            last_bgsave_try: 0,
+            bgsave: None,
+        }
+    }
+}
+
+impl RdbState {
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave.is_some()
+    }
+}
+
+/// Seconds since the UNIX epoch
+pub fn now_secs() -> u64 {
+    now_ms() / 1000
+}
+
+/// Save the dataset while holding the state, as SAVE does
+pub fn save(state: &mut AppState) -> anyhow::Result<()> {
+    ensure!(
+        !state.rdb.bgsave_in_progress(),
+        "Background save already in progress"
+    );
+    let data = dump(state.databases(), &state.functions)?;
+    write_file(&state.rdb.path(), &data)?;
+    *state.dirty.get_mut() = 0;
+    state.rdb.last_save = now_secs();
+    info!("DB saved on disk");
+    Ok(())
+}
+
+/// Save a copy of the dataset from a background thread, as BGSAVE does.
+/// The outcome is recorded by [`check_bgsave`] once the thread is done.
+pub fn bgsave(state: &mut AppState) -> anyhow::Result<()> {
+    ensure!(
+        !state.rdb.bgsave_in_progress(),
+        "Background save already in progress"
+    );
+    let dbs = clone_databases(state);
+    let functions = state.functions.clone();
+    let path = state.rdb.path();
+    let thread = thread::Builder::new()
+        .name("bgsave".to_string())
+        .spawn(move || {
+            let dbs = dbs.iter().map(|(index, db)| (*index, db));
+            write_file(&path, &dump(dbs, &functions)?)
+        })?;
+    state.rdb.bgsave = Some(BackgroundSave {
+        thread,
+        dirty: state.dirty.load(Ordering::Relaxed),
+    });
+    state.rdb.last_bgsave_try = now_secs();
+    info!("Background saving started");
+    Ok(())
+}
+
+/// Record the outcome of the background save once it is done,
+/// or wait for it to be done if `wait` is set
+pub fn check_bgsave(state: &mut AppState, wait: bool) {
+    if !state
+        .rdb
+        .bgsave
+        .as_ref()
+        .is_some_and(|bgsave| wait || bgsave.thread.is_finished())
+    {
+        return;
+    }
+    let bgsave = state.rdb.bgsave.take().expect("checked above");
+    let result = bgsave
+        .thread
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("Background save thread panicked")));
+    state.rdb.last_bgsave_ok = result.is_ok();
+    match result {
+        Ok(()) => {
+            // Changes made while saving are still unsaved
+            let dirty = state.dirty.get_mut();
+            *dirty = dirty.saturating_sub(bgsave.dirty);
+            state.rdb.last_save = now_secs();
+            info!("Background saving terminated with success");
+        }
+        Err(e) => error!("Background saving error: {e:#}"),
+    }
+}
+
+/// The save rule due at `now`, if any
+fn due_save_rule(state: &AppState, now: u64) -> Option<SaveRule> {
+    let rdb = &state.rdb;
+    // After a failure, wait a little before trying again
+    if !rdb.last_bgsave_ok && now.saturating_sub(rdb.last_bgsave_try) < SAVE_RETRY_DELAY {
+        return None;
+    }
+    rdb.save_rules.iter().copied().find(|rule| {
+        state.dirty.load(Ordering::Relaxed) >= rule.changes
+            && now.saturating_sub(rdb.last_save) > rule.seconds
+    })
+}
+
+/// Record finished background saves and start new ones according to the save rules
+pub async fn save_cycle(state: State) {
+    let mut interval = interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        let mut state = state.write().await;
+        check_bgsave(&mut state, false);
+        if state.rdb.bgsave_in_progress() {
+            continue;
+        }
+        if let Some(rule) = due_save_rule(&state, now_secs()) {
+            info!(
+                "{} changes in {} seconds. Saving...",
+                rule.changes, rule.seconds
+            );
+            if let Err(e) = bgsave(&mut state) {
+                error!("Failed to start a background save: {e:#}");
+            }
+        }
+    }
+}
+
+/// Write `data` to `path` through a temporary file, so the file is never left half written
+pub fn write_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!("temp-{}-{name}", std::process::id()));
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Failed to write {}", temp.display()));
+    }
+    fs::rename(&temp, path)
+        .with_context(|| format!("Failed to rename {} to {}", temp.display(), path.display()))
+}
+
+/// A copy of every database holding keys, for a background thread to save
+pub fn clone_databases(state: &AppState) -> Vec<(usize, Db)> {
+    state
+        .databases()
+        .map(|(index, db)| (index, db.clone()))
+        .collect()
+}
+
+/// Serialize the databases and the function libraries to the RDB format
+pub fn dump<'a>(
+    dbs: impl IntoIterator<Item = (usize, &'a Db)>,
+    functions: &Functions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut writer = Writer(MAGIC.to_vec());
+    writer.0.extend(format!("{VERSION:04}").as_bytes());
+    let ctime = now_secs().to_string();
+    for (field, value) in [
+        ("redis-ver", REDIS_VERSION),
+        ("redis-bits", "64"),
+        ("ctime", &ctime),
+        ("aof-base", "0"),
+    ] {
+        writer.byte(OPCODE_AUX);
+        writer.string(field.as_bytes());
+        writer.string(value.as_bytes());
+    }
+    for library in functions.libraries.values() {
+        writer.byte(OPCODE_FUNCTION2);
+        writer.string(library.code.as_bytes());
+    }
+    for (index, db) in dbs {
+        writer.byte(OPCODE_SELECTDB);
+        writer.count(index);
+        writer.byte(OPCODE_RESIZEDB);
+        writer.count(db.kv.len());
+        writer.count(db.kv.volatile_len());
+        for shard in db.kv.shards() {
+            for (key, value) in shard.kv.iter() {
+                if let Some(at) = shard.expires.get(key) {
+                    writer.byte(OPCODE_EXPIRETIME_MS);
+                    writer.0.extend(at.to_le_bytes());
+                }
+                write_value(&mut writer, key, value)?;
+            }
+        }
+    }
+    writer.byte(OPCODE_EOF);
+    let checksum = crc64(0, &writer.0);
+    writer.0.extend(checksum.to_le_bytes());
+    Ok(writer.0)
+}
+
+fn write_value(writer: &mut Writer, key: &str, value: &Value) -> anyhow::Result<()> {
+    let value_type = match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST_QUICKLIST_2,
+        Value::Hash(Hash::Listpack(_)) => TYPE_HASH_LISTPACK,
+        Value::Hash(Hash::Hashtable(_)) => TYPE_HASH,
+        Value::Set(Set::Intset(_)) => TYPE_SET_INTSET,
+        Value::Set(Set::Listpack(_)) => TYPE_SET_LISTPACK,
+        Value::Set(Set::Hashtable(_)) => TYPE_SET,
+        Value::SortedSet(SortedSet::Listpack(_)) => TYPE_ZSET_LISTPACK,
+        Value::SortedSet(SortedSet::Skiplist(_)) => TYPE_ZSET_2,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    };
+    writer.byte(value_type);
+    writer.string(key.as_bytes());
+    match value {
+        Value::String(value) => writer.string(value),
+        // Compact encodings are saved as they are kept in memory
+        Value::Hash(Hash::Listpack(listpack))
+        | Value::Set(Set::Listpack(listpack))
+        | Value::SortedSet(SortedSet::Listpack(listpack)) => writer.string(listpack.as_bytes()),
+        Value::Set(Set::Intset(intset)) => writer.string(intset.as_bytes()),
+        Value::List(List::Listpack(listpack)) => {
+            writer.count(1);
+            writer.length(QUICKLIST_NODE_PACKED);
+            writer.string(listpack.as_bytes());
+        }
+        Value::List(List::Quicklist(quicklist)) => {
+            writer.count(quicklist.nodes().len());
+            for node in quicklist.nodes() {
+                writer.length(QUICKLIST_NODE_PACKED);
+                match node {
+                    NodeData::Packed(listpack) => writer.string(listpack.as_bytes()),
+                    // Compressed nodes are saved as they are, as LZF strings
+                    NodeData::Compressed { data, len } => writer.lzf_string(data, *len),
+                }
+            }
+        }
+        Value::Hash(Hash::Hashtable(hash)) => {
+            writer.count(hash.len());
+            for (field, value) in hash.iter() {
+                writer.string(field);
+                writer.string(value);
+            }
+        }
+        Value::Set(Set::Hashtable(set)) => {
+            writer.count(set.len());
+            for member in set.keys() {
+                writer.string(member);
+            }
+        }
+        Value::SortedSet(SortedSet::Skiplist(zset)) => {
+            writer.count(zset.len());
+            // Highest scores first, as Redis does
+            for (member, score) in zset.iter().rev() {
+                writer.string(member);
+                writer.0.extend(score.to_le_bytes());
+            }
+        }
+        Value::Stream(stream) => write_stream(writer, stream),
+    }
+    Ok(())
+}
+
+fn write_stream(writer: &mut Writer, stream: &Stream) {
+    let entries: Vec<_> = stream.entries.iter().collect();
+    let nodes = entries.chunks(NODE_MAX_ENTRIES);
+    writer.count(nodes.len());
+    for node in nodes {
+        let master = *node[0].0;
+        writer.string(&raw_stream_id_bytes(master));
+        writer.string(Listpack::from_elements(&stream_node_elements(master, node)).as_bytes());
+    }
+    writer.count(entries.len());
+    writer.stream_id(stream.last_id);
+    writer.stream_id(stream.entries.keys().next().copied().unwrap_or_default());
+    // The largest deleted ID is not tracked
+    writer.stream_id(StreamId::MIN);
+    writer.length(stream.entries_added);
+    writer.count(stream.groups.len());
+    for (name, group) in &stream.groups {
+        writer.string(name.as_bytes());
+        writer.stream_id(group.last_delivered_id);
+        // Unknown as -1
+        writer.length(group.entries_read.unwrap_or(u64::MAX));
+        writer.count(group.pending.len());
+        for (id, entry) in &group.pending {
+            writer.0.extend(raw_stream_id_bytes(*id));
+            writer.0.extend(entry.delivery_time.to_le_bytes());
+            writer.length(entry.delivery_count);
+        }
+        writer.count(group.consumers.len());
+        for (name, consumer) in &group.consumers {
+            writer.string(name.as_bytes());
+            writer.0.extend(consumer.seen_time.to_le_bytes());
+            writer
+                .0
+                .extend(consumer.active_time.unwrap_or(u64::MAX).to_le_bytes());
+            writer.count(consumer.pending.len());
+            for id in &consumer.pending {
+                writer.0.extend(raw_stream_id_bytes(*id));
+            }
+        }
+    }
+}
+
+/// The listpack elements of stream entries, relative to the first one as the master entry
+fn stream_node_elements<'a>(
+    master: StreamId,
+    node: &[(&StreamId, &'a StreamFields)],
+) -> Vec<Element<'a>> {
+    let master_fields = node[0].1;
+    let mut elements = vec![
+        Element::count(node.len()),
+        // Deleted entries
+        Element::Int(0),
+        Element::count(master_fields.len()),
+    ];
+    elements.extend(master_fields.iter().map(|(field, _)| Element::Str(field)));
+    // The master entry terminator
+    elements.push(Element::Int(0));
+    for (id, fields) in node {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields)
+                .all(|((field, _), (master_field, _))| field == master_field);
+        let flags = if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        };
+        elements.push(Element::Int(i64::try_from(flags).unwrap_or_default()));
+        elements.push(Element::Int(id.ms.wrapping_sub(master.ms) as i64));
+        elements.push(Element::Int(id.seq.wrapping_sub(master.seq) as i64));
+        let lp_count = if same_fields {
+            elements.extend(fields.iter().map(|(_, value)| Element::Str(value)));
+            3 + fields.len()
+        } else {
+            elements.push(Element::count(fields.len()));
+            for (field, value) in fields.iter() {
+                elements.push(Element::Str(field));
+                elements.push(Element::Str(value));
+            }
+            4 + 2 * fields.len()
+        };
+        elements.push(Element::count(lp_count));
+    }
+    elements
+}
+
+fn raw_stream_id_bytes(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// Writes the RDB primitives
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn byte(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    fn length(&mut self, len: u64) {
+        match len {
+            0..64 => self.0.push(len as u8),
+            64..16_384 => self.0.extend([0x40 | (len >> 8) as u8, len as u8]),
+            _ => match u32::try_from(len) {
+                Ok(len) => {
+                    self.0.push(0x80);
+                    self.0.extend(len.to_be_bytes());
+                }
+                Err(_) => {
+                    self.0.push(0x81);
+                    self.0.extend(len.to_be_bytes());
+                }
+            },
+        }
+    }
+
+    fn count(&mut self, n: usize) {
+        self.length(n as u64);
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.count(s.len());
+        self.0.extend(s);
+    }
+
+    fn lzf_string(&mut self, compressed: &[u8], len: usize) {
+        self.byte(0xc0 | ENC_LZF);
+        self.count(compressed.len());
+        self.count(len);
+        self.0.extend(compressed);
+    }
+
+    fn stream_id(&mut self, id: StreamId) {
+        self.length(id.ms);
+        self.length(id.seq);
+    }
+}
+
+/// A length, or the special encoding of the string that follows
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+/// Reads the RDB primitives from the front of a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        ensure!(n <= self.0.len(), "Unexpected end of data");
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32_le(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64_le(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn encoded_length(&mut self) -> anyhow::Result<Length> {
+        let first = self.byte()?;
+        let length = match first >> 6 {
+            0b00 => Length::Plain(u64::from(first & 0x3f)),
+            0b01 => Length::Plain(u64::from(first & 0x3f) << 8 | u64::from(self.byte()?)),
+            0b10 => match first {
+                0x80 => Length::Plain(u64::from(u32::from_be_bytes(self.array()?))),
+                0x81 => Length::Plain(u64::from_be_bytes(self.array()?)),
+                _ => bail!("Invalid length encoding {first:#x}"),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        };
+        Ok(length)
+    }
+
+    fn length(&mut self) -> anyhow::Result<u64> {
+        match self.encoded_length()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => bail!("Expected a length, got an encoded string"),
+        }
+    }
+
+    /// A length used as an element count or a byte count
+    fn count(&mut self) -> anyhow::Result<usize> {
+        Ok(usize::try_from(self.length()?)?)
+    }
+
+    /// A string, which may be stored as an integer or compressed
+    fn string(&mut self) -> anyhow::Result<Vec<u8>> {
+        let string = match self.encoded_length()? {
+            Length::Plain(len) => self.take(usize::try_from(len)?)?.to_vec(),
+            Length::Encoded(ENC_INT8) => i8::from_le_bytes(self.array()?).to_string().into(),
+            Length::Encoded(ENC_INT16) => i16::from_le_bytes(self.array()?).to_string().into(),
+            Length::Encoded(ENC_INT32) => i32::from_le_bytes(self.array()?).to_string().into(),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.count()?;
+                let len = self.count()?;
+                lzf::decompress(self.take(compressed_len)?, len)?
+            }
+            Length::Encoded(encoding) => bail!("Invalid string encoding {encoding}"),
+        };
+        Ok(string)
+    }
+
+    /// A sorted set score in the old text format, with special lengths for NaN and infinities
+    fn double_string(&mut self) -> anyhow::Result<f64> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => std::str::from_utf8(self.take(usize::from(len))?)
+                .ok()
+                .and_then(|score| score.parse().ok())
+                .context("Invalid sorted set score"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.extend(b"\xfa\x09redis-ver\x057.2.0\xfe\x00\xfb\x05\x02");
+        // An integer encoded string expiring in the far future
+        rdb.push(OPCODE_EXPIRETIME_MS);
+        rdb.extend(u64::MAX.to_le_bytes());
+        rdb.extend(b"\x00\x01a\xc0\x7b");
+        // A string that already expired
+        rdb.push(OPCODE_EXPIRETIME_MS);
+        rdb.extend(1u64.to_le_bytes());
+        rdb.extend(b"\x00\x04gone\x01x");
+        // An intset of 1 and 2
+        rdb.extend(b"\x0b\x01s\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x02\x00");
+        // A listpack hash of f => 5
+        rdb.extend(b"\x10\x01h\x0c\x0c\x00\x00\x00\x02\x00\x81f\x02\x05\x01\xff");
+        // A compressed string of 10 "a"
+        rdb.extend(b"\x00\x01z\xc3\x05\x0a\x00a\xe0\x00\x00");
+        // A quicklist with a plain node and a listpack node of -2 and "b"
+        rdb.extend(
+            b"\x12\x01l\x02\x01\x01x\x02\x0d\x0d\x00\x00\x00\x02\x00\xdf\xfe\x02\x81b\x02\xff",
+        );
+        rdb.push(OPCODE_EOF);
+        rdb.extend([0; 8]);
+
+        let mut state = AppState::default();
+        assert_eq!(parse(&rdb, &mut state).unwrap(), 5);
+        assert!(matches!(state.db.kv.get("a").unwrap(), Value::String(value) if value == b"123"));
+        assert!(state.db.kv.expiry("a").is_some());
+        assert!(!state.db.kv.contains_key("gone"));
+        let Value::Set(set) = state.db.kv.get("s").unwrap() else {
+            panic!("expected a set");
+        };
+        assert_eq!(set.iter().collect::<Vec<_>>(), [b"1".as_slice(), b"2"]);
+        assert_eq!(state.db.kv.get("s").unwrap().encoding(), "intset");
+        let Value::Hash(hash) = state.db.kv.get("h").unwrap() else {
+            panic!("expected a hash");
+        };
+        assert_eq!(
+            hash.iter().collect::<Vec<_>>(),
+            [(b"f".as_slice().into(), b"5".as_slice().into())]
+        );
+        assert_eq!(state.db.kv.get("h").unwrap().encoding(), "listpack");
+        assert!(
+            matches!(state.db.kv.get("z").unwrap(), Value::String(value) if value == b"aaaaaaaaaa")
+        );
+        let Value::List(list) = state.db.kv.get("l").unwrap() else {
+            panic!("expected a list");
+        };
+        assert_eq!(
+            list.range(0, 10),
+            [b"x".to_vec(), b"-2".to_vec(), b"b".to_vec()]
+        );
+
+        assert!(parse(b"REDIS0011\x00\x01a", &mut state).is_err());
+        assert!(parse(b"REDIS0099\xff", &mut state).is_err());
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut state = AppState::default();
+        state
+            .db
+            .kv
+            .insert("s".to_string(), Value::String(b"value".to_vec()));
+        state.set_expiry("s", u64::MAX / 2);
+        let long = vec![b'x'; 9000];
+        // Small nodes with the interior ones compressed
+        state.encoding.list_max_listpack_size = 50;
+        state.encoding.list_compress_depth = 1;
+        let elements = (0..300)
+            .map(|i| format!("element:{i}").into_bytes())
+            .chain([long.clone()])
+            .collect();
+        let list = list(elements, &state.encoding);
+        assert!(matches!(
+            &list,
+            Value::List(List::Quicklist(quicklist))
+                if quicklist.nodes().any(|node| matches!(node, NodeData::Compressed { .. }))
+        ));
+        state.db.kv.insert("l".to_string(), list);
+        let mut zset = SortedSet::default();
+        zset.insert(b"a".to_vec(), -1.5, &state.encoding);
+        zset.insert(b"b".to_vec(), f64::INFINITY, &state.encoding);
+        state.db.kv.insert("z".to_string(), Value::SortedSet(zset));
+        let mut stream = Stream::default();
+        stream.add(StreamId::new(5, 1), vec![(b"f".to_vec(), b"1".to_vec())]);
+        stream.add(
+            StreamId::new(7, 0),
+            vec![(b"g".to_vec(), b"-70000".to_vec())],
+        );
+        stream.create_group("g".to_string(), Some(StreamId::MIN), None);
+        stream
+            .groups
+            .get_mut("g")
+            .unwrap()
+            .assign(StreamId::new(5, 1), "c", 42, 3);
+        state.db.kv.insert("x".to_string(), Value::Stream(stream));
+        state.select(3);
+        state
+            .db
+            .kv
+            .insert("s".to_string(), Value::String(b"db3".to_vec()));
+        state.select(0);
+
+        let data = dump(state.databases(), &state.functions).unwrap();
+        let mut loaded = AppState::default();
+        assert_eq!(parse(&data, &mut loaded).unwrap(), 5);
+        loaded.select(3);
+        assert!(matches!(loaded.db.kv.get("s"), Some(Value::String(value)) if value == b"db3"));
+        loaded.select(0);
+        // Files with more databases than configured are refused
+        loaded.config.databases = 2;
+        assert!(parse(&data, &mut loaded).is_err());
+        loaded.config.databases = 16;
+        assert_eq!(loaded.db.kv.expiry("s"), Some(u64::MAX / 2));
+        let Value::List(list) = loaded.db.kv.get("l").unwrap() else {
+            panic!("expected a list");
+        };
+        assert_eq!(list.len(), 301);
+        assert_eq!(list.range(299, 2), [b"element:299".to_vec(), long]);
+        assert_eq!(loaded.db.kv.get("l").unwrap().encoding(), "quicklist");
+        let Value::SortedSet(zset) = loaded.db.kv.get("z").unwrap() else {
+            panic!("expected a sorted set");
+        };
+        assert_eq!(
+            zset.iter().collect::<Vec<_>>(),
+            [
+                (b"a".as_slice().into(), -1.5),
+                (b"b".as_slice().into(), f64::INFINITY)
+            ]
+        );
+        assert_eq!(loaded.db.kv.get("z").unwrap().encoding(), "listpack");
+        let Value::Stream(stream) = loaded.db.kv.get("x").unwrap() else {
+            panic!("expected a stream");
+        };
+        assert_eq!(stream.last_id, StreamId::new(7, 0));
+        assert_eq!(stream.entries[&StreamId::new(7, 0)][0].1, b"-70000");
+        let group = &stream.groups["g"];
+        assert_eq!(group.pending[&StreamId::new(5, 1)].delivery_count, 3);
+        assert!(group.consumers["c"].pending.contains(&StreamId::new(5, 1)));
+
+        // A corrupted file fails the checksum
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 9;
+        corrupted[last - 1] ^= 1;
+        assert!(parse(&corrupted, &mut AppState::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            parse_save_rules("3600 1 300 100").unwrap(),
+            [
+                SaveRule {
+                    seconds: 3600,
+                    changes: 1
+                },
+                SaveRule {
+                    seconds: 300,
+                    changes: 100
+                }
+            ]
+        );
+        assert!(parse_save_rules("").unwrap().is_empty());
+        assert!(parse_save_rules("3600").is_err());
+        assert!(parse_save_rules("1 x").is_err());
+    }
+
+    #[test]
+    fn test_ziplist() {
+        let mut ziplist = vec![0; 10];
+        // "ab", 7, -300 as int16 and 100000 as int24
+        ziplist.extend(b"\x00\x02ab\x04\xf8\x02\xc0\xd4\xfe\x04\xf0\xa0\x86\x01\xff");
+        assert_eq!(
+            super::ziplist(&ziplist).unwrap(),
+            [
+                b"ab".to_vec(),
+                b"7".to_vec(),
+                b"-300".to_vec(),
+                b"100000".to_vec()
+            ]
+        );
+    }
+}
@@ -0,0 +1,798 @@
+use anyhow::{bail, ensure, Context};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    aof,
+    cmd::{parse_db_index, Command},
+    function::Functions,
+    rdb,
+    resp::RespData,
+    script,
+    state::{now_ms, AppState, State},
+};
+
+/// Default size of the replication backlog, in bytes
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+/// Seconds between the PINGs a master sends its replicas, so they can tell the link is alive
+const PING_PERIOD: Duration = Duration::from_secs(10);
+/// How long a replica waits for data from its master before dropping the link
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Delay between attempts to connect to the master
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often a replica acknowledges the stream it processed
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// Wait list of the clients in WAIT and WAITAOF, signalled by acknowledgements and fsyncs
+pub const ACK_WAIT_KEY: &str = "replication-ack";
+
+/// The most recent bytes of the replication stream, kept so a replica that
+/// lost its link can catch up without a full resync
+#[derive(Debug)]
+struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn feed(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        let excess = self.data.len().saturating_sub(self.size);
+        self.data.drain(..excess);
+    }
+}
+
+/// A replica connected to this server
+#[derive(Debug)]
+pub struct Replica {
+    /// Queue of replication stream bytes to send it
+    sender: UnboundedSender<Vec<u8>>,
+    /// Address the replica accepts connections on
+    pub addr: SocketAddr,
+    /// Time of the last acknowledgement, in milliseconds since the UNIX epoch
+    pub last_ack: u64,
+    /// Offset the replica acknowledged processing up to
+    pub ack_offset: u64,
+    /// Offset the replica acknowledged fsyncing to its AOF up to
+    pub aof_ack_offset: u64,
+}
+
+/// The master this server replicates
+#[derive(Debug, Clone)]
+pub struct MasterLink {
+    pub host: String,
+    pub port: u16,
+    /// Whether the initial sync is done and the stream is flowing
+    pub link_up: bool,
+}
+
+#[derive(Debug)]
+pub struct ReplicationState {
+    /// ID of the replication history this server is part of
+    pub replid: String,
+    /// Bytes of replication stream produced, or received from the master, so far
+    pub offset: u64,
+    backlog: Backlog,
+    /// Replicas by client ID
+    pub replicas: HashMap<u64, Replica>,
+    /// ID of the history this server followed before the current one, so replicas of a
+    /// promoted server can continue from it
+    pub replid2: String,
+    /// Offset up to which the history of `replid2` is shared with the current one
+    pub second_replid_offset: Option<u64>,
+    /// Set when this server is a replica
+    pub master: Option<MasterLink>,
+    /// Whether a replica rejects writes from its clients
+    pub read_only: bool,
+    /// Whether a replica serves its possibly outdated data while the link to its master is down
+    pub serve_stale_data: bool,
+    /// Task keeping the link to the master
+    link: Option<JoinHandle<()>>,
+    /// The database the stream of the master last selected
+    master_db: usize,
+}
+
+impl Default for ReplicationState {
+    fn default() -> Self {
+        Self {
+            replid: new_replid(),
+            offset: 0,
+            backlog: Backlog {
+                data: VecDeque::new(),
+                size: DEFAULT_BACKLOG_SIZE,
+            },
+            replicas: HashMap::new(),
+            replid2: "0".repeat(40),
+            second_replid_offset: None,
+            master: None,
+            read_only: true,
+            serve_stale_data: true,
+            link: None,
+            master_db: 0,
+        }
+    }
+}
+
+impl ReplicationState {
+    pub fn set_backlog_size(&mut self, size: usize) {
+        self.backlog.size = size;
+        self.backlog.feed(&[]);
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Whether this server is a replica that lost its master and should not serve stale data
+    fn is_stale(&self) -> bool {
+        !self.serve_stale_data && self.master.as_ref().is_some_and(|master| !master.link_up)
+    }
+
+    /// Switch to the history `replid`, remembering the current one up to the current offset
+    fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_replid_offset = Some(self.offset + 1);
+    }
+
+    /// Add bytes to the replication stream, sending them to every replica
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        self.backlog.feed(bytes);
+        // Replicas whose connection is gone are forgotten
+        self.replicas
+            .retain(|_, replica| replica.sender.send(bytes.to_vec()).is_ok());
+    }
+
+    /// Record a `REPLCONF ACK` from the replica with client ID `client_id`
+    pub fn ack(&mut self, client_id: u64, offset: u64, aof_offset: Option<u64>) {
+        if let Some(replica) = self.replicas.get_mut(&client_id) {
+            replica.last_ack = now_ms();
+            replica.ack_offset = replica.ack_offset.max(offset);
+            if let Some(aof_offset) = aof_offset {
+                replica.aof_ack_offset = replica.aof_ack_offset.max(aof_offset);
+            }
+        }
+    }
+
+    /// Ask every replica to acknowledge the stream right away
+    pub fn request_acks(&mut self) {
+        if !self.replicas.is_empty() {
+            self.feed(&RespData::command(["REPLCONF", "GETACK", "*"]).as_bytes());
+        }
+    }
+
+    /// Number of replicas that acknowledged processing the stream up to `offset`
+    pub fn acked(&self, offset: u64) -> usize {
+        self.replicas
+            .values()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Number of replicas that acknowledged fsyncing the stream up to `offset` to their AOF
+    pub fn aof_acked(&self, offset: u64) -> usize {
+        self.replicas
+            .values()
+            .filter(|replica| replica.aof_ack_offset >= offset)
+            .count()
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.backlog.size
+    }
+
+    /// Offset of the first byte in the backlog, and how many bytes it holds
+    pub fn backlog_range(&self) -> (u64, usize) {
+        let len = self.backlog.data.len();
+        (self.offset + 1 - len as u64, len)
+    }
+
+    /// The stream from `offset` on, if the backlog still holds all of it
+    fn backlog_from(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let shared_history = replid == self.replid2
+            && self
+                .second_replid_offset
+                .is_some_and(|second_offset| offset <= second_offset);
+        if replid != self.replid && !shared_history {
+            return None;
+        }
+        let (first, _) = self.backlog_range();
+        if offset < first || offset > self.offset + 1 {
+            return None;
+        }
+        let skip = usize::try_from(offset - first).ok()?;
+        Some(self.backlog.data.iter().skip(skip).copied().collect())
+    }
+}
+
+/// A random 40 characters replication ID
+fn new_replid() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    script::sha1_hex(&format!(
+        "{}-{nanos}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Parse `--replicaof` given as `<host> <port>`, in one argument or two.
+/// `NO ONE` means no master.
+pub fn parse_master(args: &[String]) -> anyhow::Result<Option<(String, u16)>> {
+    let joined = args.join(" ");
+    let words: Vec<&str> = joined.split_whitespace().collect();
+    match words.as_slice() {
+        [] => Ok(None),
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Ok(None),
+        [host, port] => Ok(Some((
+            (*host).to_string(),
+            port.parse().context("Invalid master port")?,
+        ))),
+        _ => bail!("replicaof requires a host and a port"),
+    }
+}
+
+/// The error a replica answers `command` with when it may not run it now,
+/// either a write while read-only or anything that needs data while stale
+pub fn check_command(state: &AppState, command: &Command) -> Option<RespData> {
+    let replication = &state.replication;
+    if replication.is_stale() && !command.allowed_when_stale() {
+        return Some(RespData::simple_error(
+            "MASTERDOWN",
+            "Link with MASTER is down and replica-serve-stale-data is set to 'no'.",
+        ));
+    }
+    // Writes of scripts are checked as they call them
+    if replication.is_replica() && replication.read_only && command.is_write() {
+        return Some(read_only_error());
+    }
+    None
+}
+
+/// The reply to writes on a read-only replica
+pub fn read_only_error() -> RespData {
+    RespData::simple_error("READONLY", "You can't write against a read only replica.")
+}
+
+/// `REPLICAOF <host> <port>` makes this server a replica of another,
+/// `REPLICAOF NO ONE` promotes it to a master with a new history
+pub fn replicaof(state: &mut AppState, shared: &State, master: Option<(String, u16)>) -> RespData {
+    let replication = &mut state.replication;
+    match master {
+        None => {
+            if let Some(master) = replication.master.take() {
+                info!(
+                    "MASTER MODE enabled, was a replica of {}:{}",
+                    master.host, master.port
+                );
+                // Replicas of this server share its history up to now and may continue from it
+                replication.shift_replid(new_replid());
+            }
+        }
+        Some((host, port)) => {
+            if replication
+                .master
+                .as_ref()
+                .is_some_and(|master| master.host == host && master.port == port)
+            {
+                return RespData::simple_string("OK Already connected to specified master");
+            }
+            info!("REPLICAOF {host}:{port} enabled");
+            replication.master = Some(MasterLink {
+                host,
+                port,
+                link_up: false,
+            });
+            // Replicas of this server have to follow the history of the new master
+            replication.replicas.clear();
+        }
+    }
+    connect(state, shared);
+    RespData::simple_string("OK")
+}
+
+/// Restart the link to the master, or drop it when this server is not a replica
+pub fn connect(state: &mut AppState, shared: &State) {
+    let port = state.config.port;
+    let replication = &mut state.replication;
+    if let Some(link) = replication.link.take() {
+        link.abort();
+    }
+    if replication.is_replica() {
+        replication.link = Some(tokio::spawn(replica_cycle(shared.clone(), port)));
+    }
+}
+
+/// Register a replica asking to sync from `offset` of `replid` (`PSYNC`), returning
+/// the reply and the queue its stream is sent through. The stream starts with the
+/// backlog it missed if that is still available, with a full RDB snapshot otherwise.
+pub fn psync(
+    state: &mut AppState,
+    client_id: u64,
+    addr: SocketAddr,
+    replid: &str,
+    offset: i64,
+) -> anyhow::Result<(RespData, UnboundedReceiver<Vec<u8>>)> {
+    let (sender, receiver) = unbounded_channel();
+    info!("Replica {client_id} listens on {addr}");
+    let backlog = u64::try_from(offset)
+        .ok()
+        .and_then(|offset| state.replication.backlog_from(replid, offset));
+    let reply = match backlog {
+        Some(backlog) => {
+            info!("Partial resynchronization of replica {client_id} accepted");
+            sender.send(backlog)?;
+            RespData::simple_string(format!("CONTINUE {}", state.replication.replid))
+        }
+        None => {
+            // The snapshot is taken under the lock, so it matches the offset exactly
+            info!("Full resynchronization of replica {client_id}");
+            let snapshot = rdb::dump(state.databases(), &state.functions)?;
+            let mut payload = format!("${}\r\n", snapshot.len()).into_bytes();
+            payload.extend(snapshot);
+            sender.send(payload)?;
+            // The replica starts the stream at database 0, as after loading any RDB
+            state.join_stream(Some(0));
+            RespData::simple_string(format!(
+                "FULLRESYNC {} {}",
+                state.replication.replid, state.replication.offset
+            ))
+        }
+    };
+    state.replication.replicas.insert(
+        client_id,
+        Replica {
+            sender,
+            addr,
+            last_ack: now_ms(),
+            ack_offset: 0,
+            aof_ack_offset: 0,
+        },
+    );
+    Ok((reply, receiver))
+}
+
+/// PING the replicas periodically, so they know the master is still there
+pub async fn ping_cycle(state: State) {
+    let mut interval = interval(PING_PERIOD);
+    loop {
+        interval.tick().await;
+        let mut state = state.write().await;
+        // Replicas pass on the PINGs of their own master instead
+        if !state.replication.replicas.is_empty() && !state.replication.is_replica() {
+            state
+                .replication
+                .feed(&RespData::command(["PING"]).as_bytes());
+        }
+    }
+}
+
+/// Keep this replica synced with its master, reconnecting whenever the link drops
+async fn replica_cycle(state: State, listening_port: u16) {
+    loop {
+        let Some(master) = state.write().await.replication.master.clone() else {
+            return;
+        };
+        info!("Connecting to MASTER {}:{}", master.host, master.port);
+        if let Err(e) = sync_with_master(&state, &master, listening_port).await {
+            error!(
+                "Replication with MASTER {}:{} failed: {e:#}",
+                master.host, master.port
+            );
+        }
+        if let Some(master) = &mut state.write().await.replication.master {
+            master.link_up = false;
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// The connection to the master, buffering what was read but not yet used
+struct MasterConnection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl MasterConnection {
+    async fn send(&mut self, args: &[&str]) -> anyhow::Result<()> {
+        self.stream
+            .write_all(&RespData::command(args).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// Read more bytes into the buffer, failing if the master closed the connection
+    /// or went silent for too long
+    async fn fill(&mut self) -> anyhow::Result<()> {
+        let mut chunk = [0; 16 * 1024];
+        let n = timeout(TIMEOUT, self.stream.read(&mut chunk))
+            .await
+            .context("Timeout waiting for the MASTER")??;
+        ensure!(n > 0, "MASTER closed the connection");
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..end]).to_string();
+                self.buf.drain(..end + 2);
+                return Ok(line);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Send a handshake command, failing unless the master answers `expected`
+    async fn expect(&mut self, args: &[&str], expected: &str) -> anyhow::Result<()> {
+        self.send(args).await?;
+        let reply = self.read_line().await?;
+        ensure!(
+            reply.eq_ignore_ascii_case(expected),
+            "MASTER replied `{reply}` to {}",
+            args.join(" ")
+        );
+        Ok(())
+    }
+
+    /// Read the RDB snapshot of a full resync, sent as a bulk string without the final CRLF
+    async fn read_snapshot(&mut self) -> anyhow::Result<Vec<u8>> {
+        let header = loop {
+            let line = self.read_line().await?;
+            // Masters send newlines to keep the link alive while they prepare the snapshot
+            if !line.is_empty() {
+                break line;
+            }
+        };
+        let len: usize = header
+            .strip_prefix('$')
+            .and_then(|len| len.parse().ok())
+            .with_context(|| format!("Unexpected snapshot header `{header}`"))?;
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+}
+
+/// Handshake with the master, sync with it, then apply its stream until the link drops
+async fn sync_with_master(
+    state: &State,
+    master: &MasterLink,
+    listening_port: u16,
+) -> anyhow::Result<()> {
+    let stream = TcpStream::connect((master.host.as_str(), master.port)).await?;
+    let mut connection = MasterConnection {
+        stream,
+        buf: Vec::new(),
+    };
+    connection.expect(&["PING"], "+PONG").await?;
+    connection
+        .expect(
+            &["REPLCONF", "listening-port", &listening_port.to_string()],
+            "+OK",
+        )
+        .await?;
+    connection
+        .expect(&["REPLCONF", "capa", "psync2"], "+OK")
+        .await?;
+    let (replid, offset) = {
+        let state = state.write().await;
+        (state.replication.replid.clone(), state.replication.offset)
+    };
+    // A server that never synced asks for a full resync, anything else tries to continue
+    let psync = if offset == 0 {
+        vec!["?".to_string(), "-1".to_string()]
+    } else {
+        vec![replid, (offset + 1).to_string()]
+    };
+    connection.send(&["PSYNC", &psync[0], &psync[1]]).await?;
+    let reply = connection.read_line().await?;
+    let words: Vec<&str> = reply.split_whitespace().collect();
+    match words.as_slice() {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset.parse().context("Invalid FULLRESYNC offset")?;
+            let snapshot = connection.read_snapshot().await?;
+            let mut state = state.write().await;
+            load_snapshot(&mut state, &snapshot, replid, offset)?;
+        }
+        ["+CONTINUE", rest @ ..] => {
+            info!("Partial resynchronization accepted");
+            let mut state = state.write().await;
+            match rest.first() {
+                // The master was promoted or switched masters, its history goes on under a new ID
+                Some(replid) if *replid != state.replication.replid => {
+                    state.replication.shift_replid((*replid).to_string());
+                }
+                _ => {}
+            }
+        }
+        _ => bail!("Unexpected reply to PSYNC: `{reply}`"),
+    }
+    if let Some(master) = &mut state.write().await.replication.master {
+        master.link_up = true;
+    }
+    info!("MASTER <-> REPLICA sync: Finished with success");
+    apply_stream(state, &mut connection).await
+}
+
+/// Replace the dataset with the snapshot of a full resync
+fn load_snapshot(
+    state: &mut AppState,
+    snapshot: &[u8],
+    replid: &str,
+    offset: u64,
+) -> anyhow::Result<()> {
+    state.clear();
+    state.functions = Functions::default();
+    let keys = rdb::parse(snapshot, state).context("Failed to load the MASTER snapshot")?;
+    info!("MASTER <-> REPLICA sync: Loaded {keys} keys");
+    let replication = &mut state.replication;
+    replication.replid = replid.to_string();
+    replication.offset = offset;
+    replication.replid2 = "0".repeat(40);
+    replication.second_replid_offset = None;
+    replication.backlog.data.clear();
+    replication.master_db = 0;
+    // Replicas of this server followed the old history, they have to sync again
+    replication.replicas.clear();
+    if state.aof.enabled && !state.aof.rewrite_in_progress() {
+        // The AOF still holds the dataset that was just replaced
+        aof::bgrewrite(state)?;
+    }
+    Ok(())
+}
+
+/// `REPLCONF ACK <offset> FACK <aof offset>`, acknowledging the stream processed so far
+fn ack_command(state: &AppState) -> Vec<u8> {
+    RespData::command([
+        "REPLCONF",
+        "ACK",
+        &state.replication.offset.to_string(),
+        "FACK",
+        &state
+            .aof
+            .synced_offset(state.replication.offset)
+            .to_string(),
+    ])
+    .as_bytes()
+}
+
+/// Apply the commands streamed by the master, replying only to `REPLCONF GETACK`,
+/// and acknowledge the stream periodically
+async fn apply_stream(state: &State, connection: &mut MasterConnection) -> anyhow::Result<()> {
+    let mut transaction: Option<Vec<(usize, Command)>> = None;
+    let mut ack_interval = interval(ACK_PERIOD);
+    loop {
+        select! {
+            filled = connection.fill() => filled?,
+            _ = ack_interval.tick() => {
+                let ack = ack_command(&*state.write().await);
+                connection.stream.write_all(&ack).await?;
+                continue;
+            }
+        }
+        let mut acks = Vec::new();
+        let mut guard = state.write().await;
+        while let Some(len) = RespData::frame_len(&connection.buf)? {
+            let frame: Vec<u8> = connection.buf.drain(..len).collect();
+            acks.extend(apply_frame(&mut guard, &frame, &mut transaction));
+        }
+        drop(guard);
+        for ack in acks {
+            connection.stream.write_all(&ack).await?;
+        }
+    }
+}
+
+/// A frame of the stream of the master, as far as passing it on is concerned
+enum Frame {
+    Command,
+    /// `PING` or `REPLCONF`, which keep the link going but are no commands to persist
+    Link {
+        getack: bool,
+    },
+}
+
+/// Apply one frame of the stream of the master and pass it on, returning the
+/// acknowledgement to reply with if it is a `REPLCONF GETACK`
+fn apply_frame(
+    state: &mut AppState,
+    frame: &[u8],
+    transaction: &mut Option<Vec<(usize, Command)>>,
+) -> Option<Vec<u8>> {
+    match apply(state, frame, transaction) {
+        Ok(Frame::Link { getack }) => {
+            // The acknowledgement covers the stream before the GETACK
+            let ack = getack.then(|| ack_command(state));
+            // Counted in the offsets and backlog, but left out of the AOF
+            state.replication.feed(frame);
+            return ack;
+        }
+        Ok(Frame::Command) => {}
+        Err(e) => warn!("Failed to apply a command from the MASTER: {e:#}"),
+    }
+    // Passed on as received, so the offsets and backlog match the master's
+    state.feed(frame);
+    None
+}
+
+/// Apply one frame of the stream of the master
+fn apply(
+    state: &mut AppState,
+    frame: &[u8],
+    transaction: &mut Option<Vec<(usize, Command)>>,
+) -> anyhow::Result<Frame> {
+    let request = RespData::try_from(frame)?;
+    let name = match &request {
+        RespData::Array(Some(elements)) => match elements.front() {
+            Some(RespData::BulkString(Some(name))) => String::from_utf8_lossy(name).to_uppercase(),
+            _ => bail!("Expected a bulk string command, got {:?}", elements.front()),
+        },
+        _ => bail!("Expected an array, got {request:?}"),
+    };
+    match (name.as_str(), transaction.as_mut()) {
+        ("REPLCONF", _) => {
+            let getack = matches!(
+                &request,
+                RespData::Array(Some(elements)) if matches!(
+                    elements.get(1),
+                    Some(RespData::BulkString(Some(option))) if option.eq_ignore_ascii_case(b"GETACK")
+                )
+            );
+            return Ok(Frame::Link { getack });
+        }
+        ("PING", _) => return Ok(Frame::Link { getack: false }),
+        ("SELECT", _) => state.replication.master_db = select_index(&request)?,
+        ("MULTI", _) => *transaction = Some(Vec::new()),
+        ("EXEC", Some(_)) => {
+            for (db, command) in transaction.take().expect("checked above") {
+                state.select(db);
+                command.execute(state)?;
+            }
+        }
+        ("EXEC", None) => bail!("EXEC without MULTI"),
+        (_, Some(commands)) => {
+            commands.push((state.replication.master_db, Command::try_from(request)?));
+        }
+        (_, None) => {
+            state.select(state.replication.master_db);
+            Command::try_from(request)?.execute(state)?;
+        }
+    }
+    Ok(Frame::Command)
+}
+
+/// The database a `SELECT index` from a replication stream or AOF switches to
+pub fn select_index(request: &RespData) -> anyhow::Result<usize> {
+    match request {
+        RespData::Array(Some(elements)) if elements.len() == 2 => match &elements[1] {
+            RespData::BulkString(Some(index)) => parse_db_index(&String::from_utf8_lossy(index)),
+            _ => bail!("SELECT index is not a bulk string"),
+        },
+        _ => bail!("wrong number of arguments for 'select' command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let mut replication = ReplicationState::default();
+        replication.set_backlog_size(8);
+        let replid = replication.replid.clone();
+        assert_eq!(replication.backlog_from(&replid, 1), Some(vec![]));
+        replication.feed(b"0123456789");
+        assert_eq!(replication.offset, 10);
+        // Only the last 8 bytes, offsets 3 to 10, are kept
+        assert_eq!(
+            replication.backlog_from(&replid, 3),
+            Some(b"23456789".to_vec())
+        );
+        assert_eq!(replication.backlog_from(&replid, 10), Some(b"9".to_vec()));
+        assert_eq!(replication.backlog_from(&replid, 11), Some(vec![]));
+        assert_eq!(replication.backlog_from(&replid, 2), None);
+        assert_eq!(replication.backlog_from(&replid, 12), None);
+        assert_eq!(replication.backlog_from("other", 5), None);
+    }
+
+    #[test]
+    fn test_shift_replid() {
+        let mut replication = ReplicationState::default();
+        let old = replication.replid.clone();
+        replication.feed(b"0123456789");
+        replication.shift_replid(new_replid());
+        assert_ne!(replication.replid, old);
+        replication.feed(b"abc");
+        // Replicas of the old history continue as long as they did not go past the shift
+        assert_eq!(replication.backlog_from(&old, 11), Some(b"abc".to_vec()));
+        assert_eq!(replication.backlog_from(&old, 12), None);
+        let replid = replication.replid.clone();
+        assert_eq!(replication.backlog_from(&replid, 12), Some(b"bc".to_vec()));
+    }
+
+    #[test]
+    fn test_check_command() {
+        let mut state = AppState::default();
+        state.replication.master = Some(MasterLink {
+            host: "localhost".to_string(),
+            port: 6379,
+            link_up: true,
+        });
+        let command = |args: &[&str]| Command::try_from(RespData::command(args)).unwrap();
+        assert!(check_command(&state, &command(&["SET", "k", "v"])).is_some());
+        assert!(check_command(&state, &command(&["GET", "k"])).is_none());
+        // Scripts are only refused the writes they call
+        assert!(check_command(&state, &command(&["EVAL", "return 1", "0"])).is_none());
+        assert!(check_command(&state, &command(&["FCALL", "f", "0"])).is_none());
+    }
+
+    #[test]
+    fn test_stream_in_aof() {
+        let dir = std::env::temp_dir().join(format!("replica-aof-{}", std::process::id()));
+        let replica = || {
+            let mut state = AppState::default();
+            state.aof.enabled = true;
+            state.aof.dir = dir.clone();
+            state.replication.master = Some(MasterLink {
+                host: "localhost".to_string(),
+                port: 6379,
+                link_up: true,
+            });
+            state
+        };
+        let mut state = replica();
+        aof::load(&mut state).unwrap();
+        let mut transaction = None;
+        let frames: [&[&str]; 3] = [&["SET", "k", "v"], &["REPLCONF", "GETACK", "*"], &["PING"]];
+        let mut acks = 0;
+        for frame in frames {
+            let frame = RespData::command(frame).as_bytes();
+            acks += apply_frame(&mut state, &frame, &mut transaction)
+                .iter()
+                .count();
+        }
+        assert_eq!(acks, 1);
+        drop(state);
+
+        // Only the commands made it to the AOF, so it loads again after a restart
+        let mut state = replica();
+        let loaded = aof::load(&mut state);
+        std::fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+        assert!(state.db.kv.contains_key("k"));
+    }
+
+    #[test]
+    fn test_parse_master() {
+        let args = |s: &str| s.split(',').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            parse_master(&args("localhost 6380")).unwrap(),
+            Some(("localhost".to_string(), 6380))
+        );
+        assert_eq!(
+            parse_master(&args("localhost,6380")).unwrap(),
+            Some(("localhost".to_string(), 6380))
+        );
+        assert_eq!(parse_master(&args("no one")).unwrap(), None);
+        assert!(parse_master(&args("localhost")).is_err());
+        assert!(parse_master(&args("localhost x")).is_err());
+    }
+}
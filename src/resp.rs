@@ -37,14 +37,18 @@ pub enum RespData {
     ///// =<length>\r\n<encoding>:<data>\r\n
     ///// Exactly three (3) bytes represent the data's encoding
     //// VerbatimString { encoding: String, data: Vec<u8> },
-    ///// %<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>
-    //// Map(HashMap<RespData, RespData>),
+    /// %<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>
+    ///
+    /// Sent as a flat array of keys and values to RESP2 clients
+    Map(Vec<(RespData, RespData)>),
     ///// |<number-of-entries>\r\n<key-1><value-1>...<key-n><value-n>
     //// Attributes(HashMap<RespData, RespData>),
     ///// ~<number-of-elements>\r\n<element-1>...<element-n>
     //// Set(HashSet<RespData>),
-    ///// ><number-of-elements>\r\n<element-1>...<element-n>
-    //// Push(Vec<RespData>),
+    /// ><number-of-elements>\r\n<element-1>...<element-n>
+    ///
+    /// Out-of-band data such as Pub/Sub messages, sent as an array to RESP2 clients
+    Push(VecDeque<RespData>),
 }
 
 fn from_lead_until_crlf(lead: char, value: &[u8]) -> anyhow::Result<&[u8]> {
@@ -70,6 +74,13 @@ impl RespData {
         Self::SimpleString(s.as_ref().to_string())
     }
 
+    pub fn simple_error(kind: impl AsRef<str>, message: impl AsRef<str>) -> Self {
+        Self::SimpleError {
+            kind: kind.as_ref().to_string(),
+            message: message.as_ref().to_string(),
+        }
+    }
+
     pub fn bulk_string(s: impl AsRef<str>) -> Self {
         Self::BulkString(Some(s.as_ref().as_bytes().to_vec()))
     }
@@ -82,6 +93,19 @@ impl RespData {
         Self::Array(Some(elements))
     }
 
+    /// A command request, an array of bulk strings
+    pub fn command<I>(args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        Self::array(
+            args.into_iter()
+                .map(|arg| Self::BulkString(Some(arg.as_ref().to_vec())))
+                .collect(),
+        )
+    }
+
     pub fn as_number<T, E>(&self) -> Option<T>
     where
         T: TryFrom<i64, Error = E> + FromStr,
@@ -114,7 +138,10 @@ impl RespData {
             }
             RespData::Integer(num) => format!(":{num}\r\n").into_bytes(),
             RespData::BulkString(Some(s)) => {
-                format!("${}\r\n{}\r\n", s.len(), String::from_utf8_lossy(s)).into_bytes()
+                let mut result = format!("${}\r\n", s.len()).into_bytes();
+                result.extend_from_slice(s);
+                result.extend_from_slice(CRLF);
+                result
             }
             RespData::BulkString(None) => b"$-1\r\n".to_vec(),
             RespData::Array(Some(elements)) => {
@@ -128,9 +155,78 @@ impl RespData {
             RespData::Null => b"_\r\n".to_vec(),
             RespData::Boolean(true) => b"#t\r\n".to_vec(),
             RespData::Boolean(false) => b"#f\r\n".to_vec(),
+            RespData::Map(entries) => {
+                let mut result = format!("%{}\r\n", entries.len()).into_bytes();
+                for (key, value) in entries {
+                    result.extend_from_slice(&key.as_bytes());
+                    result.extend_from_slice(&value.as_bytes());
+                }
+                result
+            }
+            RespData::Push(elements) => {
+                let mut result = format!(">{}\r\n", elements.len()).into_bytes();
+                for element in elements {
+                    result.extend_from_slice(&element.as_bytes());
+                }
+                result
+            }
         }
     }
 
+    /// Downgrade RESP3-only types to their RESP2 equivalents
+    pub fn into_resp2(self) -> Self {
+        match self {
+            RespData::Map(entries) => RespData::array(
+                entries
+                    .into_iter()
+                    .flat_map(|(key, value)| [key.into_resp2(), value.into_resp2()])
+                    .collect(),
+            ),
+            RespData::Push(elements) | RespData::Array(Some(elements)) => {
+                RespData::array(elements.into_iter().map(Self::into_resp2).collect())
+            }
+            RespData::Null => RespData::null_bulk_string(),
+            RespData::Boolean(b) => RespData::Integer(i64::from(b)),
+            other => other,
+        }
+    }
+
+    /// Length of the first complete frame in `buf`, or `None` if more bytes are needed.
+    /// Lets a reader split pipelined requests, or wait for the rest of a partial one.
+    pub fn frame_len(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+        let Some(line_end) = buf.windows(CRLF.len()).position(|w| w == CRLF) else {
+            return Ok(None);
+        };
+        ensure!(line_end > 0, "Empty frame header");
+        let header = std::str::from_utf8(&buf[1..line_end])?;
+        let mut len = line_end + CRLF.len();
+        match buf[0] {
+            b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => {}
+            b'$' | b'!' | b'=' => {
+                let size: i64 = header.parse().map_err(|e| anyhow!("Invalid length: {e}"))?;
+                if let Ok(size) = usize::try_from(size) {
+                    len += size + CRLF.len();
+                    if buf.len() < len {
+                        return Ok(None);
+                    }
+                }
+            }
+            b'*' | b'>' | b'~' | b'%' | b'|' => {
+                let count: i64 = header.parse().map_err(|e| anyhow!("Invalid length: {e}"))?;
+                // Maps and attributes hold a key and a value per entry
+                let per_entry = if matches!(buf[0], b'%' | b'|') { 2 } else { 1 };
+                for _ in 0..count.max(0) * per_entry {
+                    match Self::frame_len(&buf[len..])? {
+                        Some(element_len) => len += element_len,
+                        None => return Ok(None),
+                    }
+                }
+            }
+            other => bail!("Unknown RESP type: {}", char::from(other)),
+        }
+        Ok(Some(len))
+    }
+
     fn parse_simple_string(value: &mut &[u8]) -> anyhow::Result<Self> {
         let buf = from_lead_until_crlf('+', value)?;
         let buf_len = buf.len();
@@ -213,6 +309,34 @@ impl RespData {
         }
     }
 
+    fn parse_push(value: &mut &[u8]) -> anyhow::Result<Self> {
+        let buf = from_lead_until_crlf('>', value)?;
+        let len = String::from_utf8(buf.to_vec())?
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid length: {e}"))?;
+        *value = &value[1 /* Leading char */ + buf.len() + CRLF.len()..];
+        let mut elements = VecDeque::with_capacity(len);
+        for _ in 0..len {
+            elements.push_back(Self::from_bytes(value)?);
+        }
+        Ok(RespData::Push(elements))
+    }
+
+    fn parse_map(value: &mut &[u8]) -> anyhow::Result<Self> {
+        let buf = from_lead_until_crlf('%', value)?;
+        let len = String::from_utf8(buf.to_vec())?
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid length: {e}"))?;
+        *value = &value[1 /* Leading char */ + buf.len() + CRLF.len()..];
+        let mut entries = Vec::with_capacity(len);
+        for _ in 0..len {
+            let key = Self::from_bytes(value)?;
+            let value = Self::from_bytes(value)?;
+            entries.push((key, value));
+        }
+        Ok(RespData::Map(entries))
+    }
+
     fn parse_null(value: &mut &[u8]) -> anyhow::Result<Self> {
         ensure!(
             value.get(..1) == Some(b"_") && value.get(1..3) == Some(CRLF),
@@ -250,10 +374,10 @@ impl RespData {
             b'(' => todo!("Parse big number"),
             b'!' => todo!("Parse bulk error"),
             b'=' => todo!("Parse verbatim string"),
-            b'%' => todo!("Parse map"),
+            b'%' => Self::parse_map(value),
             b'|' => todo!("Parse attributes"),
             b'~' => todo!("Parse set"),
-            b'>' => todo!("Parse push"),
+            b'>' => Self::parse_push(value),
             _ => Err(anyhow!("Unknown RESP type")),
         }
     }
@@ -296,6 +420,23 @@ impl Display for RespData {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_frame_len() {
+        let pipeline = b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n";
+        assert_eq!(RespData::frame_len(pipeline).unwrap(), Some(14));
+        assert_eq!(RespData::frame_len(&pipeline[14..]).unwrap(), Some(22));
+        // Partial frames need more bytes
+        assert_eq!(RespData::frame_len(&pipeline[..10]).unwrap(), None);
+        assert_eq!(RespData::frame_len(&pipeline[..13]).unwrap(), None);
+        assert_eq!(RespData::frame_len(b"").unwrap(), None);
+        assert_eq!(RespData::frame_len(b"$-1\r\n").unwrap(), Some(5));
+        assert_eq!(
+            RespData::frame_len(b"%1\r\n+a\r\n:1\r\n").unwrap(),
+            Some(12)
+        );
+        assert!(RespData::frame_len(b"?\r\n").is_err());
+    }
+
     #[test]
     fn test_parse_simple_string() {
         let mut data = b"+OK\r\n".as_ref();
@@ -372,4 +513,36 @@ mod tests {
         assert!(matches!(resp, RespData::Boolean(false)));
         assert!(data.is_empty());
     }
+
+    #[test]
+    fn test_parse_push() {
+        let mut data = b">2\r\n+message\r\n:1\r\n".as_ref();
+        let resp = RespData::parse_push(&mut data).unwrap();
+        let RespData::Push(elements) = resp else {
+            panic!("Expected Push, got {resp:?}");
+        };
+        assert_eq!(elements.len(), 2);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let mut data = b"%1\r\n+proto\r\n:3\r\n".as_ref();
+        let resp = RespData::parse_map(&mut data).unwrap();
+        let RespData::Map(entries) = resp else {
+            panic!("Expected Map, got {resp:?}");
+        };
+        assert!(
+            matches!(entries.as_slice(), [(RespData::SimpleString(k), RespData::Integer(3))] if k == "proto")
+        );
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_into_resp2() {
+        let map = RespData::Map(vec![(RespData::bulk_string("proto"), RespData::Integer(2))]);
+        assert_eq!(map.into_resp2().as_bytes(), b"*2\r\n$5\r\nproto\r\n:2\r\n");
+        let push = RespData::Push(VecDeque::from([RespData::Integer(1)]));
+        assert_eq!(push.into_resp2().as_bytes(), b"*1\r\n:1\r\n");
+    }
 }
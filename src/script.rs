@@ -0,0 +1,609 @@
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value as LuaValue, Variadic};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::{
+    cmd::Command,
+    evict,
+    function::{parse_header, valid_name, FunctionInfo, Library, FUNCTION_FLAGS},
+    replication,
+    resp::RespData,
+    state::AppState,
+};
+
+/// Milliseconds a script may run before other clients are answered with BUSY
+pub const DEFAULT_BUSY_REPLY_THRESHOLD: u64 = 5000;
+static BUSY_REPLY_THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_BUSY_REPLY_THRESHOLD);
+
+/// Lua instructions between checks for SCRIPT KILL
+const KILL_CHECK_INTERVAL: u32 = 10_000;
+
+/// The script currently running. There is at most one, as scripts hold the state lock.
+#[derive(Debug)]
+struct RunningScript {
+    started: Instant,
+    /// Whether this is a function run with FCALL rather than an EVAL script
+    function: bool,
+    /// A script that wrote to the dataset can no longer be killed
+    wrote: bool,
+    killed: bool,
+}
+
+static RUNNING: Mutex<Option<RunningScript>> = Mutex::new(None);
+
+fn running() -> MutexGuard<'static, Option<RunningScript>> {
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Marks a script as running for as long as it is alive
+struct RunningGuard;
+
+impl RunningGuard {
+    fn start(function: bool) -> Self {
+        *running() = Some(RunningScript {
+            started: Instant::now(),
+            function,
+            wrote: false,
+            killed: false,
+        });
+        Self
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        *running() = None;
+    }
+}
+
+pub fn set_busy_reply_threshold(milliseconds: u64) {
+    BUSY_REPLY_THRESHOLD.store(milliseconds, Ordering::Relaxed);
+}
+
+pub fn busy_reply_threshold() -> u64 {
+    BUSY_REPLY_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// The lowercase hex SHA1 digest scripts are cached by
+pub fn sha1_hex(script: &str) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+/// Wait for a running script to finish. Returns the BUSY error to reply with instead
+/// if it runs past the busy reply threshold, so clients are not left hanging.
+pub async fn wait_while_busy() -> Option<RespData> {
+    loop {
+        let (elapsed, function) = running()
+            .as_ref()
+            .map(|script| (script.started.elapsed(), script.function))?;
+        let threshold = Duration::from_millis(BUSY_REPLY_THRESHOLD.load(Ordering::Relaxed));
+        if elapsed >= threshold {
+            let kill = if function {
+                "FUNCTION KILL"
+            } else {
+                "SCRIPT KILL"
+            };
+            return Some(RespData::simple_error(
+                "BUSY",
+                format!(
+                    "Redis is busy running a script. You can only call {kill} or SHUTDOWN NOSAVE."
+                ),
+            ));
+        }
+        sleep((threshold - elapsed).min(Duration::from_millis(10))).await;
+    }
+}
+
+/// Stop the running script (with SCRIPT KILL) or function (with FUNCTION KILL),
+/// unless it already wrote to the dataset
+pub fn kill(function: bool) -> RespData {
+    match running().as_mut() {
+        Some(script) if script.function == function => {
+            if script.wrote {
+                return RespData::simple_error(
+                    "UNKILLABLE",
+                    "Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.",
+                );
+            }
+            info!("Killing the running script");
+            script.killed = true;
+            RespData::simple_string("OK")
+        }
+        _ => RespData::simple_error("NOTBUSY", "No scripts in execution right now."),
+    }
+}
+
+/// What to run: an EVAL script, or a function from a library
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    Script(&'a str),
+    Function { code: &'a str, name: &'a str },
+}
+
+/// A function registered with `redis.register_function`, along with its callback
+struct Registration<'lua> {
+    name: String,
+    callback: mlua::Function<'lua>,
+    info: FunctionInfo,
+}
+
+/// Parse the arguments of `redis.register_function`, either `(name, callback)`
+/// or a table with `function_name`, `callback` and optional `flags` and `description`
+fn parse_registration(args: MultiValue<'_>) -> mlua::Result<Registration<'_>> {
+    let args = args.into_vec();
+    let (name, callback, flags, description) = match args.as_slice() {
+        [LuaValue::String(name), LuaValue::Function(callback)] => {
+            (name.to_str()?.to_string(), callback.clone(), None, None)
+        }
+        [LuaValue::Table(table)] => {
+            let name: Option<String> = table.get("function_name")?;
+            let callback: Option<mlua::Function> = table.get("callback")?;
+            let flags: Option<Vec<String>> = table.get("flags")?;
+            let description: Option<String> = table.get("description")?;
+            let name = name.ok_or_else(|| {
+                mlua::Error::runtime("redis.register_function must get a function name argument")
+            })?;
+            let callback = callback.ok_or_else(|| {
+                mlua::Error::runtime("redis.register_function must get a callback argument")
+            })?;
+            (name, callback, flags, description)
+        }
+        _ => {
+            return Err(mlua::Error::runtime(
+                "wrong number of arguments to redis.register_function",
+            ))
+        }
+    };
+    if !valid_name(&name) {
+        return Err(mlua::Error::runtime("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    let flags = flags.unwrap_or_default();
+    if let Some(flag) = flags
+        .iter()
+        .find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str()))
+    {
+        return Err(mlua::Error::runtime(format!("unknown flag given: {flag}")));
+    }
+    Ok(Registration {
+        name,
+        callback,
+        info: FunctionInfo { description, flags },
+    })
+}
+
+/// Registry table collecting the arguments of every `redis.register_function` call
+const REGISTRATIONS: &str = "registrations";
+
+/// Add `redis.register_function`, which checks its arguments and records them
+/// so the registrations can be read back with [`registrations`] once the library ran
+fn add_register_function(lua: &Lua, redis: &Table) -> mlua::Result<()> {
+    lua.set_named_registry_value(REGISTRATIONS, lua.create_table()?)?;
+    let register = lua.create_function(|lua, args: MultiValue| {
+        parse_registration(args.clone())?;
+        let registered: Table = lua.named_registry_value(REGISTRATIONS)?;
+        registered.push(lua.create_sequence_from(args)?)
+    })?;
+    redis.set("register_function", register)
+}
+
+/// Every function the library registered, in order
+fn registrations(lua: &Lua) -> mlua::Result<Vec<Registration<'_>>> {
+    let registered: Table = lua.named_registry_value(REGISTRATIONS)?;
+    registered
+        .sequence_values::<Table>()
+        .map(|args| {
+            let args = args?.sequence_values().collect::<mlua::Result<Vec<_>>>()?;
+            parse_registration(MultiValue::from_vec(args))
+        })
+        .collect()
+}
+
+/// Load the code of a function library, as FUNCTION LOAD does, running it to find
+/// the functions it registers. Returns the error message if the library is invalid.
+pub fn load_library(code: &str) -> Result<Library, String> {
+    let (name, body) = parse_header(code)?;
+    let _running = RunningGuard::start(true);
+    let lua = sandbox().map_err(|e| e.to_string())?;
+    let functions = (|| {
+        let redis = redis_library(&lua)?;
+        add_register_function(&lua, &redis)?;
+        lua.globals().set("redis", redis)?;
+        lua.load(body).set_name("@user_function").exec()?;
+        let mut functions = BTreeMap::new();
+        for registration in registrations(&lua)? {
+            if functions
+                .insert(registration.name.clone(), registration.info)
+                .is_some()
+            {
+                return Err(mlua::Error::runtime(format!(
+                    "Function {} already exists",
+                    registration.name
+                )));
+            }
+        }
+        Ok(functions)
+    })()
+    .map_err(|e| match error_reply(&e) {
+        RespData::SimpleError { kind, message } if kind == "ERR" => message,
+        RespData::SimpleError { kind, message } => format!("{kind} {message}"),
+        _ => e.to_string(),
+    })?;
+    if functions.is_empty() {
+        return Err("No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions,
+    })
+}
+
+/// Call the function `name` from a library, as FCALL does.
+/// The caller holds the state lock, so the function runs atomically.
+pub fn fcall(
+    state: &mut AppState,
+    code: &str,
+    name: &str,
+    keys: &[String],
+    args: &[Vec<u8>],
+    read_only: bool,
+) -> RespData {
+    let _running = RunningGuard::start(true);
+    let entry = match parse_header(code) {
+        Ok((_, body)) => Entry::Function { code: body, name },
+        Err(message) => return RespData::simple_error("ERR", message),
+    };
+    run(state, entry, keys, args, read_only).unwrap_or_else(|e| error_reply(&e))
+}
+
+/// Run a script as EVAL does. The caller holds the state lock,
+/// so the script runs atomically until it returns or is killed.
+pub fn eval(
+    state: &mut AppState,
+    script: &str,
+    keys: &[String],
+    args: &[Vec<u8>],
+    read_only: bool,
+) -> RespData {
+    let _running = RunningGuard::start(false);
+    run(state, Entry::Script(script), keys, args, read_only).unwrap_or_else(|e| error_reply(&e))
+}
+
+fn run(
+    state: &mut AppState,
+    entry: Entry,
+    keys: &[String],
+    args: &[Vec<u8>],
+    read_only: bool,
+) -> mlua::Result<RespData> {
+    let lua = sandbox()?;
+    let state = RefCell::new(state);
+    lua.scope(|scope| {
+        let redis = redis_library(&lua)?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args: Variadic<LuaValue>| {
+                redis_call(lua, &mut state.borrow_mut(), &args, read_only, false)
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args: Variadic<LuaValue>| {
+                redis_call(lua, &mut state.borrow_mut(), &args, read_only, true)
+            })?,
+        )?;
+        let keys = lua.create_sequence_from(keys.iter().map(String::as_str))?;
+        let args = args
+            .iter()
+            .map(|arg| lua.create_string(arg))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        let args = lua.create_sequence_from(args)?;
+        let globals = lua.globals();
+        let result: LuaValue = match entry {
+            Entry::Script(script) => {
+                globals.set("redis", redis)?;
+                globals.set("KEYS", keys)?;
+                globals.set("ARGV", args)?;
+                lua.load(script).set_name("@user_script").call(())?
+            }
+            Entry::Function { code, name } => {
+                // Run the library again to get hold of the callback
+                add_register_function(&lua, &redis)?;
+                globals.set("redis", redis)?;
+                lua.load(code).set_name("@user_function").exec()?;
+                let callback = registrations(&lua)?
+                    .into_iter()
+                    .find(|registration| registration.name == name)
+                    .map(|registration| registration.callback)
+                    .ok_or_else(|| mlua::Error::runtime("Function not found"))?;
+                callback.call((keys, args))?
+            }
+        };
+        Ok(lua_to_resp(&result))
+    })
+}
+
+/// A Lua interpreter without access to the file system, which stops when SCRIPT KILL is called
+fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    for name in ["dofile", "loadfile"] {
+        lua.globals().set(name, LuaValue::Nil)?;
+    }
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+        |_, _| {
+            if running().as_ref().is_some_and(|script| script.killed) {
+                return Err(mlua::Error::runtime(
+                    "ERR Script killed by user with SCRIPT KILL...",
+                ));
+            }
+            Ok(())
+        },
+    );
+    Ok(lua)
+}
+
+/// The `redis` table, with everything but `call` and `pcall` which need the state
+fn redis_library(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let redis = lua.create_table()?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, message: String| single_field_table(lua, "err", &message))?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, message: String| single_field_table(lua, "ok", &message))?,
+    )?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, input: mlua::String| {
+            Ok(sha1_smol::Sha1::from(input.as_bytes()).digest().to_string())
+        })?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_, (level, message): (u8, String)| {
+            match level {
+                0 | 1 => debug!("Script: {message}"),
+                2 => info!("Script: {message}"),
+                _ => warn!("Script: {message}"),
+            }
+            Ok(())
+        })?,
+    )?;
+    for (name, level) in [
+        ("LOG_DEBUG", 0),
+        ("LOG_VERBOSE", 1),
+        ("LOG_NOTICE", 2),
+        ("LOG_WARNING", 3),
+    ] {
+        redis.set(name, level)?;
+    }
+    Ok(redis)
+}
+
+fn single_field_table<'lua>(lua: &'lua Lua, field: &str, value: &str) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, value)?;
+    Ok(table)
+}
+
+/// `redis.call` and `redis.pcall`. The protected variant returns errors as an `{err=...}`
+/// table instead of raising them.
+fn redis_call<'lua>(
+    lua: &'lua Lua,
+    state: &mut AppState,
+    args: &[LuaValue],
+    read_only: bool,
+    protected: bool,
+) -> mlua::Result<LuaValue<'lua>> {
+    let reply = call_command(state, args, read_only)
+        .unwrap_or_else(|message| RespData::simple_error("ERR", message));
+    match reply {
+        RespData::SimpleError { kind, message } if !protected => {
+            Err(mlua::Error::runtime(format!("{kind} {message}")))
+        }
+        reply => resp_to_lua(lua, reply),
+    }
+}
+
+fn call_command(
+    state: &mut AppState,
+    args: &[LuaValue],
+    read_only: bool,
+) -> Result<RespData, String> {
+    if args.is_empty() {
+        return Err("Please specify at least one argument for this redis lib call".to_string());
+    }
+    let request = args
+        .iter()
+        .map(|arg| match arg {
+            LuaValue::String(s) => Ok(RespData::BulkString(Some(s.as_bytes().to_vec()))),
+            LuaValue::Integer(n) => Ok(RespData::bulk_string(n.to_string())),
+            LuaValue::Number(n) => Ok(RespData::bulk_string(n.to_string())),
+            _ => Err("Lua redis lib command arguments must be strings or integers".to_string()),
+        })
+        .collect::<Result<VecDeque<_>, _>>()?;
+    let command = Command::try_from(RespData::array(request)).map_err(|e| e.to_string())?;
+    if command.is_script() {
+        return Err("This Redis command is not allowed from script".to_string());
+    }
+    if command.is_write() {
+        if read_only {
+            return Err("Write commands are not allowed from read-only scripts.".to_string());
+        }
+        if state.replication.is_replica() && state.replication.read_only {
+            return Ok(replication::read_only_error());
+        }
+        if let Some(script) = running().as_mut() {
+            // Functions are checked for memory as they start, scripts until their first write
+            if !script.function && !script.wrote && command.denies_oom() && evict::over_limit(state)
+            {
+                return Ok(evict::oom_error());
+            }
+            script.wrote = true;
+        }
+    }
+    command.execute(state).map_err(|e| e.to_string())
+}
+
+/// Convert a command reply for Lua: status and error replies become `{ok=...}` and
+/// `{err=...}` tables, nulls become `false`
+fn resp_to_lua(lua: &Lua, reply: RespData) -> mlua::Result<LuaValue<'_>> {
+    let value = match reply.into_resp2() {
+        RespData::Integer(n) => LuaValue::Integer(n),
+        RespData::BulkString(Some(bytes)) => LuaValue::String(lua.create_string(&bytes)?),
+        RespData::Array(Some(elements)) => {
+            let elements = elements
+                .into_iter()
+                .map(|element| resp_to_lua(lua, element))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            LuaValue::Table(lua.create_sequence_from(elements)?)
+        }
+        RespData::SimpleString(status) => LuaValue::Table(single_field_table(lua, "ok", &status)?),
+        RespData::SimpleError { kind, message } => LuaValue::Table(single_field_table(
+            lua,
+            "err",
+            &format!("{kind} {message}"),
+        )?),
+        RespData::Boolean(b) => LuaValue::Boolean(b),
+        _ => LuaValue::Boolean(false),
+    };
+    Ok(value)
+}
+
+/// Convert a script result into a reply: numbers are truncated to integers,
+/// and arrays stop at the first `nil`
+fn lua_to_resp(value: &LuaValue) -> RespData {
+    match value {
+        LuaValue::Integer(n) => RespData::Integer(*n),
+        #[allow(clippy::cast_possible_truncation)]
+        LuaValue::Number(n) => RespData::Integer(*n as i64),
+        LuaValue::String(s) => RespData::BulkString(Some(s.as_bytes().to_vec())),
+        LuaValue::Boolean(true) => RespData::Integer(1),
+        LuaValue::Table(table) => {
+            if let Ok(LuaValue::String(status)) = table.raw_get::<_, LuaValue>("ok") {
+                return RespData::simple_string(status.to_string_lossy());
+            }
+            if let Ok(LuaValue::String(error)) = table.raw_get::<_, LuaValue>("err") {
+                return error_from_message(&error.to_string_lossy());
+            }
+            let elements = table
+                .clone()
+                .sequence_values::<LuaValue>()
+                .map_while(Result::ok)
+                .map(|element| lua_to_resp(&element))
+                .collect();
+            RespData::array(elements)
+        }
+        _ => RespData::null_bulk_string(),
+    }
+}
+
+/// An error reply from a message that may start with an error code, such as `WRONGTYPE ...`
+fn error_from_message(message: &str) -> RespData {
+    match message.split_once(' ') {
+        Some((kind, message))
+            if !kind.is_empty() && kind.bytes().all(|b| b.is_ascii_uppercase()) =>
+        {
+            RespData::simple_error(kind, message)
+        }
+        _ => RespData::simple_error("ERR", message),
+    }
+}
+
+/// The reply for a script that failed, errors raised by `redis.call` keep their code
+fn error_reply(error: &mlua::Error) -> RespData {
+    match error {
+        mlua::Error::CallbackError { cause, .. } => error_reply(cause),
+        mlua::Error::RuntimeError(message) | mlua::Error::SyntaxError { message, .. } => {
+            error_from_message(message)
+        }
+        error => error_from_message(&error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(state: &mut AppState, script: &str, keys: &[&str], args: &[&str]) -> RespData {
+        let keys: Vec<String> = keys.iter().map(ToString::to_string).collect();
+        let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        super::eval(state, script, &keys, &args, false)
+    }
+
+    #[test]
+    fn test_eval_conversions() {
+        let mut state = AppState::default();
+        let reply = eval(
+            &mut state,
+            "return {1, 2.9, 'three', true, false, nil, 'unreachable'}",
+            &[],
+            &[],
+        );
+        assert_eq!(
+            reply.to_string(),
+            "*5\r\n:1\r\n:2\r\n$5\r\nthree\r\n:1\r\n$-1\r\n"
+        );
+        let reply = eval(&mut state, "return redis.status_reply('FINE')", &[], &[]);
+        assert_eq!(reply.to_string(), "+FINE\r\n");
+        let reply = eval(&mut state, "return {err='MY oops'}", &[], &[]);
+        assert_eq!(reply.to_string(), "-MY oops\r\n");
+    }
+
+    #[test]
+    fn test_eval_calls() {
+        let mut state = AppState::default();
+        let script = "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('GET', KEYS[1])";
+        let reply = eval(&mut state, script, &["k"], &["v"]);
+        assert_eq!(reply.to_string(), "$1\r\nv\r\n");
+        // A missing key is `false` in Lua
+        let reply = eval(
+            &mut state,
+            "return redis.call('GET', 'missing') == false",
+            &[],
+            &[],
+        );
+        assert_eq!(reply.to_string(), ":1\r\n");
+        // Errors raised by `redis.call` keep their code, `redis.pcall` returns them
+        let reply = eval(&mut state, "return redis.call('RPUSH', 'k', 'x')", &[], &[]);
+        assert!(reply.to_string().starts_with("-WRONGTYPE"));
+        let reply = eval(
+            &mut state,
+            "return redis.pcall('RPUSH', 'k', 'x')['err']",
+            &[],
+            &[],
+        );
+        assert!(reply.to_string().starts_with("$"));
+        let keys = [String::from("k")];
+        let reply = super::eval(
+            &mut state,
+            "return redis.call('SET', KEYS[1], 'w')",
+            &keys,
+            &[],
+            true,
+        );
+        assert!(reply.to_string().contains("read-only scripts"));
+        // Blocking commands do not block inside a script
+        let reply = eval(
+            &mut state,
+            "return redis.call('XREAD', 'BLOCK', '0', 'STREAMS', 's', '0')",
+            &[],
+            &[],
+        );
+        assert_eq!(reply.to_string(), "$-1\r\n");
+    }
+}
@@ -1,7 +1,36 @@
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, Notify};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex, MutexGuard, PoisonError,
+    },
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{
+        futures::Notified, oneshot, Notify, OwnedRwLockWriteGuard, RwLock, RwLockReadGuard,
+        RwLockWriteGuard,
+    },
+    time::{interval, timeout},
+};
 
-use crate::resp::RespData;
+use crate::{
+    aof::{self, AofState},
+    cmd::PushPopDirection,
+    config::Config,
+    evict::{self, EvictionState, KeyMeta},
+    function::Functions,
+    info::Stats,
+    keyspace::{Keyspace, LockedShards},
+    pubsub::PubSub,
+    rdb::RdbState,
+    replication::ReplicationState,
+    resp::RespData,
+    value::{EncodingLimits, Value},
+};
 
 #[derive(Debug, Default)]
 pub struct WaitingList {
@@ -9,15 +38,901 @@ pub struct WaitingList {
     pub signal: Arc<Notify>,
 }
 
+/// Modification counter of a key watched by at least one client
+#[derive(Debug, Default)]
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
+}
+
+/// A client blocked in BLPOP/BRPOP, served directly by the push that unblocks it
+#[derive(Debug)]
+pub struct BlockedPop {
+    pub direction: PushPopDirection,
+    pub sender: oneshot::Sender<RespData>,
+}
+
+/// A numbered database, what SELECT switches between
+#[derive(Debug, Default, Clone)]
+pub struct Db {
+    /// The keys with their values and expiries
+    pub kv: Keyspace,
+}
+
+/// Time each database cycle spends rehashing the keyspaces, across all databases
+const REHASH_BUDGET: Duration = Duration::from_millis(1);
+
+/// Stands in for the databases nothing was written to yet
+static EMPTY_DB: LazyLock<Db> = LazyLock::new(Db::default);
+
 #[derive(Debug, Default)]
 pub struct AppState {
-    pub kv: HashMap<String, RespData>,
+    /// The selected database, the one commands run against
+    pub db: Db,
+    /// Number of the selected database
+    pub selected: usize,
+    /// The databases by number, with an empty one in place of the selected database
+    dbs: Vec<Db>,
+    /// Sum of the memory accounted for every key
+    keys_memory: AtomicUsize,
+    /// Clients blocked popping from a list, by database and key, in the order they blocked
+    pub blocked_pops: HashMap<(usize, String), VecDeque<BlockedPop>>,
+    /// Clients blocked reading a stream, keyed by [`stream_wait_key`]
     pub waiting_lists: HashMap<String, WaitingList>,
+    pub pubsub: PubSub,
+    /// Lua scripts by the SHA1 of their source
+    pub scripts: HashMap<String, String>,
+    /// Function libraries loaded with FUNCTION LOAD
+    pub functions: Functions,
+    /// Keys under WATCH by database, bumped by every modification so EXEC can detect them
+    watched_keys: Mutex<HashMap<(usize, String), WatchedKey>>,
+    /// Changes to the dataset since the last save
+    pub dirty: AtomicU64,
+    pub rdb: RdbState,
+    pub aof: AofState,
+    pub replication: ReplicationState,
+    pub config: Config,
+    pub eviction: EvictionState,
+    /// Sizes up to which collections keep a compact encoding
+    pub encoding: EncodingLimits,
+    pub stats: Stats,
+    /// Nesting of transactions and scripts whose commands are propagated together
+    atomic_depth: usize,
+    /// Commands propagated by the transaction or script still running, with their database
+    atomic_commands: Vec<(usize, RespData)>,
+    /// The database the replication stream last selected
+    stream_db: usize,
+    /// Set when a consumer of the replication stream may be at another database,
+    /// so the next command selects its database again
+    reselect: bool,
+    /// Commands propagated by writes sharing the state, with their database,
+    /// fed to the replication stream by whoever holds the state exclusively next
+    pending: Mutex<Vec<(usize, RespData)>>,
+    /// Signalled when commands are pending, for [`propagation_cycle`] to feed them
+    pending_signal: Arc<Notify>,
+}
+
+/// The server state, held exclusively by commands that reach beyond their keys and shared
+/// by those that only read or write keys, which lock just the shards of their keys
+#[derive(Debug, Clone, Default)]
+pub struct State(Arc<RwLock<AppState>>);
+
+impl State {
+    pub fn new(state: AppState) -> Self {
+        Self(Arc::new(RwLock::new(state)))
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, AppState> {
+        self.0.read().await
+    }
+
+    /// Hold the state exclusively, once the commands of writes that shared it
+    /// are in the replication stream
+    pub async fn write(&self) -> RwLockWriteGuard<'_, AppState> {
+        let mut guard = self.0.write().await;
+        guard.feed_pending();
+        guard
+    }
+
+    /// Hold the state exclusively as [`State::write`] does, with a guard that may be
+    /// moved to another thread
+    pub async fn write_owned(&self) -> OwnedRwLockWriteGuard<AppState> {
+        let mut guard = self.0.clone().write_owned().await;
+        guard.feed_pending();
+        guard
+    }
+}
+
+/// A poisoned lock is still consistent, as no command panics halfway through a write
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Cost of an entry in the keyspace besides its name and value
+pub const KEY_OVERHEAD: usize = 48;
+
+/// Cost of the expiry of `key`, kept in a map and in the ordered queue
+pub fn expiry_overhead(key: &str) -> usize {
+    2 * (key.len() + 8)
+}
+
+/// The key of the waiting list of clients blocked reading the stream at `key` of database `db`
+pub fn stream_wait_key(db: usize, key: &str) -> String {
+    format!(">{db}:{key}")
+}
+
+/// Milliseconds since the UNIX epoch
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| u64::try_from(now.as_millis()).unwrap_or(u64::MAX))
 }
-pub type State = Arc<Mutex<AppState>>;
 
 impl AppState {
+    /// Switch the database commands run against
+    pub fn select(&mut self, index: usize) {
+        if index == self.selected {
+            return;
+        }
+        let len = index.max(self.selected) + 1;
+        if self.dbs.len() < len {
+            self.dbs.resize_with(len, Db::default);
+        }
+        // The selected database goes back in place of its stand-in, the new one leaves one
+        std::mem::swap(&mut self.db, &mut self.dbs[self.selected]);
+        std::mem::swap(&mut self.db, &mut self.dbs[index]);
+        self.selected = index;
+    }
+
+    /// Database `index`, wherever it is kept
+    pub fn database(&self, index: usize) -> &Db {
+        if index == self.selected {
+            &self.db
+        } else {
+            self.dbs.get(index).unwrap_or(&EMPTY_DB)
+        }
+    }
+
+    /// Every database holding keys, by number
+    pub fn databases(&self) -> impl Iterator<Item = (usize, &Db)> {
+        (0..self.dbs.len().max(self.selected + 1))
+            .map(|index| (index, self.database(index)))
+            .filter(|(_, db)| !db.kv.is_empty())
+    }
+
+    /// Take database `index` out, leaving an empty one in its place
+    fn take_db(&mut self, index: usize) -> Db {
+        let selected = self.selected;
+        self.select(index);
+        let db = std::mem::take(&mut self.db);
+        self.select(selected);
+        db
+    }
+
+    /// Put `db` in place of database `index`, which must be empty
+    fn put_db(&mut self, index: usize, db: Db) {
+        let selected = self.selected;
+        self.select(index);
+        self.db = db;
+        self.select(selected);
+    }
+
+    /// Expire `key` at `at` milliseconds since the UNIX epoch, replacing any previous expiry
+    pub fn set_expiry(&mut self, key: &str, at: u64) {
+        self.db.kv.set_expiry(key, at);
+    }
+
+    /// Lock the shards of `keys` for a command that only reads them,
+    /// recording the access to each of them for the LRU and LFU eviction policies
+    pub fn read_keys(&self, db: usize, keys: &[&str]) -> ReadKeys<'_> {
+        let db = self.database(db);
+        let mut shards = db.kv.lock(keys);
+        let now = now_ms();
+        for key in keys {
+            if let Some(meta) = shards.shard(key).meta.get_mut(*key) {
+                record_access(meta, &self.eviction, now);
+            }
+        }
+        ReadKeys {
+            shards,
+            stats: &self.stats,
+            now,
+        }
+    }
+
+    /// Lock the shards of `keys` for a command that writes them while sharing the state,
+    /// recording the access to each of them and removing those that expired
+    pub fn write_keys(&self, db: usize, keys: &[&str]) -> WriteKeys<'_> {
+        let mut write_keys = WriteKeys {
+            state: self,
+            db,
+            shards: self.database(db).kv.lock(keys),
+        };
+        let now = now_ms();
+        let expires = !self.replication.is_replica();
+        for key in keys {
+            let shard = write_keys.shards.shard(key);
+            if expires && shard.is_expired(key, now) {
+                shard.remove(key);
+                write_keys.touch(key);
+                write_keys.propagate(RespData::command(["DEL", key]));
+                self.stats.expired_keys.fetch_add(1, Ordering::Relaxed);
+            } else if let Some(meta) = shard.meta.get_mut(*key) {
+                record_access(meta, &self.eviction, now);
+            }
+        }
+        write_keys
+    }
+
+    /// Whether writes of keys of database `db` may share the state. Each write is made
+    /// durable before it is replied to with `appendfsync always`, which takes the state
+    /// exclusively, as does the first write to a database, which creates it.
+    pub fn shares_writes(&self, db: usize) -> bool {
+        !self.aof.syncs_always() && (db == self.selected || db < self.dbs.len())
+    }
+
+    /// Rough estimate of the bytes the dataset takes in memory
+    pub fn used_memory(&self) -> usize {
+        self.keys_memory.load(Ordering::Relaxed)
+    }
+
+    /// Rough estimate of the bytes `key` takes in memory, with its value and expiry,
+    /// collections estimated from `samples` elements as in [`Value::memory_usage`]
+    pub fn key_memory(&mut self, key: &str, samples: usize) -> usize {
+        self.db.kv.shard(key).key_memory(key, samples)
+    }
+
+    /// Bring the memory accounted for `key` up to date after it was written
+    pub fn account(&mut self, key: &str) {
+        let (previous, memory) = self.db.kv.shard(key).account(key);
+        self.add_memory(previous, memory);
+    }
+
+    /// Replace `previous` bytes of the memory accounted for keys with `memory`
+    fn add_memory(&self, previous: usize, memory: usize) {
+        let total = if memory >= previous {
+            self.keys_memory
+                .fetch_add(memory - previous, Ordering::Relaxed)
+                + memory
+                - previous
+        } else {
+            self.keys_memory
+                .fetch_sub(previous - memory, Ordering::Relaxed)
+                - (previous - memory)
+        };
+        self.stats.peak_memory.fetch_max(total, Ordering::Relaxed);
+    }
+
+    /// Record an access to `key` for the LRU and LFU eviction policies
+    pub fn access_key(&mut self, key: &str) {
+        if let Some(meta) = self.db.kv.shard(key).meta.get_mut(key) {
+            record_access(meta, &self.eviction, now_ms());
+        }
+    }
+
+    /// Remove `key` from the keyspace along with its expiry
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let removed = self.db.kv.remove(key);
+        if removed.is_some() {
+            self.account(key);
+            self.touch_key(key);
+        }
+        removed
+    }
+
+    /// Record a modification of `key` of the selected database, counting it as unsaved
+    /// and failing the transactions of clients watching it
+    pub fn touch_key(&self, key: &str) {
+        self.touch(self.selected, key);
+    }
+
+    /// Record a modification of `key` of database `db`
+    fn touch(&self, db: usize, key: &str) {
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+        if let Some(watched) = lock(&self.watched_keys).get_mut(&(db, key.to_string())) {
+            watched.version += 1;
+        }
+    }
+
+    /// Fail the transactions watching keys of database `index` that `before` or `after` hold,
+    /// as the database changes from one to the other
+    fn touch_replaced(&mut self, index: usize, before: &Db, after: &Db) {
+        let watched_keys = self
+            .watched_keys
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for ((db, key), watched) in watched_keys {
+            if *db == index && (before.kv.contains_key(key) || after.kv.contains_key(key)) {
+                watched.version += 1;
+            }
+        }
+    }
+
+    /// Remove every key of database `index`, returning them so they can be dropped elsewhere
+    pub fn flush_db(&mut self, index: usize) -> Db {
+        let db = self.take_db(index);
+        let memory: usize = db
+            .kv
+            .shards()
+            .map(|shard| shard.meta.values().map(|meta| meta.memory).sum::<usize>())
+            .sum();
+        self.add_memory(memory, 0);
+        *self.dirty.get_mut() += db.kv.len() as u64;
+        self.touch_replaced(index, &db, &EMPTY_DB);
+        db
+    }
+
+    /// Remove every key of every database, as when the dataset is replaced,
+    /// returning the databases so they can be dropped elsewhere
+    pub fn clear(&mut self) -> Vec<Db> {
+        (0..self.dbs.len().max(self.selected + 1))
+            .map(|index| self.flush_db(index))
+            .collect()
+    }
+
+    /// Exchange the keys of databases `a` and `b`. Clients stay on the database they
+    /// selected, so they see the keys of the other database from now on.
+    pub fn swap_dbs(&mut self, a: usize, b: usize) {
+        let db_a = self.take_db(a);
+        let db_b = self.take_db(b);
+        self.touch_replaced(a, &db_a, &db_b);
+        self.touch_replaced(b, &db_b, &db_a);
+        self.put_db(a, db_b);
+        self.put_db(b, db_a);
+        *self.dirty.get_mut() += 1;
+    }
+
+    /// Serve the clients blocked on keys of database `index`, after its keys changed at once
+    pub fn serve_blocked_db(&mut self, index: usize) {
+        let selected = self.selected;
+        self.select(index);
+        let keys: Vec<String> = self
+            .blocked_pops
+            .keys()
+            .filter(|(db, _)| *db == index)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in keys {
+            self.serve_blocked_pops(&key);
+        }
+        let prefix = stream_wait_key(index, "");
+        for (wait_key, wait_list) in &self.waiting_lists {
+            if wait_key.starts_with(&prefix) {
+                wait_list.signal.notify_waiters();
+            }
+        }
+        self.select(selected);
+    }
+
+    /// Record a write command in the form it should be replayed in,
+    /// for the AOF and the replicas
+    pub fn propagate(&mut self, command: RespData) {
+        // Commands of writes that shared the state came first
+        self.feed_pending();
+        self.propagate_to(self.selected, command);
+    }
+
+    fn propagate_to(&mut self, db: usize, command: RespData) {
+        // A replica only passes on the stream of its master
+        if self.replication.is_replica() {
+            return;
+        }
+        if self.atomic_depth > 0 {
+            self.atomic_commands.push((db, command));
+        } else {
+            self.feed_command(db, &command);
+        }
+    }
+
+    /// Propagate the commands of writes that shared the state, in the order they ran
+    pub fn feed_pending(&mut self) {
+        let pending = std::mem::take(
+            self.pending
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for (db, command) in pending {
+            self.propagate_to(db, command);
+        }
+    }
+
+    /// The SELECT the replication stream needs before a command of database `db`
+    fn stream_select(&mut self, db: usize) -> Option<RespData> {
+        if db == self.stream_db && !std::mem::take(&mut self.reselect) {
+            return None;
+        }
+        self.stream_db = db;
+        Some(RespData::command(["SELECT", &db.to_string()]))
+    }
+
+    /// Feed a command of database `db`, selecting it first if needed
+    fn feed_command(&mut self, db: usize, command: &RespData) {
+        if let Some(select) = self.stream_select(db) {
+            self.feed(&select.as_bytes());
+        }
+        self.feed(&command.as_bytes());
+    }
+
+    /// Record that a consumer joins the replication stream at database `db`,
+    /// or at a database it does not know with `None`
+    pub fn join_stream(&mut self, db: Option<usize>) {
+        if db != Some(self.stream_db) {
+            self.reselect = true;
+        }
+    }
+
+    /// Write bytes of the replication stream to the AOF and send them to the replicas
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.replication.feed(bytes);
+        self.aof.feed(bytes, self.replication.offset);
+    }
+
+    /// Start a transaction or script, whose commands are propagated once it ends
+    pub fn begin_atomic(&mut self) {
+        self.atomic_depth += 1;
+    }
+
+    /// End what [`AppState::begin_atomic`] started, propagating its commands
+    /// wrapped in MULTI/EXEC when there is more than one
+    pub fn end_atomic(&mut self) {
+        self.atomic_depth = self.atomic_depth.saturating_sub(1);
+        if self.atomic_depth > 0 {
+            return;
+        }
+        let mut commands = std::mem::take(&mut self.atomic_commands);
+        match commands.len() {
+            0 => {}
+            1 => {
+                let (db, command) = commands.pop().expect("one command");
+                self.feed_command(db, &command);
+            }
+            _ => {
+                // The transaction starts in the database of its first command,
+                // it only selects another one inside if its commands move between them
+                if let Some(select) = self.stream_select(commands[0].0) {
+                    self.feed(&select.as_bytes());
+                }
+                let mut wrapped = Vec::with_capacity(commands.len());
+                for (db, command) in commands {
+                    wrapped.extend(self.stream_select(db));
+                    wrapped.push(command);
+                }
+                self.feed(&aof::wrap_transaction(wrapped));
+            }
+        }
+    }
+
+    /// Start watching `key` of database `db`, returning its current version
+    pub fn watch(&mut self, db: usize, key: &str) -> u64 {
+        let watched_keys = self
+            .watched_keys
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let watched = watched_keys.entry((db, key.to_string())).or_default();
+        watched.watchers += 1;
+        watched.version
+    }
+
+    /// Stop watching a key previously watched with [`AppState::watch`]
+    pub fn unwatch(&mut self, db: usize, key: &str) {
+        let watched_keys = self
+            .watched_keys
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let watched_key = (db, key.to_string());
+        if let Some(watched) = watched_keys.get_mut(&watched_key) {
+            watched.watchers = watched.watchers.saturating_sub(1);
+            if watched.watchers == 0 {
+                watched_keys.remove(&watched_key);
+            }
+        }
+    }
+
+    /// The current version of a watched key
+    pub fn key_version(&self, db: usize, key: &str) -> u64 {
+        lock(&self.watched_keys)
+            .get(&(db, key.to_string()))
+            .map_or(0, |watched| watched.version)
+    }
+
+    /// Remove every key of the selected database whose expiry has passed,
+    /// returning the removed keys. Replicas leave them to the DEL of their master.
+    pub fn remove_expired(&mut self) -> Vec<String> {
+        if self.replication.is_replica() {
+            return Vec::new();
+        }
+        let expired = self.db.kv.pop_expired(now_ms());
+        for key in &expired {
+            self.db.kv.remove(key);
+            self.account(key);
+            self.touch_key(key);
+            self.propagate(RespData::command(["DEL", key.as_str()]));
+        }
+        *self.stats.expired_keys.get_mut() += expired.len() as u64;
+        expired
+    }
+
     pub fn prune_waiting_lists(&mut self) {
         self.waiting_lists.retain(|_, list| list.count > 0);
     }
+
+    /// Register a client blocked on `wait_key`, returning the signal to wait on
+    pub fn block_on(&mut self, wait_key: String) -> Arc<Notify> {
+        let wait_list = self.waiting_lists.entry(wait_key).or_default();
+        wait_list.count += 1;
+        wait_list.signal.clone()
+    }
+
+    /// Unregister a client previously registered with [`AppState::block_on`]
+    pub fn unblock(&mut self, wait_key: &str) {
+        if let Some(wait_list) = self.waiting_lists.get_mut(wait_key) {
+            wait_list.count = wait_list.count.saturating_sub(1);
+        }
+        self.prune_waiting_lists();
+    }
+
+    /// Register a client blocked popping from the list at `key` of the selected database,
+    /// returning the receiver its reply will be sent to
+    pub fn block_pop(
+        &mut self,
+        key: &str,
+        direction: PushPopDirection,
+    ) -> oneshot::Receiver<RespData> {
+        let (sender, receiver) = oneshot::channel();
+        self.blocked_pops
+            .entry((self.selected, key.to_string()))
+            .or_default()
+            .push_back(BlockedPop { direction, sender });
+        receiver
+    }
+
+    /// Hand the elements of the list at `key` to the clients blocked popping it,
+    /// longest waiting first, and forget clients that stopped waiting
+    pub fn serve_blocked_pops(&mut self, key: &str) {
+        let Some(blocked) = self.blocked_pops.get_mut(&(self.selected, key.to_string())) else {
+            return;
+        };
+        let mut served = Vec::new();
+        if let Some(Value::List(elements)) = self.db.kv.get_mut(key) {
+            let limits = &self.encoding;
+            while !elements.is_empty() {
+                let Some(pop) = blocked.pop_front() else {
+                    break;
+                };
+                if pop.sender.is_closed() {
+                    continue;
+                }
+                let value = match pop.direction {
+                    PushPopDirection::Left => elements.pop_front(limits),
+                    PushPopDirection::Right => elements.pop_back(limits),
+                }
+                .expect("list is not empty");
+                let reply = RespData::array(VecDeque::from([
+                    RespData::bulk_string(key),
+                    RespData::BulkString(Some(value)),
+                ]));
+                match pop.sender.send(reply) {
+                    Ok(()) => served.push(pop.direction),
+                    Err(RespData::Array(Some(mut reply))) => {
+                        // The client gave up in the meantime, the value goes back where it was
+                        if let Some(RespData::BulkString(Some(value))) = reply.pop_back() {
+                            match pop.direction {
+                                PushPopDirection::Left => elements.push_front(value, limits),
+                                PushPopDirection::Right => elements.push_back(value, limits),
+                            }
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            if elements.is_empty() {
+                self.remove(key);
+            }
+        }
+        // Replayed as the pops the blocked clients would have made
+        for direction in served {
+            let command = match direction {
+                PushPopDirection::Left => "LPOP",
+                PushPopDirection::Right => "RPOP",
+            };
+            self.propagate(RespData::command([command, key]));
+        }
+        self.prune_blocked_pops(key);
+    }
+
+    /// Forget the clients blocked on `key` of the selected database that stopped waiting
+    pub fn prune_blocked_pops(&mut self, key: &str) {
+        let blocked_key = (self.selected, key.to_string());
+        if let Some(blocked) = self.blocked_pops.get_mut(&blocked_key) {
+            blocked.retain(|pop| !pop.sender.is_closed());
+            if blocked.is_empty() {
+                self.blocked_pops.remove(&blocked_key);
+            }
+        }
+    }
+
+    /// Wake every client blocked on `wait_key`
+    pub fn notify_all(&self, wait_key: &str) {
+        if let Some(wait_list) = self.waiting_lists.get(wait_key) {
+            wait_list.signal.notify_waiters();
+        }
+    }
+}
+
+/// Update the access time and frequency of a key as it is accessed at `now`
+fn record_access(meta: &mut KeyMeta, eviction: &EvictionState, now: u64) {
+    let counter = evict::lfu_decayed(meta, eviction.lfu_decay_time, now);
+    meta.frequency = evict::lfu_increment(counter, eviction.lfu_log_factor);
+    meta.last_access = now;
+}
+
+/// The keys of a command that only reads them, as locked by [`AppState::read_keys`]
+pub struct ReadKeys<'a> {
+    shards: LockedShards<'a>,
+    stats: &'a Stats,
+    now: u64,
+}
+
+impl ReadKeys<'_> {
+    /// Look up `key` for reading, counting a keyspace hit or miss.
+    /// A key past its expiry reads as missing, removing it is left to the next write.
+    pub fn lookup(&self, key: &str) -> Option<&Value> {
+        let expired = self.shards.expiry(key).is_some_and(|at| at <= self.now);
+        let value = self.shards.get(key).filter(|_| !expired);
+        self.stats.count_lookup(value.is_some());
+        value
+    }
+}
+
+/// The keys of a command that writes them while sharing the state,
+/// as locked by [`AppState::write_keys`]
+pub struct WriteKeys<'a> {
+    state: &'a AppState,
+    db: usize,
+    shards: LockedShards<'a>,
+}
+
+impl<'a> WriteKeys<'a> {
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        self.shards.shard(key).kv.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.shards.shard(key).kv.get_mut(key)
+    }
+
+    pub fn get_or_insert_with(
+        &mut self,
+        key: String,
+        default: impl FnOnce() -> Value,
+    ) -> &mut Value {
+        self.shards.shard(&key).kv.get_or_insert_with(key, default)
+    }
+
+    /// Set the value of `key`, keeping its expiry
+    pub fn insert(&mut self, key: String, value: Value) {
+        self.shards.shard(&key).kv.insert(key, value);
+    }
+
+    /// Remove `key` along with its expiry, recording the modification
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let removed = self.shards.shard(key).remove(key);
+        if removed.is_some() {
+            self.touch(key);
+        }
+        removed
+    }
+
+    pub fn set_expiry(&mut self, key: &str, at: u64) {
+        self.shards.shard(key).set_expiry(key, at);
+    }
+
+    pub fn clear_expiry(&mut self, key: &str) -> bool {
+        self.shards.shard(key).clear_expiry(key)
+    }
+
+    /// Record a modification of `key`, as [`AppState::touch_key`] does
+    pub fn touch(&self, key: &str) {
+        self.state.touch(self.db, key);
+    }
+
+    /// Queue a command to be propagated once the state is next held exclusively.
+    /// Commands of the same key are queued in the order they ran, as their shard is locked.
+    pub fn propagate(&self, command: RespData) {
+        if self.state.replication.is_replica() {
+            return;
+        }
+        lock(&self.state.pending).push((self.db, command));
+        self.state.pending_signal.notify_one();
+    }
+
+    /// Wake the clients blocked reading the stream at `key`
+    pub fn notify_stream(&self, key: &str) {
+        self.state.notify_all(&stream_wait_key(self.db, key));
+    }
+
+    pub fn encoding(&self) -> &'a EncodingLimits {
+        &self.state.encoding
+    }
+
+    /// Bring the memory accounted for `keys` up to date after they were written
+    pub fn account(&mut self, keys: &[&str]) {
+        for key in keys {
+            let (previous, memory) = self.shards.shard(key).account(key);
+            self.state.add_memory(previous, memory);
+        }
+    }
+}
+
+/// Feed the commands of writes that shared the state to the replication stream
+/// soon after they ran, when no command holding the state exclusively did it first
+pub async fn propagation_cycle(state: State) {
+    let signal = state.read().await.pending_signal.clone();
+    loop {
+        signal.notified().await;
+        drop(state.write().await);
+    }
+}
+
+/// Periodically remove expired keys of every database, so keys nobody accesses again
+/// do not linger, and move on the rehashing of their keyspaces with `activerehashing`
+pub async fn database_cycle(state: State) {
+    let mut interval = interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        let mut state = state.write().await;
+        let selected = state.selected;
+        for index in 0..state.dbs.len().max(selected + 1) {
+            state.select(index);
+            state.remove_expired();
+        }
+        state.select(selected);
+        if state.config.activerehashing {
+            // A single budget for every database, spent on those resizing a table
+            let deadline = Instant::now() + REHASH_BUDGET;
+            let state = &mut *state;
+            for db in std::iter::once(&mut state.db).chain(&mut state.dbs) {
+                if db.kv.is_rehashing() && !db.kv.rehash(deadline) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Wait until any of the `notified` futures completes, or until `duration` elapses.
+/// `None` waits indefinitely. Returns `false` if the wait timed out.
+///
+/// The futures must be created and enabled while the state lock is still held,
+/// so that no notification is missed between releasing the lock and waiting.
+pub async fn wait_any(
+    mut notified: Vec<Pin<Box<Notified<'_>>>>,
+    duration: Option<Duration>,
+) -> bool {
+    let any = poll_fn(|cx| {
+        if notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+    match duration {
+        Some(duration) => timeout(duration, any).await.is_ok(),
+        None => {
+            any.await;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::MasterLink;
+
+    #[test]
+    fn test_watched_key_versions() {
+        let mut state = AppState::default();
+        state.touch_key("unwatched");
+        assert!(state.watched_keys.get_mut().unwrap().is_empty());
+
+        let version = state.watch(0, "k");
+        assert_eq!(state.watch(0, "k"), version);
+        state
+            .db
+            .kv
+            .insert("k".to_string(), Value::String(b"v".to_vec()));
+        state.touch_key("k");
+        assert_ne!(state.key_version(0, "k"), version);
+
+        // Expiring a key counts as a modification
+        let version = state.key_version(0, "k");
+        state.set_expiry("k", now_ms() - 1);
+        state.remove_expired();
+        assert!(state.db.kv.is_empty() && state.db.kv.volatile_len() == 0);
+        assert_ne!(state.key_version(0, "k"), version);
+
+        state.unwatch(0, "k");
+        let watched_keys = state.watched_keys.get_mut().unwrap();
+        assert!(watched_keys.contains_key(&(0, "k".to_string())));
+        state.unwatch(0, "k");
+        assert!(state.watched_keys.get_mut().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expiry_propagation() {
+        let mut state = AppState::default();
+        for key in ["a", "b"] {
+            state
+                .db
+                .kv
+                .insert(key.to_string(), Value::String(b"v".to_vec()));
+            state.set_expiry(key, now_ms() - 1);
+        }
+        let propagated = |commands: &[(usize, RespData)]| -> Vec<String> {
+            commands
+                .iter()
+                .map(|(db, command)| format!("{db} {command}"))
+                .collect()
+        };
+        let del = |key| format!("0 {}", RespData::command(["DEL", key]));
+        drop(state.write_keys(0, &["a"]));
+        assert_eq!(propagated(state.pending.get_mut().unwrap()), [del("a")]);
+        state.begin_atomic();
+        state.remove_expired();
+        assert_eq!(propagated(&state.atomic_commands), [del("a"), del("b")]);
+
+        // Replicas leave expired keys to the DEL of their master
+        let mut state = AppState::default();
+        state.replication.master = Some(MasterLink {
+            host: "localhost".to_string(),
+            port: 6379,
+            link_up: true,
+        });
+        state
+            .db
+            .kv
+            .insert("k".to_string(), Value::String(b"v".to_vec()));
+        state.set_expiry("k", now_ms() - 1);
+        drop(state.write_keys(0, &["k"]));
+        assert!(state.remove_expired().is_empty());
+        assert!(state.db.kv.contains_key("k"));
+    }
+
+    #[test]
+    fn test_databases() {
+        let mut state = AppState::default();
+        state.select(2);
+        state
+            .db
+            .kv
+            .insert("k".to_string(), Value::String(b"2".to_vec()));
+        state.account("k");
+        state.set_expiry("k", u64::MAX);
+        state.select(0);
+        assert!(state.db.kv.is_empty());
+        let memory = state.used_memory();
+        assert!(memory > 0);
+        assert_eq!(
+            state
+                .databases()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>(),
+            [2]
+        );
+
+        // Swapping fails the transactions watching keys on both sides
+        let version = state.watch(5, "k");
+        state.swap_dbs(2, 5);
+        assert_ne!(state.key_version(5, "k"), version);
+        assert!(state.database(2).kv.is_empty());
+        assert!(state.database(5).kv.expiry("k").is_some());
+        state.select(5);
+        assert!(state.db.kv.contains_key("k"));
+
+        let flushed = state.flush_db(5);
+        assert_eq!(flushed.kv.len(), 1);
+        assert!(state.db.kv.is_empty() && state.databases().next().is_none());
+        assert_eq!(state.used_memory(), 0);
+    }
 }
@@ -0,0 +1,882 @@
+use anyhow::{bail, Context};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Display,
+    str::FromStr,
+};
+
+use crate::{resp::RespData, state::now_ms};
+
+/// A stream entry ID, `<milliseconds>-<sequence>`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: Self = Self { ms: 0, seq: 0 };
+    pub const MAX: Self = Self {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// The smallest ID strictly greater than this one, if any
+    pub fn next(self) -> Option<Self> {
+        if self.seq < u64::MAX {
+            Some(Self::new(self.ms, self.seq + 1))
+        } else if self.ms < u64::MAX {
+            Some(Self::new(self.ms + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// The largest ID strictly smaller than this one, if any
+    pub fn prev(self) -> Option<Self> {
+        if self.seq > 0 {
+            Some(Self::new(self.ms, self.seq - 1))
+        } else if self.ms > 0 {
+            Some(Self::new(self.ms - 1, u64::MAX))
+        } else {
+            None
+        }
+    }
+
+    /// Parse a range bound where the sequence part may be omitted,
+    /// in which case it defaults to `default_seq`
+    pub fn parse_bound(s: &str, default_seq: u64) -> anyhow::Result<Self> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Ok(Self::new(
+                ms.parse().context("Invalid stream ID")?,
+                seq.parse().context("Invalid stream ID")?,
+            )),
+            None => Ok(Self::new(
+                s.parse().context("Invalid stream ID")?,
+                default_seq,
+            )),
+        }
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_bound(s, 0)
+    }
+}
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID requested by `XADD`, which may be partially or fully auto-generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamIdRequest {
+    /// `*`
+    Auto,
+    /// `<ms>-*`
+    AutoSeq(u64),
+    /// `<ms>-<seq>`
+    Explicit(StreamId),
+}
+
+impl FromStr for StreamIdRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::Auto);
+        }
+        match s.split_once('-') {
+            Some((ms, "*")) => Ok(Self::AutoSeq(ms.parse().context("Invalid stream ID")?)),
+            _ => Ok(Self::Explicit(s.parse()?)),
+        }
+    }
+}
+
+/// Where `XREAD` should start reading a stream from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamReadFrom {
+    /// Entries strictly after this ID
+    After(StreamId),
+    /// `$`: only entries added after the command started
+    New,
+    /// `+`: the last entry of the stream
+    Last,
+}
+
+impl FromStr for StreamReadFrom {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "$" => Ok(Self::New),
+            "+" => Ok(Self::Last),
+            _ => Ok(Self::After(s.parse()?)),
+        }
+    }
+}
+
+/// Where `XREADGROUP` should read a stream from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupReadFrom {
+    /// `>`: entries never delivered to any consumer of the group
+    New,
+    /// The consumer's own pending entries strictly after this ID
+    Pending(StreamId),
+}
+
+impl FromStr for GroupReadFrom {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ">" => Ok(Self::New),
+            _ => Ok(Self::Pending(s.parse()?)),
+        }
+    }
+}
+
+/// The optional extended form of `XPENDING`
+#[derive(Debug, Clone)]
+pub struct PendingFilter {
+    pub min_idle: u64,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+/// Options of `XCLAIM`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClaimOptions {
+    /// Set the idle time of claimed entries, in milliseconds
+    pub idle: Option<u64>,
+    /// Set the delivery time of claimed entries, in milliseconds since the UNIX epoch
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    /// Create pending entries for IDs that exist in the stream but are not pending
+    pub force: bool,
+    /// Reply with IDs only, without incrementing the delivery count
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+pub type StreamFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An entry delivered to a consumer but not yet acknowledged
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Last delivery time, in milliseconds since the UNIX epoch
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Consumer {
+    /// The consumer's own pending entry list, a subset of the group's
+    pub pending: BTreeSet<StreamId>,
+    /// Last interaction with the consumer, in milliseconds since the UNIX epoch
+    pub seen_time: u64,
+    /// Last successful read or claim, in milliseconds since the UNIX epoch
+    pub active_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_delivered_id: StreamId,
+    /// Logical count of entries read by the group, unknown after an arbitrary `SETID`
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    /// The consumer named `name`, created if needed, marked as seen at `now`
+    pub fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_default();
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Acknowledge a pending entry, returns `false` if it was not pending
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Assign a pending entry to `consumer`, creating it if it was not pending
+    pub fn assign(
+        &mut self,
+        id: StreamId,
+        consumer: &str,
+        delivery_time: u64,
+        delivery_count: u64,
+    ) {
+        let previous = self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count,
+            },
+        );
+        if let Some(owner) = previous.and_then(|p| self.consumers.get_mut(&p.consumer)) {
+            owner.pending.remove(&id);
+        }
+        self.consumers
+            .entry(consumer.to_string())
+            .or_default()
+            .pending
+            .insert(id);
+    }
+
+    /// Remove a consumer and its pending entries, returning how many were pending
+    pub fn remove_consumer(&mut self, name: &str) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+}
+
+impl ConsumerGroup {
+    /// The `XINFO CONSUMERS` reply
+    pub fn info_consumers(&self, now: u64) -> RespData {
+        let consumers = self
+            .consumers
+            .iter()
+            .map(|(name, consumer)| {
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string("name"),
+                    RespData::bulk_string(name),
+                    RespData::bulk_string("pending"),
+                    RespData::Integer(consumer.pending.len() as i64),
+                    RespData::bulk_string("idle"),
+                    RespData::Integer(now.saturating_sub(consumer.seen_time) as i64),
+                    RespData::bulk_string("inactive"),
+                    RespData::Integer(
+                        consumer
+                            .active_time
+                            .map_or(-1, |active| now.saturating_sub(active) as i64),
+                    ),
+                ]))
+            })
+            .collect();
+        RespData::array(consumers)
+    }
+
+    /// The summary form of the `XPENDING` reply
+    pub fn pending_summary(&self) -> RespData {
+        let (Some((first, _)), Some((last, _))) = (
+            self.pending.first_key_value(),
+            self.pending.last_key_value(),
+        ) else {
+            return RespData::array(VecDeque::from([
+                RespData::Integer(0),
+                RespData::null_bulk_string(),
+                RespData::null_bulk_string(),
+                RespData::Array(None),
+            ]));
+        };
+        let consumers = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| !consumer.pending.is_empty())
+            .map(|(name, consumer)| {
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string(name),
+                    RespData::bulk_string(consumer.pending.len().to_string()),
+                ]))
+            })
+            .collect();
+        RespData::array(VecDeque::from([
+            RespData::Integer(self.pending.len() as i64),
+            RespData::bulk_string(first.to_string()),
+            RespData::bulk_string(last.to_string()),
+            RespData::array(consumers),
+        ]))
+    }
+
+    /// The extended form of the `XPENDING` reply
+    pub fn pending_range(&self, filter: &PendingFilter, now: u64) -> RespData {
+        if filter.start > filter.end {
+            return RespData::array(VecDeque::new());
+        }
+        let entries = self
+            .pending
+            .range(filter.start..=filter.end)
+            .filter(|(_, entry)| {
+                filter
+                    .consumer
+                    .as_ref()
+                    .is_none_or(|consumer| consumer == &entry.consumer)
+            })
+            .filter(|(_, entry)| now.saturating_sub(entry.delivery_time) >= filter.min_idle)
+            .take(filter.count)
+            .map(|(id, entry)| {
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string(id.to_string()),
+                    RespData::bulk_string(&entry.consumer),
+                    RespData::Integer(now.saturating_sub(entry.delivery_time) as i64),
+                    RespData::Integer(entry.delivery_count as i64),
+                ]))
+            })
+            .collect();
+        RespData::array(entries)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, StreamFields>,
+    pub last_id: StreamId,
+    /// Count of all entries ever added to the stream
+    pub entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    /// Resolve the requested ID against the stream top item, validating it
+    pub fn next_id(&self, request: StreamIdRequest) -> anyhow::Result<StreamId> {
+        let id = match request {
+            StreamIdRequest::Auto => {
+                let ms = now_ms().max(self.last_id.ms);
+                if ms == self.last_id.ms {
+                    self.last_id.next().context("Stream ID space exhausted")?
+                } else {
+                    StreamId::new(ms, 0)
+                }
+            }
+            StreamIdRequest::AutoSeq(ms) if ms == self.last_id.ms => {
+                self.last_id.next().context("Stream ID space exhausted")?
+            }
+            StreamIdRequest::AutoSeq(ms) => StreamId::new(ms, u64::from(ms == 0)),
+            StreamIdRequest::Explicit(id) => id,
+        };
+        if id == StreamId::MIN {
+            bail!("The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= self.last_id {
+            bail!("The ID specified in XADD is equal or smaller than the target stream top item");
+        }
+        Ok(id)
+    }
+
+    pub fn add(&mut self, id: StreamId, fields: StreamFields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Resolve a group starting point, `None` meaning `$`, to an ID and its entries-read counter
+    fn group_start(
+        &self,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    ) -> (StreamId, Option<u64>) {
+        let id = id.unwrap_or(self.last_id);
+        let entries_read = entries_read.or(if id == StreamId::MIN {
+            Some(0)
+        } else if id >= self.last_id {
+            Some(self.entries_added)
+        } else {
+            None
+        });
+        (id, entries_read)
+    }
+
+    /// Create a consumer group, returns `false` if it already exists
+    pub fn create_group(
+        &mut self,
+        name: String,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let (last_delivered_id, entries_read) = self.group_start(id, entries_read);
+        self.groups.insert(
+            name,
+            ConsumerGroup {
+                last_delivered_id,
+                entries_read,
+                ..ConsumerGroup::default()
+            },
+        );
+        true
+    }
+
+    /// Move the last delivered ID of a group, returns `false` if there is no such group
+    pub fn set_group_id(
+        &mut self,
+        name: &str,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    ) -> bool {
+        let (last_delivered_id, entries_read) = self.group_start(id, entries_read);
+        let Some(group) = self.groups.get_mut(name) else {
+            return false;
+        };
+        group.last_delivered_id = last_delivered_id;
+        group.entries_read = entries_read;
+        true
+    }
+
+    /// Number of entries not yet delivered to the group
+    pub fn lag(&self, group: &ConsumerGroup) -> u64 {
+        match group.entries_read {
+            Some(read) => self.entries_added.saturating_sub(read),
+            None => self.after(group.last_delivered_id, None).len() as u64,
+        }
+    }
+
+    /// Read entries for `consumer` of `group`, either new entries (which are then
+    /// added to the pending entries list unless `no_ack`) or the consumer's own
+    /// pending entries. Entries no longer in the stream are returned without fields.
+    /// Returns `None` if the group does not exist.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        from: GroupReadFrom,
+        count: Option<usize>,
+        no_ack: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(group)?;
+        let count = count.unwrap_or(usize::MAX);
+        let entries: Vec<_> = match from {
+            GroupReadFrom::New => {
+                let entries: Vec<_> = group
+                    .last_delivered_id
+                    .next()
+                    .map(|start| {
+                        self.entries
+                            .range(start..)
+                            .take(count)
+                            .map(|(id, fields)| (*id, Some(fields.clone())))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                for (id, _) in &entries {
+                    group.last_delivered_id = *id;
+                    group.entries_read = group.entries_read.map(|read| read + 1);
+                    if !no_ack {
+                        group.assign(*id, consumer, now, 1);
+                    }
+                }
+                entries
+            }
+            GroupReadFrom::Pending(after) => {
+                let pending = &group.consumer(consumer, now).pending;
+                after
+                    .next()
+                    .map(|start| {
+                        pending
+                            .range(start..)
+                            .take(count)
+                            .map(|id| (*id, self.entries.get(id).cloned()))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            }
+        };
+        let consumer = group.consumer(consumer, now);
+        if !entries.is_empty() {
+            consumer.active_time = Some(now);
+        }
+        Some(entries)
+    }
+
+    /// Transfer ownership of pending entries to `consumer` (`XCLAIM`).
+    /// Returns `None` if the group does not exist.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        if let Some(last_id) = options.last_id {
+            group.last_delivered_id = group.last_delivered_id.max(last_id);
+        }
+        let delivery_time = options
+            .time
+            .or_else(|| options.idle.map(|idle| now.saturating_sub(idle)))
+            .unwrap_or(now);
+        let mut claimed = Vec::new();
+        for id in ids {
+            let Some(fields) = self.entries.get(id) else {
+                // The entry was deleted from the stream, it can never be delivered again
+                group.ack(*id);
+                continue;
+            };
+            let delivery_count = match group.pending.get(id) {
+                Some(entry) if now.saturating_sub(entry.delivery_time) < min_idle => continue,
+                Some(entry) => entry.delivery_count,
+                None if options.force => 0,
+                None => continue,
+            };
+            let delivery_count = options
+                .retry_count
+                .unwrap_or(delivery_count + u64::from(!options.just_id));
+            group.assign(*id, consumer, delivery_time, delivery_count);
+            claimed.push((*id, fields.clone()));
+        }
+        if !claimed.is_empty() {
+            group.consumer(consumer, now).active_time = Some(now);
+        }
+        Some(claimed)
+    }
+
+    /// Claim up to `count` pending entries idle for at least `min_idle` milliseconds,
+    /// scanning the pending entries list from `start` (`XAUTOCLAIM`).
+    /// Returns the cursor to continue from (`0-0` when done), the claimed entries and
+    /// the IDs of pending entries no longer in the stream, or `None` if the group does not exist.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    pub fn auto_claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+        now: u64,
+    ) -> Option<(StreamId, Vec<(StreamId, StreamFields)>, Vec<StreamId>)> {
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        let mut attempts = count.saturating_mul(10);
+        let mut cursor = StreamId::MIN;
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let candidates: Vec<(StreamId, u64, u64)> = group
+            .pending
+            .range(start..)
+            .map(|(id, entry)| (*id, entry.delivery_time, entry.delivery_count))
+            .collect();
+        for (id, delivery_time, delivery_count) in candidates {
+            if attempts == 0 || claimed.len() == count {
+                cursor = id;
+                break;
+            }
+            attempts -= 1;
+            let Some(fields) = self.entries.get(&id) else {
+                group.ack(id);
+                deleted.push(id);
+                continue;
+            };
+            if now.saturating_sub(delivery_time) < min_idle {
+                continue;
+            }
+            group.assign(id, consumer, now, delivery_count + u64::from(!just_id));
+            claimed.push((id, fields.clone()));
+        }
+        if !claimed.is_empty() {
+            group.consumer(consumer, now).active_time = Some(now);
+        }
+        Some((cursor, claimed, deleted))
+    }
+
+    /// The `XINFO STREAM` reply, `full` being the `COUNT` of the `FULL` form (0 for all entries)
+    pub fn info(&self, full: Option<usize>) -> RespData {
+        let first_id = self
+            .entries
+            .first_key_value()
+            .map_or(StreamId::MIN, |(id, _)| *id);
+        let mut info = VecDeque::from([
+            RespData::bulk_string("length"),
+            RespData::Integer(self.entries.len() as i64),
+            RespData::bulk_string("last-generated-id"),
+            RespData::bulk_string(self.last_id.to_string()),
+            RespData::bulk_string("max-deleted-entry-id"),
+            RespData::bulk_string(StreamId::MIN.to_string()),
+            RespData::bulk_string("entries-added"),
+            RespData::Integer(self.entries_added as i64),
+            RespData::bulk_string("recorded-first-entry-id"),
+            RespData::bulk_string(first_id.to_string()),
+        ]);
+        let Some(count) = full else {
+            let first = self.entries.first_key_value();
+            let last = self.last_entry();
+            info.extend([
+                RespData::bulk_string("groups"),
+                RespData::Integer(self.groups.len() as i64),
+                RespData::bulk_string("first-entry"),
+                first.map_or(RespData::null_bulk_string(), |(id, fields)| {
+                    entry_to_resp(*id, Some(fields))
+                }),
+                RespData::bulk_string("last-entry"),
+                last.map_or(RespData::null_bulk_string(), |(id, fields)| {
+                    entry_to_resp(id, Some(fields))
+                }),
+            ]);
+            return RespData::array(info);
+        };
+        let count = (count > 0).then_some(count);
+        let groups = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let pending = group
+                    .pending
+                    .iter()
+                    .take(count.unwrap_or(usize::MAX))
+                    .map(|(id, entry)| {
+                        RespData::array(VecDeque::from([
+                            RespData::bulk_string(id.to_string()),
+                            RespData::bulk_string(&entry.consumer),
+                            RespData::Integer(entry.delivery_time as i64),
+                            RespData::Integer(entry.delivery_count as i64),
+                        ]))
+                    })
+                    .collect();
+                let consumers = group
+                    .consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        let pending = consumer
+                            .pending
+                            .iter()
+                            .take(count.unwrap_or(usize::MAX))
+                            .filter_map(|id| {
+                                let entry = group.pending.get(id)?;
+                                Some(RespData::array(VecDeque::from([
+                                    RespData::bulk_string(id.to_string()),
+                                    RespData::Integer(entry.delivery_time as i64),
+                                    RespData::Integer(entry.delivery_count as i64),
+                                ])))
+                            })
+                            .collect();
+                        RespData::array(VecDeque::from([
+                            RespData::bulk_string("name"),
+                            RespData::bulk_string(name),
+                            RespData::bulk_string("seen-time"),
+                            RespData::Integer(consumer.seen_time as i64),
+                            RespData::bulk_string("active-time"),
+                            RespData::Integer(consumer.active_time.map_or(-1, |t| t as i64)),
+                            RespData::bulk_string("pel-count"),
+                            RespData::Integer(consumer.pending.len() as i64),
+                            RespData::bulk_string("pending"),
+                            RespData::array(pending),
+                        ]))
+                    })
+                    .collect();
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string("name"),
+                    RespData::bulk_string(name),
+                    RespData::bulk_string("last-delivered-id"),
+                    RespData::bulk_string(group.last_delivered_id.to_string()),
+                    RespData::bulk_string("entries-read"),
+                    group
+                        .entries_read
+                        .map_or(RespData::null_bulk_string(), |read| {
+                            RespData::Integer(read as i64)
+                        }),
+                    RespData::bulk_string("lag"),
+                    RespData::Integer(self.lag(group) as i64),
+                    RespData::bulk_string("pel-count"),
+                    RespData::Integer(group.pending.len() as i64),
+                    RespData::bulk_string("pending"),
+                    RespData::array(pending),
+                    RespData::bulk_string("consumers"),
+                    RespData::array(consumers),
+                ]))
+            })
+            .collect();
+        info.extend([
+            RespData::bulk_string("entries"),
+            entries_to_resp(&self.range(StreamId::MIN, StreamId::MAX, count)),
+            RespData::bulk_string("groups"),
+            RespData::array(groups),
+        ]);
+        RespData::array(info)
+    }
+
+    /// The `XINFO GROUPS` reply
+    pub fn info_groups(&self) -> RespData {
+        let groups = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                RespData::array(VecDeque::from([
+                    RespData::bulk_string("name"),
+                    RespData::bulk_string(name),
+                    RespData::bulk_string("consumers"),
+                    RespData::Integer(group.consumers.len() as i64),
+                    RespData::bulk_string("pending"),
+                    RespData::Integer(group.pending.len() as i64),
+                    RespData::bulk_string("last-delivered-id"),
+                    RespData::bulk_string(group.last_delivered_id.to_string()),
+                    RespData::bulk_string("entries-read"),
+                    group
+                        .entries_read
+                        .map_or(RespData::null_bulk_string(), |read| {
+                            RespData::Integer(read as i64)
+                        }),
+                    RespData::bulk_string("lag"),
+                    RespData::Integer(self.lag(group) as i64),
+                ]))
+            })
+            .collect();
+        RespData::array(groups)
+    }
+
+    /// Entries in the inclusive range `start..=end`, up to `count` entries
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, &StreamFields)> {
+        if start > end {
+            return Vec::new();
+        }
+        self.entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields))
+            .collect()
+    }
+
+    /// Entries strictly after `id`, up to `count` entries
+    pub fn after(&self, id: StreamId, count: Option<usize>) -> Vec<(StreamId, &StreamFields)> {
+        id.next()
+            .map(|start| self.range(start, StreamId::MAX, count))
+            .unwrap_or_default()
+    }
+
+    pub fn last_entry(&self) -> Option<(StreamId, &StreamFields)> {
+        self.entries
+            .last_key_value()
+            .map(|(id, fields)| (*id, fields))
+    }
+}
+
+/// Serialize a stream entry as `[id, [field, value, ...]]`, or `[id, nil]` for a deleted entry
+pub fn entry_to_resp(id: StreamId, fields: Option<&StreamFields>) -> RespData {
+    let fields = fields.map_or(RespData::Array(None), |fields| {
+        let fields = fields
+            .iter()
+            .flat_map(|(field, value)| {
+                [
+                    RespData::BulkString(Some(field.clone())),
+                    RespData::BulkString(Some(value.clone())),
+                ]
+            })
+            .collect();
+        RespData::array(fields)
+    });
+    RespData::array(VecDeque::from([
+        RespData::bulk_string(id.to_string()),
+        fields,
+    ]))
+}
+
+/// Serialize stream entries as `[[id, [field, value, ...]], ...]`
+pub fn entries_to_resp(entries: &[(StreamId, &StreamFields)]) -> RespData {
+    let entries = entries
+        .iter()
+        .map(|(id, fields)| entry_to_resp(*id, Some(fields)))
+        .collect();
+    RespData::array(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stream_id() {
+        assert_eq!("5-3".parse::<StreamId>().unwrap(), StreamId::new(5, 3));
+        assert_eq!("5".parse::<StreamId>().unwrap(), StreamId::new(5, 0));
+        assert_eq!(
+            StreamId::parse_bound("5", u64::MAX).unwrap(),
+            StreamId::new(5, u64::MAX)
+        );
+        assert!("5-x".parse::<StreamId>().is_err());
+    }
+
+    #[test]
+    fn test_next_id() {
+        let mut stream = Stream::default();
+        assert_eq!(
+            stream.next_id(StreamIdRequest::AutoSeq(0)).unwrap(),
+            StreamId::new(0, 1)
+        );
+        assert!(stream
+            .next_id(StreamIdRequest::Explicit(StreamId::MIN))
+            .is_err());
+        stream.add(StreamId::new(5, 2), Vec::new());
+        assert_eq!(
+            stream.next_id(StreamIdRequest::AutoSeq(5)).unwrap(),
+            StreamId::new(5, 3)
+        );
+        assert!(stream
+            .next_id(StreamIdRequest::Explicit(StreamId::new(5, 2)))
+            .is_err());
+        assert!(stream.next_id(StreamIdRequest::Auto).unwrap() > StreamId::new(5, 2));
+    }
+
+    #[test]
+    fn test_consumer_group() {
+        let mut stream = Stream::default();
+        for seq in 1..=3 {
+            stream.add(StreamId::new(1, seq), Vec::new());
+        }
+        assert!(stream.create_group("g".to_string(), Some(StreamId::MIN), None));
+        assert!(!stream.create_group("g".to_string(), None, None));
+
+        let read = stream
+            .read_group("g", "alice", GroupReadFrom::New, Some(2), false, 100)
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(stream.lag(&stream.groups["g"]), 1);
+        let history = stream
+            .read_group(
+                "g",
+                "alice",
+                GroupReadFrom::Pending(StreamId::MIN),
+                None,
+                false,
+                100,
+            )
+            .unwrap();
+        assert_eq!(history.len(), 2);
+
+        let group = stream.groups.get_mut("g").unwrap();
+        assert!(group.ack(StreamId::new(1, 1)));
+        assert!(!group.ack(StreamId::new(1, 1)));
+
+        let options = ClaimOptions::default();
+        let claimed = stream
+            .claim("g", "bob", 50, &[StreamId::new(1, 2)], &options, 200)
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+        let group = &stream.groups["g"];
+        assert_eq!(group.pending[&StreamId::new(1, 2)].delivery_count, 2);
+        assert!(group.consumers["alice"].pending.is_empty());
+        assert_eq!(group.consumers["bob"].pending.len(), 1);
+    }
+}
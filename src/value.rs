@@ -0,0 +1,660 @@
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeSet};
+
+use crate::{
+    dict::Dict,
+    intset::Intset,
+    listpack::{parse_int, Element, Listpack},
+    quicklist::Quicklist,
+    resp::RespData,
+    stream::Stream,
+};
+
+/// Allocation and bookkeeping cost of each element in a collection
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// A value stored in the keyspace
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Vec<u8>),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The type name as reported by the `TYPE` command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// The internal representation as reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(value) => string_encoding(value),
+            Value::List(List::Listpack(_))
+            | Value::Hash(Hash::Listpack(_))
+            | Value::Set(Set::Listpack(_))
+            | Value::SortedSet(SortedSet::Listpack(_)) => "listpack",
+            Value::List(List::Quicklist(_)) => "quicklist",
+            Value::Hash(Hash::Hashtable(_)) | Value::Set(Set::Hashtable(_)) => "hashtable",
+            Value::Set(Set::Intset(_)) => "intset",
+            Value::SortedSet(SortedSet::Skiplist(_)) => "skiplist",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Rough estimate of the bytes the value takes in memory. Compact encodings are measured
+    /// exactly, other collections are extrapolated from their first `samples` elements,
+    /// or measured entirely when it is 0.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Value::String(value) => value.len(),
+            Value::List(List::Listpack(listpack))
+            | Value::Hash(Hash::Listpack(listpack))
+            | Value::Set(Set::Listpack(listpack))
+            | Value::SortedSet(SortedSet::Listpack(listpack)) => listpack.as_bytes().len(),
+            Value::Set(Set::Intset(intset)) => intset.as_bytes().len(),
+            Value::List(List::Quicklist(quicklist)) => quicklist.memory_usage(),
+            Value::Hash(Hash::Hashtable(hash)) => {
+                sampled(hash.len(), hash.iter(), samples, |(field, value)| {
+                    field.len() + value.len() + 2 * ELEMENT_OVERHEAD
+                })
+            }
+            Value::Set(Set::Hashtable(set)) => sampled(set.len(), set.keys(), samples, |member| {
+                member.len() + ELEMENT_OVERHEAD
+            }),
+            // Each member is kept twice, by name and by score
+            Value::SortedSet(SortedSet::Skiplist(zset)) => {
+                sampled(zset.len(), zset.iter(), samples, |(member, _)| {
+                    2 * (member.len() + ELEMENT_OVERHEAD + 8)
+                })
+            }
+            Value::Stream(stream) => sampled(
+                stream.entries.len(),
+                stream.entries.values(),
+                samples,
+                |fields| {
+                    fields
+                        .iter()
+                        .map(|(field, value)| field.len() + value.len() + 2 * ELEMENT_OVERHEAD)
+                        .sum::<usize>()
+                        + ELEMENT_OVERHEAD
+                },
+            ),
+        }
+    }
+}
+
+/// Redis keeps strings holding an integer as a number, and embeds short strings
+/// in the same allocation as their header
+fn string_encoding(value: &[u8]) -> &'static str {
+    // Longest string embedded in its header
+    const EMBSTR_SIZE_LIMIT: usize = 44;
+    if parse_int(value).is_some() {
+        "int"
+    } else if value.len() <= EMBSTR_SIZE_LIMIT {
+        "embstr"
+    } else {
+        "raw"
+    }
+}
+
+/// The total `size` of `len` elements, extrapolated from the first `samples` of them
+/// unless `samples` is 0
+fn sampled<T>(
+    len: usize,
+    elements: impl Iterator<Item = T>,
+    samples: usize,
+    size: impl Fn(T) -> usize,
+) -> usize {
+    if samples == 0 || len <= samples {
+        return elements.map(size).sum();
+    }
+    elements.take(samples).map(size).sum::<usize>() * len / samples
+}
+
+/// A score ordered with [`f64::total_cmp`], so it can be used in ordered collections
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Thresholds under which collections keep a compact encoding,
+/// set by the `*-max-listpack-*`, `set-max-intset-entries` and `list-compress-depth` parameters
+#[derive(Debug, Clone, Copy)]
+pub struct EncodingLimits {
+    /// Elements of a list listpack when positive, otherwise its size from 4kb for -1 to 64kb for -5
+    pub list_max_listpack_size: i64,
+    /// Nodes at either end of a quicklist left uncompressed, 0 to compress none
+    pub list_compress_depth: usize,
+    pub hash_max_listpack_entries: usize,
+    /// Longest field or value of a hash listpack
+    pub hash_max_listpack_value: usize,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+}
+
+impl Default for EncodingLimits {
+    fn default() -> Self {
+        Self {
+            list_max_listpack_size: -2,
+            list_compress_depth: 0,
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+        }
+    }
+}
+
+impl EncodingLimits {
+    /// Whether a list of `len` elements taking `bytes` fits in a listpack.
+    /// Shrinking lists go back to one only once they fit in half of it, so a list
+    /// around the limit is not converted back and forth.
+    pub fn list_fits(&self, len: usize, bytes: usize, shrinking: bool) -> bool {
+        // Listpacks limited by their elements are kept under 8kb all the same
+        const SIZE_SAFETY_LIMIT: usize = 8192;
+        let (max_len, max_bytes) = match usize::try_from(self.list_max_listpack_size) {
+            Ok(max_len) => (max_len, SIZE_SAFETY_LIMIT),
+            Err(_) => {
+                let level = self.list_max_listpack_size.unsigned_abs().min(5) - 1;
+                (usize::MAX, 4096 << level)
+            }
+        };
+        if shrinking {
+            len <= max_len / 2 && bytes <= max_bytes / 2
+        } else {
+            len <= max_len && bytes <= max_bytes
+        }
+    }
+}
+
+/// One of two iterators, for collections iterated differently in each encoding
+enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L: Iterator, R: Iterator<Item = L::Item>> Iterator for Either<L, R> {
+    type Item = L::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Either::Left(iter) => iter.next(),
+            Either::Right(iter) => iter.next(),
+        }
+    }
+}
+
+impl<L: DoubleEndedIterator, R: DoubleEndedIterator<Item = L::Item>> DoubleEndedIterator
+    for Either<L, R>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            Either::Left(iter) => iter.next_back(),
+            Either::Right(iter) => iter.next_back(),
+        }
+    }
+}
+
+/// A list, packed in a single listpack while it is small
+#[derive(Debug, Clone)]
+pub enum List {
+    Listpack(Listpack),
+    Quicklist(Quicklist),
+}
+
+impl Default for List {
+    fn default() -> Self {
+        List::Listpack(Listpack::new())
+    }
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        match self {
+            List::Listpack(listpack) => listpack.len(),
+            List::Quicklist(quicklist) => quicklist.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            List::Listpack(listpack) => listpack.is_empty(),
+            List::Quicklist(quicklist) => quicklist.len() == 0,
+        }
+    }
+
+    /// Up to `count` elements from index `start` on
+    pub fn range(&self, start: usize, count: usize) -> Vec<Vec<u8>> {
+        match self {
+            List::Listpack(listpack) => listpack
+                .iter()
+                .skip(start)
+                .take(count)
+                .map(Element::to_vec)
+                .collect(),
+            List::Quicklist(quicklist) => quicklist.range(start, count),
+        }
+    }
+
+    pub fn push_back(&mut self, element: Vec<u8>, limits: &EncodingLimits) {
+        self.grow(&element, limits);
+        match self {
+            List::Listpack(listpack) => listpack.push_back(Element::from_bytes(&element)),
+            List::Quicklist(quicklist) => quicklist.push_back(&element, limits),
+        }
+    }
+
+    pub fn push_front(&mut self, element: Vec<u8>, limits: &EncodingLimits) {
+        self.grow(&element, limits);
+        match self {
+            List::Listpack(listpack) => listpack.push_front(Element::from_bytes(&element)),
+            List::Quicklist(quicklist) => quicklist.push_front(&element, limits),
+        }
+    }
+
+    pub fn pop_back(&mut self, limits: &EncodingLimits) -> Option<Vec<u8>> {
+        let element = match self {
+            List::Listpack(listpack) => listpack.pop_back(),
+            List::Quicklist(quicklist) => quicklist.pop_back(limits),
+        };
+        self.shrink(limits);
+        element
+    }
+
+    pub fn pop_front(&mut self, limits: &EncodingLimits) -> Option<Vec<u8>> {
+        let element = match self {
+            List::Listpack(listpack) => listpack.pop_front(),
+            List::Quicklist(quicklist) => quicklist.pop_front(limits),
+        };
+        self.shrink(limits);
+        element
+    }
+
+    /// Convert to a quicklist if adding `element` would take the listpack over the limits
+    fn grow(&mut self, element: &[u8], limits: &EncodingLimits) {
+        if let List::Listpack(listpack) = self {
+            // The element itself and at most 6 bytes of encoding and length
+            let bytes = listpack.as_bytes().len() + element.len() + 6;
+            if !limits.list_fits(listpack.len() + 1, bytes, false) {
+                *self = List::Quicklist(Quicklist::from_listpack(std::mem::take(listpack)));
+            }
+        }
+    }
+
+    /// Convert back to a listpack once the quicklist is down to a node well under the limits
+    fn shrink(&mut self, limits: &EncodingLimits) {
+        if let List::Quicklist(quicklist) = self {
+            let listpack = quicklist
+                .single_node()
+                .filter(|listpack| {
+                    limits.list_fits(listpack.len(), listpack.as_bytes().len(), true)
+                })
+                .map(Cow::into_owned);
+            if let Some(listpack) = listpack {
+                *self = List::Listpack(listpack);
+            }
+        }
+    }
+}
+
+/// A hash, packed in a listpack of alternating fields and values while it is small
+#[derive(Debug, Clone)]
+pub enum Hash {
+    Listpack(Listpack),
+    Hashtable(Dict<Vec<u8>, Vec<u8>>),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::Listpack(Listpack::new())
+    }
+}
+
+impl Hash {
+    pub fn iter(&self) -> impl Iterator<Item = (Cow<'_, [u8]>, Cow<'_, [u8]>)> {
+        match self {
+            Hash::Listpack(listpack) => Either::Left(
+                listpack
+                    .pairs()
+                    .map(|(field, value)| (field.to_bytes(), value.to_bytes())),
+            ),
+            Hash::Hashtable(hash) => Either::Right(hash.iter().map(|(field, value)| {
+                (
+                    Cow::Borrowed(field.as_slice()),
+                    Cow::Borrowed(value.as_slice()),
+                )
+            })),
+        }
+    }
+
+    /// Set a field, converting to a hashtable past the limits. Returns `true` if it was added.
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>, limits: &EncodingLimits) -> bool {
+        let listpack = match self {
+            Hash::Listpack(listpack) => listpack,
+            Hash::Hashtable(hash) => return hash.insert(field, value).is_none(),
+        };
+        let existing = listpack
+            .pairs()
+            .position(|(candidate, _)| candidate.to_bytes() == field.as_slice());
+        let fits = field.len() <= limits.hash_max_listpack_value
+            && value.len() <= limits.hash_max_listpack_value
+            && (existing.is_some() || listpack.len() / 2 < limits.hash_max_listpack_entries);
+        match existing {
+            Some(index) if fits => listpack.replace(2 * index + 1, Element::from_bytes(&value)),
+            None if fits => {
+                listpack.push_back(Element::from_bytes(&field));
+                listpack.push_back(Element::from_bytes(&value));
+            }
+            _ => {
+                let mut hash: Dict<_, _> = listpack
+                    .pairs()
+                    .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                    .collect();
+                hash.insert(field, value);
+                *self = Hash::Hashtable(hash);
+            }
+        }
+        existing.is_none()
+    }
+}
+
+/// A set, packed in an intset while it only holds integers or a listpack while it is small
+#[derive(Debug, Clone)]
+pub enum Set {
+    Intset(Intset),
+    Listpack(Listpack),
+    Hashtable(Dict<Vec<u8>, ()>),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Intset(Intset::new())
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Intset(intset) => intset.len(),
+            Set::Listpack(listpack) => listpack.len(),
+            Set::Hashtable(set) => set.len(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, [u8]>> {
+        match self {
+            Set::Intset(intset) => Either::Left(
+                intset
+                    .iter()
+                    .map(|n| Cow::Owned(n.to_string().into_bytes())),
+            ),
+            Set::Listpack(listpack) => {
+                Either::Right(Either::Left(listpack.iter().map(Element::to_bytes)))
+            }
+            Set::Hashtable(set) => Either::Right(Either::Right(
+                set.keys().map(|member| Cow::Borrowed(member.as_slice())),
+            )),
+        }
+    }
+
+    /// Add a member, converting to a wider encoding as needed. Returns `true` if it was added.
+    pub fn insert(&mut self, member: Vec<u8>, limits: &EncodingLimits) -> bool {
+        let fits_listpack = self.len() < limits.set_max_listpack_entries
+            && member.len() <= limits.set_max_listpack_value;
+        match self {
+            Set::Intset(intset) => match parse_int(&member) {
+                Some(n) if intset.contains(n) => return false,
+                Some(n) if intset.len() < limits.set_max_intset_entries => {
+                    return intset.insert(n);
+                }
+                // Members that are not integers, or too many of them, need a listpack
+                // if they fit in one
+                _ if fits_listpack => {
+                    let mut members: Vec<_> = intset.iter().map(Element::Int).collect();
+                    members.push(Element::from_bytes(&member));
+                    *self = Set::Listpack(Listpack::from_elements(&members));
+                    return true;
+                }
+                _ => {}
+            },
+            Set::Listpack(listpack) => {
+                if listpack
+                    .iter()
+                    .any(|candidate| candidate.to_bytes() == member.as_slice())
+                {
+                    return false;
+                }
+                if fits_listpack {
+                    listpack.push_back(Element::from_bytes(&member));
+                    return true;
+                }
+            }
+            Set::Hashtable(set) => return set.insert(member, ()).is_none(),
+        }
+        let mut set: Dict<_, _> = self
+            .iter()
+            .map(|member| (member.into_owned(), ()))
+            .collect();
+        set.insert(member, ());
+        *self = Set::Hashtable(set);
+        true
+    }
+}
+
+/// The score of a sorted set listpack entry, stored as an integer when it is one
+fn listpack_score(element: Element) -> f64 {
+    match element {
+        #[allow(clippy::cast_precision_loss)]
+        Element::Int(n) => n as f64,
+        Element::Str(s) => std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default(),
+    }
+}
+
+/// A sorted set, packed in a listpack of members followed by their score while it is small
+#[derive(Debug, Clone)]
+pub enum SortedSet {
+    /// Members and scores in the order of the set
+    Listpack(Listpack),
+    Skiplist(Skiplist),
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet::Listpack(Listpack::new())
+    }
+}
+
+impl SortedSet {
+    /// Members and their scores, from the lowest score
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (Cow<'_, [u8]>, f64)> {
+        match self {
+            SortedSet::Listpack(listpack) => Either::Left(
+                listpack
+                    .pairs()
+                    .map(|(member, score)| (member.to_bytes(), listpack_score(score))),
+            ),
+            SortedSet::Skiplist(zset) => Either::Right(
+                zset.iter()
+                    .map(|(member, score)| (Cow::Borrowed(member), score)),
+            ),
+        }
+    }
+
+    /// Add a member or update its score, converting to a skiplist past the limits.
+    /// Returns `true` if it was added.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64, limits: &EncodingLimits) -> bool {
+        let listpack = match self {
+            SortedSet::Listpack(listpack) => listpack,
+            SortedSet::Skiplist(zset) => return zset.insert(member, score),
+        };
+        let existing = listpack
+            .pairs()
+            .position(|(candidate, _)| candidate.to_bytes() == member.as_slice());
+        let fits = member.len() <= limits.zset_max_listpack_value
+            && (existing.is_some() || listpack.len() / 2 < limits.zset_max_listpack_entries);
+        if !fits {
+            let mut zset = Skiplist::default();
+            for (member, score) in listpack.pairs() {
+                zset.insert(member.to_vec(), listpack_score(score));
+            }
+            let added = zset.insert(member, score);
+            *self = SortedSet::Skiplist(zset);
+            return added;
+        }
+        if let Some(index) = existing {
+            listpack.remove(2 * index);
+            listpack.remove(2 * index);
+        }
+        let position = listpack
+            .pairs()
+            .position(|(candidate, candidate_score)| {
+                (Score(listpack_score(candidate_score)), candidate.to_bytes())
+                    > (Score(score), Cow::Borrowed(member.as_slice()))
+            })
+            .unwrap_or(listpack.len() / 2);
+        let score = score.to_string();
+        listpack.insert(2 * position, Element::from_bytes(&member));
+        listpack.insert(2 * position + 1, Element::from_bytes(score.as_bytes()));
+        existing.is_none()
+    }
+}
+
+/// Members with a score, ordered by score and then by member
+#[derive(Debug, Clone, Default)]
+pub struct Skiplist {
+    scores: Dict<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl Skiplist {
+    /// Add a member or update its score, returns `true` if it was added
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Members and their scores, from the lowest score
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Call `f` with the members of the next bucket from `cursor` on, see [`Dict::scan`]
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(&[u8], f64)) -> u64 {
+        self.scores.scan(cursor, |member, score| f(member, *score))
+    }
+}
+
+/// The error returned when a command is used against a key of another type
+pub fn wrong_type() -> RespData {
+    RespData::simple_error(
+        "WRONGTYPE",
+        "Operation against a key holding the wrong kind of value",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_conversions() {
+        let limits = EncodingLimits {
+            list_max_listpack_size: 4,
+            set_max_intset_entries: 2,
+            zset_max_listpack_entries: 3,
+            ..EncodingLimits::default()
+        };
+        let mut list = List::default();
+        for i in 0..5 {
+            list.push_back(i.to_string().into_bytes(), &limits);
+        }
+        assert!(matches!(list, List::Quicklist(_)));
+        assert_eq!(list.pop_back(&limits), Some(b"4".to_vec()));
+        list.pop_back(&limits);
+        assert!(matches!(list, List::Quicklist(_)));
+        // Back to a listpack once down to a node at half the limit
+        list.pop_back(&limits);
+        assert!(matches!(list, List::Listpack(_)));
+        assert_eq!(list.range(0, 10), [b"0".to_vec(), b"1".to_vec()]);
+
+        let mut set = Set::default();
+        assert!(set.insert(b"2".to_vec(), &limits));
+        assert!(set.insert(b"1".to_vec(), &limits));
+        assert!(!set.insert(b"2".to_vec(), &limits));
+        assert!(matches!(set, Set::Intset(_)));
+        assert!(set.insert(b"3".to_vec(), &limits));
+        assert!(matches!(set, Set::Listpack(_)));
+        assert!(set.insert(vec![b'x'; 65], &limits));
+        assert!(matches!(set, Set::Hashtable(_)));
+        assert_eq!(set.len(), 4);
+
+        let mut zset = SortedSet::default();
+        assert!(zset.insert(b"b".to_vec(), 2.0, &limits));
+        assert!(zset.insert(b"a".to_vec(), 2.0, &limits));
+        assert!(zset.insert(b"c".to_vec(), 0.5, &limits));
+        assert!(!zset.insert(b"b".to_vec(), -1.0, &limits));
+        assert!(matches!(zset, SortedSet::Listpack(_)));
+        let ordered = |zset: &SortedSet| {
+            zset.iter()
+                .map(|(member, score)| (member.into_owned(), score))
+                .collect::<Vec<_>>()
+        };
+        let expected = [
+            (b"b".to_vec(), -1.0),
+            (b"c".to_vec(), 0.5),
+            (b"a".to_vec(), 2.0),
+        ];
+        assert_eq!(ordered(&zset), expected);
+        zset.insert(b"d".to_vec(), 3.0, &limits);
+        assert!(matches!(zset, SortedSet::Skiplist(_)));
+        assert_eq!(ordered(&zset)[..3], expected);
+    }
+}